@@ -0,0 +1,271 @@
+//! The intrinsics that the atomic operators are translated to.
+//! See `wasm::atomics`.
+//!
+//! They only work on the first memory, since the threads
+//! proposal only allows atomic operators on that one. The
+//! compiled code checks the address of stores, rmw operators
+//! and `cmpxchg` before it calls them, so those take the
+//! native address and don't check it again.
+
+use object::thread::{Thread, State};
+use object::process::Waiter;
+use arch::cpu::{Local, IrqController};
+use wasm::VmCtx;
+use wasm::atomics::RmwOp;
+use sync::atomic::{Atomic, Ordering};
+use cranelift_codegen::ir::TrapCode;
+use alloc::vec::Vec;
+use core::{mem, cmp, u32};
+use time;
+
+/// What `atomic.wait` returns.
+const WAIT_WOKEN: u32 = 0;
+const WAIT_NOT_EQUAL: u32 = 1;
+const WAIT_TIMED_OUT: u32 = 2;
+
+/// The atomic at `addr` in the first memory, if it's
+/// in bounds and aligned to its size.
+fn atomic_at<T>(addr: u64, vmctx: &VmCtx) -> Option<&Atomic<T>> {
+    let memory = vmctx.data().user_data.instance.memories.first()?;
+
+    if addr > u32::MAX as u64 || addr % mem::size_of::<T>() as u64 != 0 {
+        return None;
+    }
+
+    memory.carve(addr as u32)
+}
+
+/// Cranelift doesn't have a trap code for unaligned
+/// atomic accesses, so they're reported as out of bounds.
+fn trap(vmctx: &VmCtx) -> u64 {
+    vmctx.data().user_data.process.handle_trap(TrapCode::HeapOutOfBounds);
+    0
+}
+
+/// The atomic at the native address `ptr`, which the
+/// compiled code checked is in bounds and aligned.
+unsafe fn checked_atomic<'a, T>(ptr: u64) -> &'a Atomic<T> {
+    &*(ptr as *const Atomic<T>)
+}
+
+fn store(ptr: u64, width: u32, value: u64) {
+    unsafe {
+        match width {
+            1 => checked_atomic::<u8>(ptr).store(value as u8, Ordering::SeqCst),
+            2 => checked_atomic::<u16>(ptr).store(value as u16, Ordering::SeqCst),
+            4 => checked_atomic::<u32>(ptr).store(value as u32, Ordering::SeqCst),
+            _ => checked_atomic::<u64>(ptr).store(value, Ordering::SeqCst),
+        }
+    }
+}
+
+macro_rules! rmw {
+    ($atomic:expr, $op:expr, $value:expr) => {
+        (match $op {
+            RmwOp::Add => $atomic.fetch_add($value, Ordering::SeqCst),
+            RmwOp::Sub => $atomic.fetch_sub($value, Ordering::SeqCst),
+            RmwOp::And => $atomic.fetch_and($value, Ordering::SeqCst),
+            RmwOp::Or => $atomic.fetch_or($value, Ordering::SeqCst),
+            RmwOp::Xor => $atomic.fetch_xor($value, Ordering::SeqCst),
+            RmwOp::Xchg => $atomic.swap($value, Ordering::SeqCst),
+        }) as u64
+    };
+}
+
+fn rmw(ptr: u64, width: u32, op: u32, value: u64, vmctx: &VmCtx) -> u64 {
+    let op = match RmwOp::from_u32(op) {
+        Some(op) => op,
+        None => return trap(vmctx),
+    };
+
+    unsafe {
+        match width {
+            1 => rmw!(checked_atomic::<u8>(ptr), op, value as u8),
+            2 => rmw!(checked_atomic::<u16>(ptr), op, value as u16),
+            4 => rmw!(checked_atomic::<u32>(ptr), op, value as u32),
+            _ => rmw!(checked_atomic::<u64>(ptr), op, value),
+        }
+    }
+}
+
+macro_rules! cmpxchg {
+    ($atomic:expr, $expected:expr, $replacement:expr) => {
+        match $atomic.compare_exchange($expected, $replacement, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(old) | Err(old) => old as u64,
+        }
+    };
+}
+
+fn cmpxchg(ptr: u64, width: u32, expected: u64, replacement: u64) -> u64 {
+    unsafe {
+        match width {
+            1 => cmpxchg!(checked_atomic::<u8>(ptr), expected as u8, replacement as u8),
+            2 => cmpxchg!(checked_atomic::<u16>(ptr), expected as u16, replacement as u16),
+            4 => cmpxchg!(checked_atomic::<u32>(ptr), expected as u32, replacement as u32),
+            _ => cmpxchg!(checked_atomic::<u64>(ptr), expected, replacement),
+        }
+    }
+}
+
+pub extern fn atomic_store32(ptr: u64, width: u32, value: u32, _vmctx: &VmCtx) {
+    store(ptr, width, value as u64)
+}
+
+pub extern fn atomic_store64(ptr: u64, width: u32, value: u64, _vmctx: &VmCtx) {
+    store(ptr, width, value)
+}
+
+pub extern fn atomic_rmw32(ptr: u64, width: u32, op: u32, value: u32, vmctx: &VmCtx) -> u32 {
+    rmw(ptr, width, op, value as u64, vmctx) as u32
+}
+
+pub extern fn atomic_rmw64(ptr: u64, width: u32, op: u32, value: u64, vmctx: &VmCtx) -> u64 {
+    rmw(ptr, width, op, value, vmctx)
+}
+
+pub extern fn atomic_cmpxchg32(ptr: u64, width: u32, expected: u32, replacement: u32, _vmctx: &VmCtx) -> u32 {
+    cmpxchg(ptr, width, expected as u64, replacement as u64) as u32
+}
+
+pub extern fn atomic_cmpxchg64(ptr: u64, width: u32, expected: u64, replacement: u64, _vmctx: &VmCtx) -> u64 {
+    cmpxchg(ptr, width, expected, replacement)
+}
+
+/// Block the current thread until it's notified at `addr`, or `timeout`
+/// nanoseconds pass, unless the value there isn't `expected`. A negative
+/// timeout waits forever.
+fn wait<T>(addr: u64, expected: T, timeout: i64, vmctx: &VmCtx) -> u32
+where
+    T: Copy + PartialEq
+{
+    let user_data = &vmctx.data().user_data;
+    let process = &user_data.process;
+
+    // Nothing else could change an unshared memory, so
+    // waiting on one is a trap instead of a deadlock.
+    let shared = process.code().module().memories.first()
+        .map_or(false, |memory| memory.shared);
+
+    let atomic = match atomic_at::<T>(addr, vmctx) {
+        Some(atomic) if shared => atomic,
+        _ => return trap(vmctx) as u32,
+    };
+
+    let key = addr as u32;
+    let deadline = if timeout < 0 {
+        None
+    } else {
+        Some(time::monotonic().saturating_add(timeout as u64))
+    };
+
+    let current_thread = Thread::current();
+    let local_id = current_thread.local_id();
+
+    // `notify` takes the lock too, so it can't
+    // come in between the check and the wait.
+    let mut wait_map = process.wait_map().lock();
+
+    if atomic.load(Ordering::SeqCst) != expected {
+        return WAIT_NOT_EQUAL;
+    }
+
+    wait_map
+        .entry(key)
+        .or_insert_with(Vec::new)
+        .push(Waiter {
+            thread: local_id,
+            timed: deadline.is_some(),
+        });
+
+    loop {
+        match deadline {
+            // The timer is armed before the lock is dropped,
+            // so `notify` can always cancel it.
+            Some(deadline) => unsafe {
+                IrqController::disable();
+                Thread::arm_timer(deadline);
+            },
+            None => current_thread.set_state(State::Blocked),
+        }
+
+        drop(wait_map);
+        Thread::yield_now();
+
+        wait_map = process.wait_map().lock();
+
+        // `notify` removes the threads that it wakes.
+        let position = wait_map.get(&key)
+            .and_then(|waiters| waiters.iter().position(|waiter| waiter.thread == local_id));
+
+        let position = match position {
+            Some(position) => position,
+            None => return WAIT_WOKEN,
+        };
+
+        if deadline.map_or(false, |deadline| time::monotonic() >= deadline) {
+            let now_empty = wait_map.get_mut(&key).map_or(false, |waiters| {
+                waiters.remove(position);
+                waiters.is_empty()
+            });
+
+            if now_empty {
+                wait_map.remove(&key);
+            }
+
+            return WAIT_TIMED_OUT;
+        }
+    }
+}
+
+pub extern fn atomic_wait32(addr: u64, expected: u32, timeout: i64, vmctx: &VmCtx) -> u32 {
+    wait(addr, expected, timeout, vmctx)
+}
+
+pub extern fn atomic_wait64(addr: u64, expected: u64, timeout: i64, vmctx: &VmCtx) -> u32 {
+    wait(addr, expected, timeout, vmctx)
+}
+
+/// Wake up to `count` of the threads waiting at `addr`, in
+/// the order that they started waiting. Returns how many.
+pub extern fn atomic_notify(addr: u64, count: u32, vmctx: &VmCtx) -> u32 {
+    if atomic_at::<u32>(addr, vmctx).is_none() {
+        return trap(vmctx) as u32;
+    }
+
+    let process = &vmctx.data().user_data.process;
+    let key = addr as u32;
+
+    let mut wait_map = process.wait_map().lock();
+
+    let woken = match wait_map.get_mut(&key) {
+        Some(waiters) => {
+            let woken = cmp::min(count as usize, waiters.len());
+            let thread_list = process.thread_list().read();
+
+            for waiter in waiters.drain(..woken) {
+                if let Some(thread) = thread_list.get(waiter.thread) {
+                    // If the timer of a timed waiter already
+                    // expired, that already resumed it.
+                    let thread_ptr = &**thread as *const Thread as *mut Thread;
+                    if !waiter.timed || Local::cancel_timer(thread_ptr) {
+                        thread.resume();
+                    }
+                }
+            }
+
+            woken
+        },
+        None => 0,
+    };
+
+    if wait_map.get(&key).map_or(false, |waiters| waiters.is_empty()) {
+        wait_map.remove(&key);
+    }
+
+    drop(wait_map);
+
+    // One of the woken threads may be more important.
+    unsafe { Local::preempt_if_needed(); }
+
+    woken as u32
+}
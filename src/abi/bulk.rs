@@ -0,0 +1,185 @@
+//! The intrinsics that the bulk memory operators are translated to.
+//! See `wasm::bulk`.
+//!
+//! Each one checks all of its bounds before it writes anything,
+//! so an operation that traps doesn't do part of its work.
+
+use wasm::{VmCtx, TableEntry};
+use wasm::module::TableElement;
+use cranelift_codegen::ir::TrapCode;
+use alloc::vec::Vec;
+use core::ptr;
+
+extern "C" {
+    fn erms_memcpy(dest: *mut u8, src: *const u8, size: usize);
+    fn erms_memset(dest: *mut u8, value: u8, size: usize);
+}
+
+fn trap(vmctx: &VmCtx, trap_code: TrapCode) {
+    vmctx.data().user_data.process.handle_trap(trap_code);
+}
+
+/// The end of `len` items from `start`, if that's at most `size`.
+fn range_end(start: u32, len: u32, size: usize) -> Option<usize> {
+    (start as usize).checked_add(len as usize)
+        .filter(|&end| end <= size)
+}
+
+pub extern fn memory_init(dst: u32, src: u32, len: u32, segment: u32, vmctx: &VmCtx) {
+    let user_data = &vmctx.data().user_data;
+    let module = user_data.process.code().module();
+
+    let dropped = user_data.instance.dropped_segments.read().data
+        .get(segment as usize)
+        .cloned()
+        .unwrap_or(true);
+
+    // A dropped segment acts like an empty one.
+    let data: &[u8] = match module.passive_data.get(segment as usize) {
+        Some(data) if !dropped => data,
+        _ => &[],
+    };
+
+    let src_end = range_end(src, len, data.len());
+    let dst_slice = user_data.instance.memories.first()
+        .and_then(|memory| memory.carve_slice_mut(dst, len));
+
+    match (src_end, dst_slice) {
+        (Some(src_end), Some(dst_slice)) => unsafe {
+            let src_slice = &data[src as usize..src_end];
+            erms_memcpy(dst_slice.as_mut_ptr(), src_slice.as_ptr(), src_slice.len());
+        },
+        _ => trap(vmctx, TrapCode::HeapOutOfBounds),
+    }
+}
+
+pub extern fn data_drop(segment: u32, vmctx: &VmCtx) {
+    let instance = &vmctx.data().user_data.instance;
+
+    if let Some(dropped) = instance.dropped_segments.write().data.get_mut(segment as usize) {
+        *dropped = true;
+    }
+}
+
+pub extern fn memory_copy(dst: u32, src: u32, len: u32, vmctx: &VmCtx) {
+    let memory = vmctx.data().user_data.instance.memories.first();
+
+    let src_ptr = memory.and_then(|memory| memory.carve_slice(src, len))
+        .map(|slice| slice.as_ptr());
+    let dst_ptr = memory.and_then(|memory| memory.carve_slice_mut(dst, len))
+        .map(|slice| slice.as_mut_ptr());
+
+    match (src_ptr, dst_ptr) {
+        (Some(src_ptr), Some(dst_ptr)) => unsafe {
+            // `rep movsb` copies forwards, which overwrites the
+            // source before it's read if the destination starts
+            // inside of it.
+            if dst > src && dst - src < len {
+                ptr::copy(src_ptr, dst_ptr, len as usize);
+            } else {
+                erms_memcpy(dst_ptr, src_ptr, len as usize);
+            }
+        },
+        _ => trap(vmctx, TrapCode::HeapOutOfBounds),
+    }
+}
+
+pub extern fn memory_fill(dst: u32, value: u32, len: u32, vmctx: &VmCtx) {
+    let dst_slice = vmctx.data().user_data.instance.memories.first()
+        .and_then(|memory| memory.carve_slice_mut(dst, len));
+
+    match dst_slice {
+        Some(dst_slice) => unsafe {
+            erms_memset(dst_slice.as_mut_ptr(), value as u8, dst_slice.len());
+        },
+        None => trap(vmctx, TrapCode::HeapOutOfBounds),
+    }
+}
+
+/// Write `entries` into `table` at `dst`, if they fit.
+fn write_table(vmctx: &VmCtx, table: u32, dst: u32, entries: &[TableEntry]) -> Option<()> {
+    let mut table = vmctx.data().user_data.instance.table(table as usize).ok()?.write();
+
+    let dst_end = range_end(dst, entries.len() as u32, table.len())?;
+    table[dst as usize..dst_end].copy_from_slice(entries);
+
+    Some(())
+}
+
+pub extern fn table_init(dst: u32, src: u32, len: u32, segment: u32, table: u32, vmctx: &VmCtx) {
+    let user_data = &vmctx.data().user_data;
+    let code = user_data.process.code();
+    let module = code.module();
+
+    let dropped = user_data.instance.dropped_segments.read().elements
+        .get(segment as usize)
+        .cloned()
+        .unwrap_or(true);
+
+    let elements: &[TableElement] = match module.passive_elements.get(segment as usize) {
+        Some(elements) if !dropped => elements,
+        _ => &[],
+    };
+
+    let entries: Option<Vec<TableEntry>> = range_end(src, len, elements.len())
+        .and_then(|src_end| {
+            elements[src as usize..src_end]
+                .iter()
+                .map(|element| match *element {
+                    // Like when a table is instantiated, only
+                    // local functions can be put in a table.
+                    TableElement::Function(func_index) => {
+                        let local_index = func_index.checked_sub(module.imported_funcs.len())?;
+                        Some(TableEntry {
+                            func: code.lookup_func(local_index).ok()? as usize,
+                            sig_id: module.sig_ids[module.functions[func_index]],
+                        })
+                    },
+                    TableElement::Trap() => Some(TableEntry::null()),
+                })
+                .collect()
+        });
+
+    let written = entries.and_then(|entries| write_table(vmctx, table, dst, &entries));
+
+    if written.is_none() {
+        trap(vmctx, TrapCode::TableOutOfBounds);
+    }
+}
+
+pub extern fn elem_drop(segment: u32, vmctx: &VmCtx) {
+    let instance = &vmctx.data().user_data.instance;
+
+    if let Some(dropped) = instance.dropped_segments.write().elements.get_mut(segment as usize) {
+        *dropped = true;
+    }
+}
+
+pub extern fn table_copy(dst: u32, src: u32, len: u32, dst_table: u32, src_table: u32, vmctx: &VmCtx) {
+    let instance = &vmctx.data().user_data.instance;
+
+    // The entries are copied out first, so that only one
+    // table is locked at a time, and so that they can overlap.
+    let entries = instance.table(src_table as usize).ok()
+        .and_then(|table| {
+            let table = table.read();
+            let src_end = range_end(src, len, table.len())?;
+            Some(table[src as usize..src_end].to_vec())
+        });
+
+    let written = entries.and_then(|entries| write_table(vmctx, dst_table, dst, &entries));
+
+    if written.is_none() {
+        trap(vmctx, TrapCode::TableOutOfBounds);
+    }
+}
+
+pub extern fn table_size(table: u32, vmctx: &VmCtx) -> u32 {
+    match vmctx.data().user_data.instance.table(table as usize) {
+        Ok(table) => table.read().len() as u32,
+        Err(_) => {
+            trap(vmctx, TrapCode::TableOutOfBounds);
+            0
+        },
+    }
+}
@@ -1,17 +1,29 @@
 use wasm::UserData;
+use memory::WasmMemory;
 use nabi::{Result, Error};
 use nebulet_derive::nebulet_abi;
 
+/// Map physical memory into the first memory. Returns its offset.
 #[nebulet_abi]
 pub fn physical_map(phys_address: u64, size: u32, data: &UserData) -> Result<u32> {
-    let memory = &data.instance.memories[0];
+    let memory = data.instance.memory(0)?;
+
+    memory.physical_map(phys_address, size as usize)
+        .map(|addr| addr as u32)
+}
+
+/// Map physical memory into the memory at `memory_index`.
+/// Returns its offset in that memory.
+#[nebulet_abi]
+pub fn physical_map_into(memory_index: u32, phys_address: u64, size: u32, data: &UserData) -> Result<u32> {
+    let memory = data.instance.memory(memory_index as usize)?;
 
     memory.physical_map(phys_address, size as usize)
         .map(|addr| addr as u32)
 }
 
 // pub fn physical_unmap(sip_ptr: u32, page_count: u32, data: &UserData) -> Result<u32> {
-//     let memory = &data.instance.memories[0];
+//     let memory = data.instance.memory(0)?;
 
 //     memory.physical_unmap(sip_ptr, page_count as usize)
 //         .map(|_| 0)
@@ -19,12 +31,27 @@ pub fn physical_map(phys_address: u64, size: u32, data: &UserData) -> Result<u32
 
 #[nebulet_abi]
 pub fn physical_alloc(size: u32, physical_addr_out: u32, data: &UserData) -> Result<u32> {
-    let memory = &data.instance.memories[0];
+    let memory = data.instance.memory(0)?;
+
+    alloc_into(memory, size, physical_addr_out, data)
+}
+
+/// Like `physical_alloc`, but the memory is allocated in the memory
+/// at `memory_index`. The physical address is still written to the
+/// first memory, like every other out parameter.
+#[nebulet_abi]
+pub fn physical_alloc_into(memory_index: u32, size: u32, physical_addr_out: u32, data: &UserData) -> Result<u32> {
+    let memory = data.instance.memory(memory_index as usize)?;
+
+    alloc_into(memory, size, physical_addr_out, data)
+}
 
+fn alloc_into(memory: &WasmMemory, size: u32, physical_addr_out: u32, data: &UserData) -> Result<u32> {
     let (physical_addr, sip_addr) = memory.physical_alloc(size as usize)?;
 
     {
-        let physical_addr_out = memory.carve_mut::<u64>(physical_addr_out)
+        let out_memory = data.instance.memory(0)?;
+        let physical_addr_out = out_memory.carve_mut::<u64>(physical_addr_out)
             .ok_or(Error::OUT_OF_BOUNDS)?;
         
         *physical_addr_out = physical_addr;
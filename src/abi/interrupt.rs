@@ -1,9 +1,13 @@
 use nabi::{Result, Error};
 use object::{UserHandle, HandleRights, Channel};
-use object::interrupt::{Interrupt, InterruptFlags};
+use object::interrupt::{Interrupt, InterruptOptions};
 use wasm::UserData;
 use nebulet_derive::nebulet_abi;
 
+/// Create an interrupt object that sends a packet on the
+/// channel every time the ISA irq delivered to `vector`
+/// by the 8259 PIC fires. It stays masked until it's
+/// acknowledged.
 #[nebulet_abi]
 pub fn interrupt_create(channel_handle: UserHandle<Channel>, vector: u32, user_data: &UserData) -> Result<u32> {
     let channel = {
@@ -11,13 +15,13 @@ pub fn interrupt_create(channel_handle: UserHandle<Channel>, vector: u32, user_d
 
         let handle = handle_table
             .get(channel_handle)?;
-        
+
         handle.check_rights(HandleRights::WRITE)?;
 
         handle.dispatcher().copy_ref()
     };
 
-    let interrupt = Interrupt::new(channel, InterruptFlags::UNMASK_PREWAIT | InterruptFlags::MASK_POSTWAIT, vector);
+    let interrupt = Interrupt::with_channel(channel, vector)?;
 
     interrupt.register()?;
 
@@ -31,16 +35,80 @@ pub fn interrupt_create(channel_handle: UserHandle<Channel>, vector: u32, user_d
     }
 }
 
+/// Create an interrupt object bound to the specified
+/// global system interrupt, or ISA irq if the `ISA`
+/// option is supplied. Virtual interrupts ignore the number.
+#[nebulet_abi]
+pub fn interrupt_create_ex(number: u32, options: u32, user_data: &UserData) -> Result<u32> {
+    let options = InterruptOptions::from_bits(options)
+        .ok_or(Error::INVALID_ARG)?;
+
+    let interrupt = Interrupt::new(number, options)?;
+
+    interrupt.register()?;
+
+    {
+        let mut handle_table = user_data.process.handle_table().write();
+        let flags = HandleRights::WRITE | HandleRights::READ;
+
+        handle_table
+            .allocate(interrupt, flags)
+            .map(|handle| handle.inner())
+    }
+}
+
+/// Block until the interrupt fires. The time at which it fired,
+/// in nanoseconds since boot, is written to `timestamp_out`.
+#[nebulet_abi]
+pub fn interrupt_wait(interrupt_handle: UserHandle<Interrupt>, timestamp_out: u32, user_data: &UserData) -> Result<u32> {
+    let interrupt = {
+        let handle_table = user_data.process.handle_table().read();
+
+        let handle = handle_table
+            .get(interrupt_handle)?;
+
+        handle.check_rights(HandleRights::READ)?;
+
+        // Blocking with a handle would keep the
+        // interrupt open after its last one is closed.
+        handle.dispatcher().copy_ref()
+    };
+
+    let timestamp = interrupt.wait()?;
+
+    let memory = user_data.instance.memory(0)?;
+    let out = memory.carve_mut::<u64>(timestamp_out)
+        .ok_or(Error::OUT_OF_BOUNDS)?;
+    *out = timestamp;
+
+    Ok(0)
+}
+
 #[nebulet_abi]
 pub fn interrupt_ack(interrupt_handle: UserHandle<Interrupt>, user_data: &UserData) -> Result<u32> {
     let handle_table = user_data.process.handle_table().read();
 
     let handle = handle_table
         .get(interrupt_handle)?;
-    
+
     handle.check_rights(HandleRights::WRITE)?;
-    
+
     handle.dispatcher().ack()?;
 
     Ok(0)
-}
\ No newline at end of file
+}
+
+/// Trigger a virtual interrupt.
+#[nebulet_abi]
+pub fn interrupt_trigger(interrupt_handle: UserHandle<Interrupt>, user_data: &UserData) -> Result<u32> {
+    let handle_table = user_data.process.handle_table().read();
+
+    let handle = handle_table
+        .get(interrupt_handle)?;
+
+    handle.check_rights(HandleRights::WRITE)?;
+
+    handle.dispatcher().trigger()?;
+
+    Ok(0)
+}
@@ -1,12 +1,12 @@
 use wasm::instance::VmCtx;
 
-/// `count` is the number of wasm pages to grow the memory by.
-pub extern fn grow_memory(count: u32, vmctx: &VmCtx) -> i32 {
+/// `count` is the number of wasm pages to grow the memory at `index` by.
+pub extern fn grow_memory(count: u32, index: u32, vmctx: &VmCtx) -> i32 {
     let instance = &vmctx
         .data()
         .user_data
         .instance;
-    let memory = &instance.memories[0];
+    let memory = &instance.memories[index as usize];
 
     if let Ok(old_count) = memory.grow(count as usize) {
         old_count as i32
@@ -15,12 +15,12 @@ pub extern fn grow_memory(count: u32, vmctx: &VmCtx) -> i32 {
     }
 }
 
-pub extern fn current_memory(vmctx: &VmCtx) -> u32 {
+pub extern fn current_memory(index: u32, vmctx: &VmCtx) -> u32 {
     let instance = &vmctx
         .data()
         .user_data
         .instance;
-    let memory = &instance.memories[0];
+    let memory = &instance.memories[index as usize];
 
     memory.page_count() as u32
 }
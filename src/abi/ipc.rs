@@ -1,4 +1,5 @@
-use object::{Channel, Stream, Message, HandleRights, UserHandle};
+use object::{Channel, Stream, Ring, Message, HandleRights, UserHandle};
+use object::stream::{self, StreamOptions, StreamShutdown};
 use wasm::UserData;
 use nabi::{Result, Error};
 use nebulet_derive::nebulet_abi;
@@ -18,7 +19,7 @@ pub fn channel_create(handle_tx_offset: u32, handle_rx_offset: u32, user_data: &
 
     {
         let instance = &user_data.instance;
-        let mut memory = &instance.memories[0];
+        let mut memory = instance.memory(0)?;
 
         let h_tx = memory.carve_mut::<u32>(handle_tx_offset)?;
         *h_tx = handle_tx.inner();
@@ -35,7 +36,7 @@ pub fn channel_create(handle_tx_offset: u32, handle_rx_offset: u32, user_data: &
 pub fn channel_send(channel_handle: UserHandle<Channel>, buffer_offset: u32, buffer_size: u32, user_data: &UserData) -> Result<u32> {
     let msg = {
         let instance = &user_data.instance;
-        let wasm_memory = &instance.memories[0];
+        let wasm_memory = instance.memory(0)?;
         let data = wasm_memory.carve_slice(buffer_offset, buffer_size)
             .ok_or(Error::INVALID_ARG)?;
         Message::new(data, vec![])?
@@ -68,7 +69,7 @@ pub fn channel_recv(channel_handle: UserHandle<Channel>, buffer_offset: u32, buf
     let first_msg_len = chan.first_msg_len()?;
 
     let instance = &user_data.instance;
-    let mut memory = &instance.memories[0];
+    let mut memory = instance.memory(0)?;
 
     let msg_size = memory.carve_mut::<u32>(msg_size_out)?;
     *msg_size = first_msg_len as u32;
@@ -94,7 +95,27 @@ pub fn channel_recv(channel_handle: UserHandle<Channel>, buffer_offset: u32, buf
 
 #[nebulet_abi]
 pub fn stream_create(handle_tx_offset: u32, handle_rx_offset: u32, user_data: &UserData) -> Result<u32> {
-    let (tx, rx) = Stream::new_pair();
+    create_stream_pair(StreamOptions::empty(), stream::DEFAULT_BUFFER_SIZE, handle_tx_offset, handle_rx_offset, user_data)
+}
+
+/// Create a stream pair with the specified options.
+/// If `buffer_size` is zero, the default buffer size is used.
+#[nebulet_abi]
+pub fn stream_create_ex(options: u32, buffer_size: u32, handle_tx_offset: u32, handle_rx_offset: u32, user_data: &UserData) -> Result<u32> {
+    let options = StreamOptions::from_bits(options)
+        .ok_or(Error::INVALID_ARG)?;
+
+    let buffer_size = if buffer_size == 0 {
+        stream::DEFAULT_BUFFER_SIZE
+    } else {
+        buffer_size as usize
+    };
+
+    create_stream_pair(options, buffer_size, handle_tx_offset, handle_rx_offset, user_data)
+}
+
+fn create_stream_pair(options: StreamOptions, buffer_size: usize, handle_tx_offset: u32, handle_rx_offset: u32, user_data: &UserData) -> Result<u32> {
+    let (tx, rx) = Stream::new_pair(options, buffer_size)?;
     
     let (handle_tx, handle_rx) = {
         let mut handle_table = user_data.process.handle_table().write();
@@ -107,7 +128,7 @@ pub fn stream_create(handle_tx_offset: u32, handle_rx_offset: u32, user_data: &U
 
     {
         let instance = &user_data.instance;
-        let mut memory = &instance.memories[0];
+        let mut memory = instance.memory(0)?;
 
         let h_tx = memory.carve_mut::<u32>(handle_tx_offset)?;
         *h_tx = handle_tx.inner();
@@ -122,7 +143,7 @@ pub fn stream_create(handle_tx_offset: u32, handle_rx_offset: u32, user_data: &U
 #[nebulet_abi]
 pub fn stream_write(stream_handle: UserHandle<Stream>, buffer_offset: u32, buffer_size: u32, written_size_out: u32, user_data: &UserData) -> Result<u32> {
     let instance = &user_data.instance;
-    let mut memory = &instance.memories[0];
+    let mut memory = instance.memory(0)?;
     let data = memory.carve_slice(buffer_offset, buffer_size)
         .ok_or(Error::INVALID_ARG)?;
     
@@ -149,15 +170,141 @@ pub fn stream_read(stream_handle: UserHandle<Stream>, buffer_offset: u32, buffer
     stream.check_rights(HandleRights::READ)?;
 
     let instance = &user_data.instance;
-    let mut memory = &instance.memories[0];
+    let mut memory = instance.memory(0)?;
 
     let mut data = memory.carve_slice_mut(buffer_offset, buffer_size)
         .ok_or(Error::INVALID_ARG)?;
 
-    let read_size = stream.read(&mut data)?;
+    let read_size = match stream.read(&mut data) {
+        Err(Error::BUFFER_TOO_SMALL) => {
+            // let the caller know how large the next datagram is
+            if let Some(datagram_len) = stream.next_datagram_len() {
+                let out = memory.carve_mut::<u32>(read_size_out)?;
+                *out = datagram_len as u32;
+            }
+            return Err(Error::BUFFER_TOO_SMALL);
+        },
+        res => res?,
+    };
 
     let out = memory.carve_mut::<u32>(read_size_out)?;
     *out = read_size as u32;
 
     Ok(0)
-}
\ No newline at end of file
+}
+
+/// Shut down the read half, the write half, or both
+/// halves of the specified stream.
+#[nebulet_abi]
+pub fn stream_shutdown(stream_handle: UserHandle<Stream>, how: u32, user_data: &UserData) -> Result<u32> {
+    let how = StreamShutdown::from_bits(how)
+        .ok_or(Error::INVALID_ARG)?;
+
+    let handle_table = user_data.process.handle_table().read();
+
+    let stream = handle_table.get(stream_handle)?;
+
+    if how.contains(StreamShutdown::READ) {
+        stream.check_rights(HandleRights::READ)?;
+    }
+    if how.contains(StreamShutdown::WRITE) {
+        stream.check_rights(HandleRights::WRITE)?;
+    }
+
+    stream.shutdown(how)?;
+
+    Ok(0)
+}
+/// Create a ring stream with a data area of `capacity` bytes.
+/// `capacity` must be a power of two.
+#[nebulet_abi]
+pub fn ring_create(capacity: u32, handle_tx_offset: u32, handle_rx_offset: u32, user_data: &UserData) -> Result<u32> {
+    let (tx, rx) = Ring::new_pair(capacity as usize)?;
+
+    // Both ends map the same memory, so
+    // they can both be read and written.
+    let (handle_tx, handle_rx) = {
+        let mut handle_table = user_data.process.handle_table().write();
+
+        (
+            handle_table.allocate(tx, HandleRights::all())?,
+            handle_table.allocate(rx, HandleRights::all())?,
+        )
+    };
+
+    {
+        let instance = &user_data.instance;
+        let memory = instance.memory(0)?;
+
+        let h_tx = memory.carve_mut::<u32>(handle_tx_offset)?;
+        *h_tx = handle_tx.inner();
+
+        let h_rx = memory.carve_mut::<u32>(handle_rx_offset)?;
+        *h_rx = handle_rx.inner();
+    }
+
+    Ok(0)
+}
+
+/// Map the specified ring into the linear memory of the current
+/// process, unless it's already mapped there. Returns the offset
+/// of the ring header.
+#[nebulet_abi]
+pub fn ring_map(ring_handle: UserHandle<Ring>, user_data: &UserData) -> Result<u32> {
+    let ring = {
+        let handle_table = user_data.process.handle_table().read();
+
+        handle_table.get(ring_handle)?
+    };
+
+    let memory = user_data.instance.memory(0)?;
+
+    ring.map_into(memory)
+        .map(|offset| offset as u32)
+}
+
+/// Map the specified ring into the memory at `memory_index`, unless
+/// it's already mapped there. Returns the offset of the ring header
+/// in that memory.
+#[nebulet_abi]
+pub fn ring_map_into(ring_handle: UserHandle<Ring>, memory_index: u32, user_data: &UserData) -> Result<u32> {
+    let ring = {
+        let handle_table = user_data.process.handle_table().read();
+
+        handle_table.get(ring_handle)?
+    };
+
+    let memory = user_data.instance.memory(memory_index as usize)?;
+
+    ring.map_into(memory)
+        .map(|offset| offset as u32)
+}
+
+/// Block until the other end of the ring advances its
+/// counter past `observed`. The reading end waits on the
+/// tail, the writing end waits on the head.
+#[nebulet_abi]
+pub fn ring_wait(ring_handle: UserHandle<Ring>, observed: u32, user_data: &UserData) -> Result<u32> {
+    let ring = {
+        let handle_table = user_data.process.handle_table().read();
+
+        handle_table.get(ring_handle)?
+    };
+
+    ring.wait(observed);
+
+    Ok(0)
+}
+
+/// Wake up any threads blocked on the other end of the ring.
+/// Returns the number of threads woken.
+#[nebulet_abi]
+pub fn ring_wake(ring_handle: UserHandle<Ring>, user_data: &UserData) -> Result<u32> {
+    let ring = {
+        let handle_table = user_data.process.handle_table().read();
+
+        handle_table.get(ring_handle)?
+    };
+
+    Ok(ring.wake() as u32)
+}
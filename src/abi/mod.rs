@@ -24,7 +24,13 @@ pub mod event;
 pub mod thread;
 /// ABIs for pretty fast exclusion
 pub mod pfex;
+/// Intrinsics for atomic operators
+pub mod atomic;
+/// Intrinsics for bulk memory operators
+pub mod bulk;
 /// ABIs for interfacing with generic objects
 pub mod object;
+/// ABIs for querying and controlling the system
+pub mod system;
 // /// ABIs for services
 // pub mod service;
\ No newline at end of file
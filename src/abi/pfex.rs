@@ -1,30 +1,89 @@
 use object::thread::{Thread, State};
+use object::process::{Process, Pfex};
+use common::table::TableSlot;
+use arch::cpu::Local;
+use task::scheduler::{Priority, LOWEST_PRIORITY};
 use wasm::VmCtx;
 use sync::atomic::{Atomic, Ordering};
-use sync::mpsc::IntrusiveMpsc;
+use cranelift_codegen::ir::TrapCode;
+use hashmap_core::HashMap;
+
+/// The thread that holds the pfex at `lock_offset`.
+fn pfex_owner(process: &Process, lock_offset: u32) -> TableSlot {
+    process.thread_list()
+        .read()
+        .iter()
+        .find(|thread| thread.held_pfexes().lock().contains(&lock_offset))
+        .map(|thread| thread.local_id())
+        .unwrap_or_else(TableSlot::invalid)
+}
+
+/// Returns whether `thread` held the pfex at `lock_offset`.
+fn forget_held(thread: &Thread, lock_offset: u32) -> bool {
+    let mut held_pfexes = thread.held_pfexes().lock();
+    let index = held_pfexes.iter().position(|&offset| offset == lock_offset);
+
+    match index {
+        Some(index) => {
+            held_pfexes.swap_remove(index);
+            true
+        },
+        None => false,
+    }
+}
+
+/// The highest priority of the threads waiting on
+/// the pfexes that `thread` still holds.
+fn lent_priority(thread: &Thread, pfex_map: &HashMap<u32, Pfex>) -> Priority {
+    thread.held_pfexes()
+        .lock()
+        .iter()
+        .filter_map(|offset| pfex_map.get(offset))
+        .map(|pfex| pfex.max_waiter_priority)
+        .max()
+        .unwrap_or(LOWEST_PRIORITY)
+}
 
 /// This will crash the process when the value_offset doesn't point to committed memory.
 /// While somewhat extreme, it is safe.
 pub extern fn pfex_acquire(lock_offset: u32, vmctx: &VmCtx) {
     let user_data = &vmctx.data().user_data;
-    let lock_ptr: *const Atomic<u32> = vmctx.fastpath_offset_ptr(lock_offset);
+    let lock_ptr: *const Atomic<u32> = match vmctx.fastpath_offset_ptr(lock_offset) {
+        Some(ptr) => ptr,
+        None => return user_data.process.handle_trap(TrapCode::HeapOutOfBounds),
+    };
     let lock = unsafe { &*lock_ptr };
 
     loop {
         let mut pfex_map = user_data.process.pfex_map().lock();
         let locked = lock.load(Ordering::Relaxed);
 
+        let current_thread = Thread::current();
+
         if locked == 0 {
             lock.store(1, Ordering::Release);
+            current_thread.held_pfexes().lock().push(lock_offset);
             break;
         } else {
-            let queue = pfex_map
+            // The pfex is only tracked once it's contended.
+            let pfex = pfex_map
                 .entry(lock_offset)
-                .or_insert(IntrusiveMpsc::new());
+                .or_insert_with(|| Pfex::new(pfex_owner(&user_data.process, lock_offset)));
+
+            // Lend our priority to the owner, so that threads with a
+            // priority in between can't keep it from releasing the pfex.
+            let priority = current_thread.priority();
+            if priority > pfex.max_waiter_priority {
+                pfex.max_waiter_priority = priority;
 
-            let current_thread = Thread::current();
+                if let Some(owner) = user_data.process.thread_list().read().get(pfex.owner) {
+                    if priority > owner.inherited_priority() {
+                        owner.set_inherited_priority(priority);
+                    }
+                }
+            }
 
-            unsafe { queue.push(current_thread); } // this must be first
+            unsafe { pfex.waiters.push(current_thread); } // this must be first
             current_thread.set_state(State::Blocked);
 
             // drop the lock on the pfex_map to avoid deadlocks
@@ -39,22 +98,59 @@ pub extern fn pfex_acquire(lock_offset: u32, vmctx: &VmCtx) {
 /// This will crash the process when the value_offset doesn't point to committed memory.
 /// While somewhat extreme, it is safe.
 pub extern fn pfex_release(lock_offset: u32, vmctx: &VmCtx) {
-    let lock_ptr: *const Atomic<u32> = vmctx.fastpath_offset_ptr(lock_offset);
+    let user_data = &vmctx.data().user_data;
+    let lock_ptr: *const Atomic<u32> = match vmctx.fastpath_offset_ptr(lock_offset) {
+        Some(ptr) => ptr,
+        None => return user_data.process.handle_trap(TrapCode::HeapOutOfBounds),
+    };
     let lock = unsafe { &*lock_ptr };
 
-    let user_data = &vmctx.data().user_data;
     let mut pfex_map = user_data.process.pfex_map().lock();
     let locked = lock.load(Ordering::Relaxed);
 
     if locked != 0 {
         lock.store(0, Ordering::Release);
-        if let Some(queue) = pfex_map.remove(&lock_offset) {
+        let pfex = pfex_map.remove(&lock_offset);
+
+        let current_thread: &Thread = Thread::current();
+
+        {
+            let thread_list = user_data.process.thread_list().read();
+
+            // It's normally released by the thread that acquired it,
+            // but any thread can release it for the owner.
+            let owner = if forget_held(current_thread, lock_offset) {
+                Some(current_thread)
+            } else {
+                thread_list
+                    .iter()
+                    .map(|thread| &**thread)
+                    .find(|thread| forget_held(thread, lock_offset))
+            };
+
+            // The owner only keeps the priority lent by waiters on the pfexes
+            // that it still holds, before anyone that should run first is woken.
+            if let Some(owner) = owner {
+                let inherited_priority = lent_priority(owner, &pfex_map);
+
+                if inherited_priority != owner.inherited_priority() {
+                    owner.set_inherited_priority(inherited_priority);
+                }
+            }
+        }
+
+        if let Some(pfex) = pfex {
             unsafe {
-                while let Some(thread) = queue.pop() {
+                while let Some(thread) = pfex.waiters.pop() {
                     (*thread).resume();
                 }
             }
         }
     }
+
+    drop(pfex_map);
+
+    // One of the woken threads may be more important.
+    unsafe { Local::preempt_if_needed(); }
     // at this point, the pfex is unlocked
 }
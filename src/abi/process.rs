@@ -1,7 +1,12 @@
-use object::{Process, Wasm, Channel, HandleRights, UserHandle};
+use object::{Process, Wasm, WasmStats, CompileFlags, Channel, SystemResource, HandleRights, UserHandle};
+use abi::system::check_system_resource;
+use wasm::CompileError;
+use wasm::cache::{self, CacheStats};
 use nabi::{Result, Error};
 use nebulet_derive::nebulet_abi;
 use wasm::UserData;
+use task::scheduler::Priority;
+use arch::cpu::Local;
 
 /// Create a process with the specified compiled code.
 #[nebulet_abi]
@@ -24,6 +29,10 @@ pub fn process_create(code_handle: UserHandle<Wasm>, channel_handle: UserHandle<
 
     let new_proc = Process::create(code.dispatcher().copy_ref())?;
 
+    // A process can't give its children more priority than it has.
+    new_proc.set_max_priority(user_data.process.max_priority());
+    new_proc.set_default_affinity(user_data.process.default_affinity());
+
     {
         let mut new_handle_table = new_proc.handle_table().write();
         let rights = HandleRights::READ;
@@ -61,11 +70,123 @@ pub fn process_start(proc_handle: UserHandle<Process>, user_data: &UserData) ->
     Ok(0)
 }
 
+/// Limit the priority that threads in the supplied process can be
+/// given. It can't be raised above the current process' maximum.
+#[nebulet_abi]
+pub fn process_set_max_priority(proc_handle: UserHandle<Process>, max_priority: u32, user_data: &UserData) -> Result<u32> {
+    if max_priority > user_data.process.max_priority() as u32 {
+        return Err(Error::ACCESS_DENIED);
+    }
+
+    let handle_table = user_data.process.handle_table().read();
+    let proc_ref = handle_table.get(proc_handle)?;
+
+    proc_ref
+        .check_rights(HandleRights::WRITE)?
+        .set_max_priority(max_priority as Priority);
+
+    Ok(0)
+}
+
+/// Set the cpus that new threads in the supplied process may run
+/// on, a bit for each cpu id. At least one of them has to be online.
+#[nebulet_abi]
+pub fn process_set_affinity(proc_handle: UserHandle<Process>, affinity: u64, user_data: &UserData) -> Result<u32> {
+    if affinity & Local::online_mask() == 0 {
+        return Err(Error::INVALID_ARG);
+    }
+
+    let handle_table = user_data.process.handle_table().read();
+    let proc_ref = handle_table.get(proc_handle)?;
+
+    proc_ref
+        .check_rights(HandleRights::WRITE)?
+        .set_default_affinity(affinity);
+
+    Ok(0)
+}
+
+/// Statistics about a process.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ProcessInfo {
+    /// Nanoseconds that threads in the process ran for,
+    /// including the ones that have exited.
+    pub cpu_time: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    pub thread_count: u32,
+    pub max_priority: u32,
+    /// The `CompileFlags` that the process' code was compiled with.
+    pub code_flags: u32,
+}
+
+/// Write a `ProcessInfo` about the supplied process to `info_out`.
+#[nebulet_abi]
+pub fn process_info(proc_handle: UserHandle<Process>, info_out: u32, user_data: &UserData) -> Result<u32> {
+    let info = {
+        let handle_table = user_data.process.handle_table().read();
+        let proc_ref = handle_table.get(proc_handle)?;
+        let process = proc_ref.check_rights(HandleRights::READ)?;
+
+        ProcessInfo {
+            cpu_time: process.run_time(),
+            voluntary_switches: process.voluntary_switches(),
+            involuntary_switches: process.involuntary_switches(),
+            thread_count: process.thread_list().read().len() as u32,
+            max_priority: process.max_priority() as u32,
+            code_flags: process.code().flags().bits(),
+        }
+    };
+
+    let memory = user_data.instance.memory(0)?;
+    let out = memory.carve_mut::<ProcessInfo>(info_out)
+        .ok_or(Error::OUT_OF_BOUNDS)?;
+    *out = info;
+
+    Ok(0)
+}
+
+/// The code runs in the kernel, so unless the caller holds the
+/// system resource, it's always verified and probes the stack.
+fn untrusted_flags(flags: CompileFlags) -> CompileFlags {
+    (flags | CompileFlags::VERIFY) - CompileFlags::NO_STACK_PROBES
+}
+
 /// Compile wasm bytecode into a Wasm.
 #[nebulet_abi]
 pub fn wasm_compile(buffer_offset: u32, buffer_size: u32, user_data: &UserData) -> Result<u32> {
+    compile_with_flags(buffer_offset, buffer_size, untrusted_flags(CompileFlags::empty()), user_data)
+}
+
+/// Compile wasm bytecode into a Wasm, like `wasm_compile`. `flags`
+/// are `CompileFlags`, which pick whether functions are compiled lazily
+/// and how much they're optimized. `VERIFY` is always set, and
+/// `NO_STACK_PROBES` is ignored; see `wasm_compile_privileged`.
+#[nebulet_abi]
+pub fn wasm_compile_flags(buffer_offset: u32, buffer_size: u32, flags: u32, user_data: &UserData) -> Result<u32> {
+    let flags = CompileFlags::from_bits(flags)
+        .ok_or(Error::INVALID_ARG)?;
+
+    compile_with_flags(buffer_offset, buffer_size, untrusted_flags(flags), user_data)
+}
+
+/// Compile wasm bytecode into a Wasm with exactly the
+/// `CompileFlags` in `flags`, which can turn off the
+/// verifier and stack probes. This is privileged.
+#[nebulet_abi]
+pub fn wasm_compile_privileged(resource_handle: UserHandle<SystemResource>, buffer_offset: u32, buffer_size: u32, flags: u32, user_data: &UserData) -> Result<u32> {
+    check_system_resource(resource_handle, user_data)?;
+
+    let flags = CompileFlags::from_bits(flags)
+        .ok_or(Error::INVALID_ARG)?;
+
+    compile_with_flags(buffer_offset, buffer_size, flags, user_data)
+}
+
+fn compile_with_flags(buffer_offset: u32, buffer_size: u32, flags: CompileFlags, user_data: &UserData) -> Result<u32> {
     let code_ref = {
-        let wasm_memory = &user_data.instance.memories[0];
+        let wasm_memory = user_data.instance.memory(0)?;
         let wasm_bytecode = wasm_memory.carve_slice(buffer_offset, buffer_size)
             .ok_or(Error::INVALID_ARG)?;
 
@@ -73,7 +194,7 @@ pub fn wasm_compile(buffer_offset: u32, buffer_size: u32, user_data: &UserData)
         // in the current process
         // let copied_wasm = wasm_bytecode.to_vec();
 
-        Wasm::compile(wasm_bytecode)?
+        Wasm::compile(wasm_bytecode, flags)?
     };
 
     {
@@ -84,3 +205,175 @@ pub fn wasm_compile(buffer_offset: u32, buffer_size: u32, user_data: &UserData)
             .map(|handle| handle.inner())
     }
 }
+
+/// Load code that the `aot` tool compiled ahead of time from
+/// the wasm in `wasm_offset`, so that it doesn't have to be
+/// compiled again. Fails if the artifact is corrupt, or wasn't
+/// compiled from that wasm.
+///
+/// The artifact is native code that runs in the kernel, and the
+/// checksum only catches corruption, so this is privileged.
+#[nebulet_abi]
+pub fn wasm_load_aot(resource_handle: UserHandle<SystemResource>, artifact_offset: u32, artifact_size: u32, wasm_offset: u32, wasm_size: u32, user_data: &UserData) -> Result<u32> {
+    check_system_resource(resource_handle, user_data)?;
+
+    let code_ref = {
+        let wasm_memory = user_data.instance.memory(0)?;
+        let artifact = wasm_memory.carve_slice(artifact_offset, artifact_size)
+            .ok_or(Error::INVALID_ARG)?;
+        let wasm_bytecode = wasm_memory.carve_slice(wasm_offset, wasm_size)
+            .ok_or(Error::INVALID_ARG)?;
+
+        Wasm::load_aot(artifact, wasm_bytecode)?
+    };
+
+    {
+        let mut handle_table = user_data.process.handle_table().write();
+        let rights = HandleRights::READ | HandleRights::TRANSFER;
+
+        handle_table.allocate(code_ref, rights)
+            .map(|handle| handle.inner())
+    }
+}
+
+/// Write the `CacheStats` of the compiled code cache to `stats_out`.
+/// The cache is shared by every process, so this is privileged.
+#[nebulet_abi]
+pub fn code_cache_stats(resource_handle: UserHandle<SystemResource>, stats_out: u32, user_data: &UserData) -> Result<u32> {
+    check_system_resource(resource_handle, user_data)?;
+
+    let memory = user_data.instance.memory(0)?;
+    let out = memory.carve_mut::<CacheStats>(stats_out)
+        .ok_or(Error::OUT_OF_BOUNDS)?;
+    *out = cache::stats();
+
+    Ok(0)
+}
+
+/// Write a `WasmStats` about the compiled code to `stats_out`.
+#[nebulet_abi]
+pub fn wasm_stats(code_handle: UserHandle<Wasm>, stats_out: u32, user_data: &UserData) -> Result<u32> {
+    let stats = {
+        let handle_table = user_data.process.handle_table().read();
+        let code_handle = handle_table.get(code_handle)?;
+
+        code_handle.check_rights(HandleRights::READ)?;
+
+        code_handle.dispatcher().stats()
+    };
+
+    let memory = user_data.instance.memory(0)?;
+    let out = memory.carve_mut::<WasmStats>(stats_out)
+        .ok_or(Error::OUT_OF_BOUNDS)?;
+    *out = stats;
+
+    Ok(0)
+}
+
+/// Drop everything in the compiled code cache, so that modules are
+/// compiled again. Processes keep the code that they're running.
+/// Returns the number of modules that were dropped.
+#[nebulet_abi]
+pub fn code_cache_flush(resource_handle: UserHandle<SystemResource>, user_data: &UserData) -> Result<u32> {
+    check_system_resource(resource_handle, user_data)?;
+
+    Ok(cache::flush() as u32)
+}
+
+/// Why a module didn't compile. Strings that
+/// don't fit into their buffers are cut off.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CompileDiagnostic {
+    /// A `CompileErrorKind`, or 0 if the module compiled.
+    pub kind: u32,
+    /// The function that the error is in, in the module's
+    /// function index space, or `u32::max_value()`.
+    pub func_index: u32,
+    /// The byte offset in the module, or `u32::max_value()`.
+    pub offset: u32,
+    pub module_len: u32,
+    pub field_len: u32,
+    pub message_len: u32,
+    /// The module and field of the import that the error is about.
+    pub module: [u8; 64],
+    pub field: [u8; 64],
+    pub message: [u8; 128],
+}
+
+impl CompileDiagnostic {
+    fn new() -> CompileDiagnostic {
+        CompileDiagnostic {
+            kind: 0,
+            func_index: u32::max_value(),
+            offset: u32::max_value(),
+            module_len: 0,
+            field_len: 0,
+            message_len: 0,
+            module: [0; 64],
+            field: [0; 64],
+            message: [0; 128],
+        }
+    }
+
+    fn from_error(err: &CompileError) -> CompileDiagnostic {
+        let mut diag = CompileDiagnostic::new();
+
+        diag.kind = err.kind as u32;
+        if let Some(func_index) = err.func_index {
+            diag.func_index = func_index as u32;
+        }
+        if let Some(offset) = err.offset {
+            diag.offset = offset as u32;
+        }
+        if let Some((ref module, ref field)) = err.import {
+            diag.module_len = copy_str(&mut diag.module, module);
+            diag.field_len = copy_str(&mut diag.field, field);
+        }
+        diag.message_len = copy_str(&mut diag.message, &err.message);
+
+        diag
+    }
+}
+
+/// Copy as much of `s` into `buf` as fits and return how much that was.
+fn copy_str(buf: &mut [u8], s: &str) -> u32 {
+    let len = s.len().min(buf.len());
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    len as u32
+}
+
+/// Compile wasm bytecode into a Wasm, like `wasm_compile`, and write
+/// a `CompileDiagnostic` to `diag_out` that says why if it fails.
+#[nebulet_abi]
+pub fn wasm_compile_ex(buffer_offset: u32, buffer_size: u32, diag_out: u32, user_data: &UserData) -> Result<u32> {
+    let wasm_memory = user_data.instance.memory(0)?;
+
+    let result = {
+        let wasm_bytecode = wasm_memory.carve_slice(buffer_offset, buffer_size)
+            .ok_or(Error::INVALID_ARG)?;
+
+        Wasm::compile(wasm_bytecode, untrusted_flags(CompileFlags::empty()))
+    };
+
+    let diag = match result {
+        Ok(_) => CompileDiagnostic::new(),
+        Err(ref err) => CompileDiagnostic::from_error(err),
+    };
+
+    {
+        let out = wasm_memory.carve_mut::<CompileDiagnostic>(diag_out)
+            .ok_or(Error::OUT_OF_BOUNDS)?;
+        *out = diag;
+    }
+
+    let code_ref = result?;
+
+    {
+        let mut handle_table = user_data.process.handle_table().write();
+        let rights = HandleRights::READ | HandleRights::TRANSFER;
+
+        handle_table.allocate(code_ref, rights)
+            .map(|handle| handle.inner())
+    }
+}
@@ -0,0 +1,148 @@
+use nabi::{Result, Error};
+use nebulet_derive::nebulet_abi;
+use object::{SystemResource, UserHandle, HandleRights};
+use arch::{acpi, power};
+use arch::cpu::{Local, CpuId};
+use arch::devices::ioapic;
+use wasm::UserData;
+use core::mem;
+
+/// A summary of the system that the kernel is running on.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SysInfo {
+    /// The number of processors that can be brought up.
+    pub cpu_count: u32,
+    pub io_apic_count: u32,
+    /// The number of global system interrupts.
+    pub gsi_count: u32,
+    pub acpi_revision: u32,
+    /// The physical address of the HPET's registers, or 0.
+    pub timer_base: u64,
+    /// The physical address of the configuration
+    /// space of the first PCIe segment, or 0.
+    pub ecam_base: u64,
+    pub ecam_start_bus: u32,
+    pub ecam_end_bus: u32,
+}
+
+/// Write a `SysInfo` to `info_out`.
+#[nebulet_abi]
+pub fn sysinfo(info_out: u32, user_data: &UserData) -> Result<u32> {
+    let tables = acpi::tables();
+
+    let mut info = SysInfo {
+        cpu_count: tables.processors().count() as u32,
+        io_apic_count: tables.io_apics().len() as u32,
+        gsi_count: ioapic::gsi_count(),
+        acpi_revision: tables.revision as u32,
+        timer_base: tables.timer_base().unwrap_or(0),
+        ..SysInfo::default()
+    };
+
+    if let Some(ecam) = tables.ecam() {
+        info.ecam_base = ecam.base_address;
+        info.ecam_start_bus = ecam.start_bus as u32;
+        info.ecam_end_bus = ecam.end_bus as u32;
+    }
+
+    let memory = user_data.instance.memory(0)?;
+    let out = memory.carve_mut::<SysInfo>(info_out)
+        .ok_or(Error::OUT_OF_BOUNDS)?;
+    *out = info;
+
+    Ok(0)
+}
+
+/// The number of cpus that are online. Their ids
+/// are the ones below this, starting at 0.
+#[nebulet_abi]
+pub fn cpu_count(_: &UserData) -> Result<u32> {
+    Ok(Local::count() as u32)
+}
+
+/// The id of the cpu that the current thread is running on.
+/// The thread may have moved by the time this returns,
+/// unless its affinity only allows the one cpu.
+#[nebulet_abi]
+pub fn cpu_id(_: &UserData) -> Result<u32> {
+    Ok(Local::current().cpu().id())
+}
+
+/// Statistics about a cpu.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CpuStats {
+    /// Nanoseconds spent running threads.
+    pub busy_time: u64,
+    /// Nanoseconds spent in the idle thread.
+    pub idle_time: u64,
+    pub context_switches: u64,
+    /// The number of threads that run on the cpu.
+    pub thread_count: u32,
+    pub cpu_id: u32,
+}
+
+/// Write a `CpuStats` for each cpu that's online, up to `max_count`
+/// of them, to the array at `stats_out`. Returns the number of
+/// cpus that are online.
+#[nebulet_abi]
+pub fn system_stats(stats_out: u32, max_count: u32, user_data: &UserData) -> Result<u32> {
+    let memory = user_data.instance.memory(0)?;
+    let cpu_count = Local::count() as u32;
+
+    for cpu_id in 0..cpu_count.min(max_count) {
+        let local = Local::get(cpu_id as CpuId)
+            .ok_or(Error::INTERNAL)?;
+
+        let offset = (cpu_id as usize * mem::size_of::<CpuStats>()) as u32;
+        let out = stats_out.checked_add(offset)
+            .and_then(|offset| memory.carve_mut::<CpuStats>(offset))
+            .ok_or(Error::OUT_OF_BOUNDS)?;
+
+        *out = CpuStats {
+            busy_time: local.busy_time(),
+            idle_time: local.idle_time(),
+            context_switches: local.context_switches(),
+            thread_count: local.scheduler().thread_count() as u32,
+            cpu_id,
+        };
+    }
+
+    Ok(cpu_count)
+}
+
+/// Fails unless `handle` is a writable handle to
+/// the system resource, which privileged ABIs require.
+pub fn check_system_resource(handle: UserHandle<SystemResource>, user_data: &UserData) -> Result<()> {
+    let handle_table = user_data.process.handle_table().read();
+
+    handle_table
+        .get(handle)?
+        .check_rights(HandleRights::WRITE)?;
+
+    Ok(())
+}
+
+/// Turn the system off. This only returns if it failed.
+#[nebulet_abi]
+pub fn system_poweroff(resource_handle: UserHandle<SystemResource>, user_data: &UserData) -> Result<u32> {
+    check_system_resource(resource_handle, user_data)?;
+
+    unsafe {
+        power::poweroff()?;
+    }
+
+    Ok(0)
+}
+
+/// Restart the system. This never returns
+/// if the caller is allowed to do it.
+#[nebulet_abi]
+pub fn system_reboot(resource_handle: UserHandle<SystemResource>, user_data: &UserData) -> Result<u32> {
+    check_system_resource(resource_handle, user_data)?;
+
+    unsafe {
+        power::reboot();
+    }
+}
@@ -1,5 +1,7 @@
 use object::Thread;
 use common::table::TableSlot;
+use arch::cpu::Local;
+use task::scheduler::{Priority, HIGHEST_PRIORITY};
 use nabi::{Result, Error};
 use nebulet_derive::nebulet_abi;
 use wasm::UserData;
@@ -9,6 +11,19 @@ pub fn thread_yield(_: &UserData) {
     Thread::yield_now();
 }
 
+/// Block the current thread for at least `duration` nanoseconds.
+#[nebulet_abi]
+pub fn thread_sleep(duration: u64, _: &UserData) {
+    Thread::sleep(duration);
+}
+
+/// Block the current thread until `deadline`, in nanoseconds of
+/// monotonic time, the clock that `interrupt_wait` reports.
+#[nebulet_abi]
+pub fn thread_sleep_until(deadline: u64, _: &UserData) {
+    Thread::sleep_until(deadline);
+}
+
 #[nebulet_abi]
 pub fn thread_join(id: u32, user_data: &UserData) -> Result<u32> {
     if let Some(thread) = user_data.process.thread_list().write().free(TableSlot::from_usize(id as usize)) {
@@ -18,14 +33,108 @@ pub fn thread_join(id: u32, user_data: &UserData) -> Result<u32> {
     Ok(0)
 }
 
+/// Set the priority of a thread in the current process.
+/// It can't be higher than the maximum priority of the process.
+#[nebulet_abi]
+pub fn thread_set_priority(id: u32, priority: u32, user_data: &UserData) -> Result<u32> {
+    if priority > HIGHEST_PRIORITY as u32 {
+        return Err(Error::INVALID_ARG);
+    }
+
+    if priority > user_data.process.max_priority() as u32 {
+        return Err(Error::ACCESS_DENIED);
+    }
+
+    {
+        let thread_list = user_data.process.thread_list().read();
+        let thread = thread_list
+            .get(TableSlot::from_usize(id as usize))
+            .ok_or(Error::NOT_FOUND)?;
+
+        thread.set_priority(priority as Priority);
+    }
+
+    // The current thread may not be the most important anymore.
+    unsafe { Local::preempt_if_needed(); }
+
+    Ok(0)
+}
+
+/// Restrict the cpus that a thread in the current process may run
+/// on to the ones in `affinity`, a bit for each cpu id. At least
+/// one of them has to be online. If the thread is running on
+/// another cpu, it moves as soon as that cpu switches away from it.
+#[nebulet_abi]
+pub fn thread_set_affinity(id: u32, affinity: u64, user_data: &UserData) -> Result<u32> {
+    if affinity & Local::online_mask() == 0 {
+        return Err(Error::INVALID_ARG);
+    }
+
+    {
+        let thread_list = user_data.process.thread_list().read();
+        let thread = thread_list
+            .get(TableSlot::from_usize(id as usize))
+            .ok_or(Error::NOT_FOUND)?;
+
+        thread.set_affinity(affinity);
+    }
+
+    // The current thread may have to leave this cpu.
+    unsafe { Local::preempt_if_needed(); }
+
+    Ok(0)
+}
+
+/// Statistics about a thread.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ThreadInfo {
+    /// Nanoseconds that the thread ran for.
+    pub cpu_time: u64,
+    /// Times the thread blocked, slept, yielded or exited.
+    pub voluntary_switches: u64,
+    /// Times the thread was preempted.
+    pub involuntary_switches: u64,
+    /// The cpu that the thread runs on.
+    pub cpu: u32,
+    pub priority: u32,
+}
+
+/// Write a `ThreadInfo` about a thread in
+/// the current process to `info_out`.
+#[nebulet_abi]
+pub fn thread_info(id: u32, info_out: u32, user_data: &UserData) -> Result<u32> {
+    let info = {
+        let thread_list = user_data.process.thread_list().read();
+        let thread = thread_list
+            .get(TableSlot::from_usize(id as usize))
+            .ok_or(Error::NOT_FOUND)?;
+
+        ThreadInfo {
+            cpu_time: thread.run_time(),
+            voluntary_switches: thread.voluntary_switches(),
+            involuntary_switches: thread.involuntary_switches(),
+            cpu: thread.cpu(),
+            priority: thread.priority() as u32,
+        }
+    };
+
+    let memory = user_data.instance.memory(0)?;
+    let out = memory.carve_mut::<ThreadInfo>(info_out)
+        .ok_or(Error::OUT_OF_BOUNDS)?;
+    *out = info;
+
+    Ok(0)
+}
+
 #[nebulet_abi]
 pub fn thread_spawn(func_table_index: u32, arg: u32, new_stack_offset: u32, user_data: &UserData) -> Result<u32> {
     let func_addr = {
-        let table = user_data.instance.tables[0].write();
-        *table
+        let table = user_data.instance.table(0)?.write();
+        table
             .get(func_table_index as usize)
             .ok_or(Error::NOT_FOUND)?
-            as *const ()
+            .func as *const ()
     };
 
     let code = user_data.process.code();
@@ -18,20 +18,21 @@ pub fn page_fault_handler(faulting_addr: *const ()) -> bool {
 
     if let Some(process) = current_thread.parent() {
         let instance = process.initial_instance();
-        let memory = &instance.memories[0];
-        
-        if likely!(memory.in_mapped_bounds(faulting_addr)) {
-            // this path should be as low-latency as possible.
-            // just map in the offending page
-            let _ = memory.region().map_page(faulting_addr);
-            true
-        } else if memory.in_unmapped_bounds(faulting_addr) {
-            process.handle_trap(TrapCode::HeapOutOfBounds);
-
-            true
-        } else {
-            false
+
+        for memory in instance.memories.iter() {
+            if likely!(memory.in_mapped_bounds(faulting_addr)) {
+                // this path should be as low-latency as possible.
+                // just map in the offending page
+                let _ = memory.region().map_page(faulting_addr);
+                return true;
+            } else if memory.in_unmapped_bounds(faulting_addr) {
+                process.handle_trap(TrapCode::HeapOutOfBounds);
+
+                return true;
+            }
         }
+
+        false
     } else {
         panic!("Intrinsic thread page faulted at {:p}", faulting_addr);
     }
@@ -1,39 +1,553 @@
-use acpi::{Acpi, search_for_rsdp_bios, AcpiHandler, PhysicalMapping};
-use x86_64::{PhysAddr, VirtAddr, structures::paging::{Page, PhysFrame}};
-use alloc::alloc::{Layout, Global, Alloc};
-use super::paging::PageMapper;
+//! Discovery of the ACPI tables.
+//!
+//! The tables are found by scanning the BIOS areas for the RSDP
+//! and walking the RSDT (or XSDT). Only the tables that the kernel
+//! needs are parsed, and the parsed results are kept around so the
+//! tables themselves can be unmapped.
 
-pub struct NebuletAcpiHandler;
+use x86_64::PhysAddr;
+use alloc::vec::Vec;
+use core::ptr;
+use core::{mem, slice};
+use memory::{PhysRegion, MemFlags};
+use spin::Once;
+use nabi::{Result, Error};
 
-impl NebuletAcpiHandler {
-    pub fn find_acpi() -> Acpi {
-        let mut handler = NebuletAcpiHandler;
+/// Map `size` bytes of physical memory at `phys_addr`
+/// and pass them to `f`. They're unmapped afterwards.
+fn with_physical<F, R>(phys_addr: usize, size: usize, f: F) -> Result<R>
+    where F: FnOnce(&[u8]) -> Result<R>
+{
+    let region = PhysRegion::map(PhysAddr::new(phys_addr as u64), size, MemFlags::READ)?;
+    let bytes = unsafe { slice::from_raw_parts(region.start().as_ptr::<u8>(), size) };
 
-        if let Ok(acpi) = search_for_rsdp_bios(&mut handler) {
-            acpi
+    f(bytes)
+}
+
+/// The RSDP, including the fields added in ACPI 2.0.
+#[repr(C, packed)]
+#[allow(dead_code)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The size of the RSDP in ACPI 1.0.
+const RSDP_V1_SIZE: usize = 20;
+
+/// The header at the start of every system description table.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: usize = 36;
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Read a `T` at `offset`. Tables from the firmware can be
+/// truncated, so this fails if it's out of bounds.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Result<T> {
+    match offset.checked_add(mem::size_of::<T>()) {
+        Some(end) if end <= bytes.len() => {
+            Ok(unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset) as *const T) })
+        },
+        _ => Err(Error::INTERNAL),
+    }
+}
+
+/// Map a whole table and pass its contents,
+/// including the header, to `f`.
+fn with_table<F, R>(phys_addr: usize, f: F) -> Result<R>
+    where F: FnOnce(&[u8]) -> Result<R>
+{
+    let header: SdtHeader = with_physical(phys_addr, SDT_HEADER_SIZE, |bytes| read(bytes, 0))?;
+
+    let length = header.length as usize;
+    if length < SDT_HEADER_SIZE {
+        return Err(Error::INTERNAL);
+    }
+
+    with_physical(phys_addr, length, |bytes| {
+        if checksum(bytes) {
+            f(bytes)
         } else {
-            panic!("Failed to find system acpi tables");
+            Err(Error::INTERNAL)
+        }
+    })
+}
+
+/// Look for the RSDP in the first KiB of the EBDA
+/// and in the main BIOS area.
+fn search_for_rsdp() -> Option<Rsdp> {
+    let ebda_start = with_physical(0x40E, 2, |bytes| read::<u16>(bytes, 0))
+        .map(|segment| (segment as usize) << 4)
+        .unwrap_or(0);
+
+    let areas = [(ebda_start, 1024), (0xE0000, 0x20000)];
+
+    for &(start, size) in areas.iter() {
+        if start == 0 {
+            continue;
+        }
+
+        let found = with_physical(start, size, |area| {
+            Ok(area.chunks(16)
+                .enumerate()
+                .filter(|(_, chunk)| chunk.starts_with(b"RSD PTR "))
+                .map(|(i, _)| &area[i * 16..])
+                .find(|candidate| candidate.len() >= RSDP_V1_SIZE && checksum(&candidate[..RSDP_V1_SIZE]))
+                .map(|candidate| {
+                    let mut rsdp: Rsdp = unsafe { mem::zeroed() };
+                    let len = if candidate[15] >= 2 {
+                        mem::size_of::<Rsdp>()
+                    } else {
+                        RSDP_V1_SIZE
+                    }.min(candidate.len());
+                    unsafe {
+                        ptr::copy_nonoverlapping(candidate.as_ptr(), &mut rsdp as *mut Rsdp as *mut u8, len);
+                    }
+                    rsdp
+                }))
+        });
+
+        if let Ok(Some(rsdp)) = found {
+            return Some(rsdp);
         }
     }
+
+    None
+}
+
+/// A processor listed in the MADT.
+#[derive(Debug, Copy, Clone)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    /// The processor can be brought up.
+    pub enabled: bool,
+}
+
+/// An I/O APIC listed in the MADT.
+#[derive(Debug, Copy, Clone)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    /// The first global system interrupt that this I/O APIC handles.
+    pub gsi_base: u32,
 }
 
-impl AcpiHandler for NebuletAcpiHandler {
-    fn map_physical_region<T>(&mut self, physical_address: usize, size: usize) -> PhysicalMapping<T> {
-        let phys_addr = PhysAddr::new(physical_address as u64);
-        let mut page_mapper = unsafe { PageMapper::new() };
-        let layout = Layout::from_size_align(size, 4096).unwrap();
-        let virt_ptr = Global.alloc(layout).unwrap();
-        let virt = VirtAddr::from_ptr(virt_ptr.as_ptr());
-        let start_page = Page::containing_address(virt);
-        
-        PhysicalMapping {
-            physical_start: physical_address,
-            virtual_start: virt_ptr.cast(),
-            region_length
+/// An ISA irq that isn't identity-mapped
+/// to a global system interrupt.
+#[derive(Debug, Copy, Clone)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    /// The MPS INTI flags: polarity in bits 0-1,
+    /// trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+/// The parsed contents of the MADT.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+    /// The system also has 8259 PICs that have to be disabled.
+    pub legacy_pics: bool,
+}
+
+impl Madt {
+    fn parse(bytes: &[u8]) -> Result<Madt> {
+        let mut madt = Madt {
+            local_apic_address: read::<u32>(bytes, SDT_HEADER_SIZE)? as u64,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            legacy_pics: read::<u32>(bytes, SDT_HEADER_SIZE + 4)? & 1 != 0,
+        };
+
+        let mut offset = SDT_HEADER_SIZE + 8;
+        while offset + 2 <= bytes.len() {
+            let kind: u8 = read(bytes, offset)?;
+            let length: u8 = read(bytes, offset + 1)?;
+
+            if length < 2 || offset + length as usize > bytes.len() {
+                return Err(Error::INTERNAL);
+            }
+
+            let entry = &bytes[offset..offset + length as usize];
+
+            match kind {
+                // Processor Local APIC
+                0 => madt.processors.push(Processor {
+                    processor_id: read::<u8>(entry, 2)? as u32,
+                    apic_id: read::<u8>(entry, 3)? as u32,
+                    enabled: read::<u32>(entry, 4)? & 1 != 0,
+                }),
+                // I/O APIC
+                1 => madt.io_apics.push(IoApicEntry {
+                    id: read(entry, 2)?,
+                    address: read(entry, 4)?,
+                    gsi_base: read(entry, 8)?,
+                }),
+                // Interrupt Source Override
+                2 => madt.overrides.push(InterruptOverride {
+                    isa_irq: read(entry, 3)?,
+                    gsi: read(entry, 4)?,
+                    flags: read(entry, 8)?,
+                }),
+                // Local APIC Address Override
+                5 => madt.local_apic_address = read(entry, 4)?,
+                // Processor Local x2APIC
+                9 => madt.processors.push(Processor {
+                    processor_id: read(entry, 12)?,
+                    apic_id: read(entry, 4)?,
+                    enabled: read::<u32>(entry, 8)? & 1 != 0,
+                }),
+                _ => {},
+            }
+
+            offset += length as usize;
         }
+
+        Ok(madt)
+    }
+}
+
+/// A register described by an ACPI generic address structure.
+#[derive(Debug, Copy, Clone)]
+pub struct GenericAddress {
+    /// 0 is system memory, 1 is system I/O.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+impl GenericAddress {
+    fn parse(bytes: &[u8], offset: usize) -> Result<GenericAddress> {
+        Ok(GenericAddress {
+            address_space: read(bytes, offset)?,
+            bit_width: read(bytes, offset + 1)?,
+            bit_offset: read(bytes, offset + 2)?,
+            access_size: read(bytes, offset + 3)?,
+            address: read(bytes, offset + 4)?,
+        })
     }
+}
+
+/// The reset register in the FADT is valid.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+/// The parsed contents of the FADT.
+#[derive(Debug)]
+pub struct Fadt {
+    /// The ISA irq that the SCI is connected to.
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    pub pm_timer: u32,
+    /// The CMOS register that holds the century, if there is one.
+    pub century: u8,
+    pub boot_arch: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    /// The physical address of the DSDT.
+    pub dsdt_address: u64,
+}
+
+impl Fadt {
+    fn parse(bytes: &[u8]) -> Result<Fadt> {
+        // The fields up to the flags exist in every revision.
+        if bytes.len() < 116 {
+            return Err(Error::INTERNAL);
+        }
+
+        let mut fadt = Fadt {
+            sci_interrupt: read(bytes, 46)?,
+            smi_command: read(bytes, 48)?,
+            acpi_enable: read(bytes, 52)?,
+            pm1a_control: read(bytes, 64)?,
+            pm1b_control: read(bytes, 68)?,
+            pm_timer: read(bytes, 76)?,
+            century: read(bytes, 108)?,
+            boot_arch: read(bytes, 109)?,
+            flags: read(bytes, 112)?,
+            reset_register: None,
+            reset_value: 0,
+            dsdt_address: read::<u32>(bytes, 40)? as u64,
+        };
+
+        if bytes.len() >= 129 && fadt.flags & FADT_RESET_REG_SUP != 0 {
+            fadt.reset_register = Some(GenericAddress::parse(bytes, 116)?);
+            fadt.reset_value = read(bytes, 128)?;
+        }
 
-    fn unmap_physical_region<T>(&mut self, region: PhysicalMapping<T>) {
+        if bytes.len() >= 148 {
+            let x_dsdt: u64 = read(bytes, 140)?;
+            if x_dsdt != 0 {
+                fadt.dsdt_address = x_dsdt;
+            }
+        }
+
+        if bytes.len() >= 184 {
+            let x_pm1a_control = GenericAddress::parse(bytes, 172)?;
+            if x_pm1a_control.address != 0 && x_pm1a_control.address_space == ADDRESS_SPACE_IO {
+                fadt.pm1a_control = x_pm1a_control.address as u32;
+            }
+        }
 
+        Ok(fadt)
     }
-}
\ No newline at end of file
+}
+
+/// The parsed contents of the HPET table.
+#[derive(Debug)]
+pub struct Hpet {
+    /// The physical address of the timer block.
+    pub base_address: u64,
+    pub hpet_number: u8,
+    /// The minimum number of ticks in periodic mode.
+    pub minimum_tick: u16,
+    pub comparator_count: u8,
+    pub counter_is_64bit: bool,
+}
+
+impl Hpet {
+    fn parse(bytes: &[u8]) -> Result<Hpet> {
+        let block_id: u32 = read(bytes, SDT_HEADER_SIZE)?;
+        let base_address = GenericAddress::parse(bytes, SDT_HEADER_SIZE + 4)?;
+
+        if base_address.address_space != ADDRESS_SPACE_MEMORY {
+            return Err(Error::NOT_SUPPORTED);
+        }
+
+        Ok(Hpet {
+            base_address: base_address.address,
+            hpet_number: read(bytes, SDT_HEADER_SIZE + 16)?,
+            minimum_tick: read(bytes, SDT_HEADER_SIZE + 17)?,
+            comparator_count: (((block_id >> 8) & 0x1F) + 1) as u8,
+            counter_is_64bit: block_id & (1 << 13) != 0,
+        })
+    }
+}
+
+/// A range of PCIe configuration space listed in the MCFG.
+#[derive(Debug, Copy, Clone)]
+pub struct EcamRegion {
+    /// The physical address of the configuration space of `start_bus`.
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl EcamRegion {
+    fn parse_mcfg(bytes: &[u8]) -> Result<Vec<EcamRegion>> {
+        // The entries follow 8 reserved bytes.
+        (SDT_HEADER_SIZE + 8..bytes.len())
+            .step_by(16)
+            .filter(|offset| offset + 16 <= bytes.len())
+            .map(|offset| Ok(EcamRegion {
+                base_address: read(bytes, offset)?,
+                segment: read(bytes, offset + 8)?,
+                start_bus: read(bytes, offset + 10)?,
+                end_bus: read(bytes, offset + 11)?,
+            }))
+            .collect()
+    }
+}
+
+/// Find the `\_S5` package in the AML of the DSDT and return
+/// its SLP_TYPa and SLP_TYPb values.
+///
+/// This doesn't interpret the AML. It only understands
+/// the encodings that firmware emits for this package.
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    let is_named = (position >= 1 && aml[position - 1] == NAME_OP)
+        || (position >= 2 && aml[position - 2] == NAME_OP && aml[position - 1] == b'\\');
+
+    if !is_named || aml.get(position + 4) != Some(&PACKAGE_OP) {
+        return None;
+    }
+
+    // Skip the PkgLength, which is one to four bytes
+    // long, and the number of elements.
+    let mut offset = position + 5;
+    offset += ((aml.get(offset)? & 0xC0) >> 6) as usize + 2;
+
+    // ZeroOp and OneOp are encoded as their own values.
+    let mut read_integer = || {
+        if *aml.get(offset)? == BYTE_PREFIX {
+            offset += 1;
+        }
+        let value = *aml.get(offset)?;
+        offset += 1;
+        Some(value)
+    };
+
+    let sleep_type_a = read_integer()?;
+    let sleep_type_b = read_integer()?;
+
+    Some((sleep_type_a, sleep_type_b))
+}
+
+/// The tables that the kernel uses.
+#[derive(Debug)]
+pub struct AcpiTables {
+    pub revision: u8,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Vec<EcamRegion>,
+    /// The SLP_TYPa and SLP_TYPb values for soft-off,
+    /// from the `\_S5` object in the DSDT.
+    pub s5_sleep_type: Option<(u8, u8)>,
+}
+
+impl AcpiTables {
+    /// The processors that can be brought up.
+    pub fn processors(&self) -> impl Iterator<Item=&Processor> {
+        self.madt
+            .iter()
+            .flat_map(|madt| madt.processors.iter())
+            .filter(|processor| processor.enabled)
+    }
+
+    pub fn io_apics(&self) -> &[IoApicEntry] {
+        self.madt
+            .as_ref()
+            .map(|madt| &madt.io_apics[..])
+            .unwrap_or(&[])
+    }
+
+    /// The physical address of the HPET's registers.
+    pub fn timer_base(&self) -> Option<u64> {
+        self.hpet
+            .as_ref()
+            .map(|hpet| hpet.base_address)
+    }
+
+    /// The configuration space of the first PCIe segment.
+    pub fn ecam(&self) -> Option<&EcamRegion> {
+        self.mcfg
+            .iter()
+            .find(|region| region.segment == 0)
+    }
+}
+
+static TABLES: Once<AcpiTables> = Once::new();
+
+fn parse_tables() -> Result<AcpiTables> {
+    let rsdp = search_for_rsdp()
+        .ok_or(Error::NOT_FOUND)?;
+
+    let (sdt_address, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, 8)
+    } else {
+        (rsdp.rsdt_address as usize, 4)
+    };
+
+    let entries: Vec<usize> = with_table(sdt_address, |bytes| {
+        (SDT_HEADER_SIZE..bytes.len())
+            .step_by(entry_size)
+            .filter(|offset| offset + entry_size <= bytes.len())
+            .map(|offset| if entry_size == 8 {
+                read::<u64>(bytes, offset).map(|address| address as usize)
+            } else {
+                read::<u32>(bytes, offset).map(|address| address as usize)
+            })
+            .collect()
+    })?;
+
+    let mut tables = AcpiTables {
+        revision: rsdp.revision,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: Vec::new(),
+        s5_sleep_type: None,
+    };
+
+    for address in entries {
+        let result = with_table(address, |bytes| {
+            let header: SdtHeader = read(bytes, 0)?;
+            let signature = header.signature;
+
+            match &signature {
+                b"APIC" => tables.madt = Some(Madt::parse(bytes)?),
+                b"FACP" => tables.fadt = Some(Fadt::parse(bytes)?),
+                b"HPET" => tables.hpet = Some(Hpet::parse(bytes)?),
+                b"MCFG" => tables.mcfg = EcamRegion::parse_mcfg(bytes)?,
+                _ => {},
+            }
+
+            Ok(())
+        });
+
+        if result.is_err() {
+            println!("acpi: skipping invalid table at {:#x}", address);
+        }
+    }
+
+    let dsdt_address = tables.fadt
+        .as_ref()
+        .map(|fadt| fadt.dsdt_address as usize);
+
+    if let Some(dsdt_address) = dsdt_address {
+        tables.s5_sleep_type = with_table(dsdt_address, |bytes| {
+            Ok(find_s5(&bytes[SDT_HEADER_SIZE..]))
+        }).unwrap_or(None);
+    }
+
+    Ok(tables)
+}
+
+/// Find and parse the ACPI tables.
+pub fn init() {
+    TABLES.call_once(|| {
+        parse_tables()
+            .expect("Failed to find system acpi tables")
+    });
+}
+
+/// The parsed ACPI tables.
+pub fn tables() -> &'static AcpiTables {
+    TABLES.try()
+        .expect("acpi tables not initialized")
+}
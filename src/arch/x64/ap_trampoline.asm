@@ -0,0 +1,128 @@
+.global ap_trampoline
+.global ap_trampoline_end
+.global ap_trampoline_data
+.intel_syntax noprefix
+
+# Application Processor Trampoline
+# --------------------------------
+# This is copied to TRAMPOLINE_BASE, where application processors
+# start executing it in real mode after a startup ipi. It switches
+# to long mode with the same paging setup as the bootstrap processor
+# and calls `entry(cpu_id)` on `stack_top`.
+#
+# It runs at a different address than it was linked at, so everything
+# is addressed as an offset from `ap_trampoline`.
+#
+# ap_trampoline_data {
+#   0x0: cr0
+#   0x8: cr3
+#   0x10: cr4
+#   0x18: efer
+#   0x20: xcr0 (zero if xsave isn't enabled)
+#   0x28: stack_top
+#   0x30: entry
+#   0x38: cpu_id
+#   0x40: started
+# }
+
+.set TRAMPOLINE_BASE, 0x8000
+
+.align 16
+.code16
+ap_trampoline:
+    cli
+    cld
+
+    mov ax, cs
+    mov ds, ax
+
+    lgdt [ap_gdt_pointer - ap_trampoline]
+
+    # Enable protected mode
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    # jmp 0x08:ap_protected_mode
+    .byte 0x66, 0xea
+    .long TRAMPOLINE_BASE + (ap_protected_mode - ap_trampoline)
+    .word 0x08
+
+.code32
+ap_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    # Paging has to be set up before long mode is enabled
+    mov eax, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x10]
+    mov cr4, eax
+    mov eax, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x8]
+    mov cr3, eax
+
+    mov ecx, 0xC0000080 # EFER
+    mov eax, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x18]
+    mov edx, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x1c]
+    wrmsr
+
+    # Enable paging, which activates long mode
+    mov eax, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline)]
+    mov cr0, eax
+
+    # jmp 0x18:ap_long_mode
+    .byte 0xea
+    .long TRAMPOLINE_BASE + (ap_long_mode - ap_trampoline)
+    .word 0x18
+
+.code64
+ap_long_mode:
+    # The kernel's GDT doesn't have data segments
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rax, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x20]
+    test rax, rax
+    jz ap_xsave_done
+    mov rdx, rax
+    shr rdx, 32
+    xor ecx, ecx
+    xsetbv
+ap_xsave_done:
+
+    mov rsp, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x28]
+    mov rax, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x30]
+    mov rdi, [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x38]
+
+    mov qword ptr [TRAMPOLINE_BASE + (ap_trampoline_data - ap_trampoline) + 0x40], 1
+
+    # leap of faith
+    call rax
+
+ap_halt:
+    hlt
+    jmp ap_halt
+
+.align 8
+ap_gdt:
+    .quad 0                     # null
+    .quad 0x00CF9A000000FFFF    # 0x08: 32-bit code
+    .quad 0x00CF92000000FFFF    # 0x10: data
+    .quad 0x00AF9A000000FFFF    # 0x18: 64-bit code
+ap_gdt_end:
+
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long TRAMPOLINE_BASE + (ap_gdt - ap_trampoline)
+
+.align 8
+ap_trampoline_data:
+    .fill 9, 8, 0
+ap_trampoline_end:
+
+.att_syntax prefix
+.code64
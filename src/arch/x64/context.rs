@@ -1,5 +1,7 @@
 use core::mem;
 use x86_64::registers::rflags::RFlags;
+use arch::fpu::FpuArea;
+use nabi::Result;
 
 extern {
     fn x86_64_context_switch(prev: *mut ThreadContext, next: *const ThreadContext);
@@ -16,10 +18,13 @@ pub struct ThreadContext {
     r15: u64,
     rbp: u64,
     rsp: u64,
+    /// The floating point and vector registers.
+    /// They're not touched by `x86_64_context_switch`.
+    fpu: FpuArea,
 }
 
 impl ThreadContext {
-    pub fn new(stack_top: *mut u8, entry: extern fn()) -> ThreadContext {
+    pub fn new(stack_top: *mut u8, entry: extern fn()) -> Result<ThreadContext> {
         let mut ctx = ThreadContext {
             rflags: RFlags::INTERRUPT_FLAG.bits(),
             rbx: 0,
@@ -29,13 +34,14 @@ impl ThreadContext {
             r15: 0,
             rbp: stack_top as _,
             rsp: stack_top as _,
+            fpu: FpuArea::new()?,
         };
 
         unsafe {
             ctx.push_stack(entry as _);
         }
 
-        ctx
+        Ok(ctx)
     }
 
     /// Push an item onto the `ThreadContext`'s stack.
@@ -46,6 +52,11 @@ impl ThreadContext {
 
     #[inline]
     pub unsafe fn swap(&mut self, next: &ThreadContext) {
+        // The kernel doesn't use these registers itself, so
+        // they can be switched before the rest of the context.
+        self.fpu.save();
+        next.fpu.restore();
+
         x86_64_context_switch(self as *mut _, next as *const _);
     }
 }
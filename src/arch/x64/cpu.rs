@@ -1,30 +1,59 @@
 use x86_64::registers::model_specific::Msr;
 use x86_64::registers::rflags::RFlags;
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
+use raw_cpuid::CpuId as Cpuid;
 
 use arch::interrupt;
 use arch::asm::read_gs_offset64;
+use arch::devices::{lapic, clock_event, high_precision_timer};
+use arch::idt;
+use arch::lock::{IrqSpinlock, IrqSpinGuard};
 
 use task::scheduler::Scheduler;
+use task::timer::TimerWheel;
+use time;
 use object::thread::{Thread, State};
 
 use alloc::boxed::Box;
 use event::{Event, EventVariant};
 use sync::mpsc::{Mpsc, IntrusiveMpsc};
+use sync::atomic::{Atomic, Ordering};
 
 // static GLOBAL: Once<Global> = Once::new();
 
 pub type CpuId = u32;
 
+/// How long a thread runs for before others
+/// with the same priority get a turn, in ns.
+const TIME_SLICE: u64 = 20_000_000;
+
+/// How often a cpu that's running a thread gets a timer
+/// event, in ns. Idle cpus only get one when a sleeping
+/// thread has to wake up.
+const TICK: u64 = 2_000_000;
+
+/// The most cpus that will be brought up.
+pub const MAX_CPUS: usize = 64;
+
+/// The `Local` of every cpu that is online, indexed by cpu id.
+static mut LOCALS: [*mut Local; MAX_CPUS] = [ptr::null_mut(); MAX_CPUS];
+static CPU_COUNT: Atomic<usize> = Atomic::new(0);
+
 pub struct Cpu {
     /// The cpu id (starts at 0)
     cpu_id: CpuId,
+    /// The id of this cpu's local apic.
+    apic_id: u32,
 }
 
 impl Cpu {
     pub fn id(&self) -> CpuId {
         self.cpu_id
     }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
 }
 
 pub struct IrqController;
@@ -51,17 +80,33 @@ impl IrqController {
     }
 }
 
-pub unsafe fn init(cpu_id: u32) {
+/// The initial local apic id, according to cpuid.
+fn current_apic_id() -> u32 {
+    Cpuid::new()
+        .get_feature_info()
+        .map(|info| info.initial_local_apic_id() as u32)
+        .unwrap_or(0)
+}
+
+pub unsafe fn init(cpu_id: CpuId) {
+    assert!((cpu_id as usize) < MAX_CPUS);
+
     let cpu = Box::new(Cpu {
         cpu_id,
+        apic_id: current_apic_id(),
     });
 
     let mut cpu_local = Box::new(Local::new(Box::leak(cpu)));
 
     cpu_local.direct = (&*cpu_local).into();
 
+    let cpu_local = Box::into_raw(cpu_local);
+
     Msr::new(0xC0000101)
-        .write(Box::into_raw(cpu_local) as u64);
+        .write(cpu_local as u64);
+
+    LOCALS[cpu_id as usize] = cpu_local;
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
 }
 
 // /// Global system data
@@ -79,20 +124,50 @@ pub unsafe fn init(cpu_id: u32) {
 // }
 
 /// Each cpu contains this in the gs register.
+#[repr(C)]
 pub struct Local {
+    /// This must stay the first field.
     direct: NonNull<Local>,
     /// Reference to the local `Cpu`.
-    _cpu: *const Cpu,
+    cpu: *const Cpu,
     /// The scheduler associated with this cpu.
     scheduler: Scheduler,
     /// Pointer to current thread.
     pub current_thread: *mut Thread,
     /// The dpc instance local to this cpu
     dpc: Dpc,
+    /// Set when a thread with a higher priority than
+    /// the current thread became ready on this cpu.
+    need_resched: Atomic<bool>,
+    /// When the time slice of the current thread ends.
+    slice_end: Atomic<u64>,
+    /// The threads sleeping on this cpu. Only this cpu adds
+    /// them, but any cpu can cancel a thread's timer.
+    timers: IrqSpinlock<TimerWheel>,
+    /// The tsc at the last context switch.
+    last_switch: Atomic<u64>,
+    /// Tsc cycles spent in the idle thread.
+    idle_cycles: Atomic<u64>,
+    /// Tsc cycles spent in every other thread.
+    busy_cycles: Atomic<u64>,
+    /// Whether the idle thread has been running since the last switch.
+    running_idle: Atomic<bool>,
+    /// Odd while the fields above are being updated, so that other
+    /// cpus can read them without seeing half of a context switch.
+    switch_seq: Atomic<u64>,
+    context_switches: Atomic<u64>,
+    /// The time slice of the current thread ran out,
+    /// so the next switch isn't voluntary.
+    preempted: Atomic<bool>,
+    /// A thread that was switched away from because it may
+    /// not run on this cpu anymore, until it's handed over.
+    migrating: Atomic<*mut Thread>,
 }
 
 impl Local {
     unsafe fn new(cpu: *const Cpu) -> Local {
+        let cpu_id = (*cpu).id();
+
         let idle_thread = Thread::new(4096, || {
             loop {
                 ::arch::interrupt::halt();
@@ -101,6 +176,9 @@ impl Local {
 
         let kernel_thread = Thread::new(4096, || {}).unwrap();
 
+        idle_thread.set_cpu(cpu_id);
+        kernel_thread.set_cpu(cpu_id);
+
         idle_thread.set_state(State::Ready);
         kernel_thread.set_state(State::Dead);
 
@@ -108,15 +186,27 @@ impl Local {
 
         let (dpc_thread, dpc) = Dpc::new();
 
+        dpc_thread.set_cpu(cpu_id);
         dpc_thread.set_state(State::Ready);
         scheduler.schedule_thread(Box::into_raw(dpc_thread));
 
         Local {
             direct: NonNull::dangling(),
-            _cpu: cpu,
+            cpu,
             scheduler,
             current_thread: Box::into_raw(kernel_thread),
             dpc,
+            need_resched: Atomic::new(false),
+            slice_end: Atomic::new(0),
+            timers: IrqSpinlock::new(TimerWheel::new()),
+            last_switch: Atomic::new(high_precision_timer::rdtsc()),
+            idle_cycles: Atomic::new(0),
+            busy_cycles: Atomic::new(0),
+            running_idle: Atomic::new(false),
+            switch_seq: Atomic::new(0),
+            context_switches: Atomic::new(0),
+            preempted: Atomic::new(false),
+            migrating: Atomic::new(ptr::null_mut()),
         }
     }
 
@@ -126,6 +216,104 @@ impl Local {
         }
     }
 
+    /// The `Local` of the cpu with the id `cpu_id`, if it's online.
+    pub fn get(cpu_id: CpuId) -> Option<&'static Local> {
+        if cpu_id as usize >= Self::count() {
+            return None;
+        }
+
+        unsafe {
+            LOCALS[cpu_id as usize].as_ref()
+        }
+    }
+
+    /// The number of cpus that are online.
+    pub fn count() -> usize {
+        CPU_COUNT.load(Ordering::SeqCst)
+    }
+
+    /// The id of the current cpu.
+    pub fn cpu_id() -> CpuId {
+        Self::current().cpu().id()
+    }
+
+    /// A mask with a bit set for every cpu that's online.
+    pub fn online_mask() -> u64 {
+        match Self::count() {
+            MAX_CPUS => u64::max_value(),
+            count => (1 << count) - 1,
+        }
+    }
+
+    /// The cpu with the fewest threads on it, out of the ones
+    /// in `affinity`. If none of those are online, any cpu is.
+    pub fn least_loaded(affinity: u64) -> CpuId {
+        let affinity = if affinity & Self::online_mask() != 0 {
+            affinity
+        } else {
+            u64::max_value()
+        };
+
+        (0..Self::count() as CpuId)
+            .filter(|&cpu_id| affinity & (1 << cpu_id) != 0)
+            .filter_map(|cpu_id| Self::get(cpu_id).map(|local| (cpu_id, local.scheduler.thread_count())))
+            .min_by_key(|&(_, thread_count)| thread_count)
+            .map(|(cpu_id, _)| cpu_id)
+            .unwrap_or(0)
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        unsafe { &*self.cpu }
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// The idle and busy cycles of this cpu, including the
+    /// current run. This may be called from any cpu, so it
+    /// retries if the cpu switched threads in the meantime.
+    fn cycles(&self) -> (u64, u64) {
+        loop {
+            let seq = self.switch_seq.load(Ordering::SeqCst);
+
+            if seq % 2 == 0 {
+                let mut idle_cycles = self.idle_cycles.load(Ordering::SeqCst);
+                let mut busy_cycles = self.busy_cycles.load(Ordering::SeqCst);
+
+                let last_switch = self.last_switch.load(Ordering::SeqCst);
+                let running = high_precision_timer::rdtsc().saturating_sub(last_switch);
+                if self.running_idle.load(Ordering::SeqCst) {
+                    idle_cycles += running;
+                } else {
+                    busy_cycles += running;
+                }
+
+                if self.switch_seq.load(Ordering::SeqCst) == seq {
+                    return (idle_cycles, busy_cycles);
+                }
+            }
+
+            interrupt::pause();
+        }
+    }
+
+    /// Nanoseconds that this cpu spent idle. This
+    /// includes the time since it last went idle.
+    pub fn idle_time(&self) -> u64 {
+        high_precision_timer::cycles_to_nanos(self.cycles().0)
+    }
+
+    /// Nanoseconds that this cpu spent running threads.
+    /// This includes the time since the last switch.
+    pub fn busy_time(&self) -> u64 {
+        high_precision_timer::cycles_to_nanos(self.cycles().1)
+    }
+
+    pub fn context_switches(&self) -> u64 {
+        self.context_switches.load(Ordering::Relaxed)
+    }
+
     #[inline]
     pub fn current_thread() -> *mut Thread {
         unsafe {
@@ -136,16 +324,211 @@ impl Local {
     #[inline]
     pub fn set_current_thread(thread: *mut Thread) {
         unsafe {
-            asm!("mov $0, %gs:0x28" : : "r"(thread) : "memory" : "volatile");
+            asm!("mov gs:$0, $1" : : "i"(offset_of!(Local, current_thread)), "r"(thread) : "memory" : "intel", "volatile");
         }
     }
 
+    /// Queue the thread on the cpu that it belongs to.
+    /// If that's another cpu, it's interrupted so that
+    /// it notices the thread, in case it's idle or the
+    /// thread should preempt whatever is running there.
     pub fn schedule_thread(thread: *mut Thread) {
-        Self::current().scheduler.schedule_thread(thread);
+        let cpu_id = unsafe { (*thread).cpu() };
+
+        let local = Self::get(cpu_id)
+            .expect("thread was scheduled on a cpu that isn't online");
+
+        local.scheduler.schedule_thread(thread);
+
+        Self::notify(local, thread);
+    }
+
+    /// Move a thread whose priority changed
+    /// to the right run queue of its cpu.
+    pub fn reprioritize(thread: *mut Thread) {
+        let cpu_id = unsafe { (*thread).cpu() };
+
+        if let Some(local) = Self::get(cpu_id) {
+            local.scheduler.requeue(thread);
+
+            Self::notify(local, thread);
+        }
+    }
+
+    /// Make the cpu with the id `cpu_id` reschedule, so
+    /// that a thread that may not run there anymore leaves.
+    pub fn kick(cpu_id: CpuId) {
+        if let Some(local) = Self::get(cpu_id) {
+            if cpu_id != Self::cpu_id() {
+                lapic::local_apic().send_fixed_ipi(local.cpu().apic_id(), idt::RESCHEDULE_VECTOR);
+            } else {
+                local.need_resched.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Called by the scheduler instead of queueing `thread`,
+    /// which was running but may not run on this cpu anymore.
+    /// Interrupts must be disabled.
+    pub unsafe fn migrate_after_switch(thread: *mut Thread) {
+        let previous = Self::current().migrating.swap(thread, Ordering::Relaxed);
+        debug_assert!(previous.is_null());
+    }
+
+    /// Hand a thread that isn't running or queued over to
+    /// the least loaded cpu that it's allowed to run on.
+    pub unsafe fn migrate(thread: *mut Thread) {
+        let cpu_id = Self::least_loaded((*thread).affinity());
+        (*thread).move_to(cpu_id);
+
+        Self::schedule_thread(thread);
+    }
+
+    /// Hand over the thread that this cpu last switched away from,
+    /// if it has to move. That can only be done once the switch is
+    /// over, so that two cpus never run on the thread's stack.
+    /// This is called by the scheduler after every switch, and by
+    /// new threads before they run.
+    pub fn finish_migration() {
+        let thread = Self::current().migrating.swap(ptr::null_mut(), Ordering::Relaxed);
+
+        if !thread.is_null() {
+            unsafe { Self::migrate(thread); }
+        }
+    }
+
+    /// Let the cpu of `local` know that `thread` may have
+    /// to preempt the thread that's currently running there.
+    fn notify(local: &Local, thread: *mut Thread) {
+        if local.cpu().id() != Self::cpu_id() {
+            lapic::local_apic().send_fixed_ipi(local.cpu().apic_id(), idt::RESCHEDULE_VECTOR);
+        } else {
+            let current_thread = Self::current_thread();
+
+            if current_thread == local.scheduler.idle_thread()
+                || unsafe { (*thread).priority() > (*current_thread).priority() }
+            {
+                local.need_resched.store(true, Ordering::Relaxed);
+            }
+        }
     }
 
     pub unsafe fn context_switch() {
-        Self::current().scheduler.switch();
+        let local = Self::current();
+        local.slice_end.store(time::monotonic() + TIME_SLICE, Ordering::Relaxed);
+        local.need_resched.store(false, Ordering::Relaxed);
+        local.scheduler.switch();
+    }
+
+    /// Switch to a thread with a higher priority than
+    /// the current one, if there is one.
+    pub unsafe fn preempt() {
+        let local = Self::current();
+        local.need_resched.store(false, Ordering::Relaxed);
+        local.scheduler.preempt();
+    }
+
+    /// Preempt the current thread if a thread with a
+    /// higher priority became ready since it was scheduled.
+    pub unsafe fn preempt_if_needed() {
+        if Self::current().need_resched.load(Ordering::Relaxed) {
+            Self::preempt();
+        }
+    }
+
+    /// The timer wheel of this cpu.
+    pub fn timers(&self) -> IrqSpinGuard<TimerWheel> {
+        self.timers.lock()
+    }
+
+    /// Take a sleeping thread off the timer wheel of its cpu, so
+    /// that it can be resumed early. Returns false if its timer
+    /// already expired, which means that it was already resumed.
+    pub fn cancel_timer(thread: *mut Thread) -> bool {
+        let cpu_id = unsafe { (*thread).cpu() };
+
+        let local = Self::get(cpu_id)
+            .expect("thread slept on a cpu that isn't online");
+
+        unsafe { local.timers().remove(thread) }
+    }
+
+    /// Program the timer of this cpu for the next thing it has to
+    /// do while `thread` runs. Interrupts must be disabled.
+    unsafe fn program_timer(&self, now: u64, thread: *mut Thread) {
+        // Running threads get ticks, so that threads that became ready
+        // without an interrupt are noticed. Idle cpus stay halted.
+        let tick = if thread == self.scheduler.idle_thread() {
+            None
+        } else {
+            Some(now + TICK)
+        };
+
+        let next_expiry = self.timers().next_expiry();
+
+        let next_event = match (tick, next_expiry) {
+            (Some(tick), Some(expiry)) => Some(tick.min(expiry)),
+            (tick, expiry) => tick.or(expiry),
+        };
+
+        match next_event {
+            Some(deadline) => clock_event::program(deadline),
+            None => clock_event::cancel(),
+        }
+    }
+
+    /// Called by the scheduler right before it switches to `thread`.
+    /// The current thread is charged for the time since the last
+    /// switch. Interrupts must be disabled.
+    pub unsafe fn switching_to(thread: *mut Thread, voluntary: bool) {
+        let local = Self::current();
+        let now = time::monotonic();
+
+        local.switch_seq.fetch_add(1, Ordering::SeqCst);
+
+        let tsc = high_precision_timer::rdtsc();
+        let cycles = tsc.wrapping_sub(local.last_switch.swap(tsc, Ordering::SeqCst));
+
+        let idle_thread = local.scheduler.idle_thread();
+        let current_thread = Self::current_thread();
+        if current_thread == idle_thread {
+            local.idle_cycles.fetch_add(cycles, Ordering::SeqCst);
+        } else {
+            local.busy_cycles.fetch_add(cycles, Ordering::SeqCst);
+
+            let voluntary = voluntary && !local.preempted.load(Ordering::Relaxed);
+            (*current_thread).charge(cycles, voluntary);
+        }
+        local.running_idle.store(thread == idle_thread, Ordering::SeqCst);
+
+        local.switch_seq.fetch_add(1, Ordering::SeqCst);
+
+        local.preempted.store(false, Ordering::Relaxed);
+        local.context_switches.fetch_add(1, Ordering::Relaxed);
+
+        local.slice_end.store(now + TIME_SLICE, Ordering::Relaxed);
+        local.program_timer(now, thread);
+    }
+
+    /// Called on every timer event of this cpu.
+    pub unsafe fn timer_event() {
+        let local = Self::current();
+        let now = time::monotonic();
+
+        local.timers().advance(now, |thread| {
+            (*thread).resume();
+        });
+
+        local.program_timer(now, Self::current_thread());
+
+        if now >= local.slice_end.load(Ordering::Relaxed) {
+            local.preempted.store(true, Ordering::Relaxed);
+            Self::context_switch();
+            // Nothing else may have been ready to run.
+            Self::current().preempted.store(false, Ordering::Relaxed);
+        } else {
+            Self::preempt_if_needed();
+        }
     }
 }
 
@@ -0,0 +1,145 @@
+//! The timer interrupt of each cpu.
+//!
+//! Every cpu has a one-shot timer that is programmed for the next
+//! thing it has to do: the next tick while a thread is running, or
+//! the next deadline on its timer wheel. An idle cpu without any
+//! sleeping threads doesn't program it at all, so it stays halted.
+//!
+//! The local apic timer is used in tsc-deadline mode when the cpu
+//! supports it, and in one-shot mode otherwise. The PIT is only
+//! used to calibrate the tsc once, on the bootstrap processor.
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use raw_cpuid::CpuId as Cpuid;
+use arch::x64::interrupt;
+use arch::x64::idt;
+use arch::macros::interrupt;
+use arch::devices::{lapic, pit, high_precision_timer};
+use sync::atomic::{Atomic, Ordering};
+use time;
+use core::mem::transmute;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// Whether the timers run in tsc-deadline mode.
+static TSC_DEADLINE: Atomic<bool> = Atomic::new(false);
+
+static PIT_TICKS: Atomic<u8> = Atomic::new(0);
+
+fn calibration_idt() -> InterruptDescriptorTable {
+    interrupt!(pit, {
+        PIT_TICKS.fetch_add(1, Ordering::SeqCst);
+        // Saves CPU time by shortcutting
+        lapic::eoi();
+    });
+
+    let mut idt = idt::default_idt();
+    idt[idt::PIT_VECTOR as usize].set_handler_fn(pit);
+    idt
+}
+
+/// Measure the tsc rate over one tick of the PIT.
+unsafe fn calibrate_tsc() {
+    let idt = calibration_idt();
+    let idt_ref = transmute::<&InterruptDescriptorTable, &'static InterruptDescriptorTable>(&idt);
+    idt_ref.load();
+    interrupt::enable();
+
+    let initial_tick = PIT_TICKS.load(Ordering::SeqCst);
+
+    // Wait until the start of a cycle
+    let mut start_tick;
+    let mut start_cycle;
+    loop {
+        start_tick = PIT_TICKS.load(Ordering::SeqCst);
+        start_cycle = high_precision_timer::rdtsc();
+        if initial_tick != start_tick {
+            break;
+        }
+    }
+
+    // Wait one tick cycle;
+    let mut end_tick;
+    let mut end_cycle;
+    loop {
+        end_tick = PIT_TICKS.load(Ordering::SeqCst);
+        end_cycle = high_precision_timer::rdtsc();
+        if end_tick != start_tick {
+            break;
+        }
+    }
+
+    // Restore interrupts
+    interrupt::disable();
+    idt::IDT.load();
+
+    // Calculate and store frequency
+    assert_eq!(start_tick + 1, end_tick);
+
+    let cycles_per_billion_pits: u64 = 1_000_000_000 * (end_cycle - start_cycle);
+    let cycles_per_second = cycles_per_billion_pits / pit::RATE as u64;
+    high_precision_timer::set_tsc_rate(cycles_per_second);
+}
+
+/// Calibrate the tsc against the PIT, and the local apic timer
+/// against the tsc. The PIT is stopped afterwards.
+pub unsafe fn calibrate() {
+    pit::init();
+
+    calibrate_tsc();
+
+    pit::stop();
+
+    lapic::local_apic().calibrate_timer();
+
+    let tsc_deadline = Cpuid::new()
+        .get_feature_info()
+        .map(|info| info.has_tsc_deadline())
+        .unwrap_or(false);
+
+    TSC_DEADLINE.store(tsc_deadline, Ordering::SeqCst);
+}
+
+/// Set up the timer of the current cpu. It fires
+/// `idt::LAPIC_TIMER_VECTOR` once it's programmed.
+pub unsafe fn init() {
+    let local_apic = lapic::local_apic();
+
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        local_apic.start_tsc_deadline_timer(idt::LAPIC_TIMER_VECTOR);
+    } else {
+        local_apic.start_oneshot_timer(idt::LAPIC_TIMER_VECTOR);
+    }
+}
+
+/// Fire the timer of the current cpu at `deadline`, in nanoseconds
+/// of monotonic time, instead of whenever it was programmed for.
+/// A deadline that already passed fires right away.
+pub fn program(deadline: u64) {
+    unsafe {
+        if TSC_DEADLINE.load(Ordering::Relaxed) {
+            // Zero would disarm it.
+            let cycles = high_precision_timer::nanos_to_cycles(deadline).max(1);
+            Msr::new(IA32_TSC_DEADLINE).write(cycles);
+        } else {
+            let delta = deadline.saturating_sub(time::monotonic());
+            let count = delta.saturating_mul(lapic::timer_ticks_per_ms() as u64) / 1_000_000;
+
+            // Zero would stop it, and it can't count any further.
+            let count = count.max(1).min(u32::max_value() as u64) as u32;
+            lapic::local_apic().set_timer_count(count);
+        }
+    }
+}
+
+/// Stop the timer of the current cpu.
+pub fn cancel() {
+    unsafe {
+        if TSC_DEADLINE.load(Ordering::Relaxed) {
+            Msr::new(IA32_TSC_DEADLINE).write(0);
+        } else {
+            lapic::local_apic().set_timer_count(0);
+        }
+    }
+}
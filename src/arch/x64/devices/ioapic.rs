@@ -0,0 +1,302 @@
+use x86_64::PhysAddr;
+use memory::{PhysRegion, MemFlags};
+use arch::lock::IrqSpinlock;
+use arch::acpi::{Madt, InterruptOverride};
+use arch::devices::lapic;
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Once;
+use nabi::{Result, Error};
+
+// Register offsets
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+// Indirect registers
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// The number of legacy ISA irqs.
+pub const ISA_IRQS: u8 = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+pub struct IoApic {
+    /// The register window is a select/data pair,
+    /// so accesses have to be serialized.
+    registers: IrqSpinlock<PhysRegion>,
+    id: u8,
+    /// The first global system interrupt that this I/O APIC handles.
+    gsi_base: u32,
+    /// The number of redirection entries.
+    count: u32,
+    /// For each line, the number of interrupts that were delivered
+    /// but haven't been acknowledged yet. The line stays masked
+    /// until all of them are.
+    holds: IrqSpinlock<Vec<u32>>,
+}
+
+impl IoApic {
+    unsafe fn new(id: u8, address: PhysAddr, gsi_base: u32) -> IoApic {
+        let registers = PhysRegion::map(address, 4096, MemFlags::READ | MemFlags::WRITE | MemFlags::UNCACHED)
+            .expect("Failed to map an i/o apic");
+
+        let mut io_apic = IoApic {
+            registers: IrqSpinlock::new(registers),
+            id,
+            gsi_base,
+            count: 0,
+            holds: IrqSpinlock::new(Vec::new()),
+        };
+
+        {
+            let registers = io_apic.registers.lock();
+
+            io_apic.count = ((read(&registers, IOAPICVER) >> 16) & 0xFF) + 1;
+
+            // Nothing should be delivered until it's routed.
+            for index in 0..io_apic.count {
+                let low = read(&registers, IOREDTBL + index * 2);
+                write(&registers, IOREDTBL + index * 2, low | REDIRECTION_MASKED);
+            }
+        }
+
+        io_apic.holds = IrqSpinlock::new(vec![0; io_apic.count as usize]);
+
+        io_apic
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.count
+    }
+
+    /// Route `gsi` to `vector` on the cpu with the local apic `apic_id`.
+    /// The entry is left masked.
+    unsafe fn set_entry(&self, gsi: u32, vector: u8, trigger: Trigger, polarity: Polarity, apic_id: u32) {
+        let reg = IOREDTBL + (gsi - self.gsi_base) * 2;
+
+        let mut low = vector as u32 | REDIRECTION_MASKED;
+        if trigger == Trigger::Level {
+            low |= REDIRECTION_LEVEL;
+        }
+        if polarity == Polarity::ActiveLow {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+
+        let registers = self.registers.lock();
+
+        write(&registers, reg, REDIRECTION_MASKED);
+        write(&registers, reg + 1, apic_id << 24);
+        write(&registers, reg, low);
+    }
+
+    unsafe fn set_masked(&self, gsi: u32, masked: bool) {
+        let reg = IOREDTBL + (gsi - self.gsi_base) * 2;
+
+        // The lock is held across the read and the write, so that
+        // a line that's masked and unmasked on different cpus at
+        // the same time doesn't lose one of the changes.
+        let registers = self.registers.lock();
+
+        let low = read(&registers, reg);
+        if masked {
+            write(&registers, reg, low | REDIRECTION_MASKED);
+        } else {
+            write(&registers, reg, low & !REDIRECTION_MASKED);
+        }
+    }
+}
+
+/// Select `reg` and read it. The lock on `registers`
+/// has to be held for both of the accesses.
+unsafe fn read(registers: &PhysRegion, reg: u32) -> u32 {
+    let base = registers.start().as_mut_ptr::<u8>();
+
+    ptr::write_volatile(base.add(IOREGSEL) as *mut u32, reg);
+    ptr::read_volatile(base.add(IOWIN) as *const u32)
+}
+
+unsafe fn write(registers: &PhysRegion, reg: u32, value: u32) {
+    let base = registers.start().as_mut_ptr::<u8>();
+
+    ptr::write_volatile(base.add(IOREGSEL) as *mut u32, reg);
+    ptr::write_volatile(base.add(IOWIN) as *mut u32, value);
+}
+
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+static OVERRIDES: Once<Vec<InterruptOverride>> = Once::new();
+
+/// Map every I/O APIC listed in the MADT and mask all of their lines.
+pub unsafe fn init(madt: &Madt) {
+    IO_APICS.call_once(|| {
+        madt.io_apics
+            .iter()
+            .map(|entry| IoApic::new(entry.id, PhysAddr::new(entry.address as u64), entry.gsi_base))
+            .collect()
+    });
+
+    OVERRIDES.call_once(|| madt.overrides.clone());
+}
+
+fn io_apics() -> &'static [IoApic] {
+    IO_APICS.try()
+        .map(|io_apics| &io_apics[..])
+        .unwrap_or(&[])
+}
+
+/// One more than the highest global system
+/// interrupt that an I/O APIC handles.
+pub fn gsi_count() -> u32 {
+    io_apics()
+        .iter()
+        .map(|io_apic| io_apic.gsi_base() + io_apic.count())
+        .max()
+        .unwrap_or(0)
+}
+
+fn io_apic_for(gsi: u32) -> Result<&'static IoApic> {
+    io_apics()
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(Error::INVALID_ARG)
+}
+
+/// Translate a legacy ISA irq to the global system
+/// interrupt that it's connected to.
+pub fn isa_irq(irq: u8) -> Result<(u32, Trigger, Polarity)> {
+    if irq >= ISA_IRQS {
+        return Err(Error::INVALID_ARG);
+    }
+
+    let overridden = OVERRIDES.try()
+        .and_then(|overrides| overrides.iter().find(|entry| entry.isa_irq == irq));
+
+    Ok(match overridden {
+        Some(entry) => {
+            // "Conforms to the bus" means active-high and edge-triggered for ISA.
+            let polarity = match entry.flags & 0b11 {
+                0b11 => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            };
+            let trigger = match (entry.flags >> 2) & 0b11 {
+                0b11 => Trigger::Level,
+                _ => Trigger::Edge,
+            };
+
+            (entry.gsi, trigger, polarity)
+        },
+        None => (irq as u32, Trigger::Edge, Polarity::ActiveHigh),
+    })
+}
+
+/// Deliver `gsi` to `vector` on the bootstrap processor.
+/// The line stays masked until it's unmasked.
+pub fn route(gsi: u32, vector: u8, trigger: Trigger, polarity: Polarity) -> Result<()> {
+    let io_apic = io_apic_for(gsi)?;
+    let apic_id = lapic::local_apic().id();
+
+    unsafe {
+        io_apic.set_entry(gsi, vector, trigger, polarity, apic_id);
+    }
+
+    Ok(())
+}
+
+pub fn mask(gsi: u32) -> Result<()> {
+    let io_apic = io_apic_for(gsi)?;
+
+    unsafe {
+        io_apic.set_masked(gsi, true);
+    }
+
+    Ok(())
+}
+
+pub fn unmask(gsi: u32) -> Result<()> {
+    let io_apic = io_apic_for(gsi)?;
+
+    unsafe {
+        io_apic.set_masked(gsi, false);
+    }
+
+    Ok(())
+}
+
+/// Mask `gsi` until it's released as many times as it's held.
+/// A level-triggered line that's shared is held by every handler
+/// that the interrupt was delivered to, so that it isn't unmasked
+/// while one of their devices may still be asserting it.
+pub fn hold(gsi: u32) -> Result<()> {
+    let io_apic = io_apic_for(gsi)?;
+    let mut holds = io_apic.holds.lock();
+
+    let count = &mut holds[(gsi - io_apic.gsi_base) as usize];
+    *count += 1;
+    if *count == 1 {
+        unsafe {
+            io_apic.set_masked(gsi, true);
+        }
+    }
+
+    Ok(())
+}
+
+/// Undo a `hold`, unmasking `gsi` if nothing else holds it.
+pub fn release(gsi: u32) -> Result<()> {
+    let io_apic = io_apic_for(gsi)?;
+    let mut holds = io_apic.holds.lock();
+
+    let count = &mut holds[(gsi - io_apic.gsi_base) as usize];
+    if *count == 0 {
+        return Err(Error::BAD_STATE);
+    }
+
+    *count -= 1;
+    if *count == 0 {
+        unsafe {
+            io_apic.set_masked(gsi, false);
+        }
+    }
+
+    Ok(())
+}
+
+/// Mask `gsi` and forget every hold on it.
+/// This is for when the last handler is removed from it.
+pub fn disable(gsi: u32) -> Result<()> {
+    let io_apic = io_apic_for(gsi)?;
+    let mut holds = io_apic.holds.lock();
+
+    holds[(gsi - io_apic.gsi_base) as usize] = 0;
+    unsafe {
+        io_apic.set_masked(gsi, true);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use memory::{PhysRegion, MemFlags};
+use arch::cpu::IrqController;
+use arch::devices::high_precision_timer;
+use sync::atomic::{Atomic, Ordering};
+use core::ptr;
+use spin::Once;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Interrupts that the local apic considers spurious
+/// are delivered here. They must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Register offsets
+const ID: usize = 0x020;
+const TPR: usize = 0x080;
+const EOI: usize = 0x0B0;
+const SVR: usize = 0x0F0;
+const ESR: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_FIXED: u32 = 0b000 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_LEVEL: u32 = 1 << 15;
+
+const LVT_TIMER_ONESHOT: u32 = 0b00 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+/// Divide the bus clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// Timer ticks per millisecond, measured on the bootstrap processor.
+static TIMER_TICKS_PER_MS: Atomic<u32> = Atomic::new(0);
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// The local apic of each cpu is mapped at the same physical
+/// address, so a single mapping is used by all of them.
+pub struct LocalApic {
+    registers: PhysRegion,
+}
+
+impl LocalApic {
+    #[inline]
+    unsafe fn read(&self, reg: usize) -> u32 {
+        ptr::read_volatile(self.registers.start().as_ptr::<u8>().add(reg) as *const u32)
+    }
+
+    #[inline]
+    unsafe fn write(&self, reg: usize, value: u32) {
+        ptr::write_volatile(self.registers.start().as_mut_ptr::<u8>().add(reg) as *mut u32, value);
+    }
+
+    /// The id of the local apic of the current cpu.
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(ID) >> 24 }
+    }
+
+    /// Signal the end of the interrupt that is being handled.
+    #[inline]
+    pub fn eoi(&self) {
+        unsafe { self.write(EOI, 0) }
+    }
+
+    /// Send an inter-processor interrupt.
+    ///
+    /// `command` is written to the low half of the
+    /// interrupt command register and sends the ipi.
+    pub unsafe fn send_ipi(&self, apic_id: u32, command: u32) {
+        // An interrupt handler on this cpu could send
+        // an ipi between the two writes.
+        let was_enabled = IrqController::enabled();
+        if was_enabled {
+            IrqController::disable();
+        }
+
+        self.write(ICR_HIGH, apic_id << 24);
+        self.write(ICR_LOW, command);
+
+        while self.read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            ::arch::interrupt::pause();
+        }
+
+        if was_enabled {
+            IrqController::enable();
+        }
+    }
+
+    /// Interrupt the cpu with the local apic `apic_id` on `vector`.
+    pub fn send_fixed_ipi(&self, apic_id: u32, vector: u8) {
+        unsafe {
+            self.send_ipi(apic_id, ICR_FIXED | ICR_ASSERT | vector as u32);
+        }
+    }
+
+    /// Put the cpu with the local apic `apic_id`
+    /// into the wait-for-startup state.
+    pub unsafe fn send_init_ipi(&self, apic_id: u32) {
+        self.send_ipi(apic_id, ICR_INIT | ICR_ASSERT | ICR_LEVEL);
+    }
+
+    /// Start the cpu with the local apic `apic_id` in real
+    /// mode at the physical address `page << 12`.
+    pub unsafe fn send_startup_ipi(&self, apic_id: u32, page: u8) {
+        self.send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+    }
+
+    /// Measure how fast the timer counts down, using the tsc.
+    pub unsafe fn calibrate_timer(&self) {
+        const SAMPLE_MS: u32 = 10;
+
+        self.write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(LVT_TIMER, LVT_MASKED);
+        self.write(TIMER_INITIAL_COUNT, u32::max_value());
+
+        high_precision_timer::delay(SAMPLE_MS as u64 * 1_000_000);
+
+        let elapsed = u32::max_value() - self.read(TIMER_CURRENT_COUNT);
+        self.write(TIMER_INITIAL_COUNT, 0);
+
+        TIMER_TICKS_PER_MS.store(elapsed / SAMPLE_MS, Ordering::SeqCst);
+    }
+
+    /// Fire `vector` on this cpu once the timer count,
+    /// set with `set_timer_count`, runs out.
+    pub unsafe fn start_oneshot_timer(&self, vector: u8) {
+        self.write(TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(LVT_TIMER, LVT_TIMER_ONESHOT | vector as u32);
+        self.write(TIMER_INITIAL_COUNT, 0);
+    }
+
+    /// Fire `vector` on this cpu once the tsc reaches
+    /// the value in the IA32_TSC_DEADLINE msr.
+    pub unsafe fn start_tsc_deadline_timer(&self, vector: u8) {
+        self.write(LVT_TIMER, LVT_TIMER_TSC_DEADLINE | vector as u32);
+
+        // The mode has to be set before the deadline msr is written.
+        asm!("mfence" : : : "memory" : "volatile");
+    }
+
+    /// Start counting down from `count` in one-shot mode.
+    /// A count of zero stops the timer.
+    pub unsafe fn set_timer_count(&self, count: u32) {
+        self.write(TIMER_INITIAL_COUNT, count);
+    }
+
+    /// Enable the local apic of the current cpu.
+    pub unsafe fn enable(&self) {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let base = apic_base.read();
+        apic_base.write(base | APIC_BASE_ENABLE);
+
+        // Accept every interrupt priority.
+        self.write(TPR, 0);
+
+        self.write(LVT_TIMER, LVT_MASKED);
+        self.write(LVT_ERROR, LVT_MASKED);
+
+        // The error status register has to be written before it's read.
+        self.write(ESR, 0);
+        self.write(ESR, 0);
+
+        self.write(SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        self.eoi();
+    }
+}
+
+/// Map the local apic and enable it on the bootstrap processor.
+pub unsafe fn init(address: PhysAddr) {
+    let local_apic = LOCAL_APIC.call_once(|| {
+        let registers = PhysRegion::map(address, 4096, MemFlags::READ | MemFlags::WRITE | MemFlags::UNCACHED)
+            .expect("Failed to map the local apic");
+
+        LocalApic {
+            registers,
+        }
+    });
+
+    local_apic.enable();
+}
+
+/// Timer ticks per millisecond, once the timer has been calibrated.
+pub fn timer_ticks_per_ms() -> u32 {
+    TIMER_TICKS_PER_MS.load(Ordering::Relaxed)
+}
+
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.try()
+        .expect("local apic not initialized")
+}
+
+/// Signal the end of an interrupt to the local apic.
+#[inline]
+pub fn eoi() {
+    if let Some(local_apic) = LOCAL_APIC.try() {
+        local_apic.eoi();
+    }
+}
@@ -1,18 +1,34 @@
 pub mod serial;
 pub mod pic;
+pub mod lapic;
+pub mod ioapic;
 pub mod rtc;
 pub mod pit;
 pub mod high_precision_timer;
+pub mod clock_event;
 pub mod rand;
 
+use x86_64::PhysAddr;
+use arch::acpi;
+
 pub unsafe fn init() {
+    // Remap the PICs before masking them, so
+    // spurious interrupts don't look like exceptions.
     pic::init();
+    pic::disable();
+
+    let madt = acpi::tables().madt
+        .as_ref()
+        .expect("The system doesn't have an MADT");
+
+    lapic::init(PhysAddr::new(madt.local_apic_address));
+    ioapic::init(madt);
 }
 
 pub unsafe fn init_noncore() {
-    pit::init();
     rtc::init();
     #[cfg(feature = "serial")]
     serial::init();
-    high_precision_timer::init();
+    clock_event::calibrate();
+    clock_event::init();
 }
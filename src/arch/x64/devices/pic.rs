@@ -48,6 +48,13 @@ pub unsafe fn init() {
     SLAVE.ack();
 }
 
+/// Mask every line on both PICs. Interrupts
+/// are delivered through the I/O APICs instead.
+pub unsafe fn disable() {
+    MASTER.data.write(0xFF);
+    SLAVE.data.write(0xFF);
+}
+
 /// Mostly taken from Redox OS
 pub struct Pic {
     pub cmd: Port<u8>,
@@ -1,4 +1,6 @@
 use x86_64::instructions::port::Port;
+use arch::devices::ioapic;
+use arch::idt;
 
 // Mostly taken from Redox OS
 
@@ -15,8 +17,34 @@ static LOHI: u8 = 0x30;
 
 static CHAN0_DIVISOR: u16 = 2685;
 
+/// The ISA irq of channel 0.
+const IRQ: u8 = 0;
+
 pub unsafe fn init() {
     CMD.write(SELECT_CHAN0 | LOHI | 5);
     CHAN0.write((CHAN0_DIVISOR & 0xFF) as u8);
     CHAN0.write((CHAN0_DIVISOR >> 8) as u8);
+
+    // The PIT has its own vector, so
+    // nothing else can use its line.
+    let (gsi, trigger, polarity) = ioapic::isa_irq(IRQ)
+        .expect("Failed to find the PIT's interrupt");
+
+    let _ = idt::reserve_line(gsi);
+    ioapic::route(gsi, idt::PIT_VECTOR, trigger, polarity)
+        .and_then(|_| ioapic::unmask(gsi))
+        .expect("Failed to route the PIT's interrupt");
+}
+
+/// Stop the PIT from interrupting. It's only
+/// used until the other timers are calibrated.
+pub unsafe fn stop() {
+    // Mode 0 only fires once, when the count runs out.
+    CMD.write(SELECT_CHAN0 | LOHI);
+    CHAN0.write(0);
+    CHAN0.write(0);
+
+    if let Ok((gsi, _, _)) = ioapic::isa_irq(IRQ) {
+        let _ = ioapic::mask(gsi);
+    }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use nabi::Result;
-use arch::lock::{IrqLock, IrqGuard};
+use arch::lock::{IrqSpinlock, IrqSpinGuard};
 use rand_core::SeedableRng;
 use rand::rngs::adapter::ReseedingRng;
 use rand::prng::hc128::Hc128Core;
@@ -8,11 +8,11 @@ use arch::x64::devices::rand::entropy::EntropyRng;
 const GLOBAL_RNG_RESEED_THRESHOLD: u64 = 32*1024*1024; // 32 MiB
 
 struct GlobalRng {
-    rng: IrqLock<Option<Result<ReseedingRng<Hc128Core, EntropyRng>>>>,
+    rng: IrqSpinlock<Option<Result<ReseedingRng<Hc128Core, EntropyRng>>>>,
 }
 
 static GLOBAL_RNG : GlobalRng = GlobalRng {
-    rng: IrqLock::new(None),
+    rng: IrqSpinlock::new(None),
 };
 
 fn new_global_rng() -> Result<ReseedingRng<Hc128Core, EntropyRng>> {
@@ -28,7 +28,7 @@ fn new_global_rng() -> Result<ReseedingRng<Hc128Core, EntropyRng>> {
 pub fn with_global_rng<T, F>(f: F) -> Result<T>
 where F: FnOnce(&mut ReseedingRng<Hc128Core, EntropyRng>) -> T
 {
-    let mut guard : IrqGuard<_> = GLOBAL_RNG.rng.lock();
+    let mut guard : IrqSpinGuard<_> = GLOBAL_RNG.rng.lock();
     let rng_res = guard.get_or_insert_with(new_global_rng);
     match rng_res {
         &mut Err(ref e) => Err(e.clone()),
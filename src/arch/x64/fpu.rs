@@ -0,0 +1,211 @@
+//! Saving the floating point and vector registers of threads.
+//!
+//! The kernel itself is built without sse, but wasm code keeps
+//! floats in the xmm registers, and vectors in the ymm registers
+//! with avx. They're part of the state of a thread, so they're
+//! saved and restored on every context switch, with the most
+//! compact instruction that the cpu supports.
+
+use alloc::alloc::{Global, Layout};
+use core::alloc::Alloc;
+use core::ptr::{self, NonNull};
+use nabi::{Result, Error};
+use spin::Once;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+const CPUID_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_EDX_FXSR: u32 = 1 << 24;
+
+/// The state components that are saved: x87, sse and avx.
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// The size of the area that `fxsave` uses.
+const FXSAVE_SIZE: usize = 512;
+/// `xsave` requires this, `fxsave` only 16.
+const AREA_ALIGN: usize = 64;
+
+/// The x87 control word that masks all exceptions.
+const DEFAULT_FCW: u16 = 0x037F;
+/// The sse control register that masks all exceptions.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+const MXCSR_OFFSET: usize = 24;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mechanism {
+    XsaveOpt,
+    Xsave,
+    Fxsave,
+}
+
+struct Fpu {
+    mechanism: Mechanism,
+    /// The state components enabled in xcr0.
+    components: u64,
+    /// The size of a save area.
+    area_size: usize,
+}
+
+static FPU: Once<Fpu> = Once::new();
+
+fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+            : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            : "{eax}"(leaf), "{ecx}"(subleaf)
+            : : "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+unsafe fn read_cr0() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr0" : "=r"(value) : : : "intel", "volatile");
+    value
+}
+
+unsafe fn write_cr0(value: u64) {
+    asm!("mov cr0, $0" : : "r"(value) : "memory" : "intel", "volatile");
+}
+
+unsafe fn read_cr4() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr4" : "=r"(value) : : : "intel", "volatile");
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov cr4, $0" : : "r"(value) : "memory" : "intel", "volatile");
+}
+
+unsafe fn write_xcr0(value: u64) {
+    asm!("xsetbv" : : "{ecx}"(0u32), "{eax}"(value as u32), "{edx}"((value >> 32) as u32) : : "volatile");
+}
+
+/// Enable the floating point and vector registers on the current
+/// cpu. The bootstrap processor also picks how they're saved.
+/// This has to be done before any threads are created.
+pub unsafe fn init() {
+    let (_, _, features_ecx, features_edx) = cpuid(1, 0);
+
+    assert!(features_edx & CPUID_EDX_FXSR != 0, "the cpu doesn't support fxsave");
+
+    let cr0 = read_cr0();
+    write_cr0((cr0 | CR0_MP) & !(CR0_EM | CR0_TS));
+
+    let mut cr4 = read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+    if features_ecx & CPUID_ECX_XSAVE != 0 {
+        cr4 |= CR4_OSXSAVE;
+    }
+    write_cr4(cr4);
+
+    let fpu = FPU.call_once(|| {
+        if features_ecx & CPUID_ECX_XSAVE == 0 {
+            return Fpu {
+                mechanism: Mechanism::Fxsave,
+                components: 0,
+                area_size: FXSAVE_SIZE,
+            };
+        }
+
+        let (supported, _, _, _) = cpuid(0xD, 0);
+        let components = supported as u64 & (XCR0_X87 | XCR0_SSE | XCR0_AVX);
+
+        write_xcr0(components);
+
+        // Now that xcr0 is set, this is the size that it needs.
+        let (_, area_size, _, _) = cpuid(0xD, 0);
+        let (xsave_features, _, _, _) = cpuid(0xD, 1);
+
+        Fpu {
+            mechanism: if xsave_features & 1 != 0 { Mechanism::XsaveOpt } else { Mechanism::Xsave },
+            components,
+            area_size: area_size as usize,
+        }
+    });
+
+    if fpu.mechanism != Mechanism::Fxsave {
+        write_xcr0(fpu.components);
+    }
+}
+
+fn fpu() -> &'static Fpu {
+    FPU.try()
+        .expect("fpu not initialized")
+}
+
+/// The saved floating point and vector registers of a thread.
+#[derive(Debug)]
+pub struct FpuArea {
+    area: NonNull<u8>,
+}
+
+unsafe impl Send for FpuArea {}
+unsafe impl Sync for FpuArea {}
+
+impl FpuArea {
+    /// An area that restores the registers to their initial state.
+    pub fn new() -> Result<FpuArea> {
+        let layout = Layout::from_size_align(fpu().area_size, AREA_ALIGN)
+            .map_err(|_| Error::INTERNAL)?;
+
+        let area = unsafe { Global.alloc_zeroed(layout) }
+            .map_err(|_| Error::NO_MEMORY)?
+            .cast::<u8>();
+
+        // A zeroed area would unmask every exception.
+        unsafe {
+            ptr::write(area.as_ptr() as *mut u16, DEFAULT_FCW);
+            ptr::write(area.as_ptr().add(MXCSR_OFFSET) as *mut u32, DEFAULT_MXCSR);
+        }
+
+        Ok(FpuArea {
+            area,
+        })
+    }
+
+    /// Save the registers of the current cpu into this area.
+    #[inline]
+    pub unsafe fn save(&mut self) {
+        let fpu = fpu();
+        let area = self.area.as_ptr();
+        let (low, high) = (fpu.components as u32, (fpu.components >> 32) as u32);
+
+        match fpu.mechanism {
+            Mechanism::XsaveOpt => asm!("xsaveopt64 [$0]" : : "r"(area), "{eax}"(low), "{edx}"(high) : "memory" : "intel", "volatile"),
+            Mechanism::Xsave => asm!("xsave64 [$0]" : : "r"(area), "{eax}"(low), "{edx}"(high) : "memory" : "intel", "volatile"),
+            Mechanism::Fxsave => asm!("fxsave64 [$0]" : : "r"(area) : "memory" : "intel", "volatile"),
+        }
+    }
+
+    /// Load the registers of the current cpu from this area.
+    #[inline]
+    pub unsafe fn restore(&self) {
+        let fpu = fpu();
+        let area = self.area.as_ptr();
+        let (low, high) = (fpu.components as u32, (fpu.components >> 32) as u32);
+
+        match fpu.mechanism {
+            Mechanism::XsaveOpt | Mechanism::Xsave => asm!("xrstor64 [$0]" : : "r"(area), "{eax}"(low), "{edx}"(high) : "memory" : "intel", "volatile"),
+            Mechanism::Fxsave => asm!("fxrstor64 [$0]" : : "r"(area) : "memory" : "intel", "volatile"),
+        }
+    }
+}
+
+impl Drop for FpuArea {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(fpu().area_size, AREA_ALIGN).unwrap();
+
+        unsafe {
+            Global.dealloc(self.area.cast(), layout);
+        }
+    }
+}
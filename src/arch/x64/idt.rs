@@ -1,8 +1,12 @@
-use core::ptr;
 use x86_64::structures::idt::{InterruptDescriptorTable, ExceptionStackFrame};
 use x86_64::structures::tss::TaskStateSegment;
-use arch::interrupt::{*, self};
-use arch::devices::pic;
+use arch::interrupt::*;
+use arch::devices::lapic;
+use arch::cpu::Local;
+use arch::lock::IrqSpinlock;
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+use nabi::{Result, Error};
 use spin::Once;
 
 pub static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
@@ -33,9 +37,11 @@ pub fn default_idt() -> InterruptDescriptorTable {
     idt.virtualization.set_handler_fn(exception::virtualization);
     idt.security_exception.set_handler_fn(exception::security);
 
-    idt[32].set_handler_fn(irq::pit);
+    idt[LAPIC_TIMER_VECTOR as usize].set_handler_fn(irq::lapic_timer);
+    idt[RESCHEDULE_VECTOR as usize].set_handler_fn(irq::reschedule);
+    idt[TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(irq::tlb_shootdown);
 
-    // idt[40].set_handler_fn(irq::rtc);
+    idt[lapic::SPURIOUS_VECTOR as usize].set_handler_fn(irq::spurious);
 
     idt
 }
@@ -74,30 +80,127 @@ pub fn init() {
     }
 }
 
-pub type Handler = fn(*const ());
+/// Give an application processor its own GDT and TSS,
+/// and load the IDT that every cpu shares.
+pub unsafe fn init_ap() {
+    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::tables::load_tss;
+
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(TaskStateSegment::new()));
+
+    let mut gdt = gdt::Gdt::new();
+    let code_selector = gdt.add_entry(gdt::Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(gdt::Descriptor::tss_segment(tss));
+
+    let gdt: &'static gdt::Gdt = Box::leak(Box::new(gdt));
+
+    gdt.load();
+
+    set_cs(code_selector);
+    load_tss(tss_selector);
+    IDT.load();
+}
+
+/// What an irq handler did with an interrupt.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqResult {
+    /// The interrupt was raised by this handler's device.
+    /// No other handlers on the line are called.
+    Claimed,
+    /// The interrupt wasn't for this handler, so
+    /// the next handler on the line is called.
+    NotMine,
+}
+
+pub type Handler = fn(*const ()) -> IrqResult;
+
+#[derive(Copy, Clone)]
+struct IrqHandler {
+    handler: Handler,
+    arg: *const (),
+}
+
+unsafe impl Send for IrqHandler {}
+
+/// The handlers of the global system interrupt
+/// that's delivered to one of the irq vectors.
+struct IrqLine {
+    /// The global system interrupt that's assigned to
+    /// this vector, or `None` if the vector is free.
+    gsi: Option<u32>,
+    handlers: Vec<IrqHandler>,
+    /// The only handler on this line doesn't allow sharing.
+    exclusive: bool,
+    /// The line is used by the kernel itself.
+    reserved: bool,
+}
+
+/// The vector that the PIT is delivered to
+/// while the other timers are calibrated.
+pub const PIT_VECTOR: u8 = 0x20;
+
+/// The vector that the local apic
+/// timer of each cpu is delivered to.
+pub const LAPIC_TIMER_VECTOR: u8 = 0x21;
+
+/// Sent between cpus when a thread is
+/// scheduled on a different cpu.
+pub const RESCHEDULE_VECTOR: u8 = 0x22;
+
+/// Sent between cpus to invalidate tlb entries.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0x23;
+
+/// The first of the vectors that global system
+/// interrupts are assigned to.
+pub const IRQ_BASE: u8 = 0x30;
+
+/// The number of global system interrupts that can have
+/// handlers at the same time. Any global system interrupt
+/// can be assigned to one of these vectors.
+pub const IRQ_LINES: usize = 64;
+
+lazy_static! {
+    static ref EVENT_TABLE: IrqSpinlock<Vec<IrqLine>> = IrqSpinlock::new(
+        (0..IRQ_LINES)
+            .map(|_| IrqLine {
+                gsi: None,
+                handlers: Vec::new(),
+                exclusive: false,
+                reserved: false,
+            })
+            .collect()
+    );
+}
+
+/// Call the handlers on the line in order until one of them
+/// claims the interrupt. The handler that claimed it goes to
+/// the back of the line, so that the next interrupt is offered
+/// to the others first.
+fn dispatch_irq(index: usize) {
+    let mut event_table = EVENT_TABLE.lock();
+    let handlers = &mut event_table[index].handlers;
+
+    let claimed = handlers
+        .iter()
+        .position(|entry| (entry.handler)(entry.arg) == IrqResult::Claimed);
 
-static mut EVENT_TABLE: [(Option<Handler>, *const ()); 16] = [(None, ptr::null()); 16];
+    if let Some(position) = claimed {
+        let entry = handlers.remove(position);
+        handlers.push(entry);
+    }
+}
 
 macro_rules! idt_handlers {
     ($($name:ident ( $value:expr) ),*) => {
         [$( {
                 extern "x86-interrupt" fn $name(_: &mut ExceptionStackFrame) {
-                    let irq = $value - pic::MASTER_OFFSET;
-
-                    let (handler, arg) = unsafe { EVENT_TABLE[irq as usize] };
-                    if let Some(func) = handler {
-                        func(arg);
-                    }
-                    unsafe {
-                        if irq < 16 {
-                            if irq >= 8 {
-                                pic::MASTER.ack();
-                                pic::SLAVE.ack();
-                            } else {
-                                pic::MASTER.ack();
-                            }
-                        }
-                    }
+                    dispatch_irq(($value - IRQ_BASE) as usize);
+
+                    lapic::eoi();
+
+                    // A handler may have woken a driver thread
+                    // that should run before the current one.
+                    unsafe { Local::preempt_if_needed(); }
                 }
                 $name
             }
@@ -106,42 +209,160 @@ macro_rules! idt_handlers {
     }
 }
 
-static IDT_HANDLER: [extern "x86-interrupt" fn(&mut ExceptionStackFrame); 16] = idt_handlers! {
-    idt_handler_0x20 ( 0x20 ),
-    idt_handler_0x21 ( 0x21 ),
-    idt_handler_0x22 ( 0x22 ),
-    idt_handler_0x23 ( 0x23 ),
-    idt_handler_0x24 ( 0x24 ),
-    idt_handler_0x25 ( 0x25 ),
-    idt_handler_0x26 ( 0x26 ),
-    idt_handler_0x27 ( 0x27 ),
-    idt_handler_0x28 ( 0x28 ),
-    idt_handler_0x29 ( 0x29 ),
-    idt_handler_0x2a ( 0x2a ),
-    idt_handler_0x2b ( 0x2b ),
-    idt_handler_0x2c ( 0x2c ),
-    idt_handler_0x2d ( 0x2d ),
-    idt_handler_0x2e ( 0x2e ),
-    idt_handler_0x2f ( 0x2f )
+static IDT_HANDLER: [extern "x86-interrupt" fn(&mut ExceptionStackFrame); IRQ_LINES] = idt_handlers! {
+    idt_handler_0x30 ( 0x30 ),
+    idt_handler_0x31 ( 0x31 ),
+    idt_handler_0x32 ( 0x32 ),
+    idt_handler_0x33 ( 0x33 ),
+    idt_handler_0x34 ( 0x34 ),
+    idt_handler_0x35 ( 0x35 ),
+    idt_handler_0x36 ( 0x36 ),
+    idt_handler_0x37 ( 0x37 ),
+    idt_handler_0x38 ( 0x38 ),
+    idt_handler_0x39 ( 0x39 ),
+    idt_handler_0x3a ( 0x3a ),
+    idt_handler_0x3b ( 0x3b ),
+    idt_handler_0x3c ( 0x3c ),
+    idt_handler_0x3d ( 0x3d ),
+    idt_handler_0x3e ( 0x3e ),
+    idt_handler_0x3f ( 0x3f ),
+    idt_handler_0x40 ( 0x40 ),
+    idt_handler_0x41 ( 0x41 ),
+    idt_handler_0x42 ( 0x42 ),
+    idt_handler_0x43 ( 0x43 ),
+    idt_handler_0x44 ( 0x44 ),
+    idt_handler_0x45 ( 0x45 ),
+    idt_handler_0x46 ( 0x46 ),
+    idt_handler_0x47 ( 0x47 ),
+    idt_handler_0x48 ( 0x48 ),
+    idt_handler_0x49 ( 0x49 ),
+    idt_handler_0x4a ( 0x4a ),
+    idt_handler_0x4b ( 0x4b ),
+    idt_handler_0x4c ( 0x4c ),
+    idt_handler_0x4d ( 0x4d ),
+    idt_handler_0x4e ( 0x4e ),
+    idt_handler_0x4f ( 0x4f ),
+    idt_handler_0x50 ( 0x50 ),
+    idt_handler_0x51 ( 0x51 ),
+    idt_handler_0x52 ( 0x52 ),
+    idt_handler_0x53 ( 0x53 ),
+    idt_handler_0x54 ( 0x54 ),
+    idt_handler_0x55 ( 0x55 ),
+    idt_handler_0x56 ( 0x56 ),
+    idt_handler_0x57 ( 0x57 ),
+    idt_handler_0x58 ( 0x58 ),
+    idt_handler_0x59 ( 0x59 ),
+    idt_handler_0x5a ( 0x5a ),
+    idt_handler_0x5b ( 0x5b ),
+    idt_handler_0x5c ( 0x5c ),
+    idt_handler_0x5d ( 0x5d ),
+    idt_handler_0x5e ( 0x5e ),
+    idt_handler_0x5f ( 0x5f ),
+    idt_handler_0x60 ( 0x60 ),
+    idt_handler_0x61 ( 0x61 ),
+    idt_handler_0x62 ( 0x62 ),
+    idt_handler_0x63 ( 0x63 ),
+    idt_handler_0x64 ( 0x64 ),
+    idt_handler_0x65 ( 0x65 ),
+    idt_handler_0x66 ( 0x66 ),
+    idt_handler_0x67 ( 0x67 ),
+    idt_handler_0x68 ( 0x68 ),
+    idt_handler_0x69 ( 0x69 ),
+    idt_handler_0x6a ( 0x6a ),
+    idt_handler_0x6b ( 0x6b ),
+    idt_handler_0x6c ( 0x6c ),
+    idt_handler_0x6d ( 0x6d ),
+    idt_handler_0x6e ( 0x6e ),
+    idt_handler_0x6f ( 0x6f )
 };
 
-pub fn register_handler(irq: u8, handler: fn(arg: *const ()), arg: *const()) {
-    let index = (irq - pic::MASTER_OFFSET) as usize;
+/// The line that `gsi` is assigned to, assigning
+/// it to a free one if it doesn't have one yet.
+fn assign_line(event_table: &mut [IrqLine], gsi: u32) -> Result<usize> {
+    if let Some(index) = event_table.iter().position(|line| line.gsi == Some(gsi)) {
+        return Ok(index);
+    }
 
-    let int_handler = IDT_HANDLER[index];
+    let index = event_table
+        .iter()
+        .position(|line| line.gsi.is_none())
+        .ok_or(Error::NO_RESOURCES)?;
 
-    unsafe {
-        interrupt::mask(irq);
-        EVENT_TABLE[index] = (Some(handler), arg);
-        IDT[irq as usize].set_handler_fn(int_handler);
-        interrupt::unmask(irq);
+    event_table[index].gsi = Some(gsi);
+
+    Ok(index)
+}
+
+/// Keep handlers from being registered on a line
+/// that the kernel handles through its own vector.
+pub fn reserve_line(gsi: u32) -> Result<()> {
+    let mut event_table = EVENT_TABLE.lock();
+    let index = assign_line(&mut event_table, gsi)?;
+    let line = &mut event_table[index];
+
+    if !line.handlers.is_empty() {
+        return Err(Error::ALREADY_EXISTS);
     }
+
+    line.reserved = true;
+
+    Ok(())
 }
 
-pub fn unregister_handler(irq: u8) {
-    let index = (irq - pic::MASTER_OFFSET) as usize;
-    unsafe {
-        interrupt::mask(irq);
-        EVENT_TABLE[index] = (None, ptr::null());
+/// Add a handler to the line. If `shared` is false, the handler
+/// must be the only one on the line and no others can be added
+/// until it is removed.
+///
+/// If this is the first handler on the line, returns the
+/// vector that `gsi` has to be routed to.
+pub fn register_handler(gsi: u32, handler: Handler, arg: *const (), shared: bool) -> Result<Option<u8>> {
+    let mut event_table = EVENT_TABLE.lock();
+    let index = assign_line(&mut event_table, gsi)?;
+    let line = &mut event_table[index];
+
+    if line.reserved || (!line.handlers.is_empty() && (line.exclusive || !shared)) {
+        return Err(Error::ALREADY_EXISTS);
     }
+
+    let first = line.handlers.is_empty();
+
+    line.handlers.push(IrqHandler { handler, arg });
+    line.exclusive = !shared;
+
+    if first {
+        unsafe {
+            IDT[IRQ_BASE as usize + index].set_handler_fn(IDT_HANDLER[index]);
+        }
+
+        Ok(Some(IRQ_BASE + index as u8))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Remove the handler that was registered with `handler` and `arg`.
+/// The vector is freed once the last handler is removed.
+///
+/// Returns whether there are no handlers left on the line.
+pub fn unregister_handler(gsi: u32, handler: Handler, arg: *const ()) -> Result<bool> {
+    let mut event_table = EVENT_TABLE.lock();
+
+    let line = event_table
+        .iter_mut()
+        .find(|line| line.gsi == Some(gsi))
+        .ok_or(Error::NOT_FOUND)?;
+
+    let position = line.handlers
+        .iter()
+        .position(|entry| entry.handler as usize == handler as usize && entry.arg == arg)
+        .ok_or(Error::NOT_FOUND)?;
+
+    line.handlers.remove(position);
+
+    if line.handlers.is_empty() {
+        line.exclusive = false;
+        line.gsi = None;
+    }
+
+    Ok(line.handlers.is_empty())
 }
\ No newline at end of file
@@ -1,39 +1,8 @@
-use arch::devices::pic;
+use arch::devices::lapic;
 use arch::macros::interrupt;
 // use x86_64::instructions::port::Port;
 use arch::cpu::Local;
-use sync::atomic::{Atomic, Ordering};
-
-pub static PIT_TICKS: Atomic<usize> = Atomic::new(0);
-static CONTEXT_SWITCH_TICKS: usize = 10;
-
-#[inline]
-unsafe fn trigger(irq: u8) {
-    if irq < 16 {
-        if irq >= 8 {
-            // pic::SLAVE.mask_set(irq - 8);
-            pic::MASTER.ack();
-            pic::SLAVE.ack();
-        } else {
-            // pic::MASTER.mask_set(irq);
-            pic::MASTER.ack();
-        }
-    }
-
-    // TODO: Actually do something
-}
-
-interrupt!(pit, {
-    // Saves CPU time by shortcutting
-    pic::MASTER.ack();
-
-    // switch context
-    if PIT_TICKS.fetch_add(1, Ordering::SeqCst) >= CONTEXT_SWITCH_TICKS {
-        PIT_TICKS.store(0, Ordering::SeqCst);
-
-        Local::context_switch();
-    }
-});
+use arch::tlb;
 
 // interrupt!(keyboard, {
 //     let scancode = unsafe { Port::<u8>::new(0x60).read() };
@@ -42,8 +11,33 @@ interrupt!(pit, {
 //     trigger(1);
 // });
 
+// The timer of this cpu went off. See `clock_event`.
+interrupt!(lapic_timer, {
+    lapic::eoi();
+
+    Local::timer_event();
+});
+
+// Another cpu queued a thread on this one,
+// or changed the priority of one of its threads.
+interrupt!(reschedule, {
+    lapic::eoi();
+
+    Local::preempt();
+});
+
+// Another cpu changed page mappings.
+interrupt!(tlb_shootdown, {
+    tlb::poll();
+
+    lapic::eoi();
+});
+
 interrupt!(rtc, {
     println!("RTC interrupt");
 
-    trigger(8);
+    lapic::eoi();
 });
+
+// Spurious interrupts must not be acknowledged.
+interrupt!(spurious, {});
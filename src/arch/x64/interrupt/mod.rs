@@ -3,8 +3,9 @@
 pub mod exception;
 pub mod irq;
 pub mod gdt;
-use arch::devices::pic;
+use arch::devices::ioapic::{self, Trigger, Polarity};
 use arch::idt;
+use nabi::Result;
 
 /// Disable interrupts
 #[inline(always)]
@@ -35,42 +36,53 @@ pub fn pause() {
     }
 }
 
+/// Mask a global system interrupt.
 #[inline]
-pub unsafe fn mask(irq: u8) {
-    let irq = irq - pic::MASTER_OFFSET;
-    if irq < 8 {
-        pic::MASTER.mask_set(irq);
-    } else {
-        pic::SLAVE.mask_set(irq - 8);
-    }
+pub unsafe fn mask(gsi: u32) {
+    let _ = ioapic::mask(gsi);
 }
 
+/// Unmask a global system interrupt.
 #[inline]
-pub unsafe fn unmask(irq: u8) {
-    let irq = irq - pic::MASTER_OFFSET;
-    if irq < 8 {
-        pic::MASTER.mask_clear(irq);
-    } else {
-        pic::SLAVE.mask_clear(irq - 8);
-    }
+pub unsafe fn unmask(gsi: u32) {
+    let _ = ioapic::unmask(gsi);
 }
 
-pub unsafe fn register_handler(vector: u32, handler: fn(*const ()), arg: *const ()) -> bool {
-    if vector > u8::max_value() as _ {
-        false
-    } else {
-        idt::register_handler(vector as u8, handler, arg);
+/// Mask a global system interrupt until it's released.
+#[inline]
+pub unsafe fn hold(gsi: u32) {
+    let _ = ioapic::hold(gsi);
+}
 
-        true
-    }
+/// Release a global system interrupt that was held,
+/// unmasking it if nothing else is holding it.
+#[inline]
+pub unsafe fn release(gsi: u32) {
+    let _ = ioapic::release(gsi);
 }
 
-pub unsafe fn unregister_handler(vector: u32) -> bool {
-    if vector > u8::max_value() as _ {
-        false
-    } else {
-        idt::unregister_handler(vector as u8);
+/// Add a handler to a global system interrupt.
+/// The line is routed and unmasked when its first handler is added.
+pub unsafe fn register_handler(gsi: u32, trigger: Trigger, polarity: Polarity, handler: idt::Handler, arg: *const (), shared: bool) -> Result<()> {
+    if let Some(vector) = idt::register_handler(gsi, handler, arg, shared)? {
+        let routed = ioapic::route(gsi, vector, trigger, polarity)
+            .and_then(|_| ioapic::unmask(gsi));
 
-        true
+        if let Err(err) = routed {
+            let _ = idt::unregister_handler(gsi, handler, arg);
+            return Err(err);
+        }
     }
+
+    Ok(())
+}
+
+/// Remove a handler from a global system interrupt.
+/// The line is masked when its last handler is removed.
+pub unsafe fn unregister_handler(gsi: u32, handler: idt::Handler, arg: *const ()) -> Result<()> {
+    if idt::unregister_handler(gsi, handler, arg)? {
+        ioapic::disable(gsi)?;
+    }
+
+    Ok(())
 }
\ No newline at end of file
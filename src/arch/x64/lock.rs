@@ -4,6 +4,7 @@ use core::ops::{Deref, DerefMut, Drop};
 
 use arch::cpu::IrqController;
 use arch::interrupt;
+use arch::tlb;
 
 #[derive(Debug)]
 pub struct Spinlock<T: ?Sized> {
@@ -102,6 +103,8 @@ impl<'a, T: ?Sized> Drop for SpinGuard<'a, T> {
     }
 }
 
+/// Only disables interrupts on the current cpu, so this
+/// must not protect anything that other cpus can touch.
 pub struct IrqLock<T: ?Sized> {
     data: UnsafeCell<T>,
 }
@@ -208,19 +211,26 @@ impl<T> IrqSpinlock<T> {
     fn obtain_lock(&self) {
         while self.lock.compare_and_swap(false, true, Ordering::Acquire) != false {
             while self.lock.load(Ordering::Relaxed) {
+                // Interrupts are disabled, so a tlb shootdown from
+                // the cpu that holds the lock has to be handled here,
+                // or both cpus would wait for each other forever.
+                tlb::poll();
                 interrupt::pause();
             }
         }
     }
 
     pub fn lock(&self) -> IrqSpinGuard<T> {
-        self.obtain_lock();
-
+        // Interrupts have to be disabled before the lock is taken,
+        // otherwise an interrupt handler on this cpu could spin
+        // on it forever.
         let was_enabled = IrqController::enabled();
         if was_enabled {
             unsafe { IrqController::disable(); }
         }
 
+        self.obtain_lock();
+
         IrqSpinGuard {
             lock: &self.lock,
             was_enabled,
@@ -229,17 +239,21 @@ impl<T> IrqSpinlock<T> {
     }
 
     pub fn try_lock(&self) -> Option<IrqSpinGuard<T>> {
+        let was_enabled = IrqController::enabled();
+        if was_enabled {
+            unsafe { IrqController::disable(); }
+        }
+
         if self.lock.compare_and_swap(false, true, Ordering::Acquire) == false {
-            let was_enabled = IrqController::enabled();
-            if was_enabled {
-                unsafe { IrqController::disable(); }
-            }
             Some(IrqSpinGuard {
                 lock: &self.lock,
                 was_enabled,
                 data: unsafe { &mut *self.data.get() }
             })
         } else {
+            if was_enabled {
+                unsafe { IrqController::enable(); }
+            }
             None
         }
     }
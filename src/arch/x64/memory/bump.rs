@@ -7,6 +7,10 @@ use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
 
 use super::FrameAllocator;
 
+/// Memory below this is never handed out. The
+/// application processor trampoline is copied there.
+const LOW_MEMORY_END: u64 = 0x100000;
+
 pub struct BumpAllocator {
     next_free_frame: PhysFrame<Size4KiB>,
     current_region: Option<MemoryRegion>,
@@ -22,6 +26,19 @@ impl BumpAllocator {
 
         regions[..memory_map.len()].copy_from_slice(memory_map);
 
+        let low_memory_end = PhysFrame::containing_address(PhysAddr::new(LOW_MEMORY_END));
+        for region in regions.iter_mut() {
+            if region.region_type != MemoryRegionType::Usable {
+                continue;
+            }
+
+            if region.range.end <= low_memory_end {
+                region.region_type = MemoryRegionType::Reserved;
+            } else if region.range.start < low_memory_end {
+                region.range.start = low_memory_end;
+            }
+        }
+
         let mut allocator = BumpAllocator {
             next_free_frame: PhysFrame::containing_address(PhysAddr::new(0)),
             current_region: None,
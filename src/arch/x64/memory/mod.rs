@@ -4,14 +4,14 @@
 use bootloader::bootinfo::BootInfo;
 use x86_64::structures::paging::{PhysFrame, PhysFrameRange, Size4KiB, FrameAllocator as PhysFrameAllocator, FrameDeallocator as PhysFrameDeallocator};
 
-use arch::lock::IrqLock;
+use arch::lock::IrqSpinlock;
 use self::bump::BumpAllocator;
 use self::cache::FrameCache;
 
 mod bump;
 mod cache;
 
-pub static FRAME_ALLOCATOR: IrqLock<Option<FrameCache<BumpAllocator>>> = IrqLock::new(None);
+pub static FRAME_ALLOCATOR: IrqSpinlock<Option<FrameCache<BumpAllocator>>> = IrqSpinlock::new(None);
 
 pub fn init(boot_info: &'static BootInfo, physical_pool_size: usize) {
     *FRAME_ALLOCATOR.lock() = Some(FrameCache::new(BumpAllocator::new(&boot_info.memory_map, physical_pool_size)));
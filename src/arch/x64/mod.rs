@@ -24,10 +24,19 @@ pub mod lock;
 
 pub mod context;
 
+pub mod fpu;
+
 pub mod memory;
 
 pub mod pci;
 
-// pub mod acpi;
+pub mod acpi;
+
+pub mod power;
+
+pub mod smp;
+
+pub mod tlb;
 
 global_asm!(include_str!("routines.asm"));
+global_asm!(include_str!("ap_trampoline.asm"));
@@ -4,13 +4,19 @@ use x86_64::structures::paging::{PageTable, PageTableFlags, RecursivePageTable,
 use x86_64::ux::u9;
 
 // use arch::lock::{IrqLock, IrqGuard};
+use arch::lock::IrqSpinlock;
 use arch::memory;
+use arch::tlb::Shootdown;
 // use core::cell::UnsafeCell;
 
 const P4: *mut PageTable = 0xffffffff_fffff000 as *mut _;
 const RECURSIVE_PAGE_INDEX: u9 = u9::MAX;
 // static PAGE_TABLE_LOCK: IrqLock<Option<RecursivePageTable>> = IrqLock::new(None);
 
+/// Every `PageMapper` edits the same recursive page table,
+/// so changes are serialized between cpus.
+static PAGE_TABLE_LOCK: IrqSpinlock<()> = IrqSpinlock::new(());
+
 pub unsafe fn init() -> PageMapper {
     // *PAGE_TABLE_LOCK.lock() = Some(RecursivePageTable::new_unchecked(&mut*P4, RECURSIVE_PAGE_INDEX));
     PageMapper::new()
@@ -29,6 +35,8 @@ impl PageMapper {
     }
 
     pub fn map(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<MapperFlush<Size4KiB>, MapToError> {
+        let _guard = PAGE_TABLE_LOCK.lock();
+
         let frame = memory::allocate_frame()
             .expect("Couldn't allocate any frames!");
 
@@ -36,17 +44,44 @@ impl PageMapper {
     }
 
     pub fn map_to(&mut self, page: Page<Size4KiB>, frame: PhysFrame<Size4KiB>, flags: PageTableFlags) -> Result<MapperFlush<Size4KiB>, MapToError> {
+        let _guard = PAGE_TABLE_LOCK.lock();
+
         self.table.map_to(page, frame, flags, &mut memory::GlobalFrameAllocator)
     }
 
-    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Result<MapperFlush<Size4KiB>, UnmapError> {
+    /// Unmap a page. Its frame is returned to the frame allocator
+    /// once `shootdown` has removed the page from every tlb.
+    pub fn unmap(&mut self, page: Page<Size4KiB>, shootdown: &mut Shootdown) -> Result<(), UnmapError> {
+        let _guard = PAGE_TABLE_LOCK.lock();
+
         let (frame, mapper_flush) = self.table.unmap(page)?;
-        memory::deallocate_frame(frame);
-        Ok(mapper_flush)
+        mapper_flush.ignore();
+
+        shootdown.invalidate(page);
+        shootdown.free_frame(frame);
+        Ok(())
     }
 
-    pub fn remap(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<MapperFlush<Size4KiB>, FlagUpdateError> {
-        self.table.update_flags(page, flags)
+    /// Unmap a page without returning its frame to the frame allocator.
+    /// This is used for frames that are shared between several mappings.
+    pub fn unmap_shared(&mut self, page: Page<Size4KiB>, shootdown: &mut Shootdown) -> Result<(), UnmapError> {
+        let _guard = PAGE_TABLE_LOCK.lock();
+
+        let (_frame, mapper_flush) = self.table.unmap(page)?;
+        mapper_flush.ignore();
+
+        shootdown.invalidate(page);
+        Ok(())
+    }
+
+    pub fn remap(&mut self, page: Page<Size4KiB>, flags: PageTableFlags, shootdown: &mut Shootdown) -> Result<(), FlagUpdateError> {
+        let _guard = PAGE_TABLE_LOCK.lock();
+
+        self.table.update_flags(page, flags)?
+            .ignore();
+
+        shootdown.invalidate(page);
+        Ok(())
     }
 
     pub fn translate(&self, page: Page<Size4KiB>) -> Option<PhysFrame> {
@@ -0,0 +1,148 @@
+//! Turning the machine off and restarting it.
+
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use arch::acpi::{self, ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY};
+use arch::cpu::IrqController;
+use arch::interrupt;
+use memory::{PhysRegion, MemFlags};
+use core::ptr;
+use nabi::{Result, Error};
+
+/// The bit of PM1_CNT that is set while the system is in ACPI mode.
+const SCI_EN: u16 = 1 << 0;
+/// Writing this bit to PM1_CNT enters the sleep state in SLP_TYP.
+const SLP_EN: u16 = 1 << 13;
+const SLP_TYP_SHIFT: u16 = 10;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// Give a reset mechanism some time to take effect.
+fn settle() {
+    for _ in 0..1_000_000 {
+        interrupt::pause();
+    }
+}
+
+/// Switch the system into ACPI mode, if the firmware hasn't already.
+unsafe fn enable_acpi(fadt: &acpi::Fadt) {
+    let mut pm1a_control: Port<u16> = Port::new(fadt.pm1a_control as u16);
+
+    if pm1a_control.read() & SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+
+    Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable);
+
+    for _ in 0..1_000_000 {
+        if pm1a_control.read() & SCI_EN != 0 {
+            break;
+        }
+        interrupt::pause();
+    }
+}
+
+/// Put the system into the soft-off state.
+///
+/// This only returns if the firmware doesn't describe
+/// how to do that or the system didn't turn off.
+pub unsafe fn poweroff() -> Result<()> {
+    let tables = acpi::tables();
+
+    let fadt = tables.fadt.as_ref().ok_or(Error::NOT_SUPPORTED)?;
+    let (sleep_type_a, sleep_type_b) = tables.s5_sleep_type.ok_or(Error::NOT_SUPPORTED)?;
+
+    if fadt.pm1a_control == 0 {
+        return Err(Error::NOT_SUPPORTED);
+    }
+
+    IrqController::disable();
+
+    enable_acpi(fadt);
+
+    Port::<u16>::new(fadt.pm1a_control as u16)
+        .write((sleep_type_a as u16) << SLP_TYP_SHIFT | SLP_EN);
+
+    if fadt.pm1b_control != 0 {
+        Port::<u16>::new(fadt.pm1b_control as u16)
+            .write((sleep_type_b as u16) << SLP_TYP_SHIFT | SLP_EN);
+    }
+
+    settle();
+
+    IrqController::enable();
+
+    Err(Error::INTERNAL)
+}
+
+/// Reset through the reset register in the FADT.
+unsafe fn acpi_reset() {
+    let fadt = match acpi::tables().fadt {
+        Some(ref fadt) => fadt,
+        None => return,
+    };
+
+    let register = match fadt.reset_register {
+        Some(register) => register,
+        None => return,
+    };
+
+    match register.address_space {
+        ADDRESS_SPACE_IO => {
+            Port::<u8>::new(register.address as u16).write(fadt.reset_value);
+        },
+        ADDRESS_SPACE_MEMORY => {
+            if let Ok(region) = PhysRegion::map(PhysAddr::new(register.address), 1, MemFlags::READ | MemFlags::WRITE | MemFlags::UNCACHED) {
+                ptr::write_volatile(region.start().as_mut_ptr::<u8>(), fadt.reset_value);
+            }
+        },
+        // The reset register can also be in PCI configuration space,
+        // but that isn't used by any firmware we run on.
+        _ => return,
+    }
+
+    settle();
+}
+
+/// Pulse the reset line through the keyboard controller.
+unsafe fn keyboard_controller_reset() {
+    let mut status: Port<u8> = Port::new(KBC_STATUS);
+
+    for _ in 0..1_000_000 {
+        if status.read() & KBC_INPUT_FULL == 0 {
+            break;
+        }
+        interrupt::pause();
+    }
+
+    Port::<u8>::new(KBC_COMMAND).write(KBC_PULSE_RESET);
+
+    settle();
+}
+
+/// Load an empty IDT and raise an exception. The cpu
+/// can't deliver it, or the resulting double fault,
+/// so it triple faults and resets.
+unsafe fn triple_fault() -> ! {
+    let null_idt = [0u16; 5];
+
+    asm!("lidt ($0)
+          int3"
+        : : "r"(&null_idt) : "memory" : "volatile");
+
+    loop {
+        interrupt::halt();
+    }
+}
+
+/// Restart the system.
+pub unsafe fn reboot() -> ! {
+    IrqController::disable();
+
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault();
+}
@@ -1,6 +1,8 @@
 .global x86_64_context_switch
 .global erms_memcpy
 .global erms_memset
+.global lazy_compile_trampoline
+.global wasm_probestack
 .intel_syntax noprefix
 
 # Context Switching
@@ -89,3 +91,80 @@ erms_memset:
     rep stosb
     mov rax, r9
     ret
+
+# Lazy compilation
+# ----------------
+# The stub of a wasm function that hasn't been compiled
+# yet jumps here, with the arguments of the call still in
+# their registers, and the return address on the stack.
+#
+# r11 <- address of the function's slot
+lazy_compile_trampoline:
+    # Save the arguments
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push r8
+    push r9
+    sub rsp, 0x88 # also realigns the stack to 16 bytes
+    movdqu [rsp], xmm0
+    movdqu [rsp+0x10], xmm1
+    movdqu [rsp+0x20], xmm2
+    movdqu [rsp+0x30], xmm3
+    movdqu [rsp+0x40], xmm4
+    movdqu [rsp+0x50], xmm5
+    movdqu [rsp+0x60], xmm6
+    movdqu [rsp+0x70], xmm7
+
+    mov rdi, r11
+    call wasm_lazy_compile
+    mov r11, rax # the compiled function
+
+    # Restore the arguments
+    movdqu xmm0, [rsp]
+    movdqu xmm1, [rsp+0x10]
+    movdqu xmm2, [rsp+0x20]
+    movdqu xmm3, [rsp+0x30]
+    movdqu xmm4, [rsp+0x40]
+    movdqu xmm5, [rsp+0x50]
+    movdqu xmm6, [rsp+0x60]
+    movdqu xmm7, [rsp+0x70]
+    add rsp, 0x88
+    pop r9
+    pop r8
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+
+    jmp r11
+
+# Stack probes
+# ------------
+# Wasm functions with frames bigger than a page call this
+# before they move rsp, so that they touch every page of
+# the frame in order, and can't skip over a guard page.
+# Every register but r11 is preserved.
+#
+# rax <- size of the frame
+wasm_probestack:
+    push rbp
+    mov rbp, rsp
+    mov r11, rax
+
+1:
+    cmp r11, 0x1000
+    jna 2f
+    sub rsp, 0x1000
+    test [rsp+8], rsp # touch the page
+    sub r11, 0x1000
+    jmp 1b
+
+2:
+    sub rsp, r11
+    test [rsp+8], rsp
+
+    mov rsp, rbp
+    pop rbp
+    ret
@@ -0,0 +1,219 @@
+//! Bringing up the application processors.
+
+use x86_64::structures::paging::{Page, PhysFrame, PageTableFlags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::{VirtAddr, PhysAddr};
+use arch::acpi;
+use arch::cpu::{self, Local, CpuId, MAX_CPUS};
+use arch::devices::{lapic, clock_event, high_precision_timer};
+use arch::paging::PageMapper;
+use arch::tlb::Shootdown;
+use arch::idt;
+use arch::fpu;
+use arch::interrupt;
+use memory::WasmStack;
+use sync::atomic::{Atomic, Ordering};
+use alloc::vec::Vec;
+use core::{mem, ptr};
+
+/// The physical address that the trampoline is copied to.
+/// It has to be page-aligned and below 1 MiB.
+const TRAMPOLINE_BASE: u64 = 0x8000;
+
+/// The stack that an application processor starts on.
+/// It's only used until the cpu switches to its first thread.
+const AP_STACK_SIZE: usize = 16 * 1024;
+
+const IA32_EFER: u32 = 0xC0000080;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+extern {
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_data: u8;
+}
+
+/// The layout of `ap_trampoline_data` in ap_trampoline.asm.
+#[repr(C)]
+struct TrampolineData {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    xcr0: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_id: u64,
+    /// Set by the cpu once it has reached long mode.
+    started: u64,
+}
+
+/// Set by an application processor once it's done with the trampoline.
+static AP_READY: Atomic<bool> = Atomic::new(false);
+
+unsafe fn read_cr0() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr0" : "=r"(value) : : : "intel", "volatile");
+    value
+}
+
+unsafe fn read_cr3() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr3" : "=r"(value) : : : "intel", "volatile");
+    value
+}
+
+unsafe fn read_cr4() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr4" : "=r"(value) : : : "intel", "volatile");
+    value
+}
+
+unsafe fn read_xcr0() -> u64 {
+    let lower: u64;
+    let higher: u64;
+    asm!("xgetbv" : "={edx}"(higher), "={eax}"(lower) : "{ecx}"(0u32) : : "volatile");
+    higher << 32 | lower
+}
+
+/// Start every other enabled cpu in the MADT.
+pub unsafe fn init() {
+    let local_apic = lapic::local_apic();
+    let bsp_apic_id = local_apic.id();
+
+    let mut mapper = PageMapper::new();
+    let page = Page::containing_address(VirtAddr::new(TRAMPOLINE_BASE));
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_BASE));
+
+    // The trampoline runs with paging enabled before it
+    // jumps into the kernel, so it has to be identity-mapped.
+    let identity_mapped = match mapper.translate(page) {
+        Some(mapped) if mapped == frame => true,
+        Some(_) => {
+            println!("smp: the trampoline page is in use, not starting other cpus");
+            return;
+        },
+        None => false,
+    };
+
+    if !identity_mapped {
+        mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+            .expect("Failed to map the ap trampoline")
+            .flush();
+    }
+
+    let trampoline_start = &ap_trampoline as *const u8;
+    let trampoline_size = &ap_trampoline_end as *const u8 as usize - trampoline_start as usize;
+    let data_offset = &ap_trampoline_data as *const u8 as usize - trampoline_start as usize;
+
+    ptr::copy_nonoverlapping(trampoline_start, TRAMPOLINE_BASE as *mut u8, trampoline_size);
+
+    let data = (TRAMPOLINE_BASE as usize + data_offset) as *mut TrampolineData;
+
+    let cr4 = read_cr4();
+    ptr::write_volatile(data, TrampolineData {
+        cr0: read_cr0(),
+        cr3: read_cr3(),
+        cr4,
+        efer: Msr::new(IA32_EFER).read(),
+        xcr0: if cr4 & CR4_OSXSAVE != 0 { read_xcr0() } else { 0 },
+        stack_top: 0,
+        entry: ap_main as usize as u64,
+        cpu_id: 0,
+        started: 0,
+    });
+
+    let apic_ids: Vec<u32> = acpi::tables()
+        .processors()
+        .map(|processor| processor.apic_id)
+        .filter(|&apic_id| apic_id != bsp_apic_id)
+        .collect();
+
+    let mut cpu_id: CpuId = 1;
+    for apic_id in apic_ids {
+        if cpu_id as usize >= MAX_CPUS {
+            println!("smp: only {} cpus are supported", MAX_CPUS);
+            break;
+        }
+
+        if !start_ap(data, cpu_id, apic_id) {
+            // The cpu might still run the trampoline later,
+            // so it can't be reused for another one.
+            println!("smp: cpu with apic id {} didn't start", apic_id);
+            break;
+        }
+
+        cpu_id += 1;
+    }
+
+    if !identity_mapped {
+        mapper.unmap_shared(page, &mut Shootdown::new())
+            .expect("Failed to unmap the ap trampoline");
+    }
+}
+
+/// Send INIT-SIPI-SIPI to a cpu and wait for it to come online.
+unsafe fn start_ap(data: *mut TrampolineData, cpu_id: CpuId, apic_id: u32) -> bool {
+    let stack = match WasmStack::allocate(AP_STACK_SIZE) {
+        Some(stack) => stack,
+        None => return false,
+    };
+
+    ptr::write_volatile(&mut (*data).stack_top, stack.top() as u64);
+    ptr::write_volatile(&mut (*data).cpu_id, cpu_id as u64);
+    ptr::write_volatile(&mut (*data).started, 0);
+
+    // The stack belongs to the cpu from now on.
+    mem::forget(stack);
+
+    AP_READY.store(false, Ordering::SeqCst);
+
+    let local_apic = lapic::local_apic();
+
+    local_apic.send_init_ipi(apic_id);
+    high_precision_timer::delay(10_000_000);
+
+    // The second startup ipi is only needed if the first one was missed.
+    for _ in 0..2 {
+        local_apic.send_startup_ipi(apic_id, (TRAMPOLINE_BASE >> 12) as u8);
+        high_precision_timer::delay(200_000);
+
+        if ptr::read_volatile(&(*data).started) != 0 {
+            break;
+        }
+    }
+
+    for _ in 0..1000 {
+        if AP_READY.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        high_precision_timer::delay(1_000_000);
+    }
+
+    false
+}
+
+/// Application processors enter the kernel here from the trampoline.
+extern "C" fn ap_main(cpu_id: u64) -> ! {
+    unsafe {
+        idt::init_ap();
+
+        fpu::init();
+
+        cpu::init(cpu_id as CpuId);
+
+        lapic::local_apic().enable();
+        clock_event::init();
+
+        AP_READY.store(true, Ordering::SeqCst);
+
+        // Switch to the dpc or idle thread of this cpu.
+        // This stack is never returned to.
+        Local::context_switch();
+    }
+
+    loop {
+        unsafe { interrupt::halt(); }
+    }
+}
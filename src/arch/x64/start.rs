@@ -1,6 +1,6 @@
 use bootloader::bootinfo::BootInfo;
 use arch::memory;
-use arch::{idt, interrupt, devices, paging, cpu, pci};
+use arch::{idt, interrupt, devices, paging, cpu, fpu, pci, acpi, smp};
 
 /// Test of zero values in BSS.
 static BSS_TEST_ZERO: usize = 0x0;
@@ -25,14 +25,23 @@ fn arch_start(boot_info: &'static BootInfo) -> ! {
         // Initialize the IDT
         idt::init();
         
+        // Enable the floating point and vector registers
+        fpu::init();
+
         // Initialize the cpu and cpu local structures
         cpu::init(0);
 
+        // Find the ACPI tables
+        acpi::init();
+
         // Initialize essential devices
         devices::init();
 
         // Initialize non-essential devices
         devices::init_noncore();
+
+        // Start the other cpus
+        smp::init();
     }
 
     find_pci_devices();
@@ -0,0 +1,179 @@
+//! Keeping the tlbs of every cpu coherent.
+//!
+//! All cpus share one address space, so any of them may have
+//! a translation cached. When a page is unmapped or its flags
+//! change, every other cpu is interrupted and has to invalidate
+//! it before the page's frame can be reused.
+
+use x86_64::structures::paging::{Page, PhysFrame, Size4KiB, PageSize};
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+use arch::cpu::{Local, CpuId, IrqController};
+use arch::devices::lapic;
+use arch::lock::Spinlock;
+use arch::interrupt;
+use arch::memory;
+use arch::idt;
+use sync::atomic::{Atomic, Ordering};
+use alloc::vec::Vec;
+
+/// Invalidating more pages than this flushes the whole tlb instead.
+const FULL_FLUSH_THRESHOLD: u64 = 32;
+
+const CR4_PGE: u64 = 1 << 7;
+
+/// Only one shootdown can be in flight at a time.
+static SHOOTDOWN_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// The range of the shootdown in flight.
+static REQUEST_START: Atomic<u64> = Atomic::new(0);
+static REQUEST_END: Atomic<u64> = Atomic::new(0);
+
+/// One bit for every cpu that hasn't invalidated the range yet.
+static PENDING: Atomic<u64> = Atomic::new(0);
+
+/// The pages whose translations were changed by a single
+/// operation. They're invalidated on every cpu when this
+/// is dropped, and only then are the frames freed.
+pub struct Shootdown {
+    /// The address of the lowest page.
+    start: u64,
+    /// The end of the highest page.
+    end: u64,
+    frames: Vec<PhysFrame>,
+}
+
+impl Shootdown {
+    pub fn new() -> Shootdown {
+        Shootdown {
+            start: u64::max_value(),
+            end: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Add a page whose translation has changed.
+    pub fn invalidate(&mut self, page: Page<Size4KiB>) {
+        let addr = page.start_address().as_u64();
+
+        if addr < self.start {
+            self.start = addr;
+        }
+        if addr + Size4KiB::SIZE > self.end {
+            self.end = addr + Size4KiB::SIZE;
+        }
+    }
+
+    /// Free `frame` once no cpu can reach it anymore.
+    pub fn free_frame(&mut self, frame: PhysFrame) {
+        self.frames.push(frame);
+    }
+}
+
+impl Drop for Shootdown {
+    fn drop(&mut self) {
+        if self.start < self.end {
+            shoot(self.start, self.end);
+        }
+
+        for frame in self.frames.drain(..) {
+            memory::deallocate_frame(frame);
+        }
+    }
+}
+
+unsafe fn read_cr4() -> u64 {
+    let value: u64;
+    asm!("mov $0, cr4" : "=r"(value) : : : "intel", "volatile");
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov cr4, $0" : : "r"(value) : "memory" : "intel", "volatile");
+}
+
+/// Invalidate `start..end` in the tlb of the current cpu.
+fn invalidate(start: u64, end: u64) {
+    if (end - start) / Size4KiB::SIZE > FULL_FLUSH_THRESHOLD {
+        // Reloading cr3 would leave global pages in the tlb,
+        // but toggling global pages off and on flushes them too.
+        unsafe {
+            let cr4 = read_cr4();
+            write_cr4(cr4 & !CR4_PGE);
+            write_cr4(cr4);
+        }
+    } else {
+        let mut addr = start;
+        while addr < end {
+            tlb::flush(VirtAddr::new(addr));
+            addr += Size4KiB::SIZE;
+        }
+    }
+}
+
+/// Invalidate `start..end` on every cpu and wait until they're done.
+fn shoot(start: u64, end: u64) {
+    let was_enabled = IrqController::enabled();
+    unsafe { IrqController::disable(); }
+
+    invalidate(start, end);
+
+    // There's nothing to do on a single cpu,
+    // or before the cpu locals are set up.
+    if Local::count() > 1 {
+        let cpu_id = Local::cpu_id();
+
+        let others = (0..Local::count() as CpuId)
+            .filter(|&id| id != cpu_id)
+            .fold(0u64, |mask, id| mask | 1 << id);
+
+        let _guard = loop {
+            if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+                break guard;
+            }
+
+            // Whoever holds the lock may be waiting on this cpu,
+            // which can't take the interrupt right now.
+            poll();
+            interrupt::pause();
+        };
+
+        REQUEST_START.store(start, Ordering::SeqCst);
+        REQUEST_END.store(end, Ordering::SeqCst);
+        PENDING.store(others, Ordering::SeqCst);
+
+        let local_apic = lapic::local_apic();
+        for id in (0..Local::count() as CpuId).filter(|&id| others & 1 << id != 0) {
+            if let Some(local) = Local::get(id) {
+                local_apic.send_fixed_ipi(local.cpu().apic_id(), idt::TLB_SHOOTDOWN_VECTOR);
+            }
+        }
+
+        while PENDING.load(Ordering::SeqCst) != 0 {
+            interrupt::pause();
+        }
+    }
+
+    if was_enabled {
+        unsafe { IrqController::enable(); }
+    }
+}
+
+/// Invalidate the shootdown in flight on this cpu, if it's waiting on it.
+///
+/// This is also called while spinning on a lock with interrupts
+/// disabled, since the cpu that holds the lock may be waiting for
+/// this one. So it doesn't touch the cpu local unless it has to.
+pub fn poll() {
+    if PENDING.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+
+    let bit = 1u64 << Local::cpu_id();
+
+    if PENDING.load(Ordering::SeqCst) & bit != 0 {
+        invalidate(REQUEST_START.load(Ordering::SeqCst), REQUEST_END.load(Ordering::SeqCst));
+
+        PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
@@ -3,4 +3,5 @@ pub mod bitarray;
 pub mod table;
 pub mod util;
 pub mod slice;
-pub mod tar;
\ No newline at end of file
+pub mod tar;
+pub mod sha256;
\ No newline at end of file
@@ -44,6 +44,7 @@ impl<'a, T> From<&'a [T]> for UncheckedSlice<T> {
     }
 }
 
+#[repr(C)]
 pub struct BoundedSlice<T> {
     data: UncheckedSlice<T>,
     len: usize,
@@ -48,7 +48,6 @@ extern crate nabi;
 extern crate raw_cpuid;
 extern crate rand_core;
 extern crate rand;
-extern crate acpi;
 
 extern crate cranelift_wasm;
 extern crate cranelift_native;
@@ -79,7 +78,7 @@ pub mod event;
 
 pub use consts::*;
 
-use object::{Thread, Process, Wasm, Channel, HandleRights, Dispatcher};
+use object::{Thread, Process, Wasm, CompileFlags, Channel, SystemResource, HandleRights, Dispatcher};
 use object::channel;
 use event::{Event, EventVariant};
 use object::dispatcher::LocalObserver;
@@ -116,7 +115,15 @@ fn first_thread(init_fs: &[u8]) {
         file.path == "sipinit.wasm"
     }).unwrap();
 
-    let code = Wasm::compile(wasm.data)
+    // Prefer code that was compiled ahead of time,
+    // since compiling at boot is slow.
+    let artifact = tar.iter().find(|file| {
+        file.path == "sipinit.aot"
+    });
+
+    let code = artifact
+        .and_then(|artifact| Wasm::load_aot(artifact.data, wasm.data).ok())
+        .map_or_else(|| Wasm::compile(wasm.data, CompileFlags::empty()), Ok)
         .unwrap();
 
     let process = Process::create(code.copy_ref())
@@ -128,6 +135,11 @@ fn first_thread(init_fs: &[u8]) {
         let mut handle_table = process.handle_table().write();
         let handle = handle_table.allocate(rx, HandleRights::READ | HandleRights::TRANSFER).unwrap();
         assert!(handle.inner() == 0);
+
+        // The init process can control the system and delegate that.
+        let rights = HandleRights::WRITE | HandleRights::TRANSFER | HandleRights::DUPLICATE;
+        let handle = handle_table.allocate(SystemResource::new(), rights).unwrap();
+        assert!(handle.inner() == 1);
     }
 
     process.start().unwrap();
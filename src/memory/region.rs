@@ -4,11 +4,15 @@ use x86_64::structures::paging::{Page, PhysFrame, PageSize, Size4KiB,
     PageTableFlags, PageRangeInclusive, MapToError, UnmapError};
 
 use arch::paging::PageMapper;
+use arch::tlb::Shootdown;
 use arch::memory;
 
 use core::ops::{Deref, DerefMut};
 use core::slice;
 use sync::atomic::{Atomic, Ordering};
+use arch::lock::Spinlock;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
 
 use nabi::{Error, Result};
 
@@ -21,6 +25,9 @@ bitflags! {
         const READ  = 1 << 0;
         const WRITE = 1 << 1;
         const EXEC  = 1 << 2;
+        /// Bypass the cache. This is needed
+        /// for memory-mapped device registers.
+        const UNCACHED = 1 << 3;
     }
 }
 
@@ -37,6 +44,9 @@ impl Into<PageTableFlags> for MemFlags {
         if !self.contains(MemFlags::EXEC) {
             flags |= PageTableFlags::NO_EXECUTE;
         }
+        if self.contains(MemFlags::UNCACHED) {
+            flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        }
 
         flags
     }
@@ -83,6 +93,14 @@ impl Region {
         self.flags
     }
 
+    #[inline]
+    pub fn contains(&self, addr: *const ()) -> bool {
+        let start = self.start.as_u64() as usize;
+        let end = start + self.size;
+
+        (start..end).contains(&(addr as _))
+    }
+
     fn pages(&self) -> PageRangeInclusive {
         let start_page = Page::containing_address(self.start);
         let end_page = Page::containing_address(self.start + self.size as u64 - 1 as u64);
@@ -109,10 +127,11 @@ impl Region {
 
     fn unmap(&mut self) -> Result<()> {
         let mut mapper = unsafe { PageMapper::new() };
+        let mut shootdown = Shootdown::new();
 
         for page in self.pages() {
-            match mapper.unmap(page) {
-                Ok(mf) => mf.flush(),
+            match mapper.unmap(page, &mut shootdown) {
+                Ok(()) => {},
                 Err(UnmapError::PageNotMapped) => {},
                 Err(_) => return Err(internal_error!()),
             }
@@ -122,12 +141,12 @@ impl Region {
 
     pub fn remap(&mut self, new_flags: MemFlags) -> Result<()> {
         let mut mapper = unsafe { PageMapper::new() };
+        let mut shootdown = Shootdown::new();
         let new_flags = new_flags.into();
 
         for page in self.pages() {
-            mapper.remap(page, new_flags)
-                .map_err(|_| internal_error!())?
-                .flush();
+            mapper.remap(page, new_flags, &mut shootdown)
+                .map_err(|_| internal_error!())?;
         }
 
         self.flags = new_flags;
@@ -178,11 +197,13 @@ impl Region {
                 }
             }
         } else if new_size < self.size {
+            let mut shootdown = Shootdown::new();
+
             let start_page = Page::containing_address(self.start + new_size as u64);
             let end_page = Page::containing_address(self.start + self.size as u64 - 1 as u64);
             for page in Page::range_inclusive(start_page, end_page) {
-                match mapper.unmap(page) {
-                    Ok(mf) => mf.flush(),
+                match mapper.unmap(page, &mut shootdown) {
+                    Ok(()) => {},
                     Err(UnmapError::PageNotMapped) => {},
                     Err(_) => return Err(internal_error!()),
                 }
@@ -219,6 +240,120 @@ impl Drop for Region {
     }
 }
 
+/// Represents a region of virtual memory that is mapped
+/// to a specific range of physical memory, like firmware
+/// tables or device registers.
+///
+/// The physical frames don't belong to the region, so they
+/// are not returned to the frame allocator when it's dropped.
+#[derive(Debug)]
+pub struct PhysRegion {
+    /// The page-aligned start of the mapping.
+    start: VirtAddr,
+    /// The size of the mapping, in bytes.
+    mapped_size: usize,
+    phys_addr: PhysAddr,
+    size: usize,
+    /// The size of the space that's given back to the
+    /// SIP allocator when this is dropped, if it came from there.
+    reserved_size: usize,
+}
+
+impl PhysRegion {
+    /// Convenience method to map a region directly into the Sip memory allocator's space.
+    pub fn map(phys_addr: PhysAddr, size: usize, flags: MemFlags) -> Result<PhysRegion> {
+        let (start, reserved_size) = super::SIP_ALLOCATOR.lock().allocate_phys_space(phys_addr, size)?;
+
+        match PhysRegion::new(start, phys_addr, size, flags) {
+            Ok(mut region) => {
+                region.reserved_size = reserved_size;
+                Ok(region)
+            },
+            Err(err) => {
+                super::SIP_ALLOCATOR.lock().free_phys_space(start, reserved_size);
+                Err(err)
+            },
+        }
+    }
+
+    /// `start` must be page-aligned and have enough space after it
+    /// to map every page that `phys_addr..phys_addr + size` touches.
+    pub fn new(start: VirtAddr, phys_addr: PhysAddr, size: usize, flags: MemFlags) -> Result<Self> {
+        let mut mapper = unsafe { PageMapper::new() };
+
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+        let end_frame = PhysFrame::containing_address(phys_addr + size as u64 - 1 as u64) + 1;
+        let start_page = Page::containing_address(start);
+
+        let mut region = PhysRegion {
+            start,
+            mapped_size: 0,
+            phys_addr,
+            size,
+            reserved_size: 0,
+        };
+
+        for (i, frame) in PhysFrame::range(start_frame, end_frame).enumerate() {
+            mapper.map_to(start_page + i as u64, frame, flags.into())
+                .map_err(|_| internal_error!())?
+                .flush();
+
+            region.mapped_size += Size4KiB::SIZE as usize;
+        }
+
+        Ok(region)
+    }
+
+    /// The virtual address that `phys_addr` is mapped to.
+    pub fn start(&self) -> VirtAddr {
+        self.start + (self.phys_addr.as_u64() % Size4KiB::SIZE)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn mapped_size(&self) -> usize {
+        self.mapped_size
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    fn unmap(&mut self) -> Result<()> {
+        if self.mapped_size == 0 {
+            return Ok(());
+        }
+
+        let mut mapper = unsafe { PageMapper::new() };
+        let mut shootdown = Shootdown::new();
+
+        let start_page = Page::containing_address(self.start);
+        let end_page = Page::containing_address(self.start + self.mapped_size as u64 - 1 as u64);
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            match mapper.unmap_shared(page, &mut shootdown) {
+                Ok(()) => {},
+                Err(UnmapError::PageNotMapped) => {},
+                Err(_) => return Err(internal_error!()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PhysRegion {
+    fn drop(&mut self) {
+        // ignore the result
+        let _ = self.unmap();
+
+        if self.reserved_size != 0 {
+            super::SIP_ALLOCATOR.lock().free_phys_space(self.start, self.reserved_size);
+        }
+    }
+}
+
 /// Represents a region of virtual memory
 /// that may or may not be currently mapped to 
 /// physical memory. On accessing a lazily
@@ -228,6 +363,18 @@ pub struct LazyRegion {
     start: VirtAddr,
     size: Atomic<usize>,
     flags: PageTableFlags,
+    /// Ranges mapped to the frames of other regions.
+    shared: Spinlock<Vec<SharedMapping>>,
+}
+
+/// A range of a `LazyRegion` that is mapped to
+/// the frames of another `Region`. The other region
+/// is kept alive until the range is unmapped.
+#[derive(Debug)]
+struct SharedMapping {
+    start: VirtAddr,
+    size: usize,
+    owner: Arc<Region>,
 }
 
 impl LazyRegion {
@@ -236,6 +383,7 @@ impl LazyRegion {
             start,
             size: Atomic::new(size),
             flags: flags.into(),
+            shared: Spinlock::new(Vec::new()),
         })
     }
 
@@ -297,10 +445,11 @@ impl LazyRegion {
         let end_page = Page::containing_address(VirtAddr::new(end as _));
 
         let mut mapper = unsafe { PageMapper::new() };
+        let mut shootdown = Shootdown::new();
 
         for page in Page::range_inclusive(start_page, end_page) {
-            match mapper.unmap(page) {
-                Ok(mf) => mf.flush(),
+            match mapper.unmap(page, &mut shootdown) {
+                Ok(()) => {},
                 Err(_) => return Err(internal_error!()),
             }
         }
@@ -389,6 +538,61 @@ impl LazyRegion {
         Ok(physical_start)
     }
 
+    /// The offset of the frames backing `owner` in the
+    /// region, if they're already mapped into it.
+    pub fn shared_offset(&self, owner: &Arc<Region>) -> Option<usize> {
+        Self::find_shared(&self.shared.lock(), self.start, owner)
+    }
+
+    fn find_shared(shared: &[SharedMapping], start: VirtAddr, owner: &Arc<Region>) -> Option<usize> {
+        shared
+            .iter()
+            .find(|mapping| Arc::ptr_eq(&mapping.owner, owner))
+            .map(|mapping| (mapping.start.as_u64() - start.as_u64()) as usize)
+    }
+
+    /// Map the frames backing `owner` to the end of the region,
+    /// unless they're already mapped into it. They are unmapped,
+    /// but not freed, when the region is dropped.
+    ///
+    /// Returns the offset of the mapped frames in the region.
+    pub fn grow_shared(&self, owner: &Arc<Region>) -> Result<usize> {
+        // Held until the mapping is recorded, so that
+        // the same frames can't be mapped twice.
+        let mut shared = self.shared.lock();
+
+        if let Some(offset) = Self::find_shared(&shared, self.start, owner) {
+            return Ok(offset);
+        }
+
+        let mut mapper = unsafe { PageMapper::new() };
+
+        let by = owner.size();
+        let rounded_up_size_wasm = (((by - 1) / (1 << 16)) + 1) * (1 << 16);
+
+        let offset = self.size.fetch_add(rounded_up_size_wasm, Ordering::SeqCst);
+
+        let working_mem_start = self.start + offset as u64;
+        let start_page = Page::containing_address(working_mem_start);
+
+        for (i, owner_page) in owner.pages().enumerate() {
+            let frame = mapper.translate(owner_page)
+                .ok_or(internal_error!())?;
+
+            mapper.map_to(start_page + i as u64, frame, self.flags)
+                .map_err(|_| internal_error!())?
+                .flush();
+        }
+
+        shared.push(SharedMapping {
+            start: working_mem_start,
+            size: by,
+            owner: Arc::clone(owner),
+        });
+
+        Ok(offset)
+    }
+
     fn pages(&self) -> PageRangeInclusive {
         let size = self.size.load(Ordering::Relaxed) as u64;
         let start_page = Page::containing_address(self.start);
@@ -398,10 +602,24 @@ impl LazyRegion {
 
     fn unmap_all(&self) -> Result<()> {
         let mut mapper = unsafe { PageMapper::new() };
+        let mut shootdown = Shootdown::new();
+
+        // shared frames must not be returned to the frame allocator
+        for mapping in self.shared.lock().iter() {
+            let start_page = Page::containing_address(mapping.start);
+            let end_page = Page::containing_address(mapping.start + mapping.size as u64 - 1 as u64);
+            for page in Page::range_inclusive(start_page, end_page) {
+                match mapper.unmap_shared(page, &mut shootdown) {
+                    Ok(()) => {},
+                    Err(UnmapError::PageNotMapped) => {},
+                    Err(_) => return Err(internal_error!()),
+                }
+            }
+        }
 
         for page in self.pages() {
-            match mapper.unmap(page) {
-                Ok(mf) => mf.flush(),
+            match mapper.unmap(page, &mut shootdown) {
+                Ok(()) => {},
                 Err(UnmapError::PageNotMapped) => {},
                 Err(_) => return Err(internal_error!()),
             }
@@ -1,12 +1,16 @@
 use x86_64::structures::paging::{Size4KiB, PageSize};
-use x86_64::VirtAddr;
+use x86_64::{VirtAddr, PhysAddr};
+
+use wasm::layout::{self, MemoryPlan};
 
 use core::ops::{Deref, DerefMut};
 use core::mem;
 
 use memory::{LazyRegion, Region, MemFlags};
 
-use nabi::Result;
+use nabi::{Result, Error};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 /// Represents the entirety of the virtual memory that can be allocated to SIPs
 ///
@@ -15,6 +19,10 @@ pub struct SipAllocator {
     /// The end of available SIP memory
     end: usize,
     bump: usize,
+    /// Ranges that dropped `PhysRegion`s gave back, as (start, size).
+    /// Devices are mapped and unmapped over and over, so unlike
+    /// the rest of the space, this is reused.
+    free_phys: Option<Vec<(usize, usize)>>,
 }
 
 impl SipAllocator {
@@ -23,6 +31,7 @@ impl SipAllocator {
         SipAllocator {
             end,
             bump: start,
+            free_phys: None,
         }
     }
 
@@ -45,8 +54,59 @@ impl SipAllocator {
         }
     }
 
-    /// Allocate a `Memory`.
-    fn allocate_wasm_memory(&mut self, pre_space: usize) -> Option<WasmMemory> {
+    /// Reserve enough space to map `size` bytes of physical memory,
+    /// starting at `phys_addr`. The space is given back with
+    /// `free_phys_space`.
+    pub(super) fn allocate_phys_space(&mut self, phys_addr: PhysAddr, size: usize) -> Result<(VirtAddr, usize)> {
+        if size == 0 {
+            return Err(Error::INVALID_ARG);
+        }
+
+        let offset = phys_addr.as_u64() as usize % Size4KiB::SIZE as usize;
+        let allocated_size = (offset + size + Size4KiB::SIZE as usize - 1) & !(Size4KiB::SIZE as usize - 1);
+
+        let free_phys = self.free_phys.get_or_insert_with(Vec::new);
+        if let Some(index) = free_phys.iter().position(|&(_, free_size)| free_size >= allocated_size) {
+            let (start, free_size) = free_phys[index];
+            if free_size == allocated_size {
+                free_phys.swap_remove(index);
+            } else {
+                free_phys[index] = (start + allocated_size, free_size - allocated_size);
+            }
+
+            return Ok((VirtAddr::new(start as u64), allocated_size));
+        }
+
+        if self.bump + allocated_size > self.end {
+            Err(Error::NO_MEMORY)
+        } else {
+            let virt_addr = VirtAddr::new(self.bump as u64);
+            self.bump += allocated_size;
+            Ok((virt_addr, allocated_size))
+        }
+    }
+
+    /// Give back space that `allocate_phys_space` reserved.
+    pub(super) fn free_phys_space(&mut self, start: VirtAddr, mut size: usize) {
+        let free_phys = self.free_phys.get_or_insert_with(Vec::new);
+        let mut start = start.as_u64() as usize;
+
+        // Merge it with the ranges on either side of it.
+        if let Some(index) = free_phys.iter().position(|&(free_start, free_size)| free_start + free_size == start) {
+            let (before, before_size) = free_phys.swap_remove(index);
+            start = before;
+            size += before_size;
+        }
+        if let Some(index) = free_phys.iter().position(|&(free_start, _)| free_start == start + size) {
+            let (_, after_size) = free_phys.swap_remove(index);
+            size += after_size;
+        }
+
+        free_phys.push((start, size));
+    }
+
+    /// Allocate a `Memory`, reserving as much space as `plan` says.
+    fn allocate_wasm_memory(&mut self, pre_space: usize, plan: MemoryPlan) -> Option<WasmMemory> {
         let pre_space = if pre_space != 0 {
             let rem = pre_space % Size4KiB::SIZE as usize;
             pre_space + Size4KiB::SIZE as usize - rem
@@ -54,7 +114,7 @@ impl SipAllocator {
             0
         };
 
-        let allocated_size = WasmMemory::DEFAULT_SIZE + pre_space; // 8 GiB
+        let allocated_size = plan.reserved_size() + pre_space;
 
         if self.bump + allocated_size > self.end {
             None
@@ -75,7 +135,8 @@ impl SipAllocator {
 
             Some(WasmMemory {
                 region: region,
-                total_size: WasmMemory::DEFAULT_SIZE,
+                total_size: plan.reserved_size(),
+                max_size: plan.max_size,
                 pre_region,
             })
         }
@@ -123,20 +184,29 @@ impl SipAllocator {
 #[derive(Debug)]
 pub struct WasmMemory {
     region: LazyRegion,
+    /// The size of the reservation, including the guard.
     total_size: usize,
+    /// The most that the memory can grow to.
+    max_size: usize,
     pub pre_region: Option<Region>,
 }
 
 unsafe impl Sync for WasmMemory {}
 
 impl WasmMemory {
-    pub const WASM_PAGE_SIZE: usize = 1 << 16; // 64 KiB
-    pub const DEFAULT_HEAP_SIZE: usize = 1 << 32; // 4 GiB
-    pub const DEFAULT_GUARD_SIZE: usize = 1 << 31; // 2 GiB
-    pub const DEFAULT_SIZE: usize = Self::DEFAULT_HEAP_SIZE + Self::DEFAULT_GUARD_SIZE; // 8GiB
+    pub const WASM_PAGE_SIZE: usize = layout::WASM_PAGE_SIZE; // 64 KiB
 
-    pub fn allocate(pre_space: usize) -> Option<WasmMemory> {
-        super::SIP_ALLOCATOR.lock().allocate_wasm_memory(pre_space)
+    pub fn allocate(pre_space: usize, plan: MemoryPlan) -> Option<WasmMemory> {
+        super::SIP_ALLOCATOR.lock().allocate_wasm_memory(pre_space, plan)
+    }
+
+    /// Fails if the memory can't grow by `size` bytes
+    /// without going over its maximum.
+    fn check_grow(&self, size: usize) -> Result<()> {
+        match self.mapped_size().checked_add(size) {
+            Some(new_size) if new_size <= self.max_size => Ok(()),
+            _ => Err(Error::NO_MEMORY),
+        }
     }
 
     #[inline]
@@ -148,7 +218,8 @@ impl WasmMemory {
     /// multiples of `WasmMemory::WASM_PAGE_SIZE`.
     /// This starts at `mapped_end` and bump up.
     /// 
-    /// Returns the number of pages before growing.
+    /// Returns the number of pages before growing, or fails
+    /// if that would go over the memory's maximum.
     pub fn grow(&self, count: usize) -> Result<usize> {
         let old_count = self.page_count();
 
@@ -156,13 +227,12 @@ impl WasmMemory {
             return Ok(old_count);
         }
 
-        let new_size = (old_count + count) * Self::WASM_PAGE_SIZE; 
-        if new_size > self.total_size {
-            Err(internal_error!())
-        } else {
-            self.region.resize(new_size)?;
-            Ok(old_count)
-        }
+        let size = count.checked_mul(Self::WASM_PAGE_SIZE)
+            .ok_or(Error::NO_MEMORY)?;
+        self.check_grow(size)?;
+
+        self.region.resize(self.mapped_size() + size)?;
+        Ok(old_count)
     }
 
     /// Map the specified region of physical memory to the next free part
@@ -171,6 +241,7 @@ impl WasmMemory {
     /// Returns the offset of the mapped region in the wasm linear memory.
     pub fn physical_map(&self, phys_addr: u64, size: usize) -> Result<usize> {
         let old_count = self.page_count();
+        self.check_grow(size)?;
 
         self.region.grow_from_phys_addr(size, phys_addr as _)
             .map(|_| old_count * Self::WASM_PAGE_SIZE)
@@ -179,11 +250,31 @@ impl WasmMemory {
     /// Request a physically continuous memory region
     pub fn physical_alloc(&self, size: usize) -> Result<(u64, u32)> {
         let old_count = self.page_count();
+        self.check_grow(size)?;
 
         self.region.grow_physically_contiguous(size)
             .map(|phys_addr| (phys_addr.as_u64(), (old_count * Self::WASM_PAGE_SIZE) as u32))
     }
 
+    /// Map the frames of a region that is shared with
+    /// other processes to the next free part of the wasm
+    /// linear memory. A region is only ever mapped once,
+    /// so mapping it again doesn't grow the memory.
+    ///
+    /// Returns the offset of the mapped region in the wasm linear memory.
+    pub fn shared_map(&self, region: &Arc<Region>) -> Result<usize> {
+        if let Some(offset) = self.region.shared_offset(region) {
+            return Ok(offset);
+        }
+
+        // The memory grows by whole wasm pages.
+        let size = region.size()
+            .checked_add(Self::WASM_PAGE_SIZE - 1)
+            .ok_or(Error::NO_MEMORY)? & !(Self::WASM_PAGE_SIZE - 1);
+        self.check_grow(size)?;
+        self.region.grow_shared(region)
+    }
+
     pub fn carve_slice(&self, offset: u32, size: u32) -> Option<&[u8]> {
         let start = offset as usize;
         let end = start + size as usize;
@@ -246,6 +337,12 @@ impl WasmMemory {
         self.region.start()
     }
 
+    /// The size of the address space that the memory
+    /// reserves, including its guard.
+    pub fn reserved_size(&self) -> usize {
+        self.total_size
+    }
+
     pub fn unmapped_size(&self) -> usize {
         self.total_size - self.mapped_size()
     }
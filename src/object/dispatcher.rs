@@ -1,7 +1,7 @@
 use signals::Signal;
 use nabi::{Result, Error};
 use common::table::{Table, TableSlot};
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use spin::Mutex;
 use super::Handle;
 use sync::atomic::{Atomic, Ordering};
@@ -15,6 +15,8 @@ unsafe impl Sync for Context {}
 struct Context {
     signals: Atomic<Signal>,
     observers: Mutex<Table<*mut (dyn StateObserver)>>,
+    /// The number of handles to the object.
+    handles: Atomic<usize>,
 }
 
 impl Context {
@@ -22,6 +24,7 @@ impl Context {
         Context {
             signals: Atomic::new(Signal::empty()),
             observers: Mutex::new(Table::new()),
+            handles: Atomic::new(0),
         }
     }
 
@@ -84,6 +87,30 @@ where
         }
     }
 
+    /// The number of references to the object, including this one.
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+
+    /// A reference that doesn't keep the object alive.
+    pub fn downgrade(&self) -> WeakDispatch<T> {
+        WeakDispatch {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    pub(super) fn add_handle(&self) {
+        self.ctx().handles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Dropping the last handle to the object
+    /// calls `on_zero_handles`.
+    pub(super) fn remove_handle(&self) {
+        if self.ctx().handles.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.inner.dispatcher.on_zero_handles();
+        }
+    }
+
     fn ctx(&self) -> &Context {
         &self.inner.ctx
     }
@@ -130,6 +157,20 @@ where
     }
 }
 
+pub struct WeakDispatch<T: Dispatcher + ?Sized> {
+    inner: Weak<DispatchInner<T>>,
+}
+
+impl<T> WeakDispatch<T>
+where
+    T: Dispatcher + ?Sized
+{
+    /// Returns `None` if the object has been destroyed.
+    pub fn upgrade(&self) -> Option<Dispatch<T>> {
+        self.inner.upgrade().map(|inner| Dispatch { inner })
+    }
+}
+
 impl Dispatch<Dispatcher> {
     pub fn cast<T: Dispatcher>(&self) -> Result<Dispatch<T>> {
         if self.inner.dispatcher.get_type_id() == TypeId::of::<T>() {
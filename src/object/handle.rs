@@ -15,6 +15,7 @@ pub struct Handle<T: Dispatcher + ?Sized> {
 impl<T: Dispatcher + ?Sized> Handle<T>
 {
     pub fn new(dispatch: Dispatch<T>, rights: HandleRights) -> Handle<T> {
+        dispatch.add_handle();
         Handle {
             dispatch,
             rights,
@@ -23,10 +24,7 @@ impl<T: Dispatcher + ?Sized> Handle<T>
     
     pub fn duplicate(&self, new_rights: HandleRights) -> Option<Self> {
         if self.rights.contains(new_rights | HandleRights::DUPLICATE) {
-            Some(Handle {
-                dispatch: self.dispatch.copy_ref(),
-                rights: new_rights,
-            })
+            Some(Handle::new(self.dispatch.copy_ref(), new_rights))
         } else {
             None
         }
@@ -54,10 +52,7 @@ where
     T: Dispatcher + Sized
 {
     pub fn upcast(self) -> Handle<Dispatcher> {
-        Handle {
-            dispatch: self.dispatch.upcast(),
-            rights: self.rights,
-        }
+        Handle::new(self.dispatch.copy_ref().upcast(), self.rights)
     }
 }
 
@@ -65,10 +60,16 @@ impl Handle<Dispatcher> {
     pub fn cast<T: Dispatcher>(&self) -> Result<Handle<T>> {
         let dispatch = self.dispatch.cast()?;
 
-        Ok(Handle {
-            dispatch,
-            rights: self.rights,
-        })
+        Ok(Handle::new(dispatch, self.rights))
+    }
+}
+
+impl<T> Drop for Handle<T>
+where
+    T: Dispatcher + ?Sized
+{
+    fn drop(&mut self) {
+        self.dispatch.remove_handle();
     }
 }
 
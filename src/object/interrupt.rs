@@ -1,29 +1,62 @@
-use sync::atomic::{Atomic, Ordering};
 use object::dispatcher::{Dispatch, Dispatcher};
 use object::channel::{Channel, Message};
+use object::thread::{Thread, State};
+use sync::spsc::IntrusiveSpsc;
+use sync::atomic::{Atomic, Ordering};
+use arch::lock::IrqSpinlock;
+use arch::interrupt;
+use arch::idt::IrqResult;
+use arch::devices::ioapic::{self, Trigger, Polarity};
+use arch::devices::pic;
 use alloc::vec::Vec;
-use time::Instant;
 use core::ops::Deref;
 use core::{slice, mem};
-use arch::interrupt;
+use time;
 use nabi::{Result, Error};
 
 bitflags! {
-    pub struct InterruptFlags: u32 {
-        const UNMASK_PREWAIT = 1 << 0;
-        const MASK_POSTWAIT = 1 << 1;
+    pub struct InterruptOptions: u32 {
+        /// The line is level-triggered. It will be masked
+        /// when the interrupt fires and unmasked when the
+        /// interrupt is acknowledged. Without this option,
+        /// the line is treated as edge-triggered.
+        const LEVEL = 1 << 0;
+        /// The interrupt isn't bound to a hardware vector
+        /// and can only be triggered by software.
+        const VIRTUAL = 1 << 1;
+        /// Allow other handlers to be registered on the
+        /// same line. When a level-triggered line fires, it's
+        /// delivered to one interrupt object that isn't already
+        /// waiting to be acknowledged, and that driver has to
+        /// check whether its device raised it. If it didn't, the
+        /// line fires again once it's acknowledged and goes to the
+        /// next one. An edge-triggered line is delivered to all of
+        /// them, since it won't fire again.
+        const SHARED = 1 << 2;
+        /// The supplied number is a legacy ISA irq instead
+        /// of a global system interrupt. It's translated
+        /// through the interrupt source overrides, which
+        /// also decide how the line is triggered.
+        const ISA = 1 << 3;
     }
 }
 
-#[derive(Eq, PartialEq, Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
 enum InterruptState {
-    // Waiting,
-    // Triggered,
-    // Destroyed,
-    NeedAck,
+    /// Nothing has happened since the last wait.
     Idle,
+    /// The interrupt has fired, but no one has waited on it yet.
+    Triggered,
+    /// A level-triggered interrupt has been delivered,
+    /// but hasn't been acknowledged. The line is held masked.
+    NeedAck,
+    /// The last handle was closed, so the
+    /// interrupt won't be delivered again.
+    Closed,
 }
 
+/// What an interrupt created with a channel sends
+/// on it every time the interrupt fires.
 #[repr(C)]
 struct InterruptPacket {
     seconds: u64,
@@ -37,98 +70,316 @@ impl Deref for InterruptPacket {
     }
 }
 
+struct InterruptInner {
+    state: InterruptState,
+    /// Monotonic time, in nanoseconds, of the last trigger.
+    timestamp: u64,
+    waiters: IntrusiveSpsc<Thread>,
+}
+
+/// An interrupt that a driver can wait on.
 pub struct Interrupt {
-    channel: Dispatch<Channel>,
-    state: Atomic<InterruptState>,
-    flags: InterruptFlags,
-    vector: u32,
+    inner: IrqSpinlock<InterruptInner>,
+    options: InterruptOptions,
+    /// The global system interrupt that this is bound to.
+    gsi: u32,
+    trigger: Trigger,
+    polarity: Polarity,
+    registered: Atomic<bool>,
+    /// Packets are sent on this instead of waking waiters.
+    channel: Option<Dispatch<Channel>>,
 }
 
 impl Interrupt {
-    pub fn new(channel: Dispatch<Channel>, flags: InterruptFlags, vector: u32) -> Dispatch<Interrupt> {
-        Dispatch::new(Interrupt {
+    pub fn new(number: u32, options: InterruptOptions) -> Result<Dispatch<Interrupt>> {
+        Self::create(number, options, None)
+    }
+
+    /// Create an interrupt that sends a packet on `channel` every
+    /// time it fires, the way interrupts worked before they could be
+    /// waited on. `vector` is the vector that the 8259 PIC delivered
+    /// the ISA irq to. The line stays masked until it's acknowledged.
+    pub fn with_channel(channel: Dispatch<Channel>, vector: u32) -> Result<Dispatch<Interrupt>> {
+        let irq = vector.checked_sub(pic::MASTER_OFFSET as u32)
+            .ok_or(Error::INVALID_ARG)?;
+
+        Self::create(irq, InterruptOptions::ISA, Some(channel))
+    }
+
+    fn create(number: u32, options: InterruptOptions, channel: Option<Dispatch<Channel>>) -> Result<Dispatch<Interrupt>> {
+        let (gsi, trigger, polarity) = if options.contains(InterruptOptions::VIRTUAL) {
+            (0, Trigger::Edge, Polarity::ActiveHigh)
+        } else if options.contains(InterruptOptions::ISA) {
+            if number > u8::max_value() as u32 {
+                return Err(Error::INVALID_ARG);
+            }
+
+            ioapic::isa_irq(number as u8)?
+        } else if options.contains(InterruptOptions::LEVEL) {
+            // PCI interrupts are level-triggered and active-low.
+            (number, Trigger::Level, Polarity::ActiveLow)
+        } else {
+            (number, Trigger::Edge, Polarity::ActiveHigh)
+        };
+
+        Ok(Dispatch::new(Interrupt {
+            inner: IrqSpinlock::new(InterruptInner {
+                state: InterruptState::Idle,
+                timestamp: 0,
+                waiters: IntrusiveSpsc::new(),
+            }),
+            options,
+            gsi,
+            trigger,
+            polarity,
+            registered: Atomic::new(false),
             channel,
-            state: Atomic::new(InterruptState::Idle),
-            flags,
-            vector,
-        })
+        }))
+    }
+
+    pub fn options(&self) -> InterruptOptions {
+        self.options
+    }
+
+    pub fn gsi(&self) -> u32 {
+        self.gsi
     }
 
-    fn send_packet(&self, timestamp: Instant) -> Result<()> {
-        let duration = timestamp.duration_since(Instant::EPOCH);
+    fn is_virtual(&self) -> bool {
+        self.options.contains(InterruptOptions::VIRTUAL)
+    }
+
+    fn is_level(&self) -> bool {
+        self.trigger == Trigger::Level
+    }
+
+    /// Whether the line is masked from when the
+    /// interrupt fires until it's acknowledged.
+    fn masks_until_ack(&self) -> bool {
+        self.is_level() || self.channel.is_some()
+    }
+
+    fn send_packet(channel: &Dispatch<Channel>, timestamp: u64) -> Result<()> {
         let packet = InterruptPacket {
-            seconds: duration.as_secs(),
-            nanos: duration.subsec_nanos(),
+            seconds: timestamp / 1_000_000_000,
+            nanos: (timestamp % 1_000_000_000) as u32,
         };
 
         let msg = Message::new(&packet, Vec::new())?;
-        self.channel.send(msg)
+        channel.send(msg)
     }
 
-    pub fn mask(&self) {
-        unsafe {
-            interrupt::mask(self.vector as _);
+    /// Keep the line masked until this interrupt is acknowledged.
+    /// Other interrupts on a shared line may be holding it too.
+    fn hold_line(&self) {
+        if !self.is_virtual() {
+            unsafe {
+                interrupt::hold(self.gsi);
+            }
         }
     }
 
-    pub fn unmask(&self) {
-        unsafe {
-            interrupt::unmask(self.vector as _);
+    fn release_line(&self) {
+        if !self.is_virtual() {
+            unsafe {
+                interrupt::release(self.gsi);
+            }
         }
     }
 
-    fn interrupt_handler(this: *const ()) {
+    fn interrupt_handler(this: *const ()) -> IrqResult {
         let this = unsafe { &*(this as *const Self) };
 
-        if this.flags.contains(InterruptFlags::MASK_POSTWAIT) {
-            this.mask();
+        let delivered = this.handle();
+
+        // An edge won't come again, so every
+        // handler on a shared line has to see it.
+        if delivered && (this.is_level() || !this.options.contains(InterruptOptions::SHARED)) {
+            IrqResult::Claimed
+        } else {
+            IrqResult::NotMine
+        }
+    }
+
+    /// Returns whether the interrupt was delivered, which it
+    /// isn't if the last one is still waiting to be acknowledged.
+    fn handle(&self) -> bool {
+        let mut inner = self.inner.lock();
+
+        match inner.state {
+            InterruptState::Idle => {
+                inner.timestamp = time::monotonic();
+
+                if self.masks_until_ack() {
+                    // The device will keep the line asserted
+                    // until the driver services it.
+                    self.hold_line();
+                }
+
+                if let Some(ref channel) = self.channel {
+                    // ignore result
+                    let _ = Self::send_packet(channel, inner.timestamp);
+                    inner.state = InterruptState::NeedAck;
+                    return true;
+                }
+
+                inner.state = InterruptState::Triggered;
+            },
+            // Edge-triggered interrupts coalesce until someone waits.
+            InterruptState::Triggered if !self.masks_until_ack() => {},
+            InterruptState::Triggered | InterruptState::NeedAck | InterruptState::Closed => return false,
         }
 
-        this.handle();
+        unsafe {
+            while let Some(thread) = inner.waiters.pop() {
+                (*thread).resume();
+            }
+        }
+
+        true
+    }
+
+    /// Trigger the interrupt from software.
+    /// Only virtual interrupts can be triggered.
+    pub fn trigger(&self) -> Result<()> {
+        if !self.is_virtual() {
+            return Err(Error::BAD_STATE);
+        }
+
+        self.handle();
+
+        Ok(())
     }
 
-    fn handle(&self) {
-        if self.state.load(Ordering::Relaxed) == InterruptState::NeedAck {
-            return;
+    /// Block the current thread until the interrupt fires.
+    ///
+    /// If a level-triggered interrupt is still waiting to be
+    /// acknowledged from a previous wait, it is acknowledged first.
+    ///
+    /// Returns the monotonic time, in nanoseconds, at which the
+    /// interrupt fired, or `BAD_STATE` if the last handle to it
+    /// is closed first.
+    pub fn wait(&self) -> Result<u64> {
+        if self.channel.is_some() {
+            return Err(Error::BAD_STATE);
         }
 
-        let now = Instant::now();
-        // ignore result
-        let _ = self.send_packet(now);
+        loop {
+            let mut inner = self.inner.lock();
+
+            match inner.state {
+                InterruptState::Triggered => {
+                    inner.state = if self.is_level() {
+                        InterruptState::NeedAck
+                    } else {
+                        InterruptState::Idle
+                    };
+
+                    return Ok(inner.timestamp);
+                },
+                InterruptState::NeedAck => {
+                    inner.state = InterruptState::Idle;
+                    self.release_line();
+                    // The interrupt may fire again before we block,
+                    // so check the state again.
+                    continue;
+                },
+                InterruptState::Closed => return Err(Error::BAD_STATE),
+                InterruptState::Idle => {},
+            }
 
-        self.state.store(InterruptState::NeedAck, Ordering::Relaxed);
+            let current_thread = Thread::current();
+
+            unsafe { inner.waiters.push(current_thread); }
+            current_thread.set_state(State::Blocked);
+
+            drop(inner);
+
+            Thread::yield_now();
+        }
     }
 
+    /// Acknowledge a delivered interrupt.
+    ///
+    /// For level-triggered interrupts, this unmasks the line
+    /// once no other interrupt on it needs to be acknowledged.
+    /// Acknowledging an edge-triggered interrupt does nothing.
     pub fn ack(&self) -> Result<()> {
-        let state = self.state.load(Ordering::Relaxed);
+        let mut inner = self.inner.lock();
 
-        match state {
+        match inner.state {
             InterruptState::NeedAck => {
-                self.state.store(InterruptState::Idle, Ordering::Relaxed);
-                if self.flags.contains(InterruptFlags::UNMASK_PREWAIT) {
-                    self.unmask();
-                }
+                inner.state = InterruptState::Idle;
+                self.release_line();
                 Ok(())
             },
-            _ => unimplemented!(),
+            _ if !self.masks_until_ack() => Ok(()),
+            _ => Err(Error::BAD_STATE),
         }
     }
 
     pub fn register(&self) -> Result<()> {
-        if unsafe { interrupt::register_handler(self.vector, Self::interrupt_handler, self as *const _ as *const _) } {
-            Ok(())
-        } else {
-            Err(Error::INVALID_ARG)
+        if self.is_virtual() {
+            return Ok(());
+        }
+
+        let shared = self.options.contains(InterruptOptions::SHARED);
+
+        unsafe {
+            interrupt::register_handler(self.gsi, self.trigger, self.polarity, Self::interrupt_handler, self.handler_arg(), shared)?;
         }
+
+        self.registered.store(true, Ordering::SeqCst);
+
+        Ok(())
     }
 
     pub fn unregister(&self) -> Result<()> {
-        if unsafe { interrupt::unregister_handler(self.vector) } {
-            Ok(())
-        } else {
-            Err(Error::INVALID_ARG)
+        if !self.registered.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        unsafe {
+            interrupt::unregister_handler(self.gsi, Self::interrupt_handler, self.handler_arg())?;
+        }
+
+        // Don't leave the line masked for the others on it. If
+        // this was the last handler, it's masked for good anyway.
+        let mut inner = self.inner.lock();
+        if self.masks_until_ack() && inner.state != InterruptState::Idle {
+            inner.state = InterruptState::Idle;
+            self.release_line();
+        }
+
+        Ok(())
+    }
+
+    fn handler_arg(&self) -> *const () {
+        self as *const _ as *const _
+    }
+}
+
+impl Dispatcher for Interrupt {
+    fn on_zero_handles(&self) {
+        let _ = self.unregister();
+
+        // Threads blocked in `wait` only hold a reference,
+        // so they have to be woken up to let go of it.
+        let mut inner = self.inner.lock();
+        inner.state = InterruptState::Closed;
+
+        unsafe {
+            while let Some(thread) = inner.waiters.pop() {
+                (*thread).resume();
+            }
         }
     }
 }
 
-impl Dispatcher for Interrupt {}
\ No newline at end of file
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        // An interrupt that never got a handle
+        // is still registered at this point.
+        let _ = self.unregister();
+    }
+}
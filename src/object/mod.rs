@@ -10,6 +10,8 @@ pub mod dispatcher;
 pub mod wait_observer;
 pub mod stream;
 pub mod interrupt;
+pub mod ring;
+pub mod system;
 
 pub use self::handle::{Handle, UserHandle};
 pub use self::table::HandleTable;
@@ -18,8 +20,10 @@ pub use self::dispatcher::{Dispatch, Dispatcher};
 
 pub use self::thread::Thread;
 pub use self::process::Process;
-pub use self::wasm::Wasm;
+pub use self::wasm::{Wasm, WasmStats, CompileFlags};
 pub use self::event::EventDispatcher;
 pub use self::channel::{Channel, Message};
 pub use self::stream::Stream;
-pub use self::interrupt::Interrupt;
\ No newline at end of file
+pub use self::interrupt::Interrupt;
+pub use self::ring::Ring;
+pub use self::system::SystemResource;
\ No newline at end of file
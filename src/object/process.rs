@@ -1,17 +1,53 @@
 use object::{HandleTable, Wasm, Thread};
+use object::thread::ALL_CPUS;
 use wasm::{Instance, VmCtx};
 use cranelift_codegen::ir::TrapCode;
 use nabi::Result;
 use nil::mem::Bin;
 use spin::RwLock;
-use common::table::Table;
+use common::table::{Table, TableSlot};
 use hashmap_core::HashMap;
-use core::{mem, slice};
+use core::{mem, slice, cmp};
 use sync::mpsc::IntrusiveMpsc;
 use arch::lock::Spinlock;
+use arch::cpu::Local;
+use arch::devices::high_precision_timer;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use sync::atomic::{Atomic, Ordering};
+use task::scheduler::{Priority, DEFAULT_PRIORITY, HIGHEST_PRIORITY, LOWEST_PRIORITY};
 use super::dispatcher::{Dispatch, Dispatcher};
 
+/// A locked pfex.
+pub struct Pfex {
+    /// The thread that holds the pfex.
+    pub owner: TableSlot,
+    /// The highest priority of the threads waiting on it.
+    /// The owner runs with at least this priority.
+    pub max_waiter_priority: Priority,
+    /// The threads waiting on it.
+    pub waiters: IntrusiveMpsc<Thread>,
+}
+
+impl Pfex {
+    pub fn new(owner: TableSlot) -> Pfex {
+        Pfex {
+            owner,
+            max_waiter_priority: LOWEST_PRIORITY,
+            waiters: IntrusiveMpsc::new(),
+        }
+    }
+}
+
+/// A thread waiting in `atomic.wait`.
+pub struct Waiter {
+    pub thread: TableSlot,
+    /// Whether the wait has a timeout. Those threads are on
+    /// a timer wheel, so their timer has to be cancelled
+    /// before they can be resumed.
+    pub timed: bool,
+}
+
 /// Represents a process.
 #[allow(dead_code)]
 pub struct Process {
@@ -24,8 +60,20 @@ pub struct Process {
     /// List of threads operating in this
     /// process.
     thread_list: RwLock<Table<Box<Thread>>>,
-    /// Hashmap of offsets in the wasm memory to an event
-    pfex_map: Spinlock<HashMap<u32, IntrusiveMpsc<Thread>>>,
+    /// Hashmap of offsets in the wasm memory to the locked pfex there
+    pfex_map: Spinlock<HashMap<u32, Pfex>>,
+    /// Hashmap of offsets in the wasm memory to the
+    /// threads waiting there, in the order they started.
+    wait_map: Spinlock<HashMap<u32, Vec<Waiter>>>,
+    /// The highest priority that threads
+    /// in this process can be given.
+    max_priority: Atomic<Priority>,
+    /// The cpus that new threads in this process may run on.
+    default_affinity: Atomic<u64>,
+    /// Tsc cycles that threads in this process ran for.
+    run_cycles: Atomic<u64>,
+    voluntary_switches: Atomic<u64>,
+    involuntary_switches: Atomic<u64>,
     initial_instance: Instance,
 }
 
@@ -41,6 +89,12 @@ impl Process {
             handle_table: RwLock::new(HandleTable::new()),
             thread_list: RwLock::new(Table::new()),
             pfex_map: Spinlock::new(HashMap::new()),
+            wait_map: Spinlock::new(HashMap::new()),
+            max_priority: Atomic::new(HIGHEST_PRIORITY),
+            default_affinity: Atomic::new(ALL_CPUS),
+            run_cycles: Atomic::new(0),
+            voluntary_switches: Atomic::new(0),
+            involuntary_switches: Atomic::new(0),
             initial_instance,
         }))
     }
@@ -61,12 +115,15 @@ impl Process {
         let id = thread_list.next_slot();
 
         let mut thread = Thread::new_with_parent(self.copy_ref(), id, 1024 * 1024, move || {
-            let mut vmctx_gen = instance.generate_vmctx_backing();
+            let mut vmctx_gen = instance.generate_vmctx_backing(&process.code.module().sig_ids);
             
             let vmctx = vmctx_gen.vmctx(process, instance);
             entry_point(arg, vmctx);
         })?;
 
+        thread.set_priority(self.initial_priority());
+        thread.set_affinity(self.default_affinity());
+        thread.set_cpu(Local::least_loaded(thread.affinity()));
         thread.start();
 
         let thread_id = thread_list.allocate(thread);
@@ -92,11 +149,14 @@ impl Process {
         let mut thread = Thread::new_with_parent(self.copy_ref(), thread_id, 1024 * 1024, move || {
             let entry_point = process.code.start_func();
 
-            let mut vmctx_gen = instance.generate_vmctx_backing();
+            let mut vmctx_gen = instance.generate_vmctx_backing(&process.code.module().sig_ids);
             let vmctx = vmctx_gen.vmctx(process, instance);
             entry_point(vmctx);
         })?;
 
+        thread.set_priority(self.initial_priority());
+        thread.set_affinity(self.default_affinity());
+        thread.set_cpu(Local::least_loaded(thread.affinity()));
         thread.start();
 
         let id = thread_list.allocate(thread);
@@ -127,6 +187,9 @@ impl Process {
             assert!(thread_list.len() == 1);
         }
 
+        // The killed threads won't return from their waits.
+        self.wait_map.lock().clear();
+
         Thread::exit();
     }
 
@@ -158,10 +221,86 @@ impl Process {
         &*self.code
     }
 
-    pub fn pfex_map(&self) -> &Spinlock<HashMap<u32, IntrusiveMpsc<Thread>>> {
+    pub fn pfex_map(&self) -> &Spinlock<HashMap<u32, Pfex>> {
         &self.pfex_map
     }
 
+    /// Stop treating `thread` as the owner of the pfexes that
+    /// it still holds, since its id can be given to a new thread.
+    pub fn forget_pfexes(&self, thread: &Thread) {
+        let mut pfex_map = self.pfex_map.lock();
+
+        for offset in thread.held_pfexes().lock().drain(..) {
+            if let Some(pfex) = pfex_map.get_mut(&offset) {
+                pfex.owner = TableSlot::invalid();
+            }
+        }
+    }
+
+    /// Stop waking `thread` for the waits that it's still in,
+    /// since its id can be given to a new thread.
+    pub fn forget_waits(&self, thread: &Thread) {
+        let local_id = thread.local_id();
+
+        self.wait_map.lock().retain(|_, waiters| {
+            waiters.retain(|waiter| waiter.thread != local_id);
+            !waiters.is_empty()
+        });
+    }
+
+    pub fn wait_map(&self) -> &Spinlock<HashMap<u32, Vec<Waiter>>> {
+        &self.wait_map
+    }
+
+    pub fn max_priority(&self) -> Priority {
+        self.max_priority.load(Ordering::Relaxed)
+    }
+
+    /// Threads that already have a higher
+    /// priority than `max_priority` keep it.
+    pub fn set_max_priority(&self, max_priority: Priority) {
+        self.max_priority.store(max_priority, Ordering::Relaxed);
+    }
+
+    pub fn default_affinity(&self) -> u64 {
+        self.default_affinity.load(Ordering::Relaxed)
+    }
+
+    /// Threads that already exist keep their affinity.
+    pub fn set_default_affinity(&self, affinity: u64) {
+        self.default_affinity.store(affinity, Ordering::Relaxed);
+    }
+
+    /// Account for one of the threads having run for `cycles`
+    /// tsc cycles. This includes threads that have exited.
+    pub fn charge(&self, cycles: u64, voluntary: bool) {
+        self.run_cycles.fetch_add(cycles, Ordering::Relaxed);
+
+        if voluntary {
+            self.voluntary_switches.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.involuntary_switches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Nanoseconds that threads in this process ran for.
+    pub fn run_time(&self) -> u64 {
+        high_precision_timer::cycles_to_nanos(self.run_cycles.load(Ordering::Relaxed))
+    }
+
+    pub fn voluntary_switches(&self) -> u64 {
+        self.voluntary_switches.load(Ordering::Relaxed)
+    }
+
+    pub fn involuntary_switches(&self) -> u64 {
+        self.involuntary_switches.load(Ordering::Relaxed)
+    }
+
+    /// The priority that new threads start with.
+    fn initial_priority(&self) -> Priority {
+        cmp::min(DEFAULT_PRIORITY, self.max_priority())
+    }
+
     pub fn initial_instance(&self) -> &Instance {
         &self.initial_instance
     }
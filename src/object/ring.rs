@@ -0,0 +1,182 @@
+use super::dispatcher::{Dispatch, Dispatcher};
+use object::thread::{Thread, State};
+use memory::{Region, WasmMemory};
+use sync::atomic::{Atomic, Ordering};
+use sync::spsc::IntrusiveSpsc;
+use arch::lock::Spinlock;
+use nabi::{Result, Error};
+use alloc::sync::Arc;
+use core::mem;
+
+pub const MIN_CAPACITY: usize = 4096; // 4 KiB
+pub const MAX_CAPACITY: usize = 16 * 1024 * 1024; // 16 MiB
+
+/// The data area of a ring starts this far
+/// from the beginning of the mapping.
+pub const HEADER_SIZE: usize = 4096;
+
+/// The header at the start of every ring mapping.
+///
+/// `head` and `tail` are free-running byte counters,
+/// so the amount of buffered data is `tail - head`
+/// (wrapping) and the position in the data area is
+/// the counter modulo `capacity`. Only the reader
+/// advances `head` and only the writer advances `tail`.
+#[repr(C)]
+pub struct RingHeader {
+    /// Counter of the next byte to be read.
+    pub head: Atomic<u32>,
+    /// Counter of the next byte to be written.
+    pub tail: Atomic<u32>,
+    /// The size of the data area, always a power of two.
+    pub capacity: u32,
+    /// Non-zero when a reader is, or is about to be, blocked.
+    pub reader_waiting: Atomic<u32>,
+    /// Non-zero when a writer is, or is about to be, blocked.
+    pub writer_waiting: Atomic<u32>,
+}
+
+/// The ends of a ring.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum RingSide {
+    Reader,
+    Writer,
+}
+
+/// The buffer and wait queues that both ends of a ring share.
+struct RingBuffer {
+    region: Arc<Region>,
+    readers: Spinlock<IntrusiveSpsc<Thread>>,
+    writers: Spinlock<IntrusiveSpsc<Thread>>,
+}
+
+/// One end of a stream whose ring buffer is mapped
+/// into the linear memory of every process that uses it.
+///
+/// Data is transferred entirely in userspace.
+/// The kernel is only involved when a reader
+/// has to wait for data or a writer has to wait
+/// for space.
+pub struct Ring {
+    buffer: Arc<RingBuffer>,
+    side: RingSide,
+}
+
+impl Ring {
+    /// Create the writing and the reading end of a ring.
+    pub fn new_pair(capacity: usize) -> Result<(Dispatch<Ring>, Dispatch<Ring>)> {
+        if !capacity.is_power_of_two() || capacity < MIN_CAPACITY || capacity > MAX_CAPACITY {
+            return Err(Error::INVALID_ARG);
+        }
+
+        debug_assert!(mem::size_of::<RingHeader>() <= HEADER_SIZE);
+
+        let region = Region::allocate(HEADER_SIZE + capacity)
+            .ok_or(Error::NO_MEMORY)?;
+
+        // The region is zeroed, so only the capacity has to be set.
+        unsafe {
+            (*region.start().as_mut_ptr::<RingHeader>()).capacity = capacity as u32;
+        }
+
+        let buffer = Arc::new(RingBuffer {
+            region: Arc::new(region),
+            readers: Spinlock::new(IntrusiveSpsc::new()),
+            writers: Spinlock::new(IntrusiveSpsc::new()),
+        });
+
+        let writer = Dispatch::new(Ring {
+            buffer: Arc::clone(&buffer),
+            side: RingSide::Writer,
+        });
+
+        let reader = Dispatch::new(Ring {
+            buffer,
+            side: RingSide::Reader,
+        });
+
+        Ok((writer, reader))
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*(self.buffer.region.start().as_ptr::<RingHeader>()) }
+    }
+
+    /// Which end of the ring this is.
+    pub fn side(&self) -> RingSide {
+        self.side
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.header().capacity as usize
+    }
+
+    /// Map the ring into the supplied linear memory. If it's
+    /// already mapped there, the existing mapping is used.
+    ///
+    /// Returns the offset of the `RingHeader`.
+    pub fn map_into(&self, memory: &WasmMemory) -> Result<usize> {
+        memory.shared_map(&self.buffer.region)
+    }
+
+    /// Block the current thread until the counter that
+    /// the other end advances no longer equals `observed`.
+    ///
+    /// This has the same contract as `pfex_acquire`: the
+    /// check and the enqueue happen under the same lock
+    /// that `wake` takes, so a wakeup can't be lost.
+    pub fn wait(&self, observed: u32) {
+        let header = self.header();
+
+        let (queue, counter, waiting) = match self.side {
+            RingSide::Reader => (&self.buffer.readers, &header.tail, &header.reader_waiting),
+            RingSide::Writer => (&self.buffer.writers, &header.head, &header.writer_waiting),
+        };
+
+        let mut queue = queue.lock();
+
+        waiting.store(1, Ordering::SeqCst);
+
+        if counter.load(Ordering::SeqCst) != observed {
+            return;
+        }
+
+        let current_thread = Thread::current();
+
+        unsafe { queue.push(current_thread); }
+        current_thread.set_state(State::Blocked);
+
+        // drop the lock on the queue to avoid deadlocks
+        drop(queue);
+
+        Thread::yield_now();
+    }
+
+    /// Wake every thread waiting on the other end.
+    ///
+    /// Returns the number of threads that were woken.
+    pub fn wake(&self) -> usize {
+        let header = self.header();
+
+        let (queue, waiting) = match self.side {
+            RingSide::Reader => (&self.buffer.writers, &header.writer_waiting),
+            RingSide::Writer => (&self.buffer.readers, &header.reader_waiting),
+        };
+
+        let mut queue = queue.lock();
+
+        waiting.store(0, Ordering::SeqCst);
+
+        let mut wake_count = 0;
+        unsafe {
+            while let Some(thread) = queue.pop() {
+                (*thread).resume();
+                wake_count += 1;
+            }
+        }
+
+        wake_count
+    }
+}
+
+impl Dispatcher for Ring {}
@@ -1,4 +1,4 @@
-use super::dispatcher::{Dispatch, Dispatcher};
+use super::dispatcher::{Dispatch, Dispatcher, WeakDispatch};
 use signals::Signal;
 use nabi::{Result, Error};
 use alloc::collections::vec_deque::VecDeque;
@@ -6,10 +6,60 @@ use alloc::sync::Arc;
 use arch::lock::Spinlock;
 use core::cmp::min;
 
-pub const BUFFER_SIZE: usize = 64 * 1024; // 64 KiB
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024; // 64 KiB
+pub const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
 
-struct SharedData {
+bitflags! {
+    /// Options that can be supplied when
+    /// creating a stream pair.
+    pub struct StreamOptions: u32 {
+        /// Preserve the boundaries between writes.
+        /// Every read returns exactly one write.
+        const DATAGRAM = 1 << 0;
+    }
+}
+
+bitflags! {
+    /// The halves of a stream that can be shut down.
+    pub struct StreamShutdown: u32 {
+        const READ  = 1 << 0;
+        const WRITE = 1 << 1;
+    }
+}
+
+/// The data going in one direction of a stream.
+struct Pipe {
+    /// This grows as data is written, up to the buffer size,
+    /// so an idle stream doesn't hold onto a large buffer.
     data: VecDeque<u8>,
+    /// The lengths of the queued datagrams, oldest first.
+    /// This is only used in datagram mode.
+    datagrams: VecDeque<usize>,
+    /// The reading end won't read anymore.
+    read_closed: bool,
+    /// The writing end won't write anymore.
+    write_closed: bool,
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            data: VecDeque::new(),
+            datagrams: VecDeque::new(),
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty() && self.datagrams.is_empty()
+    }
+}
+
+struct SharedData {
+    /// Each end of the pair writes into the pipe
+    /// at its side and reads from the other one.
+    pipes: [Pipe; 2],
 }
 
 /// Represents a writable
@@ -18,86 +68,276 @@ struct SharedData {
 /// between processes.
 pub struct Stream {
     shared: Arc<Spinlock<SharedData>>,
-    peer: Spinlock<Option<Dispatch<Stream>>>,
+    /// The peer only holds a weak reference back,
+    /// so that the pair doesn't keep itself alive.
+    peer: Spinlock<Option<WeakDispatch<Stream>>>,
+    /// Which end of the pair this is, either 0 or 1.
+    side: usize,
+    options: StreamOptions,
+    buffer_size: usize,
 }
 
 impl Stream {
-    pub fn new_pair() -> (Dispatch<Self>, Dispatch<Self>) {
+    pub fn new_pair(options: StreamOptions, buffer_size: usize) -> Result<(Dispatch<Self>, Dispatch<Self>)> {
+        if buffer_size == 0 || buffer_size > MAX_BUFFER_SIZE {
+            return Err(Error::INVALID_ARG);
+        }
+
         let shared = Arc::new(Spinlock::new(SharedData {
-            data: VecDeque::new(),
+            pipes: [Pipe::new(), Pipe::new()],
         }));
 
         let first = Dispatch::new(Self {
             shared: Arc::clone(&shared),
             peer: Spinlock::new(None),
+            side: 0,
+            options,
+            buffer_size,
         });
 
         let second = Dispatch::new(Self {
             shared: Arc::clone(&shared),
-            peer: Spinlock::new(Some(first.copy_ref())),
+            peer: Spinlock::new(Some(first.downgrade())),
+            side: 1,
+            options,
+            buffer_size,
         });
 
-        *first.peer.lock() = Some(second.copy_ref());
+        *first.peer.lock() = Some(second.downgrade());
+
+        Ok((first, second))
+    }
+
+    pub fn peer(&self) -> Option<Dispatch<Stream>> {
+        let peer_guard = self.peer.lock();
+        peer_guard.as_ref().and_then(|weak| weak.upgrade())
+    }
+
+    pub fn options(&self) -> StreamOptions {
+        self.options
+    }
 
-        (first, second)
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
     }
 
     pub fn write(self: &Dispatch<Self>, data: &[u8]) -> Result<usize> {
         let mut shared = self.shared.lock();
+        let pipe = &mut shared.pipes[self.side];
 
-        let peer_guard = self.peer.lock();  
+        let peer = self.peer();
 
-        if let Some(peer) = peer_guard.as_ref() {
-            let len_to_write = min(BUFFER_SIZE - shared.data.len(), data.len());
+        if pipe.write_closed {
+            return Err(Error::BAD_STATE);
+        }
 
-            shared.data.extend(&data[..len_to_write]);
+        let peer = match peer {
+            Some(ref peer) if !pipe.read_closed => peer,
+            _ => return Err(Error::PEER_CLOSED),
+        };
 
-            if shared.data.len() == BUFFER_SIZE {
-                self.signal(Signal::empty(), Signal::WRITABLE)?;
-            }
+        let free = self.buffer_size - pipe.data.len();
 
-            peer.signal(Signal::READABLE, Signal::empty())?;
+        let len_to_write = if self.options.contains(StreamOptions::DATAGRAM) {
+            // A datagram is either written entirely or not at all.
+            if data.len() > self.buffer_size {
+                return Err(Error::INVALID_ARG);
+            } else if data.len() > free {
+                return Err(Error::SHOULD_WAIT);
+            }
 
-            Ok(len_to_write)
+            pipe.datagrams.push_back(data.len());
+            data.len()
         } else {
-            Err(Error::PEER_CLOSED)
+            if free == 0 && data.len() != 0 {
+                return Err(Error::SHOULD_WAIT);
+            }
+
+            min(free, data.len())
+        };
+
+        pipe.data.extend(&data[..len_to_write]);
+
+        if pipe.data.len() == self.buffer_size {
+            self.signal(Signal::empty(), Signal::WRITABLE)?;
+        }
+
+        if !pipe.is_empty() {
+            peer.signal(Signal::READABLE, Signal::empty())?;
         }
+
+        Ok(len_to_write)
     }
 
+    /// Read from the stream. Buffered data is
+    /// always drained before the closure of the
+    /// writing end is reported.
     pub fn read(self: &Dispatch<Self>, out: &mut [u8]) -> Result<usize> {
         let mut shared = self.shared.lock();
+        let pipe = &mut shared.pipes[1 - self.side];
 
-        let peer_guard = self.peer.lock();
+        let peer = self.peer();
+
+        if pipe.read_closed {
+            return Err(Error::BAD_STATE);
+        }
+
+        if pipe.is_empty() {
+            return if peer.is_some() && !pipe.write_closed {
+                Err(Error::SHOULD_WAIT)
+            } else {
+                Err(Error::PEER_CLOSED)
+            };
+        }
 
-        if let Some(peer) = peer_guard.as_ref() {
-            let len_to_read = min(shared.data.len(), out.len());
+        let len_to_read = if self.options.contains(StreamOptions::DATAGRAM) {
+            let datagram_len = *pipe.datagrams.front().unwrap();
 
-            for (src, dest) in shared.data.drain(..len_to_read).zip(out) {
-                *dest = src;
+            if datagram_len > out.len() {
+                return Err(Error::BUFFER_TOO_SMALL);
             }
 
-            if shared.data.len() < BUFFER_SIZE {
+            pipe.datagrams.pop_front();
+            datagram_len
+        } else {
+            min(pipe.data.len(), out.len())
+        };
+
+        for (src, dest) in pipe.data.drain(..len_to_read).zip(out) {
+            *dest = src;
+        }
+
+        if let Some(ref peer) = peer {
+            if pipe.data.len() < self.buffer_size {
                 peer.signal(Signal::WRITABLE, Signal::empty())?;
             }
+        }
+
+        if pipe.is_empty() {
+            self.signal(Signal::empty(), Signal::READABLE)?;
+        }
+
+        Ok(len_to_read)
+    }
+
+    /// Returns the length of the next datagram
+    /// waiting to be read, if there is one.
+    pub fn next_datagram_len(&self) -> Option<usize> {
+        let shared = self.shared.lock();
+
+        shared.pipes[1 - self.side].datagrams.front().cloned()
+    }
+
+    /// Shut down one or both halves of this end of the stream.
+    ///
+    /// Shutting down the read half discards any
+    /// buffered data and makes further writes from
+    /// the peer fail. Shutting down the write half
+    /// lets the peer drain what has already been
+    /// written before it sees `PEER_CLOSED`.
+    pub fn shutdown(self: &Dispatch<Self>, how: StreamShutdown) -> Result<()> {
+        let mut shared = self.shared.lock();
+
+        let peer = self.peer();
+
+        if how.contains(StreamShutdown::READ) {
+            let incoming = &mut shared.pipes[1 - self.side];
+            incoming.read_closed = true;
+            incoming.data.clear();
+            incoming.datagrams.clear();
+
+            self.signal(Signal::empty(), Signal::READABLE)?;
 
-            if shared.data.len() == 0 {
-                self.signal(Signal::empty(), Signal::READABLE)?;
+            if let Some(ref peer) = peer {
+                peer.signal(Signal::PEER_CLOSED, Signal::WRITABLE)?;
             }
+        }
 
-            Ok(len_to_read)
-        } else {
-            Err(Error::PEER_CLOSED)
+        if how.contains(StreamShutdown::WRITE) {
+            shared.pipes[self.side].write_closed = true;
+
+            self.signal(Signal::empty(), Signal::WRITABLE)?;
+
+            if let Some(ref peer) = peer {
+                // Wake up any readers so they can observe the end of the stream.
+                peer.signal(Signal::READABLE | Signal::PEER_CLOSED, Signal::empty())?;
+            }
         }
+
+        Ok(())
     }
 }
 
 impl Dispatcher for Stream {
     fn allowed_user_signals(&self) -> Signal {
         Signal::READABLE
-        | Signal::WRITABLE 
+        | Signal::WRITABLE
         | Signal::PEER_CLOSED
         | Signal::PEER_SIGNALED
     }
 
     fn allows_observers(&self) -> bool { true }
-}
\ No newline at end of file
+
+    fn on_zero_handles(&self) {
+        if let Some(peer) = self.peer() {
+            {
+                let mut this_guard = peer.peer.lock();
+                *this_guard = None;
+            }
+
+            // the peer can still drain whatever is buffered
+            let _ = peer.signal(Signal::READABLE | Signal::PEER_CLOSED, Signal::empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_pair() -> (Dispatch<Stream>, Dispatch<Stream>) {
+        Stream::new_pair(StreamOptions::empty(), DEFAULT_BUFFER_SIZE).unwrap()
+    }
+
+    #[test]
+    fn test_shutdown_write_from_each_end() {
+        for &first_closes in &[true, false] {
+            let (first, second) = new_pair();
+            let (closer, peer) = if first_closes { (&first, &second) } else { (&second, &first) };
+            let mut buf = [0; 8];
+
+            assert_eq!(closer.write(b"bye").unwrap(), 3);
+            closer.shutdown(StreamShutdown::WRITE).unwrap();
+            assert!(closer.write(b"more").err() == Some(Error::BAD_STATE));
+
+            // the peer drains what was written before it sees the end
+            assert_eq!(peer.read(&mut buf).unwrap(), 3);
+            assert_eq!(&buf[..3], b"bye");
+            assert!(peer.read(&mut buf).err() == Some(Error::PEER_CLOSED));
+
+            // the other direction is still open
+            assert_eq!(peer.write(b"hi").unwrap(), 2);
+            assert_eq!(closer.read(&mut buf).unwrap(), 2);
+            assert_eq!(&buf[..2], b"hi");
+        }
+    }
+
+    #[test]
+    fn test_shutdown_read_from_each_end() {
+        for &first_closes in &[true, false] {
+            let (first, second) = new_pair();
+            let (closer, peer) = if first_closes { (&first, &second) } else { (&second, &first) };
+            let mut buf = [0; 8];
+
+            assert_eq!(peer.write(b"lost").unwrap(), 4);
+            closer.shutdown(StreamShutdown::READ).unwrap();
+            assert!(closer.read(&mut buf).err() == Some(Error::BAD_STATE));
+            assert!(peer.write(b"more").err() == Some(Error::PEER_CLOSED));
+
+            // the other direction is still open
+            assert_eq!(closer.write(b"hi").unwrap(), 2);
+            assert_eq!(peer.read(&mut buf).unwrap(), 2);
+            assert_eq!(&buf[..2], b"hi");
+        }
+    }
+}
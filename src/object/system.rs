@@ -0,0 +1,17 @@
+use super::dispatcher::{Dispatch, Dispatcher};
+
+/// Holding a handle to this with the `WRITE` right
+/// allows a process to control the whole system,
+/// like turning it off or restarting it.
+///
+/// Only the init process gets one. It can transfer
+/// it, or duplicates of it, to other processes.
+pub struct SystemResource;
+
+impl SystemResource {
+    pub fn new() -> Dispatch<SystemResource> {
+        Dispatch::new(SystemResource)
+    }
+}
+
+impl Dispatcher for SystemResource {}
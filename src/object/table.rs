@@ -111,3 +111,13 @@ impl HandleTable {
         self.allocate_handle(dup)
     }
 }
+
+impl Drop for HandleTable {
+    fn drop(&mut self) {
+        // `Array` doesn't drop its elements, and the
+        // objects have to know that these handles are closed.
+        for index in 0..self.array.len() {
+            self.array.replace_at(index, None);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use object::Process;
 use event::{Event, EventVariant};
 use common::table::TableSlot;
-use arch::cpu::Local;
+use arch::cpu::{Local, CpuId};
 use nabi::{Result, Error};
 use sync::atomic::{Atomic, Ordering};
 use sync::mpsc::IntrusiveNode;
@@ -9,8 +9,16 @@ use core::ptr;
 use arch::cpu::Dpc;
 use memory::sip::WasmStack;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use arch::lock::Spinlock;
 use arch::context::ThreadContext;
 use super::dispatcher::Dispatch;
+use task::scheduler::{Priority, RunLink, DEFAULT_PRIORITY, LOWEST_PRIORITY};
+use task::timer::TimerLink;
+use arch::cpu::IrqController;
+use arch::devices::high_precision_timer;
+use time;
+use core::cmp;
 
 impl IntrusiveNode for Thread {
     #[inline]
@@ -51,6 +59,12 @@ pub enum State {
 unsafe impl Send for Thread {}
 unsafe impl Sync for Thread {}
 
+/// The cpu of a thread that hasn't been assigned to one yet.
+const NO_CPU: CpuId = CpuId::max_value();
+
+/// The affinity of a thread that may run on any cpu.
+pub const ALL_CPUS: u64 = u64::max_value();
+
 /// Represents a thread.
 pub struct Thread {
     pub ctx: ThreadContext,
@@ -69,6 +83,33 @@ pub struct Thread {
     local_id: TableSlot,
 
     state: Atomic<State>,
+
+    /// The cpu that this thread runs on.
+    cpu: Atomic<CpuId>,
+    /// The cpus that this thread may run on, a bit for each cpu id.
+    affinity: Atomic<u64>,
+
+    /// The priority that the thread was given.
+    base_priority: Atomic<Priority>,
+    /// The priority of the most important thread
+    /// blocked on a pfex that this thread holds.
+    inherited_priority: Atomic<Priority>,
+    /// The offsets of the pfexes that this thread holds,
+    /// so that a thread that waits on one can find it.
+    held_pfexes: Spinlock<Vec<u32>>,
+
+    /// Links the thread into the run queues of its cpu.
+    pub run_link: RunLink,
+
+    /// Links the thread into the timer wheel of its cpu while it sleeps.
+    pub timer_link: TimerLink,
+
+    /// Tsc cycles that the thread ran for.
+    run_cycles: Atomic<u64>,
+    /// Times the thread gave up the cpu.
+    voluntary_switches: Atomic<u64>,
+    /// Times the thread was preempted.
+    involuntary_switches: Atomic<u64>,
 }
 
 impl Thread {
@@ -81,7 +122,7 @@ impl Thread {
         let exit_event = Event::new(EventVariant::Normal);
 
         Ok(Box::new(Thread {
-            ctx: ThreadContext::new(stack.top(), common_thread_entry::<F>),
+            ctx: ThreadContext::new(stack.top(), common_thread_entry::<F>)?,
             stack,
             exit_event,
             func: Box::into_raw(Box::new(f)) as *const (),
@@ -89,6 +130,16 @@ impl Thread {
             next_thread: ptr::null_mut(),
             local_id: TableSlot::invalid(),
             state: Atomic::new(State::Initial),
+            cpu: Atomic::new(NO_CPU),
+            affinity: Atomic::new(ALL_CPUS),
+            base_priority: Atomic::new(DEFAULT_PRIORITY),
+            inherited_priority: Atomic::new(LOWEST_PRIORITY),
+            held_pfexes: Spinlock::new(Vec::new()),
+            run_link: RunLink::new(),
+            timer_link: TimerLink::new(),
+            run_cycles: Atomic::new(0),
+            voluntary_switches: Atomic::new(0),
+            involuntary_switches: Atomic::new(0),
         }))
     }
 
@@ -101,7 +152,7 @@ impl Thread {
         let exit_event = Event::new(EventVariant::Normal);
 
         Ok(Box::new(Thread {
-            ctx: ThreadContext::new(stack.top(), common_thread_entry::<F>),
+            ctx: ThreadContext::new(stack.top(), common_thread_entry::<F>)?,
             stack,
             exit_event,
             func: Box::into_raw(Box::new(f)) as *const (),
@@ -109,10 +160,34 @@ impl Thread {
             next_thread: ptr::null_mut(),
             local_id,
             state: Atomic::new(State::Initial),
+            cpu: Atomic::new(NO_CPU),
+            affinity: Atomic::new(ALL_CPUS),
+            base_priority: Atomic::new(DEFAULT_PRIORITY),
+            inherited_priority: Atomic::new(LOWEST_PRIORITY),
+            held_pfexes: Spinlock::new(Vec::new()),
+            run_link: RunLink::new(),
+            timer_link: TimerLink::new(),
+            run_cycles: Atomic::new(0),
+            voluntary_switches: Atomic::new(0),
+            involuntary_switches: Atomic::new(0),
         }))
     }
 
     pub fn start(&mut self) {
+        if self.cpu() == NO_CPU {
+            let cpu_id = Local::cpu_id();
+            if self.allowed_on(cpu_id) {
+                self.set_cpu(cpu_id);
+            } else {
+                self.set_cpu(Local::least_loaded(self.affinity()));
+            }
+        }
+
+        Local::get(self.cpu())
+            .expect("thread was started on a cpu that isn't online")
+            .scheduler()
+            .add_thread();
+
         let old_state = self.state.compare_and_swap(State::Initial, State::Ready, Ordering::SeqCst);
 
         debug_assert!(old_state == State::Initial);
@@ -120,6 +195,122 @@ impl Thread {
         Local::schedule_thread(self);
     }
 
+    pub fn cpu(&self) -> CpuId {
+        self.cpu.load(Ordering::Relaxed)
+    }
+
+    /// Pick the cpu that the thread will run on. Once it's
+    /// started, it only moves when its affinity changes.
+    pub fn set_cpu(&self, cpu_id: CpuId) {
+        debug_assert!(self.state() == State::Initial);
+
+        self.cpu.store(cpu_id, Ordering::Relaxed);
+    }
+
+    /// Move a started thread that isn't running
+    /// or queued anywhere to another cpu.
+    pub unsafe fn move_to(&self, cpu_id: CpuId) {
+        if let Some(local) = Local::get(self.cpu()) {
+            local.scheduler().remove_thread();
+        }
+
+        self.cpu.store(cpu_id, Ordering::Relaxed);
+
+        Local::get(cpu_id)
+            .expect("thread was moved to a cpu that isn't online")
+            .scheduler()
+            .add_thread();
+    }
+
+    pub fn affinity(&self) -> u64 {
+        self.affinity.load(Ordering::Relaxed)
+    }
+
+    /// Restrict the cpus that the thread may run on. If it's on
+    /// one that isn't in `affinity` anymore, it moves the next
+    /// time that cpu switches away from it or dequeues it.
+    pub fn set_affinity(&self, affinity: u64) {
+        self.affinity.store(affinity, Ordering::Relaxed);
+
+        let cpu_id = self.cpu();
+        if cpu_id != NO_CPU && self.state() != State::Initial && !self.allowed_on(cpu_id) {
+            Local::kick(cpu_id);
+        }
+    }
+
+    pub fn allowed_on(&self, cpu_id: CpuId) -> bool {
+        self.affinity() & (1 << cpu_id) != 0
+    }
+
+    /// The priority that the thread is scheduled with.
+    pub fn priority(&self) -> Priority {
+        cmp::max(self.base_priority(), self.inherited_priority())
+    }
+
+    pub fn base_priority(&self) -> Priority {
+        self.base_priority.load(Ordering::Relaxed)
+    }
+
+    pub fn set_priority(&self, priority: Priority) {
+        self.base_priority.store(priority, Ordering::Relaxed);
+        self.reprioritize();
+    }
+
+    pub fn held_pfexes(&self) -> &Spinlock<Vec<u32>> {
+        &self.held_pfexes
+    }
+
+    pub fn inherited_priority(&self) -> Priority {
+        self.inherited_priority.load(Ordering::Relaxed)
+    }
+
+    /// Boost the thread while it holds a pfex that
+    /// a more important thread is waiting on.
+    pub fn set_inherited_priority(&self, priority: Priority) {
+        self.inherited_priority.store(priority, Ordering::Relaxed);
+        self.reprioritize();
+    }
+
+    fn reprioritize(&self) {
+        if self.cpu() != NO_CPU && self.state() != State::Initial {
+            Local::reprioritize(self as *const _ as *mut _);
+        }
+    }
+
+    /// Account for the thread having run for `cycles` tsc
+    /// cycles before it was switched away from.
+    pub fn charge(&self, cycles: u64, voluntary: bool) {
+        self.run_cycles.fetch_add(cycles, Ordering::Relaxed);
+
+        if voluntary {
+            self.voluntary_switches.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.involuntary_switches.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(parent) = self.parent() {
+            parent.charge(cycles, voluntary);
+        }
+    }
+
+    /// Nanoseconds that the thread ran for,
+    /// up to the last time it was switched away from.
+    pub fn run_time(&self) -> u64 {
+        high_precision_timer::cycles_to_nanos(self.run_cycles.load(Ordering::Relaxed))
+    }
+
+    pub fn voluntary_switches(&self) -> u64 {
+        self.voluntary_switches.load(Ordering::Relaxed)
+    }
+
+    pub fn involuntary_switches(&self) -> u64 {
+        self.involuntary_switches.load(Ordering::Relaxed)
+    }
+
+    pub fn local_id(&self) -> TableSlot {
+        self.local_id
+    }
+
     pub fn state(&self) -> State {
         self.state.load(Ordering::Relaxed)
     }
@@ -139,17 +330,53 @@ impl Thread {
         }
     }
 
+    /// Block the current thread until `deadline`,
+    /// in nanoseconds of monotonic time.
+    pub fn sleep_until(deadline: u64) {
+        if deadline <= time::monotonic() {
+            return;
+        }
+
+        unsafe {
+            IrqController::disable();
+            Thread::arm_timer(deadline);
+            Local::context_switch();
+        }
+    }
+
+    /// Put the current thread on the timer wheel of its cpu and
+    /// block it, so that it sleeps until `deadline` once it's switched
+    /// out, unless its timer is cancelled and it's resumed earlier.
+    ///
+    /// Interrupts have to be disabled until the switch, since the
+    /// timer interrupt mustn't wake the thread before it's off the cpu.
+    pub unsafe fn arm_timer(deadline: u64) {
+        debug_assert!(!IrqController::enabled());
+
+        let current_thread = Thread::current();
+
+        Local::current().timers().insert(current_thread, deadline);
+        current_thread.set_state(State::Blocked);
+    }
+
+    /// Block the current thread for at least `duration` nanoseconds.
+    pub fn sleep(duration: u64) {
+        Thread::sleep_until(time::monotonic().saturating_add(duration));
+    }
+
     pub fn parent(&self) -> Option<&Dispatch<Process>> {
         self.parent.as_ref()
     }
 
     pub fn resume(&self) {
-        debug_assert!({
-            let state = self.state();
-            state == State::Blocked || state == State::Suspended 
-        });
-        
-        self.set_state(State::Ready);
+        let state = self.state();
+        debug_assert!(state == State::Blocked || state == State::Suspended || state == State::Killable);
+
+        // A killed thread still has to be
+        // queued so that the scheduler reaps it.
+        if state != State::Killable {
+            self.set_state(State::Ready);
+        }
         
         Local::schedule_thread(self as *const _ as *mut _);
     }
@@ -176,8 +403,11 @@ impl Thread {
             return;
         }
 
-        if !self.next_thread.is_null() {
-            // the thread is on the runqueue
+        if self.run_link.is_queued() || self.timer_link.is_queued() || !self.next_thread.is_null()
+            || self.state() == State::Ready
+        {
+            // the thread is on a runqueue, a wait queue, sleeping
+            // or on its way to another cpu
             self.set_state(State::Killable);
             // Don't drop the thread now the scheduler will take care of it.
             Box::into_raw(self);
@@ -196,6 +426,9 @@ impl Thread {
         current_thread.exit_event.signal(false);
 
         if let Some(parent) = current_thread.parent() {
+            parent.forget_pfexes(current_thread);
+            parent.forget_waits(current_thread);
+
             let boxed_thread = {
                 let mut thread_list = parent.thread_list().write();
                 thread_list.free(current_thread.local_id).unwrap()
@@ -212,9 +445,22 @@ impl Thread {
     }
 }
 
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if self.state() != State::Initial {
+            if let Some(local) = Local::get(self.cpu()) {
+                local.scheduler().remove_thread();
+            }
+        }
+    }
+}
+
 extern fn common_thread_entry<F>()
     where F: FnOnce() + Send + Sync
 {
+    // This runs right after the switch to the thread.
+    Local::finish_migration();
+
     let current_thread = Thread::current();
 
     let f = unsafe { Box::from_raw(current_thread.func as *mut F) };
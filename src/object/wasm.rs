@@ -1,6 +1,12 @@
 use wasm::instance::{Instance, VmCtx, get_function_addr};
 use wasm::{Module, ModuleEnvironment, DataInitializer};
-use wasm::compilation::TrapData;
+use wasm::{CompileError, CompileErrorKind, CompileResult, Compilation, LazyCode};
+use wasm::compilation;
+use wasm::bulk;
+use wasm::cache;
+use wasm::aot::Artifact;
+use wasm::sig_registry::SIG_REGISTRY;
+use wasm::translate::{TrapData, VALIDATING_CONFIG};
 use memory::{Region, MemFlags};
 use nabi::{Result, Error};
 use core::mem;
@@ -8,10 +14,45 @@ use alloc::vec::Vec;
 use cranelift_codegen::settings::{self, Configurable};
 use cranelift_codegen::ir::TrapCode;
 use cranelift_wasm::translate_module;
-use cranelift_native;
+use wasmparser::{Parser, ValidatingParser, WasmDecoder, ParserState};
+use sync::atomic::Atomic;
 
 use super::dispatcher::{Dispatch, Dispatcher};
 
+bitflags! {
+    /// How to compile a module.
+    pub struct CompileFlags: u32 {
+        /// Only compile each function when it's first called.
+        const LAZY = 1 << 0;
+        /// Compile quickly, at the cost of slower code,
+        /// which is handy while debugging.
+        const FAST_COMPILE = 1 << 1;
+        /// Run the cranelift verifier on every function,
+        /// for code that isn't trusted. Processes without
+        /// the system resource can't turn this off.
+        const VERIFY = 1 << 2;
+        /// Don't probe the stack in functions with big frames.
+        /// Only processes with the system resource can set this.
+        const NO_STACK_PROBES = 1 << 3;
+    }
+}
+
+/// Information about a `Wasm`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct WasmStats {
+    /// The size of the machine code, including the stubs
+    /// of functions that are compiled lazily.
+    pub code_size: u64,
+    /// The `CompileFlags` that the code was compiled with.
+    pub flags: u32,
+    /// The number of local functions in the module.
+    pub functions: u32,
+    /// The number of local functions that have been compiled,
+    /// which is all of them unless they're compiled lazily.
+    pub compiled_functions: u32,
+}
+
 /// A `Wasm` represents
 /// webassembly code compiled
 /// into machine code. You must
@@ -23,47 +64,135 @@ pub struct Wasm {
     functions: Vec<usize>,
     traps: Vec<TrapData>,
     module: Module,
+    /// The functions, or their stubs if they're compiled lazily.
     region: Region,
     start_func: extern fn(&VmCtx),
+    flags: CompileFlags,
+    lazy: Option<LazyCode>,
 }
 
 impl Wasm {
-    /// Compile webassembly bytecode into a Wasm.
-    pub fn compile(wasm: &[u8]) -> Result<Dispatch<Wasm>> {
-        let (mut flag_builder, isa_builder) = cranelift_native::builders()
-            .map_err(|_| internal_error!())?;
+    /// Compile webassembly bytecode into a Wasm. If that fails,
+    /// the error says why, and it's also written to the log.
+    pub fn compile(wasm: &[u8], flags: CompileFlags) -> CompileResult<Dispatch<Wasm>> {
+        Self::compile_module(wasm, flags)
+            .map_err(|err| {
+                println!("wasm compile error: {}", err);
+                err
+            })
+    }
 
-        flag_builder.set("opt_level", "best")
+    /// The cranelift settings to compile with, for `flags`.
+    fn settings(flags: CompileFlags) -> Result<settings::Flags> {
+        let opt_level = if flags.contains(CompileFlags::FAST_COMPILE) {
+            "fastest"
+        } else {
+            "best"
+        };
+
+        let enable = |on: bool| if on { "true" } else { "false" };
+
+        let mut flag_builder = settings::builder();
+
+        flag_builder.set("opt_level", opt_level)
+            .map_err(|_| internal_error!())?;
+        flag_builder.set("enable_verifier", enable(flags.contains(CompileFlags::VERIFY)))
+            .map_err(|_| internal_error!())?;
+        flag_builder.set("probestack_enabled", enable(!flags.contains(CompileFlags::NO_STACK_PROBES)))
             .map_err(|_| internal_error!())?;
 
-        let isa = isa_builder.finish(settings::Flags::new(flag_builder));
+        Ok(settings::Flags::new(flag_builder))
+    }
 
-        let module = Module::new();
-        let mut environ = ModuleEnvironment::new(isa.flags(), module);
+    fn compile_module(wasm: &[u8], compile_flags: CompileFlags) -> CompileResult<Dispatch<Wasm>> {
+        let isa = compilation::native_isa(Self::settings(compile_flags)?)?;
+
+        let key = cache::key(wasm, isa.flags(), compile_flags);
+        if let Some(code) = cache::lookup(&key) {
+            return Ok(code);
+        }
+
+        // The module is lowered to what the parser understands first.
+        let mut module = Module::new();
+        let lowered = bulk::lower(wasm, &mut module)
+            .map_err(|err| CompileError::from_wasm(err, 0))?;
+        let wasm = lowered.as_ref().map_or(wasm, |lowered| &lowered[..]);
+
+        validate(wasm)?;
+
+        let mut environ = ModuleEnvironment::new(isa.flags(), module, wasm);
 
         translate_module(wasm, &mut environ)
-            .map_err(|_| internal_error!())?;
+            .map_err(|err| CompileError::from_wasm(err, 0))?;
 
         let translation = environ.finish_translation();
         
-        let (compliation, module, data_initializers) = translation.compile(&*isa)?;
+        let (compliation, module, data_initializers) = if compile_flags.contains(CompileFlags::LAZY) {
+            translation.compile_lazy()?
+        } else {
+            translation.compile(&*isa)?
+        };
 
-        compliation.emit(module, data_initializers)
+        let code = compliation.emit(module, data_initializers, compile_flags)?;
+
+        Ok(cache::insert(key, code))
+    }
+
+    /// Load `artifact`, which the `aot` tool compiled from `wasm`
+    /// ahead of time. If it's corrupt, or wasn't compiled from
+    /// `wasm`, the error says why, and it's also written to the log.
+    pub fn load_aot(artifact: &[u8], wasm: &[u8]) -> CompileResult<Dispatch<Wasm>> {
+        Self::load_artifact(artifact, wasm)
+            .map_err(|err| {
+                println!("wasm artifact error: {}", err);
+                err
+            })
+    }
+
+    fn load_artifact(artifact: &[u8], wasm: &[u8]) -> CompileResult<Dispatch<Wasm>> {
+        // Artifacts have to be compiled like code from a process
+        // without the system resource, so the kernel can trust them
+        // as much as anything that it compiles itself.
+        let settings = format!("{}", Self::settings(CompileFlags::VERIFY)?);
+
+        let artifact = Artifact::deserialize(artifact, wasm, &settings)
+            .map_err(|message| CompileError::new(CompileErrorKind::Artifact, message))?;
+
+        // The code didn't come from the compiler, so it's never put in
+        // the cache, where `compile` would hand it out for the same wasm.
+        compilation::check_imports(&artifact.module)?;
+
+        let compilation = Compilation::load(
+            &artifact.code,
+            &artifact.module,
+            &artifact.functions,
+            artifact.relocations,
+            artifact.traps,
+        )?;
+
+        compilation.emit(artifact.module, artifact.data_initializers, CompileFlags::VERIFY)
     }
 
     /// Used for internal use.
     pub fn new(
-        module: Module,
+        mut module: Module,
         data_initializers: Vec<DataInitializer>,
         mut region: Region,
         start_func: *const (),
         functions: Vec<usize>,
         traps: Vec<TrapData>,
+        lazy: Option<LazyCode>,
+        flags: CompileFlags,
     )
         -> Result<Dispatch<Wasm>>
     {
-        let flags = MemFlags::READ | MemFlags::EXEC;
-        region.remap(flags)?;
+        let mem_flags = MemFlags::READ | MemFlags::EXEC;
+        region.remap(mem_flags)?;
+
+        module.sig_ids = module.signatures
+            .iter()
+            .map(|sig| SIG_REGISTRY.get_id(sig))
+            .collect();
 
         let start_func = unsafe {
             mem::transmute(start_func)
@@ -76,6 +205,8 @@ impl Wasm {
             region,
             start_func,
             traps,
+            flags,
+            lazy,
         }))
     }
 
@@ -98,12 +229,47 @@ impl Wasm {
     }
 
     pub fn lookup_trap_code(&self, inst: *const ()) -> Option<TrapCode> {
+        if !self.region.contains(inst) {
+            return self.lazy.as_ref()?.lookup_trap_code(inst);
+        }
+
         let offset = (inst as *const u8) as usize - self.region.start().as_ptr::<u8>() as usize;
         self.traps.iter()
             .find(|trap_data| trap_data.offset == offset)
             .map(|trap_data| trap_data.code)
     }
 
+    /// Compile the lazily compiled function whose stub
+    /// jumps through `slot`. Returns its address.
+    pub fn compile_lazy_function(&self, slot: *const Atomic<usize>) -> CompileResult<usize> {
+        let lazy = self.lazy.as_ref().ok_or(Error::INVALID_ARG)?;
+        let index = lazy.slot_index(slot).ok_or(Error::INVALID_ARG)?;
+        let stubs = self.region.start().as_u64() as usize;
+
+        lazy.compile_function(&self.module, stubs, index)
+    }
+
+    /// The flags that the code was compiled with.
+    pub fn flags(&self) -> CompileFlags {
+        self.flags
+    }
+
+    pub fn stats(&self) -> WasmStats {
+        let functions = self.functions.len();
+
+        let (compiled_functions, compiled_size) = match self.lazy {
+            Some(ref lazy) => (lazy.compiled_count(), lazy.compiled_size()),
+            None => (functions, 0),
+        };
+
+        WasmStats {
+            code_size: (self.code_size() + compiled_size) as u64,
+            flags: self.flags.bits(),
+            functions: functions as u32,
+            compiled_functions: compiled_functions as u32,
+        }
+    }
+
     /// Returns the index of the specified function in the module function index space.
     pub fn lookup_func_index(&self, addr: *const ()) -> Option<usize> {
         let base = self.region.as_ptr() as _;
@@ -120,6 +286,37 @@ impl Wasm {
     pub fn module(&self) -> &Module {
         &self.module
     }
+
+    /// The size of the compiled code, in bytes.
+    pub fn code_size(&self) -> usize {
+        self.region.size()
+    }
 }
 
 impl Dispatcher for Wasm {}
+
+/// The first error that `decoder` runs into, and its offset.
+fn first_error<'a, D: WasmDecoder<'a>>(mut decoder: D) -> Option<(&'static str, usize)> {
+    loop {
+        match *decoder.read() {
+            ParserState::Error(ref err) => return Some((err.message, err.offset)),
+            ParserState::EndWasm => return None,
+            _ => {},
+        }
+    }
+}
+
+/// Translation assumes that the module is valid, so check
+/// that first. Parsing it on its own tells malformed
+/// modules apart from ones that just aren't valid.
+fn validate(wasm: &[u8]) -> CompileResult<()> {
+    if let Some((message, offset)) = first_error(Parser::new(wasm)) {
+        return Err(CompileError::new(CompileErrorKind::Parse, message).at_offset(offset));
+    }
+
+    if let Some((message, offset)) = first_error(ValidatingParser::new(wasm, Some(VALIDATING_CONFIG))) {
+        return Err(CompileError::new(CompileErrorKind::Validation, message).at_offset(offset));
+    }
+
+    Ok(())
+}
@@ -1,2 +1,3 @@
 
 pub mod scheduler;
+pub mod timer;
@@ -1,73 +1,311 @@
 use object::thread::{Thread, State};
 use arch::cpu::Local;
-use sync::mpsc::{IntrusiveMpsc, IntrusiveNode};
 use arch::cpu::{Dpc, IrqController};
+use arch::lock::IrqSpinlock;
+use sync::atomic::{Atomic, Ordering};
+use core::ptr;
+
+pub type Priority = u8;
+
+/// The number of priority levels.
+/// Threads with a higher priority always run first.
+pub const PRIORITY_LEVELS: usize = 32;
+
+pub const LOWEST_PRIORITY: Priority = 0;
+pub const DEFAULT_PRIORITY: Priority = 16;
+pub const HIGHEST_PRIORITY: Priority = (PRIORITY_LEVELS - 1) as Priority;
+
+/// Links a thread into the run queues of its cpu.
+/// This is only touched with the run queues locked.
+pub struct RunLink {
+    next: *mut Thread,
+    prev: *mut Thread,
+    /// The priority of the queue that the thread is on, if any.
+    queued: Option<Priority>,
+}
+
+impl RunLink {
+    pub fn new() -> RunLink {
+        RunLink {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            queued: None,
+        }
+    }
+
+    pub fn is_queued(&self) -> bool {
+        self.queued.is_some()
+    }
+}
+
+/// A fifo of ready threads for every priority level.
+struct RunQueues {
+    heads: [*mut Thread; PRIORITY_LEVELS],
+    tails: [*mut Thread; PRIORITY_LEVELS],
+    /// Bit `n` is set when there are threads queued at priority `n`.
+    ready: u32,
+}
+
+unsafe impl Send for RunQueues {}
+
+impl RunQueues {
+    fn new() -> RunQueues {
+        RunQueues {
+            heads: [ptr::null_mut(); PRIORITY_LEVELS],
+            tails: [ptr::null_mut(); PRIORITY_LEVELS],
+            ready: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, thread: *mut Thread) {
+        let priority = (*thread).priority();
+        let index = priority as usize;
+
+        {
+            let link = &mut (*thread).run_link;
+            debug_assert!(!link.is_queued());
+
+            link.next = ptr::null_mut();
+            link.prev = self.tails[index];
+            link.queued = Some(priority);
+        }
+
+        if self.tails[index].is_null() {
+            self.heads[index] = thread;
+        } else {
+            (*self.tails[index]).run_link.next = thread;
+        }
+        self.tails[index] = thread;
+
+        self.ready |= 1 << index;
+    }
+
+    unsafe fn remove(&mut self, thread: *mut Thread) {
+        let (next, prev, index) = {
+            let link = &mut (*thread).run_link;
+            let index = match link.queued.take() {
+                Some(priority) => priority as usize,
+                None => return,
+            };
+
+            let next = link.next;
+            let prev = link.prev;
+            link.next = ptr::null_mut();
+            link.prev = ptr::null_mut();
+
+            (next, prev, index)
+        };
+
+        if prev.is_null() {
+            self.heads[index] = next;
+        } else {
+            (*prev).run_link.next = next;
+        }
+
+        if next.is_null() {
+            self.tails[index] = prev;
+        } else {
+            (*next).run_link.prev = prev;
+        }
+
+        if self.heads[index].is_null() {
+            self.ready &= !(1 << index);
+        }
+    }
+
+    /// The highest priority that has threads queued.
+    fn highest(&self) -> Option<Priority> {
+        if self.ready == 0 {
+            None
+        } else {
+            Some((31 - self.ready.leading_zeros()) as Priority)
+        }
+    }
+
+    unsafe fn pop(&mut self, priority: Priority) -> Option<*mut Thread> {
+        let thread = self.heads[priority as usize];
+
+        if thread.is_null() {
+            None
+        } else {
+            self.remove(thread);
+            Some(thread)
+        }
+    }
+}
 
 /// The Scheduler schedules threads to be run.
-/// Currently, it's a simple round-robin.
+///
+/// The ready thread with the highest priority runs. Threads
+/// with the same priority take turns, round-robin.
+///
+/// Each cpu has its own scheduler, but any cpu
+/// can add threads to it.
 pub struct Scheduler {
-    thread_queue: IntrusiveMpsc<Thread>,
+    run_queues: IrqSpinlock<RunQueues>,
     idle_thread: *mut Thread,
+    /// The number of live threads that run on this scheduler.
+    thread_count: Atomic<usize>,
 }
 
 impl Scheduler {
     pub fn new(idle_thread: *mut Thread) -> Scheduler {
         Scheduler {
-            thread_queue: IntrusiveMpsc::new(),
+            run_queues: IrqSpinlock::new(RunQueues::new()),
             idle_thread,
+            thread_count: Atomic::new(0),
         }
     }
 
+    pub fn idle_thread(&self) -> *mut Thread {
+        self.idle_thread
+    }
+
+    pub fn add_thread(&self) {
+        self.thread_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_thread(&self) {
+        self.thread_count.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.thread_count.load(Ordering::Relaxed)
+    }
+
     pub fn schedule_thread(&self, thread: *mut Thread) {
         unsafe {
-            self.thread_queue.push(thread);
+            self.run_queues.lock().push(thread);
+        }
+    }
+
+    /// Move a queued thread to the queue of its current priority.
+    pub fn requeue(&self, thread: *mut Thread) {
+        let mut run_queues = self.run_queues.lock();
+
+        unsafe {
+            if (*thread).run_link.is_queued() {
+                run_queues.remove(thread);
+                run_queues.push(thread);
+            }
         }
     }
 
+    /// Switch to the next thread. Threads with the same
+    /// priority as the current thread get a turn.
     pub unsafe fn switch(&self) {
+        self.reschedule(true, true);
+    }
+
+    /// Only switch threads if one with a higher
+    /// priority than the current thread is ready.
+    pub unsafe fn preempt(&self) {
+        self.reschedule(false, false);
+    }
+
+    unsafe fn reschedule(&self, rotate: bool, voluntary: bool) {
         // disable irqs while in the scheduler.
+        let was_enabled = IrqController::enabled();
         IrqController::disable();
 
+        // A thread that this cpu switched away from may still
+        // be waiting to move, if it hadn't run since.
+        Local::finish_migration();
+
+        let cpu_id = Local::cpu_id();
         let current_thread = Thread::current();
+        let current_ptr = current_thread as *mut Thread;
+        let is_idle = current_ptr == self.idle_thread;
+        let must_leave = !is_idle && !current_thread.allowed_on(cpu_id);
+
+        let mut run_queues = self.run_queues.lock();
 
         let next_thread = loop {
-            if let Some(next_thread) = self.thread_queue.pop() {
-                debug_assert!(!next_thread.is_on_queue());
-
-                let state = (*next_thread).state();
-                if state == State::Ready {
-                    break next_thread;
-                } else if state == State::Killable {
-                    // the scheduler should kill this thread
-                    (*next_thread).set_state(State::Dead);
-                    Dpc::cleanup_thread(next_thread);
-                }
-            } else { // no threads in the run queue
-                if (*current_thread).state() == State::Running {
+            let highest = run_queues.highest();
+
+            if current_thread.state() == State::Running {
+                let priority = current_thread.priority();
+
+                let keep_running = match highest {
+                    // Its affinity changed, so it has to go.
+                    _ if must_leave => false,
                     // One thread running in this scheduler,
                     // so no need to context switch.
+                    None => true,
+                    Some(_) if is_idle => false,
+                    Some(highest) => highest < priority || (highest == priority && !rotate),
+                };
+
+                if keep_running {
+                    drop(run_queues);
+                    if was_enabled {
+                        IrqController::enable();
+                    }
                     return;
-                } else {
-                    break self.idle_thread;
                 }
             }
+
+            let next_thread = match highest.and_then(|priority| run_queues.pop(priority)) {
+                Some(next_thread) => next_thread,
+                None => break self.idle_thread,
+            };
+
+            let state = (*next_thread).state();
+            if state == State::Ready && !(*next_thread).allowed_on(cpu_id) && next_thread == current_ptr {
+                // The current thread blocked and was woken up again,
+                // but its affinity changed. This cpu is still on its
+                // stack, so it can only move once it's switched away from.
+                Local::migrate_after_switch(next_thread);
+            } else if state == State::Ready && !(*next_thread).allowed_on(cpu_id) {
+                // Its affinity changed while it was queued.
+                // It's not running, so it can move right away.
+                drop(run_queues);
+
+                Local::migrate(next_thread);
+
+                run_queues = self.run_queues.lock();
+            } else if state == State::Ready {
+                break next_thread;
+            } else if state == State::Killable {
+                // the scheduler should kill this thread.
+                // Cleaning it up can schedule the dpc thread,
+                // so the run queues can't be locked.
+                drop(run_queues);
+
+                (*next_thread).set_state(State::Dead);
+                Dpc::cleanup_thread(next_thread);
+
+                run_queues = self.run_queues.lock();
+            }
         };
 
+        // A thread that blocked gave up the cpu, even
+        // if it was preempted before it could switch.
+        let voluntary = voluntary || current_thread.state() != State::Running;
+
         if current_thread.state() == State::Running {
             current_thread.set_state(State::Ready);
-            if current_thread as *const _ != self.idle_thread as *const _ {
-                self.thread_queue.push(current_thread);
+            if must_leave {
+                // Another cpu can only pick it up once it's switched away from.
+                Local::migrate_after_switch(current_ptr);
+            } else if !is_idle {
+                run_queues.push(current_ptr);
             }
         }
 
+        drop(run_queues);
+
         debug_assert!((*next_thread).state() == State::Ready);
 
         (*next_thread).set_state(State::Running);
 
+        Local::switching_to(next_thread, voluntary);
         Local::set_current_thread(next_thread);
 
         current_thread.ctx.swap(&(*next_thread).ctx);
 
+        Local::finish_migration();
+
         IrqController::enable();
     }
 }
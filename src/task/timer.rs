@@ -0,0 +1,261 @@
+//! A hierarchical timer wheel for sleeping threads.
+//!
+//! Each cpu has one, which only that cpu adds threads to, though
+//! other cpus can take a thread off early. It's advanced
+//! on every timer event of the cpu, and the timer is always
+//! programmed for the wheel's next expiry, so a thread wakes up
+//! within a jiffy of its deadline.
+//!
+//! Deadlines are rounded up to jiffies of `1 << JIFFY_SHIFT` ns.
+//! The first level has a slot for each of the next 64 jiffies,
+//! the second a slot for each of the next 64 runs of 64 jiffies,
+//! and so on. Once the wheel reaches a slot on a higher level,
+//! its threads cascade down to the levels below.
+
+use object::thread::Thread;
+use time;
+use core::ptr;
+
+/// About 131µs.
+const JIFFY_SHIFT: u32 = 17;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+
+/// The furthest ahead that the wheel can hold a thread, in jiffies.
+/// Threads that sleep for longer are cascaded until they're due.
+const MAX_DELTA: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+/// Links a sleeping thread into a slot of its cpu's timer wheel.
+/// This is only touched with that wheel locked.
+pub struct TimerLink {
+    next: *mut Thread,
+    /// The jiffy that the thread should wake up at.
+    expiry: u64,
+    queued: bool,
+}
+
+impl TimerLink {
+    pub fn new() -> TimerLink {
+        TimerLink {
+            next: ptr::null_mut(),
+            expiry: 0,
+            queued: false,
+        }
+    }
+
+    pub fn is_queued(&self) -> bool {
+        self.queued
+    }
+}
+
+/// A list of threads, linked through their `TimerLink`.
+#[derive(Copy, Clone)]
+struct TimerList {
+    head: *mut Thread,
+}
+
+impl TimerList {
+    fn new() -> TimerList {
+        TimerList {
+            head: ptr::null_mut(),
+        }
+    }
+
+    unsafe fn push(&mut self, thread: *mut Thread) {
+        (*thread).timer_link.next = self.head;
+        self.head = thread;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Thread> {
+        if self.head.is_null() {
+            None
+        } else {
+            let thread = self.head;
+            self.head = (*thread).timer_link.next;
+            (*thread).timer_link.next = ptr::null_mut();
+            Some(thread)
+        }
+    }
+
+    /// Unlink `thread`, if it's in the list.
+    unsafe fn remove(&mut self, thread: *mut Thread) -> bool {
+        let mut link: *mut *mut Thread = &mut self.head;
+
+        while !(*link).is_null() {
+            if *link == thread {
+                *link = (*thread).timer_link.next;
+                (*thread).timer_link.next = ptr::null_mut();
+                return true;
+            }
+            link = &mut (**link).timer_link.next;
+        }
+
+        false
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    fn take(&mut self) -> TimerList {
+        let head = self.head;
+        self.head = ptr::null_mut();
+        TimerList { head }
+    }
+}
+
+pub struct TimerWheel {
+    levels: [[TimerList; SLOTS]; LEVELS],
+    /// The next jiffy that hasn't been expired yet.
+    now: u64,
+    /// The number of threads on the wheel.
+    count: usize,
+}
+
+unsafe impl Send for TimerWheel {}
+
+fn jiffy(nanos: u64) -> u64 {
+    nanos >> JIFFY_SHIFT
+}
+
+impl TimerWheel {
+    pub fn new() -> TimerWheel {
+        TimerWheel {
+            levels: [[TimerList::new(); SLOTS]; LEVELS],
+            now: 0,
+            count: 0,
+        }
+    }
+
+    /// Wake `thread` once `deadline`, in nanoseconds
+    /// of monotonic time, has passed.
+    pub unsafe fn insert(&mut self, thread: *mut Thread, deadline: u64) {
+        debug_assert!(!(*thread).timer_link.is_queued());
+
+        if self.count == 0 {
+            // The wheel doesn't keep up with time while it's empty.
+            self.now = jiffy(time::monotonic());
+        }
+
+        // Round up, so that threads never wake up early.
+        let expiry = jiffy(deadline) + 1;
+
+        (*thread).timer_link.expiry = expiry;
+        (*thread).timer_link.queued = true;
+        self.count += 1;
+
+        self.place(thread);
+    }
+
+    /// Take `thread` off the wheel before its deadline. Returns
+    /// false if it isn't on the wheel, because it already expired.
+    pub unsafe fn remove(&mut self, thread: *mut Thread) -> bool {
+        if !(*thread).timer_link.is_queued() {
+            return false;
+        }
+
+        // It could have been cascaded to any level since it was inserted.
+        let removed = self.levels
+            .iter_mut()
+            .flat_map(|level| level.iter_mut())
+            .any(|list| list.remove(thread));
+
+        if removed {
+            (*thread).timer_link.queued = false;
+            self.count -= 1;
+        }
+
+        removed
+    }
+
+    /// Put a thread into the slot for its expiry.
+    unsafe fn place(&mut self, thread: *mut Thread) {
+        let expiry = (*thread).timer_link.expiry;
+
+        // Threads that are already due expire with the next jiffy.
+        let delta = expiry.saturating_sub(self.now);
+        let target = self.now + delta.min(MAX_DELTA);
+
+        let level = (0..LEVELS)
+            .find(|&level| delta < 1 << (SLOT_BITS * (level as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+
+        let slot = (target >> (SLOT_BITS * level as u32)) & SLOT_MASK;
+
+        self.levels[level][slot as usize].push(thread);
+    }
+
+    /// When the wheel next has to be advanced, in nanoseconds of
+    /// monotonic time. That's either when a thread has to wake up,
+    /// or when threads have to be cascaded down a level.
+    pub fn next_expiry(&self) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        (0..LEVELS)
+            .filter_map(|level| {
+                let shift = SLOT_BITS * level as u32;
+                let base = self.now >> shift;
+
+                // The slot of the current run on a higher level was
+                // already cascaded, unless the run is just starting.
+                let first = if level > 0 && self.now & ((1 << shift) - 1) != 0 { 1 } else { 0 };
+
+                (first..first + SLOTS as u64)
+                    .map(|run| base + run)
+                    .find(|run| !self.levels[level][(run & SLOT_MASK) as usize].is_empty())
+                    .map(|run| (run << shift).max(self.now))
+            })
+            .min()
+            .map(|jiffy| jiffy << JIFFY_SHIFT)
+    }
+
+    /// Advance the wheel to `now_nanos` and call
+    /// `wake` with every thread that's due.
+    pub fn advance<F>(&mut self, now_nanos: u64, mut wake: F)
+        where F: FnMut(*mut Thread)
+    {
+        let target = jiffy(now_nanos);
+
+        // Nothing can expire while the wheel is empty.
+        while self.count > 0 && self.now <= target {
+            let now = self.now;
+
+            // Move the threads that are due within the next run
+            // of jiffies on each level down to the level below.
+            for level in 1..LEVELS {
+                if now & ((1 << (SLOT_BITS * level as u32)) - 1) != 0 {
+                    break;
+                }
+
+                let slot = (now >> (SLOT_BITS * level as u32)) & SLOT_MASK;
+                let mut list = self.levels[level][slot as usize].take();
+
+                while let Some(thread) = unsafe { list.pop() } {
+                    unsafe { self.place(thread); }
+                }
+            }
+
+            let mut list = self.levels[0][(now & SLOT_MASK) as usize].take();
+
+            while let Some(thread) = unsafe { list.pop() } {
+                unsafe {
+                    if (*thread).timer_link.expiry > now {
+                        // Sleeping for longer than the wheel reaches.
+                        self.place(thread);
+                    } else {
+                        (*thread).timer_link.queued = false;
+                        self.count -= 1;
+                        wake(thread);
+                    }
+                }
+            }
+
+            self.now += 1;
+        }
+    }
+}
@@ -31,6 +31,41 @@ abi_map! {
         returns: I64,
         abi::process::wasm_compile,
     },
+    wasm_compile_flags: {
+        params: [I32, I32, I32],
+        returns: I64,
+        abi::process::wasm_compile_flags,
+    },
+    wasm_compile_privileged: {
+        params: [I32, I32, I32, I32],
+        returns: I64,
+        abi::process::wasm_compile_privileged,
+    },
+    wasm_compile_ex: {
+        params: [I32, I32, I32],
+        returns: I64,
+        abi::process::wasm_compile_ex,
+    },
+    wasm_load_aot: {
+        params: [I32, I32, I32, I32, I32],
+        returns: I64,
+        abi::process::wasm_load_aot,
+    },
+    wasm_stats: {
+        params: [I32, I32],
+        returns: I64,
+        abi::process::wasm_stats,
+    },
+    code_cache_stats: {
+        params: [I32, I32],
+        returns: I64,
+        abi::process::code_cache_stats,
+    },
+    code_cache_flush: {
+        params: [I32],
+        returns: I64,
+        abi::process::code_cache_flush,
+    },
     process_create: {
         params: [I32, I32],
         returns: I64,
@@ -41,6 +76,21 @@ abi_map! {
         returns: I64,
         abi::process::process_start,
     },
+    process_set_max_priority: {
+        params: [I32, I32],
+        returns: I64,
+        abi::process::process_set_max_priority,
+    },
+    process_set_affinity: {
+        params: [I32, I64],
+        returns: I64,
+        abi::process::process_set_affinity,
+    },
+    process_info: {
+        params: [I32, I32],
+        returns: I64,
+        abi::process::process_info,
+    },
 
     // ipc
     channel_create: {
@@ -64,6 +114,11 @@ abi_map! {
         returns: I64,
         abi::ipc::stream_create,
     },
+    stream_create_ex: {
+        params: [I32, I32, I32, I32],
+        returns: I64,
+        abi::ipc::stream_create_ex,
+    },
     stream_write: {
         params: [I32, I32, I32, I32],
         returns: I64,
@@ -74,6 +129,37 @@ abi_map! {
         returns: I64,
         abi::ipc::stream_read,
     },
+    stream_shutdown: {
+        params: [I32, I32],
+        returns: I64,
+        abi::ipc::stream_shutdown,
+    },
+
+    ring_create: {
+        params: [I32, I32, I32],
+        returns: I64,
+        abi::ipc::ring_create,
+    },
+    ring_map: {
+        params: [I32],
+        returns: I64,
+        abi::ipc::ring_map,
+    },
+    ring_map_into: {
+        params: [I32, I32],
+        returns: I64,
+        abi::ipc::ring_map_into,
+    },
+    ring_wait: {
+        params: [I32, I32],
+        returns: I64,
+        abi::ipc::ring_wait,
+    },
+    ring_wake: {
+        params: [I32],
+        returns: I64,
+        abi::ipc::ring_wake,
+    },
 
     // debug
     print: {
@@ -93,6 +179,16 @@ abi_map! {
         returns: I64,
         abi::driver::physical_alloc,
     },
+    physical_map_into: {
+        params: [I32, I64, I32],
+        returns: I64,
+        abi::driver::physical_map_into,
+    },
+    physical_alloc_into: {
+        params: [I32, I32, I32],
+        returns: I64,
+        abi::driver::physical_alloc_into,
+    },
     read_port_u8: {
         params: [I32],
         returns: I32,
@@ -108,11 +204,26 @@ abi_map! {
         returns: I64,
         abi::interrupt::interrupt_create,
     },
+    interrupt_create_ex: {
+        params: [I32, I32],
+        returns: I64,
+        abi::interrupt::interrupt_create_ex,
+    },
     interrupt_ack: {
         params: [I32],
         returns: I64,
         abi::interrupt::interrupt_ack,
     },
+    interrupt_wait: {
+        params: [I32, I32],
+        returns: I64,
+        abi::interrupt::interrupt_wait,
+    },
+    interrupt_trigger: {
+        params: [I32],
+        returns: I64,
+        abi::interrupt::interrupt_trigger,
+    },
 
     // events
     event_create: {
@@ -147,6 +258,31 @@ abi_map! {
         returns: I64,
         abi::thread::thread_join,
     },
+    thread_set_priority: {
+        params: [I32, I32],
+        returns: I64,
+        abi::thread::thread_set_priority,
+    },
+    thread_set_affinity: {
+        params: [I32, I64],
+        returns: I64,
+        abi::thread::thread_set_affinity,
+    },
+    thread_sleep: {
+        params: [I64],
+        returns: VOID,
+        abi::thread::thread_sleep,
+    },
+    thread_sleep_until: {
+        params: [I64],
+        returns: VOID,
+        abi::thread::thread_sleep_until,
+    },
+    thread_info: {
+        params: [I32, I32],
+        returns: I64,
+        abi::thread::thread_info,
+    },
 
     // Pretty fast exclusion
     pfex_acquire: {
@@ -159,20 +295,137 @@ abi_map! {
         returns: VOID,
         abi::pfex::pfex_release,
     },
+
+    // system
+    sysinfo: {
+        params: [I32],
+        returns: I64,
+        abi::system::sysinfo,
+    },
+    system_stats: {
+        params: [I32, I32],
+        returns: I64,
+        abi::system::system_stats,
+    },
+    cpu_count: {
+        params: [],
+        returns: I64,
+        abi::system::cpu_count,
+    },
+    cpu_id: {
+        params: [],
+        returns: I64,
+        abi::system::cpu_id,
+    },
+    system_poweroff: {
+        params: [I32],
+        returns: I64,
+        abi::system::system_poweroff,
+    },
+    system_reboot: {
+        params: [I32],
+        returns: I64,
+        abi::system::system_reboot,
+    },
 }
 
 abi_map! {
     INTRINSIC_MAP,
     grow_memory: {
-        params: [I32],
+        params: [I32, I32],
         returns: I32,
         abi::intrinsics::grow_memory,
     },
     current_memory: {
-        params: [],
+        params: [I32],
         returns: I32,
         abi::intrinsics::current_memory,
     },
+    atomic_store32: {
+        params: [I64, I32, I32],
+        returns: VOID,
+        abi::atomic::atomic_store32,
+    },
+    atomic_store64: {
+        params: [I64, I32, I64],
+        returns: VOID,
+        abi::atomic::atomic_store64,
+    },
+    atomic_rmw32: {
+        params: [I64, I32, I32, I32],
+        returns: I32,
+        abi::atomic::atomic_rmw32,
+    },
+    atomic_rmw64: {
+        params: [I64, I32, I32, I64],
+        returns: I64,
+        abi::atomic::atomic_rmw64,
+    },
+    atomic_cmpxchg32: {
+        params: [I64, I32, I32, I32],
+        returns: I32,
+        abi::atomic::atomic_cmpxchg32,
+    },
+    atomic_cmpxchg64: {
+        params: [I64, I32, I64, I64],
+        returns: I64,
+        abi::atomic::atomic_cmpxchg64,
+    },
+    atomic_wait32: {
+        params: [I64, I32, I64],
+        returns: I32,
+        abi::atomic::atomic_wait32,
+    },
+    atomic_wait64: {
+        params: [I64, I64, I64],
+        returns: I32,
+        abi::atomic::atomic_wait64,
+    },
+    atomic_notify: {
+        params: [I64, I32],
+        returns: I32,
+        abi::atomic::atomic_notify,
+    },
+    memory_init: {
+        params: [I32, I32, I32, I32],
+        returns: VOID,
+        abi::bulk::memory_init,
+    },
+    data_drop: {
+        params: [I32],
+        returns: VOID,
+        abi::bulk::data_drop,
+    },
+    memory_copy: {
+        params: [I32, I32, I32],
+        returns: VOID,
+        abi::bulk::memory_copy,
+    },
+    memory_fill: {
+        params: [I32, I32, I32],
+        returns: VOID,
+        abi::bulk::memory_fill,
+    },
+    table_init: {
+        params: [I32, I32, I32, I32, I32],
+        returns: VOID,
+        abi::bulk::table_init,
+    },
+    elem_drop: {
+        params: [I32],
+        returns: VOID,
+        abi::bulk::elem_drop,
+    },
+    table_copy: {
+        params: [I32, I32, I32, I32, I32],
+        returns: VOID,
+        abi::bulk::table_copy,
+    },
+    table_size: {
+        params: [I32],
+        returns: I32,
+        abi::bulk::table_size,
+    },
     // debug_addr: {
     //     params: [I64],
     //     returns: VOID,
@@ -27,12 +27,6 @@ impl AbiFunction {
             return false;
         }
 
-        for i in 0..(sig.params.len() - 1) {
-            if sig.params[i].value_type != self.params[i] {
-                return false;
-            }
-        }
-
         if let Some(last_param) = sig.params.last() {
             if last_param.purpose != ArgumentPurpose::VMContext {
                 return false;
@@ -42,6 +36,16 @@ impl AbiFunction {
             return false;
         }
 
+        if sig.params.len() - 1 != self.params.len() {
+            return false;
+        }
+
+        for i in 0..(sig.params.len() - 1) {
+            if sig.params[i].value_type != self.params[i] {
+                return false;
+            }
+        }
+
         return true;
     }
 }
@@ -0,0 +1,817 @@
+//! The format of modules that are compiled ahead of time.
+//!
+//! The `aot` tool compiles wasm on the host with the same
+//! translation as the kernel, and writes an `Artifact` that the
+//! kernel only has to relocate, instead of compiling the wasm at
+//! boot. Like the rest of the translation, this doesn't depend on
+//! the rest of the kernel, so the tool builds it too.
+//!
+//! An artifact starts with a header:
+//!
+//! | field            | size |
+//! |------------------|------|
+//! | `MAGIC`          | 8    |
+//! | `FORMAT_VERSION` | 4    |
+//! | `LAYOUT_VERSION` | 4    |
+//! | wasm hash        | 32   |
+//! | payload hash     | 32   |
+//!
+//! The hashes are SHA-256 hashes of the wasm that the artifact was
+//! compiled from, and of the payload after the header. The payload
+//! is the Cranelift version and settings that compiled the code,
+//! the code itself, and everything needed to load it. Integers are
+//! little endian, and lists and strings are prefixed with their
+//! length as a `u32`.
+
+use cranelift_codegen::ir::{self, types, Signature, AbiParam, ArgumentPurpose, TrapCode};
+use cranelift_codegen::binemit::Reloc;
+use cranelift_codegen::settings::CallConv;
+use cranelift_wasm::{Global, GlobalInit, Table, TableElementType, Memory};
+use common::sha256::{Sha256, Digest};
+use super::module::{Module, Export, TableElements, TableElement};
+use super::translate::{DataInitializer, Relocation, RelocationType, Relocations, TrapData};
+use super::layout::{self, LAYOUT_VERSION};
+
+use core::str;
+use alloc::vec::Vec;
+use alloc::string::String;
+
+pub const MAGIC: &[u8; 8] = b"\0nebaot\0";
+
+/// Bump this whenever the format changes.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// The Cranelift version that the kernel is built with. Code
+/// compiled by any other version isn't trusted to match.
+pub const CRANELIFT_VERSION: &str = "0.18.1";
+
+const HEADER_SIZE: usize = 8 + 4 + 4 + 32 + 32;
+
+/// Why an artifact couldn't be written or read.
+pub type ArtifactError = &'static str;
+
+pub type ArtifactResult<T> = Result<T, ArtifactError>;
+
+/// A module that was compiled ahead of time.
+pub struct Artifact {
+    /// The shared settings flags that the code was compiled
+    /// with, as they're displayed.
+    pub settings: String,
+    /// The machine code of every local function.
+    pub code: Vec<u8>,
+    /// The offset and size of each local function in `code`.
+    pub functions: Vec<(usize, usize)>,
+    /// The relocations of each local function.
+    pub relocations: Relocations,
+    pub traps: Vec<TrapData>,
+    pub module: Module,
+    pub data_initializers: Vec<DataInitializer>,
+}
+
+impl Artifact {
+    /// Write the artifact, which was compiled from `wasm`.
+    pub fn serialize(&self, wasm: &[u8]) -> ArtifactResult<Vec<u8>> {
+        let mut w = Writer::new();
+
+        w.str(CRANELIFT_VERSION);
+        w.str(&self.settings);
+        w.bytes(&self.code);
+
+        w.len(self.functions.len());
+        for &(offset, size) in &self.functions {
+            w.u64(offset as u64);
+            w.u64(size as u64);
+        }
+
+        w.len(self.relocations.len());
+        for function_relocs in &self.relocations {
+            w.len(function_relocs.len());
+            for (reloc, reloc_type) in function_relocs {
+                w.u8(reloc_code(reloc.reloc)?);
+                w.u32(reloc.offset);
+                w.i64(reloc.addend);
+                match reloc_type {
+                    RelocationType::Normal(func_index) => {
+                        w.u8(0);
+                        w.u32(*func_index as u32);
+                    },
+                    RelocationType::Intrinsic(name) => {
+                        w.u8(1);
+                        w.str(name);
+                    },
+                }
+            }
+        }
+
+        w.len(self.traps.len());
+        for trap in &self.traps {
+            w.u64(trap.offset as u64);
+            write_trap_code(&mut w, trap.code);
+        }
+
+        write_module(&mut w, &self.module)?;
+
+        w.len(self.data_initializers.len());
+        for init in &self.data_initializers {
+            w.u32(init.memory_index as u32);
+            w.opt(init.base.map(|base| base as u64));
+            w.u64(init.offset as u64);
+            w.bytes(&init.data);
+        }
+
+        let payload = w.buf;
+
+        let mut out = Vec::with_capacity(HEADER_SIZE + payload.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&le_bytes(FORMAT_VERSION as u64, 4));
+        out.extend_from_slice(&le_bytes(LAYOUT_VERSION as u64, 4));
+        out.extend_from_slice(&Sha256::digest(wasm));
+        out.extend_from_slice(&Sha256::digest(&payload));
+        out.extend_from_slice(&payload);
+
+        Ok(out)
+    }
+
+    /// Read an artifact, checking that it's intact, that it was
+    /// compiled from `wasm` by this Cranelift version with `settings`
+    /// against this layout, and that everything in it is in bounds.
+    pub fn deserialize(bytes: &[u8], wasm: &[u8], settings: &str) -> ArtifactResult<Artifact> {
+        if bytes.len() < HEADER_SIZE || &bytes[..8] != MAGIC {
+            return Err("not an artifact");
+        }
+
+        let mut header = Reader::new(&bytes[8..HEADER_SIZE]);
+        if header.u32()? != FORMAT_VERSION {
+            return Err("unsupported artifact format version");
+        }
+        if header.u32()? != LAYOUT_VERSION {
+            return Err("artifact was compiled against a different kernel layout");
+        }
+        if header.digest()? != Sha256::digest(wasm) {
+            return Err("artifact wasn't compiled from this wasm");
+        }
+
+        let payload = &bytes[HEADER_SIZE..];
+        if header.digest()? != Sha256::digest(payload) {
+            return Err("artifact is corrupt");
+        }
+
+        let mut r = Reader::new(payload);
+
+        if r.str()? != CRANELIFT_VERSION {
+            return Err("artifact was compiled by a different cranelift version");
+        }
+
+        if r.str()? != settings {
+            return Err("artifact was compiled with different settings");
+        }
+
+        let settings = String::from(settings);
+        let code = r.bytes()?.to_vec();
+
+        let count = r.len()?;
+        let mut functions = Vec::with_capacity(count);
+        for _ in 0..count {
+            functions.push((r.usize()?, r.usize()?));
+        }
+
+        let count = r.len()?;
+        let mut relocations = Vec::with_capacity(count);
+        for _ in 0..count {
+            let reloc_count = r.len()?;
+            let mut function_relocs = Vec::with_capacity(reloc_count);
+            for _ in 0..reloc_count {
+                let reloc = Relocation {
+                    reloc: reloc_from_code(r.u8()?)?,
+                    offset: r.u32()?,
+                    addend: r.i64()?,
+                };
+                let reloc_type = match r.u8()? {
+                    0 => RelocationType::Normal(r.u32()? as usize),
+                    1 => RelocationType::Intrinsic(String::from(r.str()?)),
+                    _ => return Err("unknown relocation target"),
+                };
+                function_relocs.push((reloc, reloc_type));
+            }
+            relocations.push(function_relocs);
+        }
+
+        let count = r.len()?;
+        let mut traps = Vec::with_capacity(count);
+        for _ in 0..count {
+            traps.push(TrapData {
+                offset: r.usize()?,
+                code: read_trap_code(&mut r)?,
+            });
+        }
+
+        let module = read_module(&mut r)?;
+
+        let count = r.len()?;
+        let mut data_initializers = Vec::with_capacity(count);
+        for _ in 0..count {
+            data_initializers.push(DataInitializer {
+                memory_index: r.u32()? as usize,
+                base: r.opt()?.map(|base| base as usize),
+                offset: r.usize()?,
+                data: r.bytes()?.to_vec(),
+            });
+        }
+
+        if !r.is_empty() {
+            return Err("trailing bytes after the artifact");
+        }
+
+        let artifact = Artifact {
+            settings,
+            code,
+            functions,
+            relocations,
+            traps,
+            module,
+            data_initializers,
+        };
+
+        artifact.check()?;
+
+        Ok(artifact)
+    }
+
+    /// Check that every offset and index in the artifact is in
+    /// bounds, so that loading it can't write outside of the code,
+    /// or index outside of the module.
+    fn check(&self) -> ArtifactResult<()> {
+        let module = &self.module;
+        let num_imported = module.imported_funcs.len();
+
+        if module.functions.len() != num_imported + self.functions.len() {
+            return Err("function count doesn't match the module");
+        }
+        if self.functions.is_empty() {
+            return Err("artifact has no local functions");
+        }
+        if self.relocations.len() != self.functions.len() {
+            return Err("relocation count doesn't match the functions");
+        }
+
+        for &(offset, size) in &self.functions {
+            match offset.checked_add(size) {
+                Some(end) if end <= self.code.len() => {},
+                _ => return Err("function is outside of the code"),
+            }
+        }
+
+        for (&(_, size), function_relocs) in self.functions.iter().zip(&self.relocations) {
+            for (reloc, reloc_type) in function_relocs {
+                // Only `Abs8` relocations can be read back.
+                if reloc.offset as usize + 8 > size {
+                    return Err("relocation is outside of its function");
+                }
+                if let RelocationType::Normal(func_index) = reloc_type {
+                    if *func_index >= module.functions.len() {
+                        return Err("relocation to a function that doesn't exist");
+                    }
+                }
+            }
+        }
+
+        if self.traps.iter().any(|trap| trap.offset >= self.code.len()) {
+            return Err("trap is outside of the code");
+        }
+
+        if module.sig_ids.len() != 0 {
+            return Err("signature ids can't be set ahead of time");
+        }
+        if module.functions.iter().any(|&sig_index| sig_index >= module.signatures.len()) {
+            return Err("function has a signature that doesn't exist");
+        }
+
+        if let Some(start_func) = module.start_func {
+            if start_func >= module.functions.len() {
+                return Err("start function doesn't exist");
+            }
+        }
+
+        for export in module.exports.values() {
+            let in_bounds = match *export {
+                Export::Function(index) => index < module.functions.len(),
+                Export::Table(index) => index < module.tables.len(),
+                Export::Memory(index) => index < module.memories.len(),
+                Export::Global(index) => index < module.globals.len(),
+            };
+            if !in_bounds {
+                return Err("export doesn't exist");
+            }
+        }
+
+        for memory in &module.memories {
+            let max_pages = layout::HEAP_SIZE / layout::WASM_PAGE_SIZE;
+            if memory.maximum.map_or(false, |max| max > max_pages || max < memory.pages_count) {
+                return Err("memory has an invalid maximum");
+            }
+        }
+
+        for table_element in &module.table_elements {
+            if table_element.table_index >= module.tables.len() {
+                return Err("table elements for a table that doesn't exist");
+            }
+            if table_element.elements.iter().any(|&func_index| func_index >= module.functions.len()) {
+                return Err("table element is a function that doesn't exist");
+            }
+        }
+
+        let passive_functions = module.passive_elements.iter()
+            .flat_map(|elements| elements.iter());
+        for element in passive_functions {
+            if let TableElement::Function(func_index) = *element {
+                if func_index >= module.functions.len() {
+                    return Err("table element is a function that doesn't exist");
+                }
+            }
+        }
+
+        for init in &self.data_initializers {
+            if init.memory_index >= module.memories.len() {
+                return Err("data for a memory that doesn't exist");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_module(w: &mut Writer, module: &Module) -> ArtifactResult<()> {
+    w.len(module.signatures.len());
+    for sig in &module.signatures {
+        write_signature(w, sig)?;
+    }
+
+    w.len(module.imported_funcs.len());
+    for (import_module, field) in &module.imported_funcs {
+        w.str(import_module);
+        w.str(field);
+    }
+
+    w.len(module.functions.len());
+    for &sig_index in &module.functions {
+        w.u32(sig_index as u32);
+    }
+
+    w.len(module.tables.len());
+    for table in &module.tables {
+        match table.ty {
+            TableElementType::Func() => w.u8(0),
+            TableElementType::Val(ty) => w.u8(type_code(ty)?),
+        }
+        w.u64(table.size as u64);
+        w.opt(table.maximum.map(|max| max as u64));
+    }
+
+    w.len(module.memories.len());
+    for memory in &module.memories {
+        w.u64(memory.pages_count as u64);
+        w.opt(memory.maximum.map(|max| max as u64));
+        w.u8(memory.shared as u8);
+    }
+
+    w.len(module.globals.len());
+    for global in &module.globals {
+        w.u8(type_code(global.ty)?);
+        w.u8(global.mutability as u8);
+        match global.initializer {
+            GlobalInit::I32Const(value) => { w.u8(0); w.u64(value as u32 as u64); },
+            GlobalInit::I64Const(value) => { w.u8(1); w.u64(value as u64); },
+            GlobalInit::F32Const(bits) => { w.u8(2); w.u64(bits as u64); },
+            GlobalInit::F64Const(bits) => { w.u8(3); w.u64(bits); },
+            GlobalInit::GetGlobal(index) => { w.u8(4); w.u64(index as u64); },
+            GlobalInit::Import() => { w.u8(5); w.u64(0); },
+        }
+    }
+
+    // Sorted, so that the same module always
+    // produces the same artifact.
+    let mut exports: Vec<_> = module.exports.iter().collect();
+    exports.sort_by(|a, b| a.0.cmp(b.0));
+
+    w.len(exports.len());
+    for (name, export) in exports {
+        w.str(name);
+        let (kind, index) = match *export {
+            Export::Function(index) => (0, index),
+            Export::Table(index) => (1, index),
+            Export::Memory(index) => (2, index),
+            Export::Global(index) => (3, index),
+        };
+        w.u8(kind);
+        w.u32(index as u32);
+    }
+
+    w.opt(module.start_func.map(|index| index as u64));
+
+    w.len(module.table_elements.len());
+    for table_element in &module.table_elements {
+        w.u32(table_element.table_index as u32);
+        w.opt(table_element.base.map(|base| base as u64));
+        w.u64(table_element.offset as u64);
+        w.len(table_element.elements.len());
+        for &func_index in &table_element.elements {
+            w.u32(func_index as u32);
+        }
+    }
+
+    w.len(module.passive_elements.len());
+    for elements in &module.passive_elements {
+        w.len(elements.len());
+        for element in elements {
+            w.opt(match *element {
+                TableElement::Function(func_index) => Some(func_index as u64),
+                TableElement::Trap() => None,
+            });
+        }
+    }
+
+    w.len(module.passive_data.len());
+    for data in &module.passive_data {
+        w.bytes(data);
+    }
+
+    Ok(())
+}
+
+fn read_module(r: &mut Reader) -> ArtifactResult<Module> {
+    let mut module = Module::new();
+
+    for _ in 0..r.len()? {
+        module.signatures.push(read_signature(r)?);
+    }
+
+    for _ in 0..r.len()? {
+        let import_module = String::from(r.str()?);
+        let field = String::from(r.str()?);
+        module.imported_funcs.push((import_module, field));
+    }
+
+    for _ in 0..r.len()? {
+        module.functions.push(r.u32()? as usize);
+    }
+
+    for _ in 0..r.len()? {
+        let ty = match r.u8()? {
+            0 => TableElementType::Func(),
+            code => TableElementType::Val(type_from_code(code)?),
+        };
+        module.tables.push(Table {
+            ty,
+            size: r.usize()?,
+            maximum: r.opt()?.map(|max| max as usize),
+        });
+    }
+
+    for _ in 0..r.len()? {
+        module.memories.push(Memory {
+            pages_count: r.usize()?,
+            maximum: r.opt()?.map(|max| max as usize),
+            shared: r.u8()? != 0,
+        });
+    }
+
+    for _ in 0..r.len()? {
+        let ty = type_from_code(r.u8()?)?;
+        let mutability = r.u8()? != 0;
+        let tag = r.u8()?;
+        let value = r.u64()?;
+        let initializer = match tag {
+            0 => GlobalInit::I32Const(value as u32 as i32),
+            1 => GlobalInit::I64Const(value as i64),
+            2 => GlobalInit::F32Const(value as u32),
+            3 => GlobalInit::F64Const(value),
+            4 => GlobalInit::GetGlobal(value as usize),
+            5 => GlobalInit::Import(),
+            _ => return Err("unknown global initializer"),
+        };
+        module.globals.push(Global {
+            ty,
+            mutability,
+            initializer,
+        });
+    }
+
+    for _ in 0..r.len()? {
+        let name = String::from(r.str()?);
+        let kind = r.u8()?;
+        let index = r.u32()? as usize;
+        let export = match kind {
+            0 => Export::Function(index),
+            1 => Export::Table(index),
+            2 => Export::Memory(index),
+            3 => Export::Global(index),
+            _ => return Err("unknown export kind"),
+        };
+        module.exports.insert(name, export);
+    }
+
+    module.start_func = r.opt()?.map(|index| index as usize);
+
+    for _ in 0..r.len()? {
+        let table_index = r.u32()? as usize;
+        let base = r.opt()?.map(|base| base as usize);
+        let offset = r.usize()?;
+        let count = r.len()?;
+        let mut elements = Vec::with_capacity(count);
+        for _ in 0..count {
+            elements.push(r.u32()? as usize);
+        }
+        module.table_elements.push(TableElements {
+            table_index,
+            base,
+            offset,
+            elements,
+        });
+    }
+
+    for _ in 0..r.len()? {
+        let count = r.len()?;
+        let mut elements = Vec::with_capacity(count);
+        for _ in 0..count {
+            elements.push(match r.opt()? {
+                Some(func_index) => TableElement::Function(func_index as usize),
+                None => TableElement::Trap(),
+            });
+        }
+        module.passive_elements.push(elements);
+    }
+
+    for _ in 0..r.len()? {
+        module.passive_data.push(r.bytes()?.to_vec());
+    }
+
+    Ok(module)
+}
+
+/// Wasm signatures only use the default calling convention,
+/// and only have normal and vmctx parameters.
+fn write_signature(w: &mut Writer, sig: &Signature) -> ArtifactResult<()> {
+    if sig.call_conv != CallConv::SystemV {
+        return Err("unsupported calling convention");
+    }
+
+    for params in &[&sig.params, &sig.returns] {
+        w.len(params.len());
+        for param in params.iter() {
+            w.u8(type_code(param.value_type)?);
+            w.u8(match param.purpose {
+                ArgumentPurpose::Normal => 0,
+                ArgumentPurpose::VMContext => 1,
+                _ => return Err("unsupported argument purpose"),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn read_signature(r: &mut Reader) -> ArtifactResult<Signature> {
+    let mut sig = Signature::new(CallConv::SystemV);
+
+    for returns in &[false, true] {
+        for _ in 0..r.len()? {
+            let ty = type_from_code(r.u8()?)?;
+            let param = match r.u8()? {
+                0 => AbiParam::new(ty),
+                1 => AbiParam::special(ty, ArgumentPurpose::VMContext),
+                _ => return Err("unsupported argument purpose"),
+            };
+            if *returns {
+                sig.returns.push(param);
+            } else {
+                sig.params.push(param);
+            }
+        }
+    }
+
+    Ok(sig)
+}
+
+fn type_code(ty: ir::Type) -> ArtifactResult<u8> {
+    Ok(match ty {
+        types::I32 => 1,
+        types::I64 => 2,
+        types::F32 => 3,
+        types::F64 => 4,
+        _ => return Err("unsupported value type"),
+    })
+}
+
+fn type_from_code(code: u8) -> ArtifactResult<ir::Type> {
+    Ok(match code {
+        1 => types::I32,
+        2 => types::I64,
+        3 => types::F32,
+        4 => types::F64,
+        _ => return Err("unsupported value type"),
+    })
+}
+
+/// The kernel only applies `Abs8` relocations.
+fn reloc_code(reloc: Reloc) -> ArtifactResult<u8> {
+    match reloc {
+        Reloc::Abs8 => Ok(0),
+        _ => Err("unsupported relocation"),
+    }
+}
+
+fn reloc_from_code(code: u8) -> ArtifactResult<Reloc> {
+    match code {
+        0 => Ok(Reloc::Abs8),
+        _ => Err("unsupported relocation"),
+    }
+}
+
+fn write_trap_code(w: &mut Writer, code: TrapCode) {
+    let tag = match code {
+        TrapCode::StackOverflow => 0,
+        TrapCode::HeapOutOfBounds => 1,
+        TrapCode::TableOutOfBounds => 2,
+        TrapCode::OutOfBounds => 3,
+        TrapCode::IndirectCallToNull => 4,
+        TrapCode::BadSignature => 5,
+        TrapCode::IntegerOverflow => 6,
+        TrapCode::IntegerDivisionByZero => 7,
+        TrapCode::BadConversionToInteger => 8,
+        TrapCode::Interrupt => 9,
+        TrapCode::User(user_code) => {
+            w.u8(10);
+            w.u16(user_code);
+            return;
+        },
+    };
+    w.u8(tag);
+}
+
+fn read_trap_code(r: &mut Reader) -> ArtifactResult<TrapCode> {
+    Ok(match r.u8()? {
+        0 => TrapCode::StackOverflow,
+        1 => TrapCode::HeapOutOfBounds,
+        2 => TrapCode::TableOutOfBounds,
+        3 => TrapCode::OutOfBounds,
+        4 => TrapCode::IndirectCallToNull,
+        5 => TrapCode::BadSignature,
+        6 => TrapCode::IntegerOverflow,
+        7 => TrapCode::IntegerDivisionByZero,
+        8 => TrapCode::BadConversionToInteger,
+        9 => TrapCode::Interrupt,
+        10 => TrapCode::User(r.u16()?),
+        _ => return Err("unknown trap code"),
+    })
+}
+
+fn le_bytes(value: u64, size: usize) -> Vec<u8> {
+    (0..size).map(|i| (value >> (i * 8)) as u8).collect()
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer {
+            buf: Vec::new(),
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&le_bytes(value as u64, 2));
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&le_bytes(value as u64, 4));
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&le_bytes(value, 8));
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.u64(value as u64);
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn opt(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.u64(value);
+            },
+            None => self.u8(0),
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> ArtifactResult<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err("artifact is truncated");
+        }
+
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, size: usize) -> ArtifactResult<u64> {
+        let bytes = self.take(size)?;
+        Ok(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn u8(&mut self) -> ArtifactResult<u8> {
+        self.uint(1).map(|value| value as u8)
+    }
+
+    fn u16(&mut self) -> ArtifactResult<u16> {
+        self.uint(2).map(|value| value as u16)
+    }
+
+    fn u32(&mut self) -> ArtifactResult<u32> {
+        self.uint(4).map(|value| value as u32)
+    }
+
+    fn u64(&mut self) -> ArtifactResult<u64> {
+        self.uint(8)
+    }
+
+    fn i64(&mut self) -> ArtifactResult<i64> {
+        self.u64().map(|value| value as i64)
+    }
+
+    fn usize(&mut self) -> ArtifactResult<usize> {
+        let value = self.u64()?;
+        if value > usize::max_value() as u64 {
+            return Err("value doesn't fit in a usize");
+        }
+        Ok(value as usize)
+    }
+
+    /// A length, which can't be more than the bytes that are left,
+    /// so that a corrupt one can't make a huge allocation.
+    fn len(&mut self) -> ArtifactResult<usize> {
+        let len = self.u32()? as usize;
+        if len > self.buf.len() {
+            return Err("artifact is truncated");
+        }
+        Ok(len)
+    }
+
+    fn opt(&mut self) -> ArtifactResult<Option<u64>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => self.u64().map(Some),
+            _ => Err("invalid option"),
+        }
+    }
+
+    fn bytes(&mut self) -> ArtifactResult<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> ArtifactResult<&'a str> {
+        str::from_utf8(self.bytes()?).map_err(|_| "invalid string")
+    }
+
+    fn digest(&mut self) -> ArtifactResult<Digest> {
+        let mut digest = [0; 32];
+        digest.copy_from_slice(self.take(32)?);
+        Ok(digest)
+    }
+}
@@ -0,0 +1,264 @@
+//! The atomic operators of the threads proposal.
+//!
+//! Cranelift can't translate these yet, so before a function is
+//! translated, each one is replaced with a call to a function past
+//! the end of the module's function index space. `FuncEnvironment`
+//! translates those calls itself: loads are done inline, and the
+//! other accesses are checked inline and then done by intrinsics
+//! with the cpu's own atomics. Like `translate`, this doesn't depend
+//! on the rest of the kernel, so the `aot` tool builds it too.
+
+use cranelift_codegen::ir::Type;
+use cranelift_codegen::ir::types::{I32, I64};
+use cranelift_wasm::{WasmError, WasmResult};
+use wasmparser::BinaryReader;
+use alloc::vec::Vec;
+
+/// The byte that the atomic operators start with.
+const ATOMIC_PREFIX: u8 = 0xfe;
+/// The opcode of `call`.
+const CALL: u8 = 0x10;
+
+/// The type and size, in bytes, of each of the
+/// seven variants of loads, stores, and rmw operators.
+const ACCESSES: [(Type, u32); 7] = [
+    (I32, 4),
+    (I64, 8),
+    (I32, 1),
+    (I32, 2),
+    (I64, 1),
+    (I64, 2),
+    (I64, 4),
+];
+
+/// A read-modify-write operation. The intrinsics take it as an `i32`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum RmwOp {
+    Add = 0,
+    Sub = 1,
+    And = 2,
+    Or = 3,
+    Xor = 4,
+    Xchg = 5,
+}
+
+impl RmwOp {
+    pub fn from_u32(op: u32) -> Option<RmwOp> {
+        Some(match op {
+            0 => RmwOp::Add,
+            1 => RmwOp::Sub,
+            2 => RmwOp::And,
+            3 => RmwOp::Or,
+            4 => RmwOp::Xor,
+            5 => RmwOp::Xchg,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AtomicKind {
+    Notify,
+    Wait,
+    Load,
+    Store,
+    Rmw(RmwOp),
+    Cmpxchg,
+}
+
+/// An atomic operator, as it appears in a function.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AtomicOp {
+    pub kind: AtomicKind,
+    /// The type of the value that's accessed.
+    pub ty: Type,
+    /// The size of the access, in bytes.
+    pub width: u32,
+    /// The constant offset that's added to the address.
+    pub offset: u32,
+}
+
+impl AtomicOp {
+    fn decode(opcode: u32, offset: u32) -> Option<AtomicOp> {
+        let (kind, ty, width) = match opcode {
+            0x00 => (AtomicKind::Notify, I32, 4),
+            0x01 => (AtomicKind::Wait, I32, 4),
+            0x02 => (AtomicKind::Wait, I64, 8),
+            0x10..=0x4e => {
+                let (ty, width) = ACCESSES[((opcode - 0x10) % 7) as usize];
+                let kind = match (opcode - 0x10) / 7 {
+                    0 => AtomicKind::Load,
+                    1 => AtomicKind::Store,
+                    2 => AtomicKind::Rmw(RmwOp::Add),
+                    3 => AtomicKind::Rmw(RmwOp::Sub),
+                    4 => AtomicKind::Rmw(RmwOp::And),
+                    5 => AtomicKind::Rmw(RmwOp::Or),
+                    6 => AtomicKind::Rmw(RmwOp::Xor),
+                    7 => AtomicKind::Rmw(RmwOp::Xchg),
+                    _ => AtomicKind::Cmpxchg,
+                };
+                (kind, ty, width)
+            },
+            _ => return None,
+        };
+
+        Some(AtomicOp { kind, ty, width, offset })
+    }
+
+    /// The types of the operands on the wasm stack, starting with the address.
+    pub fn params(&self) -> Vec<Type> {
+        match self.kind {
+            AtomicKind::Notify => vec![I32, I32],
+            AtomicKind::Wait => vec![I32, self.ty, I64],
+            AtomicKind::Load => vec![I32],
+            AtomicKind::Store | AtomicKind::Rmw(_) => vec![I32, self.ty],
+            AtomicKind::Cmpxchg => vec![I32, self.ty, self.ty],
+        }
+    }
+
+    /// The type of the result that's pushed onto the wasm stack.
+    pub fn result(&self) -> Option<Type> {
+        match self.kind {
+            AtomicKind::Notify | AtomicKind::Wait => Some(I32),
+            AtomicKind::Store => None,
+            AtomicKind::Load | AtomicKind::Rmw(_) | AtomicKind::Cmpxchg => Some(self.ty),
+        }
+    }
+
+    /// Whether the intrinsic takes the size of the access, because
+    /// it handles more than one.
+    pub fn takes_width(&self) -> bool {
+        match self.kind {
+            AtomicKind::Notify | AtomicKind::Wait => false,
+            _ => true,
+        }
+    }
+
+    /// The name of the intrinsic that does the operation.
+    /// Loads don't have one, since they're done inline.
+    pub fn intrinsic(&self) -> &'static str {
+        let wide = self.ty == I64;
+        match self.kind {
+            AtomicKind::Notify => "atomic_notify",
+            AtomicKind::Wait if wide => "atomic_wait64",
+            AtomicKind::Wait => "atomic_wait32",
+            AtomicKind::Load => unreachable!("atomic loads are done inline"),
+            AtomicKind::Store if wide => "atomic_store64",
+            AtomicKind::Store => "atomic_store32",
+            AtomicKind::Rmw(_) if wide => "atomic_rmw64",
+            AtomicKind::Rmw(_) => "atomic_rmw32",
+            AtomicKind::Cmpxchg if wide => "atomic_cmpxchg64",
+            AtomicKind::Cmpxchg => "atomic_cmpxchg32",
+        }
+    }
+}
+
+/// Read an unsigned LEB128 number at `pos`, and move past it.
+pub fn read_var_u32(bytes: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut result = 0;
+    for shift in (0..5).map(|i| i * 7) {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+    None
+}
+
+/// Write `value` as an unsigned LEB128 number.
+pub fn write_var_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn malformed(offset: usize) -> WasmError {
+    WasmError::InvalidWebAssembly {
+        message: "malformed function body",
+        offset,
+    }
+}
+
+/// The offset of the code in a function body, after the locals.
+pub fn code_start(body: &[u8]) -> WasmResult<usize> {
+    let mut pos = 0;
+    let entries = read_var_u32(body, &mut pos).ok_or_else(|| malformed(pos))?;
+    for _ in 0..entries {
+        read_var_u32(body, &mut pos).ok_or_else(|| malformed(pos))?;
+        // the type of the locals
+        pos += 1;
+    }
+
+    if pos > body.len() {
+        Err(malformed(body.len()))
+    } else {
+        Ok(pos)
+    }
+}
+
+/// Replace the atomic operators in a function body with calls to
+/// `first_index` and up, one for each distinct operator. Returns
+/// the new body and the operators, or `None` if there aren't any.
+pub fn rewrite(body: &[u8], first_index: usize) -> WasmResult<Option<(Vec<u8>, Vec<AtomicOp>)>> {
+    // The prefix could be part of an immediate,
+    // but most functions won't have it at all.
+    if !body.contains(&ATOMIC_PREFIX) {
+        return Ok(None);
+    }
+
+    let code_start = code_start(body)?;
+    let code = &body[code_start..];
+
+    let mut reader = BinaryReader::new(code);
+    let mut out = Vec::with_capacity(body.len());
+    out.extend_from_slice(&body[..code_start]);
+    let mut ops: Vec<AtomicOp> = Vec::new();
+
+    while !reader.eof() {
+        let start = reader.current_position();
+        reader.read_operator()?;
+        let end = reader.current_position();
+
+        let bytes = &code[start..end];
+        if bytes[0] != ATOMIC_PREFIX {
+            out.extend_from_slice(bytes);
+            continue;
+        }
+
+        // The opcode, then the alignment and offset.
+        let mut pos = 1;
+        let op = read_var_u32(bytes, &mut pos)
+            .and_then(|opcode| {
+                read_var_u32(bytes, &mut pos)?;
+                let offset = read_var_u32(bytes, &mut pos)?;
+                AtomicOp::decode(opcode, offset)
+            })
+            .ok_or(WasmError::Unsupported("atomic operator"))?;
+
+        let index = match ops.iter().position(|other| *other == op) {
+            Some(index) => index,
+            None => {
+                ops.push(op);
+                ops.len() - 1
+            },
+        };
+
+        out.push(CALL);
+        write_var_u32(&mut out, (first_index + index) as u32);
+    }
+
+    if ops.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some((out, ops)))
+    }
+}
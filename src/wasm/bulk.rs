@@ -0,0 +1,649 @@
+//! The bulk memory operators, passive segments, and the parts of
+//! reference types that don't need reference values.
+//!
+//! The wasmparser that Cranelift uses can't read any of these, so
+//! modules are lowered to the MVP before they're validated. Each
+//! operator becomes a call to a function that's appended to the
+//! module, with the operator's signature, so the rest of the function
+//! is still validated as usual. `FuncEnvironment` turns those calls
+//! into calls to intrinsics. Passive segments are taken out of the
+//! module and kept in the `Module` for `memory.init` and `table.init`.
+//! Like `translate`, this doesn't depend on the rest of the kernel,
+//! so the `aot` tool builds it too.
+
+use cranelift_codegen::ir::Type;
+use cranelift_codegen::ir::types::I32;
+use cranelift_wasm::{WasmError, WasmResult};
+use wasmparser::{BinaryReader, Operator};
+use super::atomics::{read_var_u32, write_var_u32, code_start};
+use super::module::{Module, TableElement};
+use alloc::vec::Vec;
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_TABLE: u8 = 4;
+const SECTION_MEMORY: u8 = 5;
+const SECTION_ELEMENT: u8 = 9;
+const SECTION_CODE: u8 = 10;
+const SECTION_DATA: u8 = 11;
+const SECTION_DATA_COUNT: u8 = 12;
+
+const BULK_PREFIX: u8 = 0xfc;
+const CALL: u8 = 0x10;
+const CALL_INDIRECT: u8 = 0x11;
+const TABLE_GET: u8 = 0x25;
+const TABLE_SET: u8 = 0x26;
+const REF_NULL: u8 = 0xd0;
+const REF_IS_NULL: u8 = 0xd1;
+const REF_FUNC: u8 = 0xd2;
+const END: u8 = 0x0b;
+const FUNC_TYPE: u8 = 0x60;
+const I32_TYPE: u8 = 0x7f;
+
+/// The body of each appended function, which is never called:
+/// no locals, `unreachable` and `end`.
+const UNREACHABLE_BODY: [u8; 3] = [0x00, 0x00, END];
+
+/// An operator that's lowered to a call.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BulkOp {
+    MemoryInit { segment: u32 },
+    DataDrop { segment: u32 },
+    MemoryCopy,
+    MemoryFill,
+    TableInit { segment: u32, table: u32 },
+    ElemDrop { segment: u32 },
+    TableCopy { dst_table: u32, src_table: u32 },
+    TableSize { table: u32 },
+}
+
+/// The signatures of the appended functions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum HelperType {
+    /// `[i32, i32, i32] -> []`
+    ThreeOperands,
+    /// `[] -> []`
+    NoOperands,
+    /// `[] -> [i32]`
+    Size,
+}
+
+const HELPER_TYPES: [HelperType; 3] = [HelperType::ThreeOperands, HelperType::NoOperands, HelperType::Size];
+
+impl BulkOp {
+    fn helper_type(&self) -> HelperType {
+        match *self {
+            BulkOp::DataDrop { .. } | BulkOp::ElemDrop { .. } => HelperType::NoOperands,
+            BulkOp::TableSize { .. } => HelperType::Size,
+            _ => HelperType::ThreeOperands,
+        }
+    }
+
+    /// The name of the intrinsic that does the operation.
+    pub fn intrinsic(&self) -> &'static str {
+        match *self {
+            BulkOp::MemoryInit { .. } => "memory_init",
+            BulkOp::DataDrop { .. } => "data_drop",
+            BulkOp::MemoryCopy => "memory_copy",
+            BulkOp::MemoryFill => "memory_fill",
+            BulkOp::TableInit { .. } => "table_init",
+            BulkOp::ElemDrop { .. } => "elem_drop",
+            BulkOp::TableCopy { .. } => "table_copy",
+            BulkOp::TableSize { .. } => "table_size",
+        }
+    }
+
+    /// The immediates of the operator, which the
+    /// intrinsic takes as `i32`s after the operands.
+    pub fn immediates(&self) -> Vec<u32> {
+        match *self {
+            BulkOp::MemoryInit { segment }
+            | BulkOp::DataDrop { segment }
+            | BulkOp::ElemDrop { segment } => vec![segment],
+            BulkOp::TableInit { segment, table } => vec![segment, table],
+            BulkOp::TableCopy { dst_table, src_table } => vec![dst_table, src_table],
+            BulkOp::TableSize { table } => vec![table],
+            BulkOp::MemoryCopy | BulkOp::MemoryFill => vec![],
+        }
+    }
+
+    /// The type of the result that's pushed onto the wasm stack.
+    pub fn result(&self) -> Option<Type> {
+        match self.helper_type() {
+            HelperType::Size => Some(I32),
+            _ => None,
+        }
+    }
+}
+
+fn error(message: &'static str, offset: usize) -> WasmError {
+    WasmError::InvalidWebAssembly { message, offset }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// The offset of `bytes` in the module, for errors.
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], base: usize) -> Reader<'a> {
+        Reader { bytes, pos: 0, base }
+    }
+
+    fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    fn eof(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn u8(&mut self) -> WasmResult<u8> {
+        let byte = *self.bytes.get(self.pos).ok_or_else(|| error("unexpected end", self.offset()))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn var_u32(&mut self) -> WasmResult<u32> {
+        let offset = self.offset();
+        read_var_u32(self.bytes, &mut self.pos).ok_or_else(|| error("invalid LEB128", offset))
+    }
+
+    fn bytes(&mut self, len: usize) -> WasmResult<&'a [u8]> {
+        let start = self.pos;
+        let end = start.checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| error("unexpected end", self.offset()))?;
+        self.pos = end;
+        Ok(&self.bytes[start..end])
+    }
+
+    fn name(&mut self) -> WasmResult<&'a [u8]> {
+        let len = self.var_u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn limits(&mut self) -> WasmResult<()> {
+        let flags = self.u8()?;
+        self.var_u32()?;
+        if flags & 1 != 0 {
+            self.var_u32()?;
+        }
+        Ok(())
+    }
+
+    /// A constant expression, including its `end`.
+    fn expr(&mut self) -> WasmResult<&'a [u8]> {
+        let start = self.pos;
+        let mut reader = BinaryReader::new(&self.bytes[start..]);
+        loop {
+            if let Operator::End = reader.read_operator()? {
+                break;
+            }
+        }
+        self.bytes(reader.current_position())?;
+        Ok(&self.bytes[start..self.pos])
+    }
+
+    /// An element of a segment that's written as an expression.
+    fn elem_expr(&mut self) -> WasmResult<TableElement> {
+        let element = match self.u8()? {
+            REF_FUNC => TableElement::Function(self.var_u32()? as usize),
+            REF_NULL => {
+                self.u8()?;
+                TableElement::Trap()
+            },
+            _ => return Err(WasmError::Unsupported("element expression")),
+        };
+
+        if self.u8()? != END {
+            return Err(error("expected end of element expression", self.offset()));
+        }
+
+        Ok(element)
+    }
+}
+
+struct Section<'a> {
+    id: u8,
+    payload: &'a [u8],
+    /// The offset of the payload in the module.
+    offset: usize,
+}
+
+/// How many of each kind of entity the module has, including imports.
+#[derive(Default)]
+struct Counts {
+    types: u32,
+    functions: u32,
+    tables: u32,
+    memories: u32,
+    elements: u32,
+    data: u32,
+}
+
+fn count_entities(sections: &[Section]) -> WasmResult<Counts> {
+    let mut counts = Counts::default();
+
+    for section in sections {
+        let mut r = Reader::new(section.payload, section.offset);
+        match section.id {
+            SECTION_TYPE => counts.types = r.var_u32()?,
+            SECTION_IMPORT => {
+                for _ in 0..r.var_u32()? {
+                    r.name()?;
+                    r.name()?;
+                    match r.u8()? {
+                        0 => {
+                            r.var_u32()?;
+                            counts.functions += 1;
+                        },
+                        1 => {
+                            r.u8()?;
+                            r.limits()?;
+                            counts.tables += 1;
+                        },
+                        2 => {
+                            r.limits()?;
+                            counts.memories += 1;
+                        },
+                        3 => {
+                            r.u8()?;
+                            r.u8()?;
+                        },
+                        _ => return Err(error("invalid import kind", r.offset())),
+                    }
+                }
+            },
+            SECTION_FUNCTION => counts.functions += r.var_u32()?,
+            SECTION_TABLE => counts.tables += r.var_u32()?,
+            SECTION_MEMORY => counts.memories += r.var_u32()?,
+            SECTION_ELEMENT => counts.elements = r.var_u32()?,
+            SECTION_DATA => counts.data = r.var_u32()?,
+            _ => {},
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Write active element segments in the MVP's form, and take out the
+/// rest. Returns the new section, and the contents of the passive
+/// segments, with the active and declared ones empty.
+fn lower_elements(section: &Section, counts: &Counts) -> WasmResult<(Vec<u8>, Vec<Vec<TableElement>>)> {
+    let mut r = Reader::new(section.payload, section.offset);
+    let mut active = Vec::new();
+    let mut active_count = 0;
+    let mut segments = Vec::new();
+
+    for _ in 0..r.var_u32()? {
+        let flags = r.var_u32()?;
+        if flags > 7 {
+            return Err(error("invalid element segment flags", r.offset()));
+        }
+
+        let is_active = flags & 1 == 0;
+        let table = if is_active && flags & 2 != 0 { r.var_u32()? } else { 0 };
+        let offset_expr = if is_active { Some(r.expr()?) } else { None };
+        if flags & 3 != 0 {
+            // The element kind or reference type, which can only be funcref.
+            r.u8()?;
+        }
+
+        let mut elements = Vec::new();
+        for _ in 0..r.var_u32()? {
+            let element = if flags & 4 != 0 {
+                r.elem_expr()?
+            } else {
+                TableElement::Function(r.var_u32()? as usize)
+            };
+
+            if let TableElement::Function(index) = element {
+                if index >= counts.functions as usize {
+                    return Err(error("element is a function that doesn't exist", r.offset()));
+                }
+            }
+
+            elements.push(element);
+        }
+
+        match offset_expr {
+            Some(offset_expr) => {
+                write_var_u32(&mut active, table);
+                active.extend_from_slice(offset_expr);
+                write_var_u32(&mut active, elements.len() as u32);
+                for element in &elements {
+                    match *element {
+                        TableElement::Function(index) => write_var_u32(&mut active, index as u32),
+                        TableElement::Trap() => return Err(WasmError::Unsupported("null element in an active segment")),
+                    }
+                }
+                active_count += 1;
+                segments.push(Vec::new());
+            },
+            // Declared segments only declare functions for `ref.func`.
+            None if flags & 2 != 0 => segments.push(Vec::new()),
+            None => segments.push(elements),
+        }
+    }
+
+    let mut payload = Vec::with_capacity(active.len() + 5);
+    write_var_u32(&mut payload, active_count);
+    payload.extend_from_slice(&active);
+
+    Ok((payload, segments))
+}
+
+/// Like `lower_elements`, for data segments.
+fn lower_data(section: &Section) -> WasmResult<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut r = Reader::new(section.payload, section.offset);
+    let mut active = Vec::new();
+    let mut active_count = 0;
+    let mut segments = Vec::new();
+
+    for _ in 0..r.var_u32()? {
+        let flags = r.var_u32()?;
+        let memory = match flags {
+            0 => Some(0),
+            1 => None,
+            2 => Some(r.var_u32()?),
+            _ => return Err(error("invalid data segment flags", r.offset())),
+        };
+
+        match memory {
+            Some(memory) => {
+                let offset_expr = r.expr()?;
+                let data = r.name()?;
+                write_var_u32(&mut active, memory);
+                active.extend_from_slice(offset_expr);
+                write_var_u32(&mut active, data.len() as u32);
+                active.extend_from_slice(data);
+                active_count += 1;
+                segments.push(Vec::new());
+            },
+            None => segments.push(r.name()?.to_vec()),
+        }
+    }
+
+    let mut payload = Vec::with_capacity(active.len() + 5);
+    write_var_u32(&mut payload, active_count);
+    payload.extend_from_slice(&active);
+
+    Ok((payload, segments))
+}
+
+/// Replace the bulk operators in a function body with calls
+/// to the functions from `first_helper` on, adding the ones
+/// that aren't in `ops` yet. Returns `None` if there aren't any.
+fn lower_body(body: &[u8], offset: usize, counts: &Counts, first_helper: u32, ops: &mut Vec<BulkOp>) -> WasmResult<Option<Vec<u8>>> {
+    let candidates = [BULK_PREFIX, CALL_INDIRECT, TABLE_GET, TABLE_SET, REF_NULL, REF_IS_NULL, REF_FUNC];
+    if !body.iter().any(|byte| candidates.contains(byte)) {
+        return Ok(None);
+    }
+
+    let code_start = code_start(body)?;
+    let mut out = Vec::with_capacity(body.len());
+    out.extend_from_slice(&body[..code_start]);
+    let mut changed = false;
+
+    let mut pos = code_start;
+    while pos < body.len() {
+        let mut r = Reader::new(&body[pos..], offset + pos);
+
+        let op = match r.u8()? {
+            BULK_PREFIX => match r.var_u32()? {
+                8 => {
+                    let segment = r.var_u32()?;
+                    r.u8()?;
+                    Some(BulkOp::MemoryInit { segment })
+                },
+                9 => Some(BulkOp::DataDrop { segment: r.var_u32()? }),
+                10 => {
+                    r.u8()?;
+                    r.u8()?;
+                    Some(BulkOp::MemoryCopy)
+                },
+                11 => {
+                    r.u8()?;
+                    Some(BulkOp::MemoryFill)
+                },
+                12 => {
+                    let segment = r.var_u32()?;
+                    let table = r.var_u32()?;
+                    Some(BulkOp::TableInit { segment, table })
+                },
+                13 => Some(BulkOp::ElemDrop { segment: r.var_u32()? }),
+                14 => {
+                    let dst_table = r.var_u32()?;
+                    let src_table = r.var_u32()?;
+                    Some(BulkOp::TableCopy { dst_table, src_table })
+                },
+                16 => Some(BulkOp::TableSize { table: r.var_u32()? }),
+                15 | 17 => return Err(WasmError::Unsupported("reference types")),
+                // The saturating conversions, which are left for the parser.
+                _ => None,
+            },
+            CALL_INDIRECT => {
+                // With reference types, the table is an index that
+                // may be padded, instead of a single zero byte.
+                let type_index = r.var_u32()?;
+                if r.var_u32()? != 0 {
+                    return Err(WasmError::Unsupported("call_indirect through a table other than the first"));
+                }
+                out.push(CALL_INDIRECT);
+                write_var_u32(&mut out, type_index);
+                out.push(0);
+                pos += r.pos;
+                changed = true;
+                continue;
+            },
+            TABLE_GET | TABLE_SET | REF_NULL | REF_IS_NULL | REF_FUNC => {
+                return Err(WasmError::Unsupported("reference types"));
+            },
+            _ => None,
+        };
+
+        match op {
+            Some(op) => {
+                let in_bounds = match op {
+                    BulkOp::MemoryInit { segment } => counts.memories > 0 && segment < counts.data,
+                    BulkOp::DataDrop { segment } => segment < counts.data,
+                    BulkOp::MemoryCopy | BulkOp::MemoryFill => counts.memories > 0,
+                    BulkOp::TableInit { segment, table } => segment < counts.elements && table < counts.tables,
+                    BulkOp::ElemDrop { segment } => segment < counts.elements,
+                    BulkOp::TableCopy { dst_table, src_table } => dst_table < counts.tables && src_table < counts.tables,
+                    BulkOp::TableSize { table } => table < counts.tables,
+                };
+                if !in_bounds {
+                    return Err(error("unknown segment, memory or table", offset + pos));
+                }
+
+                let index = match ops.iter().position(|other| *other == op) {
+                    Some(index) => index,
+                    None => {
+                        ops.push(op);
+                        ops.len() - 1
+                    },
+                };
+
+                out.push(CALL);
+                write_var_u32(&mut out, first_helper + index as u32);
+                pos += r.pos;
+                changed = true;
+            },
+            None => {
+                let mut reader = BinaryReader::new(&body[pos..]);
+                reader.read_operator()?;
+                let end = pos + reader.current_position();
+                out.extend_from_slice(&body[pos..end]);
+                pos = end;
+            },
+        }
+    }
+
+    Ok(if changed { Some(out) } else { None })
+}
+
+/// The new code section, and the new bodies that were appended.
+fn lower_code(section: &Section, counts: &Counts, ops: &mut Vec<BulkOp>) -> WasmResult<Option<Vec<u8>>> {
+    let mut r = Reader::new(section.payload, section.offset);
+    let count = r.var_u32()?;
+    let mut payload = Vec::with_capacity(section.payload.len());
+    let mut changed = false;
+
+    write_var_u32(&mut payload, count);
+    for _ in 0..count {
+        let size = r.var_u32()? as usize;
+        let offset = r.offset();
+        let body = r.bytes(size)?;
+
+        match lower_body(body, offset, counts, counts.functions, ops)? {
+            Some(new_body) => {
+                write_var_u32(&mut payload, new_body.len() as u32);
+                payload.extend_from_slice(&new_body);
+                changed = true;
+            },
+            None => {
+                write_var_u32(&mut payload, size as u32);
+                payload.extend_from_slice(body);
+            },
+        }
+    }
+
+    Ok(if changed { Some(payload) } else { None })
+}
+
+/// Append `count` entries to a section that's a vector.
+fn append_entries(payload: &[u8], offset: usize, count: usize, entries: &[u8]) -> WasmResult<Vec<u8>> {
+    let mut r = Reader::new(payload, offset);
+    let old_count = r.var_u32()?;
+
+    let mut out = Vec::with_capacity(payload.len() + entries.len() + 5);
+    write_var_u32(&mut out, old_count + count as u32);
+    out.extend_from_slice(&payload[r.pos..]);
+    out.extend_from_slice(entries);
+    Ok(out)
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+    out.push(id);
+    write_var_u32(out, payload.len() as u32);
+    out.extend_from_slice(payload);
+}
+
+/// Lower `wasm` to the MVP, recording the passive segments and the
+/// operators that the appended functions stand in for in `module`.
+/// Returns the new module, or `None` if it doesn't need lowering.
+/// Anything malformed that doesn't get in the way is left for the
+/// parser to report.
+pub fn lower(wasm: &[u8], module: &mut Module) -> WasmResult<Option<Vec<u8>>> {
+    const HEADER_SIZE: usize = 8;
+    if wasm.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+
+    let mut sections = Vec::new();
+    let mut r = Reader::new(&wasm[HEADER_SIZE..], HEADER_SIZE);
+    while !r.eof() {
+        let id = r.u8()?;
+        let size = r.var_u32()? as usize;
+        let offset = r.offset();
+        let payload = r.bytes(size)?;
+        sections.push(Section { id, payload, offset });
+    }
+
+    let counts = count_entities(&sections)?;
+
+    let mut changed = false;
+    let mut new_payloads: Vec<Option<Vec<u8>>> = Vec::with_capacity(sections.len());
+    let mut ops = Vec::new();
+
+    for section in &sections {
+        let new_payload = match section.id {
+            SECTION_ELEMENT => {
+                let (payload, segments) = lower_elements(section, &counts)?;
+                module.passive_elements = segments;
+                Some(payload)
+            },
+            SECTION_DATA => {
+                let (payload, segments) = lower_data(section)?;
+                module.passive_data = segments;
+                Some(payload)
+            },
+            SECTION_DATA_COUNT => {
+                let mut r = Reader::new(section.payload, section.offset);
+                if r.var_u32()? != counts.data {
+                    return Err(error("data count doesn't match the data segments", section.offset));
+                }
+                changed = true;
+                Some(Vec::new())
+            },
+            SECTION_CODE => lower_code(section, &counts, &mut ops)?,
+            _ => None,
+        };
+
+        if let Some(ref payload) = new_payload {
+            changed |= &payload[..] != section.payload;
+        }
+        new_payloads.push(new_payload);
+    }
+
+    if !changed && ops.is_empty() {
+        module.passive_elements.clear();
+        module.passive_data.clear();
+        return Ok(None);
+    }
+
+    // The appended functions need their types, which go after the
+    // module's own, and their bodies, after the module's own.
+    let mut types = Vec::new();
+    let mut functions = Vec::new();
+    let mut bodies = Vec::new();
+    if !ops.is_empty() {
+        for helper_type in &HELPER_TYPES {
+            types.push(FUNC_TYPE);
+            let (params, results) = match *helper_type {
+                HelperType::ThreeOperands => (3, 0),
+                HelperType::NoOperands => (0, 0),
+                HelperType::Size => (0, 1),
+            };
+            write_var_u32(&mut types, params);
+            types.extend((0..params).map(|_| I32_TYPE));
+            write_var_u32(&mut types, results);
+            types.extend((0..results).map(|_| I32_TYPE));
+        }
+
+        for op in &ops {
+            let type_offset = HELPER_TYPES.iter().position(|ty| *ty == op.helper_type()).unwrap();
+            write_var_u32(&mut functions, counts.types + type_offset as u32);
+            write_var_u32(&mut bodies, UNREACHABLE_BODY.len() as u32);
+            bodies.extend_from_slice(&UNREACHABLE_BODY);
+        }
+
+        if !sections.iter().any(|section| section.id == SECTION_TYPE) {
+            return Err(error("functions without a type section", HEADER_SIZE));
+        }
+    }
+
+    let mut out = Vec::with_capacity(wasm.len() + types.len() + functions.len() + bodies.len());
+    out.extend_from_slice(&wasm[..HEADER_SIZE]);
+
+    for (section, new_payload) in sections.iter().zip(new_payloads) {
+        let payload = new_payload.unwrap_or_else(|| section.payload.to_vec());
+        let payload = match section.id {
+            SECTION_DATA_COUNT => continue,
+            SECTION_TYPE if !ops.is_empty() => append_entries(&payload, section.offset, HELPER_TYPES.len(), &types)?,
+            SECTION_FUNCTION if !ops.is_empty() => append_entries(&payload, section.offset, ops.len(), &functions)?,
+            SECTION_CODE if !ops.is_empty() => append_entries(&payload, section.offset, ops.len(), &bodies)?,
+            _ => payload,
+        };
+        write_section(&mut out, section.id, &payload);
+    }
+
+    module.bulk_ops = ops;
+
+    Ok(Some(out))
+}
@@ -0,0 +1,199 @@
+//! A cache of compiled modules.
+//!
+//! Compiling the same wasm with the same settings always produces
+//! the same code, and `Wasm` objects are shared between processes
+//! anyways, so a module that was already compiled is handed out
+//! again. Entries are keyed by a hash of the wasm and the settings.
+//!
+//! The cache holds at most `MAX_CACHE_SIZE` bytes of compiled code,
+//! and drops the least recently used entries to stay under that.
+//! Lazily compiled modules grow as their functions are compiled,
+//! so the size of each entry is read again whenever it's needed.
+//! When the kernel runs low on memory for code, entries that no
+//! process is using are dropped too.
+
+use object::{Dispatch, Wasm, CompileFlags};
+use common::sha256::{Sha256, Digest};
+use arch::lock::Spinlock;
+use cranelift_codegen::settings;
+use alloc::vec::Vec;
+
+/// The most compiled code that's kept around, in bytes.
+const MAX_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+pub type CacheKey = Digest;
+
+struct Entry {
+    key: CacheKey,
+    code: Dispatch<Wasm>,
+    /// The `clock` when the entry was last used.
+    last_used: u64,
+}
+
+/// Statistics about the cache.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Bytes of compiled code in the cache, including the
+    /// functions of lazy modules compiled so far.
+    pub size: u64,
+    pub entries: u32,
+    pub max_size: u32,
+}
+
+struct CodeCache {
+    entries: Vec<Entry>,
+    /// Counts up on every lookup and insert.
+    clock: u64,
+    stats: CacheStats,
+}
+
+lazy_static! {
+    static ref CODE_CACHE: Spinlock<CodeCache> = Spinlock::new(CodeCache {
+        entries: Vec::new(),
+        clock: 0,
+        stats: CacheStats {
+            max_size: MAX_CACHE_SIZE as u32,
+            ..CacheStats::default()
+        },
+    });
+}
+
+impl CodeCache {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, index: usize) -> Entry {
+        let entry = self.entries.swap_remove(index);
+        self.stats.evictions += 1;
+        entry
+    }
+
+    /// The bytes of compiled code in the cache right now.
+    fn size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| entry.code.stats().code_size)
+            .sum()
+    }
+
+    /// Drop the least recently used entries that `filter` picks,
+    /// until at most `size` bytes are left. Returns how many
+    /// entries were dropped.
+    fn shrink_to<F>(&mut self, size: usize, filter: F) -> usize
+        where F: Fn(&Entry) -> bool
+    {
+        let mut count = 0;
+        let mut total = self.size();
+
+        while total > size as u64 {
+            let lru = self.entries
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| filter(entry))
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(index, _)| index);
+
+            match lru {
+                Some(index) => {
+                    // The entry may have grown since `total` was read.
+                    let entry = self.remove(index);
+                    total = total.saturating_sub(entry.code.stats().code_size);
+                    count += 1;
+                },
+                None => break,
+            }
+        }
+
+        count
+    }
+}
+
+/// The key of `wasm` compiled with `flags` and `compile_flags`.
+/// The host cpu's features don't change while the kernel runs,
+/// so the isa flags don't have to be part of it.
+pub fn key(wasm: &[u8], flags: &settings::Flags, compile_flags: CompileFlags) -> CacheKey {
+    let bits = compile_flags.bits();
+
+    let mut hasher = Sha256::new();
+    hasher.update(wasm);
+    hasher.update(format!("{}", flags).as_bytes());
+    hasher.update(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+    hasher.finish()
+}
+
+/// The compiled module for `key`, if there is one.
+pub fn lookup(key: &CacheKey) -> Option<Dispatch<Wasm>> {
+    let mut cache = CODE_CACHE.lock();
+    let now = cache.tick();
+
+    let code = cache.entries
+        .iter_mut()
+        .find(|entry| entry.key == *key)
+        .map(|entry| {
+            entry.last_used = now;
+            entry.code.copy_ref()
+        });
+
+    if code.is_some() {
+        cache.stats.hits += 1;
+    } else {
+        cache.stats.misses += 1;
+    }
+
+    code
+}
+
+/// Add a module that was just compiled. If the same module was
+/// compiled at the same time, that one is returned instead.
+pub fn insert(key: CacheKey, code: Dispatch<Wasm>) -> Dispatch<Wasm> {
+    let mut cache = CODE_CACHE.lock();
+    let now = cache.tick();
+
+    if let Some(entry) = cache.entries.iter_mut().find(|entry| entry.key == key) {
+        entry.last_used = now;
+        return entry.code.copy_ref();
+    }
+
+    let code_size = code.stats().code_size as usize;
+    if code_size > MAX_CACHE_SIZE {
+        return code;
+    }
+
+    cache.shrink_to(MAX_CACHE_SIZE - code_size, |_| true);
+
+    cache.entries.push(Entry {
+        key,
+        code: code.copy_ref(),
+        last_used: now,
+    });
+
+    code
+}
+
+/// Drop every entry that no process is using, so that their code
+/// can be freed. This is called when there's no memory left for
+/// new code. Returns how many entries were dropped.
+pub fn reclaim() -> usize {
+    CODE_CACHE.lock().shrink_to(0, |entry| entry.code.ref_count() == 1)
+}
+
+/// Drop every entry. Returns how many there were.
+pub fn flush() -> usize {
+    CODE_CACHE.lock().shrink_to(0, |_| true)
+}
+
+pub fn stats() -> CacheStats {
+    let cache = CODE_CACHE.lock();
+
+    CacheStats {
+        size: cache.size(),
+        entries: cache.entries.len() as u32,
+        ..cache.stats
+    }
+}
@@ -3,18 +3,28 @@
 
 use super::module::{Module, Export};
 use super::{Relocation, Relocations, RelocationType, DataInitializer};
-use cranelift_codegen::{self, isa::TargetIsa, binemit::{self, Reloc}, ir::{Signature, TrapCode, SourceLoc}};
+use super::error::{CompileError, CompileErrorKind, CompileResult};
+use cranelift_codegen::{self, isa::TargetIsa, binemit::Reloc, ir::Signature};
 use cranelift_wasm::FunctionIndex;
-use super::RelocSink;
+use super::translate::{RelocSink, TrapSink, TrapData, PROBESTACK};
+use super::lazy::LazyCode;
+use super::cache;
 use super::abi::{ABI_MAP, INTRINSIC_MAP};
+use cranelift_codegen::settings;
+use cranelift_native;
 
 use memory::Region;
-use object::Wasm;
+use object::{Wasm, CompileFlags};
 use object::Dispatch;
 
 use nabi::{Result, Error};
 use alloc::vec::Vec;
 use alloc::string::String;
+use alloc::boxed::Box;
+
+extern {
+    fn wasm_probestack();
+}
 
 pub fn get_abi_func(name: &str, sig: &Signature) -> Result<*const ()> {
     let abi_func = ABI_MAP.get(name).ok_or_else(|| internal_error!())?;
@@ -26,12 +36,64 @@ pub fn get_abi_func(name: &str, sig: &Signature) -> Result<*const ()> {
     }
 }
 
-fn get_abi_intrinsic(name: &str) -> Result<*const()> {
+/// Check that the kernel provides every function
+/// that the module imports, with the same signature.
+pub fn check_imports(module: &Module) -> CompileResult<()> {
+    for (func_index, (import_module, field)) in module.imported_funcs.iter().enumerate() {
+        let sig = &module.signatures[module.functions[func_index]];
+
+        let err = if import_module != "abi" {
+            CompileError::new(CompileErrorKind::UnknownImport, "functions can only be imported from \"abi\"")
+        } else {
+            match ABI_MAP.get(field.as_str()) {
+                Some(abi_func) if abi_func.same_sig(sig) => continue,
+                Some(abi_func) => {
+                    let message = format!("expected params {:?} and return {}", abi_func.params, abi_func.returns);
+                    CompileError::new(CompileErrorKind::SignatureMismatch, message)
+                },
+                None => CompileError::new(CompileErrorKind::UnknownImport, "no such abi function"),
+            }
+        };
+
+        return Err(err.for_import(import_module, field).in_function(func_index));
+    }
+
+    Ok(())
+}
+
+pub fn get_abi_intrinsic(name: &str) -> Result<*const()> {
+    if name == PROBESTACK {
+        return Ok(wasm_probestack as *const ());
+    }
+
     let func = INTRINSIC_MAP.get(name)?;
 
     Ok(func.ptr)
 }
 
+/// The isa of the cpu that the kernel is running on.
+pub fn native_isa(flags: settings::Flags) -> Result<Box<TargetIsa>> {
+    let (_, isa_builder) = cranelift_native::builders()
+        .map_err(|_| internal_error!())?;
+
+    Ok(isa_builder.finish(flags))
+}
+
+/// Apply the relocation `r` of the function at `body_addr`,
+/// so that it refers to `target`.
+pub fn apply_relocation(body_addr: *const (), r: &Relocation, target: *const ()) {
+    let reloc_addr = unsafe { (body_addr as *const u8).offset(r.offset as isize) };
+
+    match r.reloc {
+        Reloc::Abs8 => {
+            unsafe {
+                (reloc_addr as *mut usize).write(target as usize);
+            }
+        }
+        _ => unimplemented!()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum FunctionType {
     Local {
@@ -58,6 +120,10 @@ pub struct Compilation {
 
     /// List of traps and their offsets in the generated code
     traps: Vec<TrapData>,
+
+    /// Set if the functions are stubs, which compile
+    /// the real ones when they're first called.
+    lazy: Option<LazyCode>,
 }
 
 impl Compilation {
@@ -76,21 +142,34 @@ impl Compilation {
             first_local_function,
             relocations,
             traps,
+            lazy: None,
         }
     }
 
-    fn relocate_function(&self, module: &Module, reloc_num: usize, r: &Relocation, target_func_addr: *const ()) -> Result<()> {
-        let body_addr = self.get_function_addr(module, reloc_num + self.first_local_function)?;
-        let reloc_addr = unsafe { (body_addr as *const u8).offset(r.offset as isize) };
+    /// The stubs of functions that are compiled lazily,
+    /// which `lazy` emitted into `region`.
+    pub fn lazy(region: Region, module: &Module, lazy: LazyCode) -> Self {
+        let mut functions = external_functions(module);
+        let num_local = module.functions.len() - functions.len();
 
-        match r.reloc {
-            Reloc::Abs8 => {
-                unsafe {
-                    (reloc_addr as *mut usize).write(target_func_addr as usize);
-                }
+        functions.extend((0..num_local).map(|index| {
+            FunctionType::Local {
+                offset: index * LazyCode::STUB_SIZE,
+                size: LazyCode::STUB_SIZE,
             }
-            _ => unimplemented!()
+        }));
+
+        let relocations = (0..num_local).map(|_| Vec::new()).collect();
+
+        Compilation {
+            lazy: Some(lazy),
+            ..Compilation::new(region, functions, relocations, Vec::new())
         }
+    }
+
+    fn relocate_function(&self, module: &Module, reloc_num: usize, r: &Relocation, target_func_addr: *const ()) -> Result<()> {
+        let body_addr = self.get_function_addr(module, reloc_num + self.first_local_function)?;
+        apply_relocation(body_addr, r, target_func_addr);
 
         Ok(())
     }
@@ -117,6 +196,33 @@ impl Compilation {
         Ok(())
     }
 
+    /// Copy code that was compiled ahead of time into a new region.
+    /// `local_functions` are the offset and size of each local
+    /// function in `code`, which the caller has checked.
+    pub fn load(
+        code: &[u8],
+        module: &Module,
+        local_functions: &[(usize, usize)],
+        relocations: Relocations,
+        traps: Vec<TrapData>,
+    ) -> Result<Compilation> {
+        let region = allocate_code(code.len())?;
+
+        unsafe {
+            (region.start().as_u64() as *mut u8).copy_from_nonoverlapping(code.as_ptr(), code.len());
+        }
+
+        let mut functions = external_functions(module);
+        functions.extend(local_functions.iter().map(|&(offset, size)| {
+            FunctionType::Local {
+                offset,
+                size,
+            }
+        }));
+
+        Ok(Compilation::new(region, functions, relocations, traps))
+    }
+
     pub fn get_function_addr(&self, module_ref: &Module, func_index: FunctionIndex) -> Result<*const ()> {
         match self.functions[func_index] {
             FunctionType::Local {
@@ -145,7 +251,7 @@ impl Compilation {
     }
 
     /// Emit a `Code` instance
-    pub fn emit(mut self, module: Module, data_initializers: Vec<DataInitializer>) -> Result<Dispatch<Wasm>> {
+    pub fn emit(mut self, module: Module, data_initializers: Vec<DataInitializer>, flags: CompileFlags) -> CompileResult<Dispatch<Wasm>> {
         self.relocate(&module)?;
 
         let start_index;
@@ -156,8 +262,7 @@ impl Compilation {
             start_index = index;
         }
         else {
-            // TODO: We really need to handle this error nicely
-            return Err(internal_error!());
+            return Err(CompileError::new(CompileErrorKind::MissingEntry, "no start function or \"main\" export"));
         }
 
         // TODO: Check start func abi
@@ -176,7 +281,7 @@ impl Compilation {
             })
             .collect();
 
-        Wasm::new(module, data_initializers, self.region, start_ptr, local_func_list, self.traps)
+        Ok(Wasm::new(module, data_initializers, self.region, start_ptr, local_func_list, self.traps, self.lazy, flags)?)
     }
 }
 
@@ -204,12 +309,9 @@ impl<'isa> Compiler<'isa> {
     }
 
     /// Define a function. This also compiles the function.
-    pub fn define_function(&mut self, mut ctx: cranelift_codegen::Context) -> Result<()> {
+    pub fn define_function(&mut self, mut ctx: cranelift_codegen::Context) -> CompileResult<()> {
         let code_size = ctx.compile(self.isa)
-            .map_err(|e| {
-                println!("Compile error: {:?}", e);
-                internal_error!()
-            })? as usize;
+            .map_err(|e| CompileError::new(CompileErrorKind::Codegen, e))? as usize;
 
         self.contexts.push((ctx, code_size));
 
@@ -225,23 +327,15 @@ impl<'isa> Compiler<'isa> {
     /// alignment, which is true on x86_64, but may not
     /// be true on other architectures.
     pub fn compile(self, module: &Module) -> Result<Compilation> {
-        let region = Region::allocate(self.total_size)
-            .ok_or(Error::NO_MEMORY)?;
+        let region = allocate_code(self.total_size)?;
 
-        let mut functions = Vec::with_capacity(module.functions.len());
+        let mut functions = external_functions(module);
         let mut relocs = Vec::with_capacity(self.contexts.len());
         let mut traps = Vec::new();
 
         let mut offset = 0;
         let region_start = region.start().as_u64() as usize;
 
-        for (module, name) in module.imported_funcs.iter().cloned() {
-            functions.push(FunctionType::External {
-                module,
-                name,
-            });
-        }
-
         // emit functions to memory
         for (ref ctx, size) in self.contexts.iter() {
             let mut trap_sink = TrapSink::new(offset);
@@ -267,32 +361,31 @@ impl<'isa> Compiler<'isa> {
     }
 }
 
-pub struct TrapData {
-    pub offset: usize,
-    pub code: TrapCode,
-}
-
-/// Simple implementation of a TrapSink
-/// that saves the info for later.
-pub struct TrapSink {
-    current_func_offset: usize,
-    trap_datas: Vec<TrapData>,
+/// Allocate a region for `size` bytes of code.
+pub fn allocate_code(size: usize) -> Result<Region> {
+    // Cached code that isn't used can make room.
+    Region::allocate(size)
+        .or_else(|| {
+            if cache::reclaim() > 0 {
+                Region::allocate(size)
+            } else {
+                None
+            }
+        })
+        .ok_or(Error::NO_MEMORY)
 }
 
-impl TrapSink {
-    pub fn new(current_func_offset: usize) -> TrapSink {
-        TrapSink {
-            current_func_offset,
-            trap_datas: Vec::new(),
-        }
-    }
-}
+/// The `FunctionType`s of the functions that `module` imports,
+/// which come before its local functions.
+fn external_functions(module: &Module) -> Vec<FunctionType> {
+    let mut functions = Vec::with_capacity(module.functions.len());
 
-impl binemit::TrapSink for TrapSink {
-    fn trap(&mut self, offset: u32, _: SourceLoc, code: TrapCode) {
-        self.trap_datas.push(TrapData {
-            offset: self.current_func_offset + offset as usize,
-            code,
+    for (module, name) in module.imported_funcs.iter().cloned() {
+        functions.push(FunctionType::External {
+            module,
+            name,
         });
     }
+
+    functions
 }
@@ -0,0 +1,148 @@
+//! Errors from compiling a wasm module, with enough
+//! detail to tell what was wrong with it.
+
+use cranelift_wasm::{FunctionIndex, WasmError};
+use nabi;
+use core::fmt;
+use alloc::string::{String, ToString};
+
+/// What kind of problem stopped a module from compiling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CompileErrorKind {
+    /// The module isn't well-formed wasm.
+    Parse = 1,
+    /// The module is well-formed, but isn't valid.
+    Validation = 2,
+    /// The module imports something that the kernel doesn't provide.
+    UnknownImport = 3,
+    /// The module imports an abi function with the wrong signature.
+    SignatureMismatch = 4,
+    /// The module uses a feature that isn't supported.
+    Unsupported = 5,
+    /// Cranelift couldn't compile a function.
+    Codegen = 6,
+    /// The module has neither a start function nor a `main` export.
+    MissingEntry = 7,
+    /// Anything else, like running out of memory.
+    Other = 8,
+    /// An ahead of time compiled artifact is corrupt, or
+    /// wasn't compiled from the wasm that it's loaded with.
+    Artifact = 9,
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+#[derive(Debug)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    /// The error that the compile is reported as.
+    pub error: nabi::Error,
+    pub message: String,
+    /// The module and field of the import, if it's about one.
+    pub import: Option<(String, String)>,
+    /// The function, in the module's function index space.
+    pub func_index: Option<FunctionIndex>,
+    /// The byte offset in the module.
+    pub offset: Option<usize>,
+}
+
+impl CompileError {
+    pub fn new<M: ToString>(kind: CompileErrorKind, message: M) -> CompileError {
+        let error = match kind {
+            CompileErrorKind::Parse
+            | CompileErrorKind::Validation
+            | CompileErrorKind::MissingEntry
+            | CompileErrorKind::Artifact => nabi::Error::INVALID_ARG,
+            CompileErrorKind::UnknownImport => nabi::Error::NOT_FOUND,
+            CompileErrorKind::SignatureMismatch => nabi::Error::WRONG_TYPE,
+            CompileErrorKind::Unsupported => nabi::Error::NOT_SUPPORTED,
+            CompileErrorKind::Codegen
+            | CompileErrorKind::Other => nabi::Error::INTERNAL,
+        };
+
+        CompileError {
+            kind,
+            error,
+            message: message.to_string(),
+            import: None,
+            func_index: None,
+            offset: None,
+        }
+    }
+
+    /// An error from translating the module, or the function
+    /// body at `base` bytes into it.
+    pub fn from_wasm(err: WasmError, base: usize) -> CompileError {
+        match err {
+            WasmError::InvalidWebAssembly { message, offset } => {
+                CompileError::new(CompileErrorKind::Parse, message)
+                    .at_offset(base + offset)
+            },
+            WasmError::Unsupported(feature) => {
+                CompileError::new(CompileErrorKind::Unsupported, feature)
+                    .at_offset(base)
+            },
+            WasmError::ImplLimitExceeded => {
+                CompileError::new(CompileErrorKind::Unsupported, "implementation limit exceeded")
+                    .at_offset(base)
+            },
+        }
+    }
+
+    pub fn for_import(mut self, module: &str, field: &str) -> CompileError {
+        self.import = Some((String::from(module), String::from(field)));
+        self
+    }
+
+    pub fn in_function(mut self, func_index: FunctionIndex) -> CompileError {
+        self.func_index = Some(func_index);
+        self
+    }
+
+    pub fn at_offset(mut self, offset: usize) -> CompileError {
+        self.offset = Some(offset);
+        self
+    }
+}
+
+impl From<nabi::Error> for CompileError {
+    fn from(error: nabi::Error) -> CompileError {
+        let message = format!("{:?}", error);
+
+        CompileError {
+            error,
+            ..CompileError::new(CompileErrorKind::Other, message)
+        }
+    }
+}
+
+impl From<CompileError> for nabi::Error {
+    fn from(err: CompileError) -> nabi::Error {
+        err.error
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.kind)?;
+
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+
+        if let Some((ref module, ref field)) = self.import {
+            write!(f, ", import \"{}\" \"{}\"", module, field)?;
+        }
+
+        if let Some(func_index) = self.func_index {
+            write!(f, ", function {}", func_index)?;
+        }
+
+        if let Some(offset) = self.offset {
+            write!(f, ", at byte {:#x}", offset)?;
+        }
+
+        Ok(())
+    }
+}
@@ -5,10 +5,12 @@
 use cranelift_wasm::{GlobalInit};
 use super::module::Module;
 use super::{DataInitializer, FunctionIndex};
+use super::sig_registry::{SigId, NULL_SIG_ID};
+use super::layout::MemoryPlan;
 
-use memory::WasmMemory;
+use memory::{Region, WasmMemory};
 use object::{Dispatch, Process};
-use nabi::Result;
+use nabi::{Result, Error};
 use core::marker::PhantomData;
 use core::{slice, mem};
 use alloc::vec::Vec;
@@ -21,20 +23,47 @@ pub fn get_function_addr(base: *const (), functions: &[usize], func_index: Funct
     (base as usize + offset) as _
 }
 
+/// An element of a table, as `call_indirect` reads it.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct TableEntry {
+    /// The address of the function, or 0.
+    pub func: usize,
+    /// The registry id of the function's signature.
+    pub sig_id: SigId,
+}
+
+impl TableEntry {
+    pub fn null() -> TableEntry {
+        TableEntry {
+            func: 0,
+            sig_id: NULL_SIG_ID,
+        }
+    }
+}
+
 pub struct VmCtxGenerator {
+    /// Where the `VmCtx` points, which is the start of
+    /// the first memory if the module has one.
+    vmctx: *mut u8,
+    sig_ids: UncheckedSlice<SigId>,
     globals: UncheckedSlice<u8>,
     memories: Vec<UncheckedSlice<u8>>,
-    tables: Vec<BoundedSlice<usize>>,
+    tables: Vec<BoundedSlice<TableEntry>>,
 }
 
 impl VmCtxGenerator {
     pub fn vmctx(&mut self, process: Dispatch<Process>, instance: Instance) -> &VmCtx {
-        assert!(self.memories.len() >= 1, "modules must have at least one memory");
-        // the first memory has a space of `mem::size_of::<VmCtxData>()` rounded
-        // up to the 4KiB before it. We write the VmCtxData into that.
+        // There's a space of `mem::size_of::<VmCtxData>()` rounded up
+        // to 4KiB before the first memory, or the vmctx backing if
+        // there isn't one. We write the VmCtxData into that. The other
+        // memories are found through the `memories` field.
+        let other_memories = self.memories.get(1..).unwrap_or(&[]);
+
         let data = VmCtxData {
+            sig_ids: self.sig_ids,
             globals: self.globals,
-            memories: self.memories[1..].into(),
+            memories: other_memories.into(),
             tables: self.tables[..].into(),
             user_data: UserData {
                 process,
@@ -43,12 +72,12 @@ impl VmCtxGenerator {
             phantom: PhantomData,
         };
 
-        let main_heap_ptr = self.memories[0].as_mut_ptr() as *mut VmCtxData;
+        let vmctx_ptr = self.vmctx as *mut VmCtxData;
         unsafe {
-            main_heap_ptr
+            vmctx_ptr
                 .sub(1)
                 .write(data);
-            &*(main_heap_ptr as *const VmCtx)
+            &*(vmctx_ptr as *const VmCtx)
         }
     }
 }
@@ -64,12 +93,21 @@ impl VmCtx {
         }
     }
 
-    /// This is safe because the offset is 32 bits and thus
-    /// cannot extend out of the guarded wasm memory.
-    pub fn fastpath_offset_ptr<T>(&self, offset: u32) -> *const T {
-        let heap_ptr = self as *const _ as *const u8;
-        unsafe {
-            heap_ptr.add(offset as usize) as *const T
+    /// A pointer `offset` bytes into the first memory. That's in
+    /// its reservation, so touching it either maps in the page or
+    /// traps. Returns `None` if the module has no memory, or if
+    /// the reservation of a small memory doesn't reach that far.
+    pub fn fastpath_offset_ptr<T>(&self, offset: u32) -> Option<*const T> {
+        let memory = self.data().user_data.instance.memories.first()?;
+        let end = offset as usize + mem::size_of::<T>();
+
+        if end <= memory.reserved_size() {
+            let heap_ptr = self as *const _ as *const u8;
+            unsafe {
+                Some(heap_ptr.add(offset as usize) as *const T)
+            }
+        } else {
+            None
         }
     }
 }
@@ -77,12 +115,22 @@ impl VmCtx {
 #[repr(C)]
 pub struct VmCtxData<'a> {
     pub user_data: UserData,
+    sig_ids: UncheckedSlice<SigId>,
     globals: UncheckedSlice<u8>,
     memories: UncheckedSlice<UncheckedSlice<u8>>,
-    tables: UncheckedSlice<BoundedSlice<usize>>,
+    tables: UncheckedSlice<BoundedSlice<TableEntry>>,
     phantom: PhantomData<&'a ()>,
 }
 
+/// The passive segments that an instance dropped with `data.drop`
+/// and `elem.drop`. The segments themselves stay in the `Module`,
+/// for the other instances of it.
+#[derive(Debug)]
+pub struct DroppedSegments {
+    pub data: Vec<bool>,
+    pub elements: Vec<bool>,
+}
+
 #[repr(C)]
 pub struct UserData {
     pub process: Dispatch<Process>,
@@ -90,35 +138,37 @@ pub struct UserData {
 }
 
 struct InstanceBuilder {
-    tables: Vec<Vec<usize>>,
+    tables: Vec<Vec<TableEntry>>,
     memories: Vec<WasmMemory>,
+    vmctx_backing: Option<Region>,
     globals: Vec<u8>,
 }
 
 impl InstanceBuilder {
-    pub fn new(module: &Module, data_initializers: &[DataInitializer], code_base: *const (), functions: &[usize]) -> InstanceBuilder {
+    pub fn new(module: &Module, data_initializers: &[DataInitializer], code_base: *const (), functions: &[usize]) -> Result<InstanceBuilder> {
         let mut builder = InstanceBuilder {
             tables: Vec::new(),
             memories: Vec::new(),
+            vmctx_backing: None,
             globals: Vec::new(),
         };
 
-        builder.instantiate_tables(module, code_base, functions);
-        builder.instantiate_memories(module, data_initializers);
+        builder.instantiate_tables(module, code_base, functions)?;
+        builder.instantiate_memories(module, data_initializers)?;
         builder.instantiate_globals(module);
 
-        builder
+        Ok(builder)
     }
 
     /// Allocate memory in `self` for just the tables of the current module.
-    fn instantiate_tables(&mut self, module: &Module, code_base: *const (), functions: &[usize]) {
+    fn instantiate_tables(&mut self, module: &Module, code_base: *const (), functions: &[usize]) -> Result<()> {
         debug_assert!(self.tables.is_empty());
 
         self.tables.reserve_exact(module.tables.len());
         for table in &module.tables {
             let len = table.size;
             let mut v = Vec::with_capacity(len);
-            v.resize(len, 0);
+            v.resize(len, TableEntry::null());
             self.tables.push(v);
         }
         // instantiate tables
@@ -126,20 +176,34 @@ impl InstanceBuilder {
             assert!(table_element.base.is_none(), "globalvalue base not supported yet.");
             let base = 0;
 
-            let table = &mut self.tables[table_element.table_index];
-            for (i, elem) in table_element.elements.iter().enumerate() {
+            let table = self.tables.get_mut(table_element.table_index)
+                .ok_or(Error::OUT_OF_BOUNDS)?;
+
+            let start = base + table_element.offset;
+            let end = start.checked_add(table_element.elements.len())
+                .filter(|&end| end <= table.len())
+                .ok_or(Error::OUT_OF_BOUNDS)?;
+
+            for (entry, &elem) in table[start..end].iter_mut().zip(&table_element.elements) {
                 // since the table just contains functions in the MVP
                 // we get the address of the specified function indexes
                 // to populate the table.
-                let func_index = *elem - module.imported_funcs.len();
+                let func_index = elem.checked_sub(module.imported_funcs.len())
+                    .ok_or(Error::NOT_SUPPORTED)?;
                 let func_addr = get_function_addr(code_base, functions, func_index);
-                table[base + table_element.offset + i] = func_addr as _;
+
+                *entry = TableEntry {
+                    func: func_addr as _,
+                    sig_id: module.sig_ids[module.functions[elem]],
+                };
             }
         }
+
+        Ok(())
     }
 
     /// Allocate memory in `self` for just the memories of the current module.
-    fn instantiate_memories(&mut self, module: &Module, data_initializers: &[DataInitializer]) {
+    fn instantiate_memories(&mut self, module: &Module, data_initializers: &[DataInitializer]) -> Result<()> {
         debug_assert!(self.memories.is_empty());
         // Allocate the underlying memory and initialize it to all zeros
         self.memories.reserve_exact(module.memories.len());
@@ -151,27 +215,38 @@ impl InstanceBuilder {
                 0
             };
 
-            let heap = WasmMemory::allocate(pre_space)
-                .expect("Could not allocate wasm memory");
-            heap.grow(memory.pages_count)
-                .expect("Could not grow wasm heap to initial size");
+            let heap = WasmMemory::allocate(pre_space, MemoryPlan::new(memory.maximum))
+                .ok_or(Error::NO_MEMORY)?;
+            heap.grow(memory.pages_count)?;
             self.memories.push(heap);
         }
 
+        // Without a memory, the `VmCtxData` needs a place of its own.
+        if self.memories.is_empty() {
+            let backing = Region::allocate(mem::size_of::<VmCtxData>())
+                .ok_or(Error::NO_MEMORY)?;
+            self.vmctx_backing = Some(backing);
+        }
+
         // the wasm memories are lazily mapped,
         // so we need to be caArcul to map
         // in the pages that get initialized here.
         for init in data_initializers {
             assert!(init.base.is_none(), "globalvalue base not supported yet.");
-            let memory = &mut self.memories[init.memory_index];
+            let memory = self.memories.get_mut(init.memory_index)
+                .ok_or(Error::OUT_OF_BOUNDS)?;
 
             let start_offset = init.offset;
-            let end_offset = init.offset + init.data.len();
-            memory.map_range(start_offset, end_offset).unwrap();
+            let end_offset = start_offset.checked_add(init.data.len())
+                .filter(|&end| end <= memory.mapped_size())
+                .ok_or(Error::OUT_OF_BOUNDS)?;
+            memory.map_range(start_offset, end_offset)?;
 
             let to_init = &mut memory[start_offset..end_offset];
             to_init.copy_from_slice(&init.data);
         }
+
+        Ok(())
     }
 
     /// Allocate memory in `self` for just the globals of the current module.
@@ -202,28 +277,52 @@ impl InstanceBuilder {
 #[derive(Debug)]
 pub struct Instance {
     /// WebAssembly table data
-    pub tables: Arc<Vec<RwLock<Vec<usize>>>>,
+    pub tables: Arc<Vec<RwLock<Vec<TableEntry>>>>,
 
     /// WebAssembly linear memory data
     pub memories: Arc<Vec<WasmMemory>>,
 
+    /// Where the `VmCtxData` goes if there are no memories.
+    vmctx_backing: Option<Arc<Region>>,
+
     /// WebAssembly global variable data
     pub globals: Vec<u8>,
+
+    /// The passive segments that have been dropped.
+    pub dropped_segments: Arc<RwLock<DroppedSegments>>,
 }
 
 impl Instance {
     /// Create a new `Instance`.
     pub fn build(module: &Module, data_initializers: &[DataInitializer], code_base: *const (), functions: &[usize]) -> Result<Instance> {
-        let builder = InstanceBuilder::new(module, data_initializers, code_base, functions);
+        let builder = InstanceBuilder::new(module, data_initializers, code_base, functions)?;
+
+        let dropped_segments = DroppedSegments {
+            data: vec![false; module.passive_data.len()],
+            elements: vec![false; module.passive_elements.len()],
+        };
 
         Ok(Instance {
             tables: Arc::new(builder.tables.into_iter().map(|table| RwLock::new(table)).collect()),
             memories: Arc::new(builder.memories.into_iter().collect()),
+            vmctx_backing: builder.vmctx_backing.map(Arc::new),
             globals: builder.globals,
+            dropped_segments: Arc::new(RwLock::new(dropped_segments)),
         })
     }
 
-    pub fn generate_vmctx_backing(&mut self) -> VmCtxGenerator {
+    /// `sig_ids` are the registry ids of the module's signatures,
+    /// which `call_indirect` checks table entries against.
+    pub fn generate_vmctx_backing(&mut self, sig_ids: &[SigId]) -> VmCtxGenerator {
+        let vmctx = match self.memories.first() {
+            Some(memory) => memory.start().as_mut_ptr::<u8>(),
+            None => {
+                let backing = self.vmctx_backing.as_ref()
+                    .expect("instances without memory have a vmctx backing");
+                unsafe { backing.start().as_mut_ptr::<u8>().add(backing.size()) }
+            },
+        };
+
         let memories = self.memories.iter()
             .map(|mem| mem[..].into())
             .collect();
@@ -233,6 +332,8 @@ impl Instance {
             .collect();
         
         VmCtxGenerator {
+            vmctx,
+            sig_ids: sig_ids.into(),
             globals: self.globals[..].into(),
             memories,
             tables,
@@ -242,6 +343,20 @@ impl Instance {
     pub fn memories(&self) -> Arc<Vec<WasmMemory>> {
         self.memories.clone()
     }
+
+    /// The memory at `index`. Buffers that are passed
+    /// to ABIs are in the first one, memory 0.
+    pub fn memory(&self, index: usize) -> Result<&WasmMemory> {
+        self.memories.get(index)
+            .ok_or(Error::NOT_FOUND)
+    }
+
+    /// The table at `index`. Function pointers
+    /// that are passed to ABIs index table 0.
+    pub fn table(&self, index: usize) -> Result<&RwLock<Vec<TableEntry>>> {
+        self.tables.get(index)
+            .ok_or(Error::NOT_FOUND)
+    }
 }
 
 impl Clone for Instance {
@@ -249,7 +364,9 @@ impl Clone for Instance {
         Instance {
             tables: Arc::clone(&self.tables),
             memories: Arc::clone(&self.memories),
+            vmctx_backing: self.vmctx_backing.clone(),
             globals: self.globals.clone(),
+            dropped_segments: Arc::clone(&self.dropped_segments),
         }
     }
 }
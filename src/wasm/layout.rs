@@ -0,0 +1,69 @@
+//! The parts of the kernel's memory layout that compiled code
+//! depends on. Code that's compiled ahead of time has these baked
+//! into it, so the `aot` tool builds against this file too.
+
+/// Bump this whenever the layout that compiled code depends on
+/// changes, including the `VmCtxData` fields and `TableEntry`,
+/// so that code compiled against the old one isn't loaded.
+pub const LAYOUT_VERSION: u32 = 3;
+
+/// The size of the address space that a linear memory can use.
+pub const HEAP_SIZE: usize = 1 << 32; // 4 GiB
+
+/// The size of the unmapped space after a linear memory, so that
+/// any 32 bit offset from a 32 bit address hits it.
+pub const GUARD_SIZE: usize = 1 << 31; // 2 GiB
+
+pub const WASM_PAGE_SIZE: usize = 1 << 16; // 64 KiB
+
+/// Memories that declare a maximum of at most this many bytes
+/// only reserve that much address space, and have their accesses
+/// bounds checked instead of relying on a guard.
+pub const SMALL_HEAP_MAX: usize = 1 << 30; // 1 GiB
+
+/// The size of the unmapped space after a small memory. Accesses
+/// are checked against the bound, but not their offset, as long
+/// as it's smaller than this.
+pub const SMALL_GUARD_SIZE: usize = 1 << 16; // 64 KiB
+
+/// How much address space a linear memory reserves, and how the
+/// code that accesses it is compiled. This only depends on the
+/// limits that the module declares, so the compiled code and the
+/// memories that it runs against always agree.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryPlan {
+    /// The size of the space that accesses can reach without
+    /// a bounds check, not counting the guard.
+    pub bound: usize,
+    pub guard_size: usize,
+    /// The most bytes that the memory can grow to.
+    pub max_size: usize,
+}
+
+impl MemoryPlan {
+    /// The plan for a memory with a maximum of `maximum` pages.
+    pub fn new(maximum: Option<usize>) -> MemoryPlan {
+        let max_size = maximum
+            .and_then(|pages| pages.checked_mul(WASM_PAGE_SIZE))
+            .map_or(HEAP_SIZE, |size| size.min(HEAP_SIZE));
+
+        if max_size <= SMALL_HEAP_MAX {
+            MemoryPlan {
+                bound: max_size,
+                guard_size: SMALL_GUARD_SIZE,
+                max_size,
+            }
+        } else {
+            MemoryPlan {
+                bound: HEAP_SIZE,
+                guard_size: GUARD_SIZE,
+                max_size,
+            }
+        }
+    }
+
+    /// The address space that the memory reserves.
+    pub fn reserved_size(&self) -> usize {
+        self.bound + self.guard_size
+    }
+}
@@ -0,0 +1,299 @@
+//! Compiling functions when they're first called.
+//!
+//! Big programs, like ones that link in Rust's std, have lots of
+//! functions that are never called. When a module is compiled
+//! lazily, each local function starts out as a stub:
+//!
+//! ```text
+//! movabs r11, <address of the function's slot>
+//! jmp qword ptr [r11]
+//! ```
+//!
+//! Every slot starts out holding `lazy_compile_trampoline`, which
+//! saves the arguments of the call, compiles the function, stores
+//! its address in the slot, and jumps to it. Tables and the start
+//! function point to the stubs, so they never change, but functions
+//! that are compiled later call the ones that already are directly.
+
+use super::translate::{self, RelocSink, TrapSink, TrapData, RelocationType, ModuleTranslation};
+use super::module::Module;
+use super::compilation;
+use super::error::{CompileError, CompileErrorKind, CompileResult};
+use object::Thread;
+use object::thread::State;
+use memory::{Region, MemFlags};
+use arch::lock::Spinlock;
+use sync::mpsc::IntrusiveMpsc;
+use sync::atomic::{Atomic, Ordering};
+use cranelift_codegen::settings;
+use cranelift_codegen::ir::TrapCode;
+use nabi::Result;
+use core::mem;
+use alloc::vec::Vec;
+use alloc::boxed::Box;
+
+extern {
+    fn lazy_compile_trampoline();
+}
+
+struct CompiledFunction {
+    region: Region,
+    traps: Vec<TrapData>,
+}
+
+/// A function that a thread is compiling.
+struct Compiling {
+    index: usize,
+    /// The other threads that called it in the meantime.
+    waiters: IntrusiveMpsc<Thread>,
+}
+
+pub struct LazyCode {
+    flags: settings::Flags,
+    /// A copy of the module, to translate the function bodies from.
+    wasm: Vec<u8>,
+    /// The offset and size of each local function body in `wasm`.
+    bodies: Vec<(usize, usize)>,
+    /// The address that the stub of each local function jumps to.
+    slots: Box<[Atomic<usize>]>,
+    /// The functions that have been compiled.
+    compiled: Spinlock<Vec<CompiledFunction>>,
+    /// The functions that are being compiled, so that each is only
+    /// compiled once. Compiling can take a while, so other threads
+    /// that call one of them block instead of spinning.
+    compiling: Spinlock<Vec<Compiling>>,
+}
+
+impl LazyCode {
+    pub const STUB_SIZE: usize = 16;
+
+    pub fn new(translation: &ModuleTranslation) -> LazyCode {
+        let lazy = &translation.lazy;
+
+        let bodies = lazy.function_body_inputs
+            .iter()
+            .map(|body| (lazy.body_offset(body), body.len()))
+            .collect();
+
+        let slots: Vec<_> = lazy.function_body_inputs
+            .iter()
+            .map(|_| Atomic::new(lazy_compile_trampoline as usize))
+            .collect();
+
+        LazyCode {
+            flags: translation.flags.clone(),
+            wasm: lazy.wasm.to_vec(),
+            bodies,
+            slots: slots.into_boxed_slice(),
+            compiled: Spinlock::new(Vec::new()),
+            compiling: Spinlock::new(Vec::new()),
+        }
+    }
+
+    /// Allocate a region and emit a stub for each local function into it.
+    pub fn emit_stubs(&self) -> Result<Region> {
+        let mut region = compilation::allocate_code(self.slots.len() * Self::STUB_SIZE)?;
+
+        for (index, slot) in self.slots.iter().enumerate() {
+            let slot_addr = slot as *const _ as u64;
+            let stub = &mut region[index * Self::STUB_SIZE..(index + 1) * Self::STUB_SIZE];
+
+            // movabs r11, slot_addr
+            stub[0] = 0x49;
+            stub[1] = 0xbb;
+            for (i, byte) in stub[2..10].iter_mut().enumerate() {
+                *byte = (slot_addr >> (i * 8)) as u8;
+            }
+
+            // jmp qword ptr [r11]
+            stub[10] = 0x41;
+            stub[11] = 0xff;
+            stub[12] = 0x23;
+
+            // int3
+            for byte in &mut stub[13..] {
+                *byte = 0xcc;
+            }
+        }
+
+        Ok(region)
+    }
+
+    /// The local function whose stub jumps through `slot`.
+    pub fn slot_index(&self, slot: *const Atomic<usize>) -> Option<usize> {
+        let offset = (slot as usize).checked_sub(self.slots.as_ptr() as usize)?;
+        let index = offset / mem::size_of::<Atomic<usize>>();
+
+        if offset % mem::size_of::<Atomic<usize>>() == 0 && index < self.slots.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn is_compiled(&self, index: usize) -> Option<usize> {
+        let addr = self.slots[index].load(Ordering::Acquire);
+
+        if addr != lazy_compile_trampoline as usize {
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    /// The address to call the local function `index` at. That's
+    /// the function itself if it's compiled, and its stub if not.
+    fn function_addr(&self, stubs: usize, index: usize) -> usize {
+        self.is_compiled(index)
+            .unwrap_or(stubs + index * Self::STUB_SIZE)
+    }
+
+    /// Compile the local function `index` of `module`, whose stubs
+    /// are at `stubs`, if it isn't yet. Returns its address.
+    pub fn compile_function(&self, module: &Module, stubs: usize, index: usize) -> CompileResult<usize> {
+        loop {
+            let mut compiling = self.compiling.lock();
+
+            // Another thread may have compiled it while this one waited.
+            if let Some(addr) = self.is_compiled(index) {
+                return Ok(addr);
+            }
+
+            match compiling.iter().find(|entry| entry.index == index) {
+                Some(entry) => {
+                    let current_thread = Thread::current();
+
+                    unsafe { entry.waiters.push(current_thread); }
+                    current_thread.set_state(State::Blocked);
+
+                    drop(compiling);
+
+                    Thread::yield_now();
+                },
+                None => {
+                    compiling.push(Compiling {
+                        index,
+                        waiters: IntrusiveMpsc::new(),
+                    });
+                    break;
+                },
+            }
+        }
+
+        let result = self.compile(module, stubs, index);
+
+        let mut compiling = self.compiling.lock();
+        if let Some(position) = compiling.iter().position(|entry| entry.index == index) {
+            let entry = compiling.swap_remove(position);
+
+            // If it failed, they'll find out for themselves.
+            unsafe {
+                while let Some(thread) = entry.waiters.pop() {
+                    (*thread).resume();
+                }
+            }
+        }
+
+        result
+    }
+
+    fn compile(&self, module: &Module, stubs: usize, index: usize) -> CompileResult<usize> {
+        let num_imported = module.imported_funcs.len();
+        let (offset, size) = self.bodies[index];
+        let body = &self.wasm[offset..offset + size];
+
+        let mut context = translate::translate_function(&self.flags, module, index, body)
+            .map_err(|err| CompileError::from_wasm(err, offset).in_function(num_imported + index))?;
+
+        let isa = compilation::native_isa(self.flags.clone())?;
+
+        let code_size = context.compile(&*isa)
+            .map_err(|err| {
+                CompileError::new(CompileErrorKind::Codegen, err)
+                    .in_function(num_imported + index)
+                    .at_offset(offset)
+            })? as usize;
+
+        let mut region = compilation::allocate_code(code_size)?;
+        let addr = region.start().as_u64() as usize;
+
+        let mut reloc_sink = RelocSink::new();
+        let mut trap_sink = TrapSink::new(0);
+
+        unsafe {
+            context.emit_to_memory(&*isa, addr as *mut u8, &mut reloc_sink, &mut trap_sink);
+        }
+
+        for (reloc, reloc_type) in &reloc_sink.func_relocs {
+            let target = match reloc_type {
+                RelocationType::Normal(func_index) => {
+                    match func_index.checked_sub(num_imported) {
+                        Some(local_index) => self.function_addr(stubs, local_index) as *const (),
+                        None => {
+                            let (_, ref name) = module.imported_funcs[*func_index];
+                            let sig = &module.signatures[module.functions[*func_index]];
+                            compilation::get_abi_func(name, sig)?
+                        },
+                    }
+                },
+                RelocationType::Intrinsic(name) => compilation::get_abi_intrinsic(name)?,
+            };
+
+            compilation::apply_relocation(addr as *const (), reloc, target);
+        }
+
+        region.remap(MemFlags::READ | MemFlags::EXEC)?;
+
+        let mut compiled = self.compiled.lock();
+
+        self.slots[index].store(addr, Ordering::Release);
+
+        compiled.push(CompiledFunction {
+            region,
+            traps: trap_sink.trap_datas,
+        });
+
+        Ok(addr)
+    }
+
+    /// How many local functions have been compiled.
+    pub fn compiled_count(&self) -> usize {
+        self.compiled.lock().len()
+    }
+
+    /// The size of the code of the functions that have been compiled.
+    pub fn compiled_size(&self) -> usize {
+        self.compiled.lock()
+            .iter()
+            .map(|function| function.region.size())
+            .sum()
+    }
+
+    pub fn lookup_trap_code(&self, inst: *const ()) -> Option<TrapCode> {
+        let compiled = self.compiled.lock();
+        let function = compiled.iter().find(|function| function.region.contains(inst))?;
+        let offset = inst as usize - function.region.start().as_u64() as usize;
+
+        function.traps.iter()
+            .find(|trap_data| trap_data.offset == offset)
+            .map(|trap_data| trap_data.code)
+    }
+}
+
+/// Called by `lazy_compile_trampoline` with the slot of the stub
+/// that was called. Returns the address of the compiled function.
+#[no_mangle]
+pub extern "C" fn wasm_lazy_compile(slot: *const Atomic<usize>) -> usize {
+    let process = Thread::current()
+        .parent()
+        .expect("only wasm code calls stubs");
+
+    match process.code().compile_lazy_function(slot) {
+        Ok(addr) => addr,
+        Err(err) => {
+            println!("wasm lazy compile error: {}", err);
+            process.exit();
+            unreachable!();
+        },
+    }
+}
@@ -6,6 +6,8 @@
 use cranelift_wasm::{FunctionIndex, GlobalIndex, TableIndex, MemoryIndex, Global, Table, Memory,
                 SignatureIndex};
 use cranelift_codegen::ir;
+use super::sig_registry::SigId;
+use super::bulk::BulkOp;
 
 use alloc::vec::Vec;
 use alloc::string::String;
@@ -53,6 +55,10 @@ pub struct Module {
     /// Unprocessed signatures exactly as provided by `declare_signature()`.
     pub signatures: Vec<ir::Signature>,
 
+    /// The registry id of each signature. These are
+    /// filled in when the compiled module is loaded.
+    pub sig_ids: Vec<SigId>,
+
     /// Names of imported functions.
     pub imported_funcs: Vec<(String, String)>,
 
@@ -76,6 +82,18 @@ pub struct Module {
 
     /// WebAssembly table initializers.
     pub table_elements: Vec<TableElements>,
+
+    /// The contents of each element segment, for `table.init`. Active
+    /// segments are empty, since they're dropped once they're applied.
+    pub passive_elements: Vec<Vec<TableElement>>,
+
+    /// The contents of each data segment, for `memory.init`. Like the
+    /// element segments, the active ones are empty.
+    pub passive_data: Vec<Vec<u8>>,
+
+    /// The bulk memory operators that the last functions of the module
+    /// stand in for. See `bulk`. This is only used while translating.
+    pub bulk_ops: Vec<BulkOp>,
 }
 
 impl Module {
@@ -83,6 +101,7 @@ impl Module {
     pub fn new() -> Self {
         Self {
             signatures: Vec::new(),
+            sig_ids: Vec::new(),
             imported_funcs: Vec::new(),
             functions: Vec::new(),
             tables: Vec::new(),
@@ -91,6 +110,9 @@ impl Module {
             exports: HashMap::new(),
             start_func: None,
             table_elements: Vec::new(),
+            passive_elements: Vec::new(),
+            passive_data: Vec::new(),
+            bulk_ops: Vec::new(),
         }
     }
 }
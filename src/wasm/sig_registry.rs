@@ -1,7 +1,24 @@
+//! Signatures of wasm functions, deduplicated across every module.
+//!
+//! Each distinct signature gets an id that never changes. Table
+//! entries carry the id of their function's signature, and
+//! `call_indirect` checks it against the id of the signature that
+//! it expects, so compiled code can be shared between processes.
+
 use cranelift_codegen::ir::Signature;
 use alloc::vec::Vec;
 use spin::RwLock;
 
+pub type SigId = u32;
+
+/// The signature id of a table entry without a function.
+/// It never matches a registered signature.
+pub const NULL_SIG_ID: SigId = SigId::max_value();
+
+lazy_static! {
+    pub static ref SIG_REGISTRY: SigRegistry = SigRegistry::new();
+}
+
 pub struct SigRegistry {
     table: RwLock<Vec<Signature>>,
 }
@@ -13,5 +30,29 @@ impl SigRegistry {
         }
     }
 
-    pub fn get_id(sig: &Signature)
+    /// The id of `sig`, which is registered if it wasn't yet.
+    pub fn get_id(&self, sig: &Signature) -> SigId {
+        if let Some(id) = self.lookup(sig) {
+            return id;
+        }
+
+        let mut table = self.table.write();
+
+        // It may have been registered while the lock was released.
+        if let Some(index) = table.iter().position(|registered| registered == sig) {
+            return index as SigId;
+        }
+
+        table.push(sig.clone());
+        (table.len() - 1) as SigId
+    }
+
+    /// The id of `sig`, if it's registered.
+    pub fn lookup(&self, sig: &Signature) -> Option<SigId> {
+        self.table
+            .read()
+            .iter()
+            .position(|registered| registered == sig)
+            .map(|index| index as SigId)
+    }
 }
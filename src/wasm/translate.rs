@@ -0,0 +1,1080 @@
+//! Translating wasm into Cranelift IR. None of this depends on the
+//! rest of the kernel, so the `aot` tool builds it too, and code
+//! compiled ahead of time matches code compiled by the kernel.
+
+use cranelift_wasm::{self, FuncEnvironment as FuncEnvironmentTrait, FunctionIndex, GlobalIndex, TableIndex, MemoryIndex, Global, Table, Memory,
+                GlobalVariable, SignatureIndex, FuncTranslator, WasmResult};
+use cranelift_codegen::ir::{self, InstBuilder, FuncRef, ExtFuncData, ExternalName, Signature, AbiParam,
+                   ArgumentPurpose, ArgumentLoc, ArgumentExtension, Function, TrapCode, SourceLoc, LibCall};
+use cranelift_codegen::ir::types::*;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::settings::CallConv;
+use cranelift_codegen::cursor::FuncCursor;
+use cranelift_codegen::{self, settings, binemit};
+use target_lexicon::{Triple, Architecture, Vendor, OperatingSystem, Environment, BinaryFormat, PointerWidth};
+use wasmparser;
+
+use super::module::{self, Module};
+use super::sig_registry::SigId;
+use super::layout::MemoryPlan;
+use super::atomics::{self, AtomicOp, AtomicKind};
+use super::bulk::BulkOp;
+use core::mem;
+use alloc::vec::Vec;
+use alloc::string::String;
+
+/// The intrinsic that functions with big stack frames call,
+/// when stack probes are on, to touch each page of the frame.
+pub const PROBESTACK: &str = "probestack";
+
+/// The configuration that modules are validated with. Translation
+/// supports the atomic operators, so the threads proposal is on.
+pub const VALIDATING_CONFIG: wasmparser::ValidatingParserConfig = wasmparser::ValidatingParserConfig {
+    operator_config: wasmparser::OperatorValidatorConfig {
+        enable_threads: true,
+    },
+};
+
+/// Compute a `ir::ExternalName` for a given wasm function index.
+pub fn get_func_name(func_index: FunctionIndex) -> cranelift_codegen::ir::ExternalName {
+    debug_assert!(func_index as u32 as FunctionIndex == func_index);
+    ir::ExternalName::user(0, func_index as u32)
+}
+
+/// Implementation of a relocation sink that just saves all the information for later
+pub struct RelocSink {
+    // func: &'func ir::Function,
+    /// Relocations recorded for the function.
+    pub func_relocs: Vec<(Relocation, RelocationType)>,
+}
+
+impl binemit::RelocSink for RelocSink {
+    fn reloc_ebb(
+        &mut self,
+        _offset: binemit::CodeOffset,
+        _reloc: binemit::Reloc,
+        _ebb_offset: binemit::CodeOffset,
+    ) {
+        // This should use the `offsets` field of `ir::Function`.
+        unimplemented!();
+    }
+    fn reloc_external(
+        &mut self,
+        offset: binemit::CodeOffset,
+        reloc: binemit::Reloc,
+        name: &ExternalName,
+        addend: binemit::Addend,
+    ) {
+        match *name {
+            ExternalName::User {
+                namespace: 0,
+                index,
+            } => {
+                self.func_relocs.push(
+                    (
+                        Relocation {
+                            reloc,
+                            offset,
+                            addend,
+                        },
+                        RelocationType::Normal(index as _),
+                    )
+                );
+            },
+            ExternalName::TestCase {
+                length,
+                ascii,
+            } => {
+                let (slice, _) = ascii.split_at(length as usize);
+                let name = String::from_utf8(slice.to_vec()).unwrap();
+
+                self.func_relocs.push(
+                    (
+                        Relocation {
+                            reloc,
+                            offset,
+                            addend,
+                        },
+                        RelocationType::Intrinsic(name),
+                    )
+                );
+            },
+            ExternalName::LibCall(LibCall::Probestack) => {
+                self.func_relocs.push(
+                    (
+                        Relocation {
+                            reloc,
+                            offset,
+                            addend,
+                        },
+                        RelocationType::Intrinsic(String::from(PROBESTACK)),
+                    )
+                );
+            },
+            _ => {
+                unimplemented!();
+            }
+        }
+    }
+    fn reloc_jt(
+        &mut self,
+        _offset: binemit::CodeOffset,
+        _reloc: binemit::Reloc,
+        _jt: ir::JumpTable,
+    ) {
+        unimplemented!();
+    }
+}
+
+impl RelocSink {
+    pub fn new() -> RelocSink {
+        RelocSink {
+            func_relocs: Vec::new(),
+        }
+    }
+}
+
+/// A data initializer for linear memory.
+#[derive(Debug)]
+pub struct DataInitializer {
+    /// The index of the memory to initialize.
+    pub memory_index: MemoryIndex,
+    /// Optionally a globalvalue base to initialize at.
+    pub base: Option<GlobalIndex>,
+    /// A constant offset to initialize at.
+    pub offset: usize,
+    /// The initialization data.
+    pub data: Vec<u8>,
+}
+
+/// References to the input wasm data buffer to be decoded and processed later.
+/// separately from the main module translation.
+pub struct LazyContents<'data> {
+    /// The whole module, which the function bodies point into.
+    pub wasm: &'data [u8],
+
+    /// References to the function bodies.
+    pub function_body_inputs: Vec<&'data [u8]>,
+
+    /// References to the data initializers.
+    pub data_initializers: Vec<DataInitializer>,
+}
+
+impl<'data> LazyContents<'data> {
+    fn new(wasm: &'data [u8]) -> Self {
+        Self {
+            wasm,
+            function_body_inputs: Vec::new(),
+            data_initializers: Vec::new(),
+        }
+    }
+
+    /// The offset of a function body in the module.
+    pub fn body_offset(&self, body: &[u8]) -> usize {
+        body.as_ptr() as usize - self.wasm.as_ptr() as usize
+    }
+}
+
+/// Object containing the standalone runtime information. To be passed after creation as argument
+/// to `cton_wasm::translatemodule`.
+pub struct ModuleEnvironment<'data, 'flags> {
+    /// Compilation setting flags.
+    pub flags: &'flags settings::Flags,
+
+    /// Module information.
+    pub module: Module,
+
+    /// References to information to be decoded later.
+    pub lazy: LazyContents<'data>,
+}
+
+impl<'data, 'flags> ModuleEnvironment<'data, 'flags> {
+    /// Allocates the runtime data structures with the given isa,
+    /// for translating `wasm`.
+    pub fn new(flags: &'flags settings::Flags, module: Module, wasm: &'data [u8]) -> Self {
+        Self {
+            flags,
+            module,
+            lazy: LazyContents::new(wasm),
+        }
+    }
+
+    fn func_env(&self) -> FuncEnvironment {
+        FuncEnvironment::new(&self.flags, &self.module)
+    }
+
+    fn native_pointer(&self) -> ir::Type {
+        self.func_env().pointer_type()
+    }
+
+    /// Declare that translation of the module is complete. This consumes the
+    /// `ModuleEnvironment` with its mutable reference to the `Module` and
+    /// produces a `ModuleTranslation` with an immutable reference to the
+    /// `Module`.
+    pub fn finish_translation(self) -> ModuleTranslation<'data, 'flags> {
+        ModuleTranslation {
+            flags: self.flags,
+            module: self.module,
+            lazy: self.lazy,
+        }
+    }
+}
+
+/// The FuncEnvironment implementation for use by the `ModuleEnvironment`.
+pub struct FuncEnvironment<'module_environment> {
+    /// Compilation setting flags.
+    settings_flags: &'module_environment settings::Flags,
+
+    /// The module-level environment which this function-level environment belongs to.
+    pub module: &'module_environment Module,
+
+    pub main_memory_base: Option<ir::GlobalValue>,
+
+    /// The Cranelift global holding the base address of the memories vector.
+    pub memory_base: Option<ir::GlobalValue>,
+
+    /// The Cranelift global holding the base address of the globals vector.
+    pub globals_base: Option<ir::GlobalValue>,
+
+    /// The Cranelift global holding the base address
+    /// of the registry ids of the module's signatures.
+    pub sig_ids_base: Option<ir::GlobalValue>,
+
+    /// The list of globals that hold table bases and bounds.
+    pub tables: Vec<Option<ir::Table>>,
+    /// The Cranelift global holding the address
+    /// of the `tables` field of the `VmCtxData`.
+    pub tables_base: Option<ir::GlobalValue>,
+
+    /// The external function declaration for implementing wasm's `current_memory`.
+    pub current_memory_extfunc: Option<FuncRef>,
+
+    /// The external function declaration for implementing wasm's `grow_memory`.
+    pub grow_memory_extfunc: Option<FuncRef>,
+    
+    pub debug_addr_extfunc: Option<FuncRef>,
+
+    /// The atomic operators in the function. Calls to the functions
+    /// past the end of the module stand in for them.
+    pub atomics: Vec<AtomicOp>,
+    /// The heap of the first memory, which the atomic operators access.
+    pub atomic_heap: Option<ir::Heap>,
+}
+
+impl<'module_environment> FuncEnvironment<'module_environment> {
+    fn new(
+        flags: &'module_environment settings::Flags,
+        module: &'module_environment Module,
+    ) -> Self {
+        Self {
+            settings_flags: flags,
+            module,
+            main_memory_base: None,
+            memory_base: None,
+            globals_base: None,
+            sig_ids_base: None,
+            tables: vec![None; module.tables.len()],
+            tables_base: None,
+            current_memory_extfunc: None,
+            grow_memory_extfunc: None,
+            debug_addr_extfunc: None,
+            atomics: Vec::new(),
+            atomic_heap: None,
+        }
+    }
+
+    /// The atomic operator that a call to the function `index` stands in for, if any.
+    fn atomic_op(&self, index: FunctionIndex) -> Option<AtomicOp> {
+        index.checked_sub(self.module.functions.len())
+            .map(|index| self.atomics[index])
+    }
+
+    /// The bulk operator that a call to the function `index` stands in for, if any.
+    fn bulk_op(&self, index: FunctionIndex) -> Option<BulkOp> {
+        let first_helper = self.module.functions.len() - self.module.bulk_ops.len();
+        index.checked_sub(first_helper)
+            .and_then(|index| self.module.bulk_ops.get(index).cloned())
+    }
+
+    /// Call the intrinsic `name`, which takes `params` and then the vmctx.
+    fn call_intrinsic(
+        pos: &mut FuncCursor,
+        name: &str,
+        mut params: Vec<AbiParam>,
+        returns: Vec<AbiParam>,
+        args: &[ir::Value],
+    ) -> ir::Inst {
+        params.push(AbiParam::special(I64, ArgumentPurpose::VMContext));
+
+        let sig_ref = pos.func.import_signature(Signature {
+            call_conv: CallConv::SystemV,
+            argument_bytes: None,
+            params,
+            returns,
+        });
+        let intrinsic = pos.func.import_function(ExtFuncData {
+            name: ExternalName::testcase(name),
+            signature: sig_ref,
+            colocated: false,
+        });
+
+        let real_call_args = FuncEnvironment::get_real_call_args(pos.func, args);
+        pos.ins().call(intrinsic, &real_call_args)
+    }
+
+    /// Call the intrinsic that does the bulk operation `op`. It takes
+    /// the operands, then the immediates of the operator.
+    fn translate_bulk(&mut self, mut pos: FuncCursor, op: BulkOp, call_args: &[ir::Value]) -> ir::Inst {
+        let mut params: Vec<_> = call_args.iter().map(|_| AbiParam::new(I32)).collect();
+        let mut args = call_args.to_vec();
+
+        for immediate in op.immediates() {
+            params.push(AbiParam::new(I32));
+            args.push(pos.ins().iconst(I32, immediate as i64));
+        }
+
+        let returns = op.result().into_iter().map(AbiParam::new).collect();
+        FuncEnvironment::call_intrinsic(&mut pos, op.intrinsic(), params, returns, &args)
+    }
+
+    /// The native address that the atomic operation `op` accesses,
+    /// after checking that it's in bounds and aligned to its size.
+    fn atomic_addr(&mut self, pos: &mut FuncCursor, op: AtomicOp, index: ir::Value) -> ir::Value {
+        let heap = match self.atomic_heap {
+            Some(heap) => heap,
+            None => {
+                let heap = FuncEnvironmentTrait::make_heap(self, pos.func, 0);
+                self.atomic_heap = Some(heap);
+                heap
+            },
+        };
+
+        // The checked size covers the offset, so adding it can't go out of bounds.
+        let access_size = op.offset.saturating_add(op.width);
+        let pointer_type = FuncEnvironmentTrait::pointer_type(self);
+        let base = pos.ins().heap_addr(pointer_type, heap, index, access_size);
+        let addr = pos.ins().iadd_imm(base, op.offset as i64);
+
+        // Memories are page aligned, so the native address is
+        // aligned exactly when the wasm address is. Cranelift
+        // doesn't have a trap code for unaligned accesses.
+        if op.width > 1 {
+            let misaligned = pos.ins().band_imm(addr, (op.width - 1) as i64);
+            pos.ins().trapnz(misaligned, TrapCode::HeapOutOfBounds);
+        }
+
+        addr
+    }
+
+    /// Translate the atomic operation `op`.
+    ///
+    /// Loads are done inline: every atomic store goes through a locked
+    /// instruction, so a plain aligned load is sequentially consistent
+    /// on x86. Cranelift has no atomic instructions, so the others check
+    /// the address inline and then call an intrinsic that takes its
+    /// native address, the size of the access and the operation when it
+    /// handles more than one, then the rest of the operands. `wait` and
+    /// `notify` call into the scheduler, so they're only intrinsics.
+    fn translate_atomic(&mut self, mut pos: FuncCursor, op: AtomicOp, call_args: &[ir::Value]) -> ir::Inst {
+        let mut params = vec![AbiParam::new(I64)];
+        let mut args = match op.kind {
+            AtomicKind::Notify | AtomicKind::Wait => {
+                // The address is extended first, so that adding
+                // the offset can't wrap around.
+                let addr = pos.ins().uextend(I64, call_args[0]);
+                vec![pos.ins().iadd_imm(addr, op.offset as i64)]
+            },
+            _ => vec![self.atomic_addr(&mut pos, op, call_args[0])],
+        };
+
+        if op.kind == AtomicKind::Load {
+            let mut flags = ir::MemFlags::new();
+            flags.set_aligned();
+            let value = match (op.ty, op.width) {
+                (_, 1) => pos.ins().uload8(op.ty, flags, args[0], 0),
+                (_, 2) => pos.ins().uload16(op.ty, flags, args[0], 0),
+                (I64, 4) => pos.ins().uload32(flags, args[0], 0),
+                _ => pos.ins().load(op.ty, flags, args[0], 0),
+            };
+            return pos.func.dfg.value_def(value).unwrap_inst();
+        }
+
+        if op.takes_width() {
+            params.push(AbiParam::new(I32));
+            args.push(pos.ins().iconst(I32, op.width as i64));
+        }
+
+        if let AtomicKind::Rmw(rmw_op) = op.kind {
+            params.push(AbiParam::new(I32));
+            args.push(pos.ins().iconst(I32, rmw_op as i64));
+        }
+
+        for (&arg, ty) in call_args[1..].iter().zip(&op.params()[1..]) {
+            params.push(AbiParam::new(*ty));
+            args.push(arg);
+        }
+
+        let returns = op.result().into_iter().map(AbiParam::new).collect();
+        FuncEnvironment::call_intrinsic(&mut pos, op.intrinsic(), params, returns, &args)
+    }
+
+    /// Transform the call argument list in preparation for making a call.
+    /// This pushes the VMContext into the args list.
+    fn get_real_call_args(func: &Function, call_args: &[ir::Value]) -> Vec<ir::Value> {
+        let mut real_call_args = Vec::with_capacity(call_args.len() + 1);
+        real_call_args.extend_from_slice(call_args);
+        real_call_args.push(func.special_param(ArgumentPurpose::VMContext).unwrap());
+        real_call_args
+    }
+
+    fn ptr_size(&self) -> usize {
+        use cranelift_wasm::FuncEnvironment;
+        if self.triple().pointer_width().unwrap() == PointerWidth::U64 {
+            8
+        } else {
+            4
+        }
+    }
+
+    // /// Returns `Some(_)` if `value` can be resolved to an immediate value.
+    // fn iconst_inline(&self, func: &Function, value: ir::Value) -> Option<i64> {
+    //     let dfg = func.dfg;
+
+    //     if let ir::ValueDef::Result(inst, _) = dfg.value_def(value) {
+    //         if let ir::InstructionData::UnaryImm {imm, ..} = dfg[inst] {
+    //             Some(imm.into())
+    //         } else {
+    //             None
+    //         }
+    //     } else {
+    //         None
+    //     }
+    // }
+
+    // fn debug_addr(&mut self, pos: &mut FuncCursor, addr: ir::Value) {
+    //     let debug_addr_func = self.debug_addr_extfunc.unwrap_or_else(|| {
+    //         let sig_ref = pos.func.import_signature(Signature {
+    //             call_conv: CallConv::SystemV,
+    //             argument_bytes: None,
+    //             params: vec![AbiParam::new(I64), AbiParam::special(I64, ArgumentPurpose::VMContext)],
+    //             returns: vec![],
+    //         });
+    //         // FIXME: Use a real ExternalName system.
+    //         // TODO(gmorenz): Can colocated be true?
+    //         pos.func.import_function(ExtFuncData {
+    //             name: ExternalName::testcase("debug_addr"),
+    //             signature: sig_ref,
+    //             colocated: false,
+    //         })
+    //     });
+    //     self.debug_addr_extfunc = Some(debug_addr_func);
+    //     let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+    //     pos.ins().call(debug_addr_func, &[addr, vmctx]);
+    // }
+}
+
+impl<'module_environment> cranelift_wasm::FuncEnvironment for FuncEnvironment<'module_environment> {
+    fn flags(&self) -> &settings::Flags {
+        &self.settings_flags
+    }
+
+    fn triple(&self) -> &Triple {
+        #[cfg(target_arch = "x86_64")]
+        const ARCH: Architecture = Architecture::X86_64;
+        #[cfg(target_arch = "riscv64")]
+        const ARCH: Architecture = Architecture::Riscv64;
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "riscv64")))]
+        compile_error!("Nebulet only supports `x86_64` and `riscv64`");
+
+        &Triple {
+            architecture: ARCH,
+            vendor: Vendor::Unknown,
+            operating_system: OperatingSystem::Nebulet,
+            environment: Environment::Unknown,
+            binary_format: BinaryFormat::Unknown,
+        }
+    }
+
+    fn make_global(&mut self, func: &mut ir::Function, index: GlobalIndex) -> GlobalVariable {
+        let globals_base = self.globals_base.unwrap_or_else(|| {
+            let globals_offset = self.ptr_size() as i32 * -3;
+            let new_base = func.create_global_value(ir::GlobalValueData::VMContext {
+                offset: globals_offset.into(),
+            });
+            self.globals_base = Some(new_base);
+            new_base
+        });
+        let offset = index as usize * self.ptr_size();
+        let gv = func.create_global_value(ir::GlobalValueData::Deref {
+            base: globals_base,
+            offset: (offset as i32).into(),
+        });
+        GlobalVariable::Memory {
+            gv,
+            ty: self.module.globals[index].ty,
+        }
+    }
+
+    fn make_heap(&mut self, func: &mut ir::Function, index: MemoryIndex) -> ir::Heap {
+        let plan = MemoryPlan::new(self.module.memories[index].maximum);
+
+        if index == 0 {
+            let heap_base = self.main_memory_base.unwrap_or_else(|| {
+                let new_base = func.create_global_value(ir::GlobalValueData::VMContext {
+                    offset: 0.into(),
+                });
+                self.main_memory_base = Some(new_base);
+                new_base
+            });
+
+            func.create_heap(ir::HeapData {
+                base: heap_base,
+                min_size: 0.into(),
+                guard_size: (plan.guard_size as i64).into(),
+                style: ir::HeapStyle::Static {
+                    bound: (plan.bound as i64).into(),
+                },
+            })
+        } else {
+            let memory_base = self.memory_base.unwrap_or_else(|| {
+                let memories_offset = self.ptr_size() as i32 * -2;
+                let new_base = func.create_global_value(ir::GlobalValueData::VMContext {
+                    offset: memories_offset.into(),
+                });
+                self.memory_base = Some(new_base);
+                new_base
+            });
+
+            // The `memories` field points to the base of each
+            // memory after the first, so load that, then the base.
+            let memory_offset = (index - 1) * self.ptr_size();
+            let heap_base_addr = func.create_global_value(ir::GlobalValueData::Deref {
+                base: memory_base,
+                offset: (memory_offset as i32).into(),
+            });
+            let heap_base = func.create_global_value(ir::GlobalValueData::Deref {
+                base: heap_base_addr,
+                offset: 0.into(),
+            });
+
+            func.create_heap(ir::HeapData {
+                base: heap_base,
+                min_size: 0.into(),
+                guard_size: (plan.guard_size as i64).into(),
+                style: ir::HeapStyle::Static {
+                    bound: (plan.bound as i64).into(),
+                },
+            })
+        }
+    }
+
+    fn make_table(&mut self, func: &mut Function, table_index: TableIndex) -> ir::Table {
+        if let Some(table) = self.tables[table_index] {
+            return table;
+        }
+
+        let ptr_size = self.ptr_size();
+
+        let base = self.tables_base.unwrap_or_else(|| {
+            // The `tables` field of the `VmCtxData`,
+            // which points to a `BoundedSlice` per table.
+            let tables_offset = ptr_size as i32 * -1;
+            let new_base = func.create_global_value(ir::GlobalValueData::VMContext {
+                offset: tables_offset.into(),
+            });
+            self.tables_base = Some(new_base);
+            new_base
+        });
+
+        // Each table is a `BoundedSlice`, which is a pointer and a length.
+        let table_data_offset = (table_index * 2 * ptr_size) as i32;
+
+        let new_table_addr_addr = func.create_global_value(ir::GlobalValueData::Deref {
+            base,
+            offset: table_data_offset.into(),
+        });
+        let new_table_addr = func.create_global_value(ir::GlobalValueData::Deref {
+            base: new_table_addr_addr,
+            offset: 0.into(),
+        });
+
+        // The length of the table, in entries.
+        let new_table_bounds_addr = func.create_global_value(ir::GlobalValueData::Deref {
+            base,
+            offset: (table_data_offset + ptr_size as i32).into(),
+        });
+        let new_table_bounds = func.create_global_value(ir::GlobalValueData::Deref {
+            base: new_table_bounds_addr,
+            offset: 0.into(),
+        });
+
+        let table = func.create_table(ir::TableData {
+            base_gv: new_table_addr,
+            min_size: (self.module.tables[table_index].size as i64).into(),
+            bound_gv: new_table_bounds,
+            // A `TableEntry` is a function pointer and a signature
+            // id, padded to the alignment of the pointer.
+            element_size: (2 * ptr_size as i64).into(),
+        });
+
+        self.tables[table_index] = Some(table);
+        table
+    }
+
+    fn make_indirect_sig(&mut self, func: &mut ir::Function, index: SignatureIndex) -> ir::SigRef {
+        func.import_signature(self.module.signatures[index].clone())
+    }
+
+    fn make_direct_func(&mut self, func: &mut ir::Function, index: FunctionIndex) -> ir::FuncRef {
+        if let Some(op) = self.atomic_op(index) {
+            // Only the operands and result matter, since
+            // `translate_call` translates the operator instead.
+            let mut params: Vec<_> = op.params().into_iter().map(AbiParam::new).collect();
+            params.push(AbiParam::special(I64, ArgumentPurpose::VMContext));
+            let signature = func.import_signature(Signature {
+                call_conv: CallConv::SystemV,
+                argument_bytes: None,
+                params,
+                returns: op.result().into_iter().map(AbiParam::new).collect(),
+            });
+            return func.import_function(ir::ExtFuncData {
+                name: get_func_name(index),
+                signature,
+                colocated: false,
+            });
+        }
+
+        let sigidx = self.module.functions[index];
+        let signature = func.import_signature(self.module.signatures[sigidx].clone());
+        let name = get_func_name(index);
+        // TODO(gmorenz): Can colocated be true?
+        func.import_function(ir::ExtFuncData { name, signature, colocated: false })
+    }
+
+    fn translate_call_indirect(
+        &mut self,
+        mut pos: FuncCursor,
+        _table_index: TableIndex,
+        table: ir::Table,
+        sig_index: SignatureIndex,
+        sig_ref: ir::SigRef,
+        callee: ir::Value,
+        call_args: &[ir::Value],
+    ) -> WasmResult<ir::Inst> {
+        let callee = if self.pointer_type() != ir::types::I32 {
+            pos.ins().uextend(self.pointer_type(), callee)
+        } else {
+            callee
+        };
+
+        let entry_addr = pos.ins().table_addr(
+            self.pointer_type(),
+            table,
+            callee,
+            0,
+        );
+
+        let callee_func = pos.ins().load(
+            self.pointer_type(),
+            ir::MemFlags::new(),
+            entry_addr,
+            0,
+        );
+
+        pos.ins().trapz(
+            callee_func,
+            ir::TrapCode::IndirectCallToNull,
+        );
+
+        // Cranelift doesn't check signatures. A function called with
+        // the wrong one would read arguments that weren't passed.
+        let callee_sig_id = pos.ins().load(
+            I32,
+            ir::MemFlags::new(),
+            entry_addr,
+            self.ptr_size() as i32,
+        );
+
+        // The registry ids are only known once the module is loaded,
+        // so the expected one is read from the `sig_ids` field of the
+        // `VmCtxData` instead of being baked into the code.
+        let ptr_size = self.ptr_size();
+        let sig_ids_base = self.sig_ids_base.unwrap_or_else(|| {
+            let sig_ids_offset = ptr_size as i32 * -4;
+            let sig_ids_field = pos.func.create_global_value(ir::GlobalValueData::VMContext {
+                offset: sig_ids_offset.into(),
+            });
+            pos.func.create_global_value(ir::GlobalValueData::Deref {
+                base: sig_ids_field,
+                offset: 0.into(),
+            })
+        });
+        self.sig_ids_base = Some(sig_ids_base);
+
+        let sig_ids = pos.ins().global_value(self.pointer_type(), sig_ids_base);
+        let expected_sig_id = pos.ins().load(
+            I32,
+            ir::MemFlags::new(),
+            sig_ids,
+            (sig_index * mem::size_of::<SigId>()) as i32,
+        );
+
+        let sig_mismatch = pos.ins().icmp(
+            IntCC::NotEqual,
+            callee_sig_id,
+            expected_sig_id,
+        );
+
+        pos.ins().trapnz(
+            sig_mismatch,
+            ir::TrapCode::BadSignature,
+        );
+
+        let real_call_args = FuncEnvironment::get_real_call_args(pos.func, call_args);
+        Ok(pos.ins().call_indirect(sig_ref, callee_func, &real_call_args))
+    }
+
+    fn translate_call(
+        &mut self,
+        mut pos: FuncCursor,
+        callee_index: FunctionIndex,
+        callee: ir::FuncRef,
+        call_args: &[ir::Value],
+    ) -> WasmResult<ir::Inst> {
+        if let Some(op) = self.atomic_op(callee_index) {
+            return Ok(self.translate_atomic(pos, op, call_args));
+        }
+
+        if let Some(op) = self.bulk_op(callee_index) {
+            return Ok(self.translate_bulk(pos, op, call_args));
+        }
+
+        let real_call_args = FuncEnvironment::get_real_call_args(pos.func, call_args);
+
+        // Since imported functions are declared first,
+        // this will be true if the callee is an imported function
+        if callee_index < self.module.imported_funcs.len() { // external function
+            let sig_ref = pos.func.dfg.ext_funcs[callee].signature;
+            // convert callee into value needed for `call_indirect`
+            let callee_value = pos.ins()
+                .func_addr(self.pointer_type(), callee);
+
+            Ok(pos.ins()
+                .call_indirect(sig_ref, callee_value, &real_call_args))
+        } else { // internal function
+            Ok(pos.ins()
+                .call(callee, &real_call_args))
+        }
+    }
+
+    fn translate_memory_grow(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: ir::Heap,
+        val: ir::Value,
+    ) -> WasmResult<ir::Value> {
+        let grow_mem_func = self.grow_memory_extfunc.unwrap_or_else(|| {
+            let sig_ref = pos.func.import_signature(Signature {
+                call_conv: CallConv::SystemV,
+                argument_bytes: None,
+                params: vec![AbiParam::new(I32), AbiParam::new(I32), AbiParam::special(I64, ArgumentPurpose::VMContext)],
+                returns: vec![AbiParam::new(I32)],
+            });
+            // FIXME: Use a real ExternalName system.
+            // TODO(gmorenz): Can colocated be true?
+            pos.func.import_function(ExtFuncData {
+                name: ExternalName::testcase("grow_memory"),
+                signature: sig_ref,
+                colocated: false,
+            })
+        });
+
+        self.grow_memory_extfunc = Some(grow_mem_func);
+
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+
+        let memory_index = pos.ins().iconst(I32, index as i64);
+        let call_inst = pos.ins().call(grow_mem_func, &[val, memory_index, vmctx]);
+        Ok(*pos.func.dfg.inst_results(call_inst).first().unwrap())
+    }
+
+    fn translate_memory_size(
+        &mut self,
+        mut pos: FuncCursor,
+        index: MemoryIndex,
+        _heap: ir::Heap,
+    ) -> WasmResult<ir::Value> {
+        let cur_mem_func = self.current_memory_extfunc.unwrap_or_else(|| {
+            let sig_ref = pos.func.import_signature(Signature {
+                call_conv: CallConv::SystemV,
+                argument_bytes: None,
+                params: vec![AbiParam::new(I32), AbiParam::special(I64, ArgumentPurpose::VMContext)],
+                returns: vec![AbiParam::new(I32)],
+            });
+            // FIXME: Use a real ExternalName system.
+            // TODO(gmorenz): Can colocated be true?
+            pos.func.import_function(ExtFuncData {
+                name: ExternalName::testcase("current_memory"),
+                signature: sig_ref,
+                colocated: false,
+            })
+        });
+
+        self.current_memory_extfunc = Some(cur_mem_func);
+
+        let vmctx = pos.func.special_param(ArgumentPurpose::VMContext).unwrap();
+
+        let memory_index = pos.ins().iconst(I32, index as i64);
+        let call_inst = pos.ins().call(cur_mem_func, &[memory_index, vmctx]);
+        Ok(*pos.func.dfg.inst_results(call_inst).first().unwrap())
+    }
+}
+
+/// This trait is useful for
+/// `cton_wasm::translatemodule` because it
+/// tells how to translate runtime-dependent wasm instructions. These functions should not be
+/// called by the user.
+impl<'data, 'flags> cranelift_wasm::ModuleEnvironment<'data> for ModuleEnvironment<'data, 'flags> {
+    fn get_func_name(&self, func_index: FunctionIndex) -> cranelift_codegen::ir::ExternalName {
+        get_func_name(func_index)
+    }
+
+    fn flags(&self) -> &settings::Flags {
+        self.flags
+    }
+
+    fn declare_signature(&mut self, sig: &ir::Signature) {
+        let mut sig = sig.clone();
+        sig.params.push(AbiParam {
+            value_type: self.native_pointer(),
+            purpose: ArgumentPurpose::VMContext,
+            extension: ArgumentExtension::None,
+            location: ArgumentLoc::Unassigned,
+        });
+        self.module.signatures.push(sig);
+    }
+
+    fn get_signature(&self, sig_index: SignatureIndex) -> &ir::Signature {
+        &self.module.signatures[sig_index]
+    }
+
+    fn declare_func_import(&mut self, sig_index: SignatureIndex, module: &str, field: &str) {
+        debug_assert_eq!(
+            self.module.functions.len(),
+            self.module.imported_funcs.len(),
+            "Imported functions must be declared first"
+        );
+        self.module.functions.push(sig_index);
+
+        self.module.imported_funcs.push((
+            String::from(module),
+            String::from(field),
+        ));
+    }
+
+    fn get_num_func_imports(&self) -> usize {
+        self.module.imported_funcs.len()
+    }
+
+    fn declare_func_type(&mut self, sig_index: SignatureIndex) {
+        self.module.functions.push(sig_index);
+    }
+
+    fn get_func_type(&self, func_index: FunctionIndex) -> SignatureIndex {
+        self.module.functions[func_index]
+    }
+
+    fn declare_global(&mut self, global: Global) {
+        self.module.globals.push(global);
+    }
+
+    fn get_global(&self, global_index: GlobalIndex) -> &cranelift_wasm::Global {
+        &self.module.globals[global_index]
+    }
+
+    fn declare_table(&mut self, table: Table) {
+        self.module.tables.push(table);
+    }
+
+    fn declare_table_elements(
+        &mut self,
+        table_index: TableIndex,
+        base: Option<GlobalIndex>,
+        offset: usize,
+        elements: Vec<FunctionIndex>,
+    ) {
+        debug_assert!(base.is_none(), "global-value offsets not supported yet");
+        self.module.table_elements.push(module::TableElements {
+            table_index,
+            base,
+            offset,
+            elements,
+        });
+    }
+
+    fn declare_memory(&mut self, memory: Memory) {
+        self.module.memories.push(memory);
+    }
+
+    fn declare_data_initialization(
+        &mut self,
+        memory_index: MemoryIndex,
+        base: Option<GlobalIndex>,
+        offset: usize,
+        data: &'data [u8],
+    ) {
+        debug_assert!(base.is_none(), "global-value offsets not supported yet");
+        self.lazy.data_initializers.push(DataInitializer {
+            memory_index,
+            base,
+            offset,
+            data: data.to_vec(),
+        });
+    }
+
+    fn declare_func_export(&mut self, func_index: FunctionIndex, name: &str) {
+        self.module.exports.insert(
+            String::from(name),
+            module::Export::Function(func_index),
+        );
+    }
+
+    fn declare_table_export(&mut self, table_index: TableIndex, name: &str) {
+        self.module.exports.insert(
+            String::from(name),
+            module::Export::Table(table_index),
+        );
+    }
+
+    fn declare_memory_export(&mut self, memory_index: MemoryIndex, name: &str) {
+        self.module.exports.insert(
+            String::from(name),
+            module::Export::Memory(memory_index),
+        );
+    }
+
+    fn declare_global_export(&mut self, global_index: GlobalIndex, name: &str) {
+        self.module.exports.insert(
+            String::from(name),
+            module::Export::Global(global_index),
+        );
+    }
+
+    fn declare_start_func(&mut self, func_index: FunctionIndex) {
+        debug_assert!(self.module.start_func.is_none());
+        self.module.start_func = Some(func_index);
+    }
+
+    fn define_function_body(&mut self, body_bytes: &'data [u8]) -> WasmResult<()> {
+        self.lazy.function_body_inputs.push(body_bytes);
+        Ok(())
+    }
+}
+
+/// A record of a relocation to perform.
+#[derive(Debug)]
+pub struct Relocation {
+    /// The relocation code.
+    pub reloc: binemit::Reloc,
+    /// The offset where to apply the relocation.
+    pub offset: binemit::CodeOffset,
+    /// The addend to add to the relocation value.
+    pub addend: binemit::Addend,
+}
+
+/// Specify the type of relocation
+#[derive(Debug)]
+pub enum RelocationType {
+    Normal(FunctionIndex),
+    Intrinsic(String),
+}
+
+/// Relocations to apply to function bodies.
+pub type Relocations = Vec<Vec<(Relocation, RelocationType)>>;
+
+/// The result of translating via `ModuleEnvironment`.
+pub struct ModuleTranslation<'data, 'flags> {
+    /// Compilation setting flags.
+    pub flags: &'flags settings::Flags,
+
+    /// Module information.
+    pub module: Module,
+
+    /// Pointers into the raw data buffer.
+    pub lazy: LazyContents<'data>,
+}
+
+impl<'data, 'flags> ModuleTranslation<'data, 'flags> {
+    pub fn func_env(&self) -> FuncEnvironment {
+        FuncEnvironment::new(&self.flags, &self.module)
+    }
+
+    /// Translate the body of the local function `index` into
+    /// a `Context`, ready to be compiled.
+    pub fn translate_function(&self, index: usize) -> WasmResult<cranelift_codegen::Context> {
+        translate_function(self.flags, &self.module, index, self.lazy.function_body_inputs[index])
+    }
+}
+
+/// Translate `body`, the body of the local function `index`
+/// of `module`, into a `Context`, ready to be compiled.
+pub fn translate_function(
+    flags: &settings::Flags,
+    module: &Module,
+    index: usize,
+    body: &[u8],
+) -> WasmResult<cranelift_codegen::Context> {
+    let num_imported = module.imported_funcs.len();
+
+    let mut context = cranelift_codegen::Context::new();
+    context.func.name = get_func_name(index);
+    context.func.signature = module.signatures[module.functions[num_imported + index]].clone();
+
+    let mut func_env = FuncEnvironment::new(flags, module);
+
+    let rewritten = atomics::rewrite(body, module.functions.len())?;
+    let body = match rewritten {
+        Some((ref new_body, ref ops)) => {
+            func_env.atomics = ops.clone();
+            &new_body[..]
+        },
+        None => body,
+    };
+
+    let mut trans = FuncTranslator::new();
+    let reader = wasmparser::BinaryReader::new(body);
+    trans.translate_from_reader(reader, &mut context.func, &mut func_env)?;
+
+    Ok(context)
+}
+
+pub struct TrapData {
+    pub offset: usize,
+    pub code: TrapCode,
+}
+
+/// Simple implementation of a TrapSink
+/// that saves the info for later.
+pub struct TrapSink {
+    current_func_offset: usize,
+    pub trap_datas: Vec<TrapData>,
+}
+
+impl TrapSink {
+    pub fn new(current_func_offset: usize) -> TrapSink {
+        TrapSink {
+            current_func_offset,
+            trap_datas: Vec::new(),
+        }
+    }
+}
+
+impl binemit::TrapSink for TrapSink {
+    fn trap(&mut self, offset: u32, _: SourceLoc, code: TrapCode) {
+        self.trap_datas.push(TrapData {
+            offset: self.current_func_offset + offset as usize,
+            code,
+        });
+    }
+}
@@ -0,0 +1,168 @@
+//! Compile wasm ahead of time, into an artifact that the kernel
+//! loads with `Wasm::load_aot` instead of compiling the wasm at boot.
+//!
+//! The translation is the kernel's own, so that the code is the
+//! same as what the kernel would compile. The only difference is
+//! that it's compiled for a baseline x86_64 cpu, instead of the
+//! one that the kernel happens to be running on.
+//!
+//! usage: aot <input.wasm> <output.aot>
+
+#![feature(alloc)]
+
+extern crate alloc;
+extern crate cranelift_codegen;
+extern crate cranelift_wasm;
+extern crate hashmap_core;
+extern crate target_lexicon;
+extern crate wasmparser;
+
+#[path = "../../../src/common/sha256.rs"]
+mod sha256;
+#[path = "../../../src/wasm/layout.rs"]
+mod layout;
+#[path = "../../../src/wasm/module.rs"]
+mod module;
+#[path = "../../../src/wasm/translate.rs"]
+mod translate;
+#[path = "../../../src/wasm/atomics.rs"]
+mod atomics;
+#[path = "../../../src/wasm/bulk.rs"]
+mod bulk;
+#[path = "../../../src/wasm/aot.rs"]
+mod aot;
+
+/// The paths that the shared sources use in the kernel.
+mod common {
+    pub use sha256;
+}
+
+mod sig_registry {
+    pub type SigId = u32;
+}
+
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::isa;
+use cranelift_wasm::translate_module;
+use target_lexicon::{Triple, Architecture, Vendor, OperatingSystem, Environment, BinaryFormat};
+
+use aot::Artifact;
+use module::Module;
+use translate::{ModuleEnvironment, ModuleTranslation, RelocSink, TrapSink};
+
+use std::process::exit;
+use std::env;
+use std::fs;
+
+fn compile(wasm: &[u8]) -> Result<Artifact, String> {
+    let mut module = Module::new();
+    let lowered = bulk::lower(wasm, &mut module)
+        .map_err(|err| format!("{:?}", err))?;
+    let wasm = lowered.as_ref().map_or(wasm, |lowered| &lowered[..]);
+
+    if !wasmparser::validate(wasm, Some(translate::VALIDATING_CONFIG)) {
+        return Err(String::from("invalid wasm"));
+    }
+
+    // The same settings as `Wasm::compile` in the kernel with only
+    // the `VERIFY` flag. The kernel won't load the artifact otherwise.
+    let mut flag_builder = settings::builder();
+    flag_builder.set("opt_level", "best")
+        .map_err(|err| format!("{:?}", err))?;
+    flag_builder.set("enable_verifier", "true")
+        .map_err(|err| format!("{:?}", err))?;
+    flag_builder.set("probestack_enabled", "true")
+        .map_err(|err| format!("{:?}", err))?;
+
+    let triple = Triple {
+        architecture: Architecture::X86_64,
+        vendor: Vendor::Unknown,
+        operating_system: OperatingSystem::Nebulet,
+        environment: Environment::Unknown,
+        binary_format: BinaryFormat::Unknown,
+    };
+
+    let isa = isa::lookup(triple)
+        .map_err(|err| format!("{:?}", err))?
+        .finish(settings::Flags::new(flag_builder));
+
+    let mut environ = ModuleEnvironment::new(isa.flags(), module, wasm);
+    translate_module(wasm, &mut environ)
+        .map_err(|err| format!("{:?}", err))?;
+
+    let translation = environ.finish_translation();
+
+    let mut contexts = Vec::with_capacity(translation.lazy.function_body_inputs.len());
+    let mut total_size = 0;
+
+    for index in 0..translation.lazy.function_body_inputs.len() {
+        let mut context = translation.translate_function(index)
+            .map_err(|err| format!("local function {}: {:?}", index, err))?;
+
+        let size = context.compile(&*isa)
+            .map_err(|err| format!("local function {}: {}", index, err))? as usize;
+
+        contexts.push((context, size));
+        total_size += size;
+    }
+
+    let mut code = vec![0; total_size];
+    let mut functions = Vec::with_capacity(contexts.len());
+    let mut relocations = Vec::with_capacity(contexts.len());
+    let mut traps = Vec::new();
+
+    let mut offset = 0;
+    for (context, size) in &contexts {
+        let mut reloc_sink = RelocSink::new();
+        let mut trap_sink = TrapSink::new(offset);
+
+        unsafe {
+            context.emit_to_memory(&*isa, code.as_mut_ptr().add(offset), &mut reloc_sink, &mut trap_sink);
+        }
+
+        functions.push((offset, *size));
+        relocations.push(reloc_sink.func_relocs);
+        traps.append(&mut trap_sink.trap_datas);
+
+        offset += size;
+    }
+
+    let ModuleTranslation { module, lazy, .. } = translation;
+
+    Ok(Artifact {
+        settings: format!("{}", isa.flags()),
+        code,
+        functions,
+        relocations,
+        traps,
+        module,
+        data_initializers: lazy.data_initializers,
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: aot <input.wasm> <output.aot>");
+        exit(1);
+    }
+
+    let wasm = fs::read(&args[1])
+        .unwrap_or_else(|err| {
+            eprintln!("aot: can't read {}: {}", args[1], err);
+            exit(1);
+        });
+
+    let artifact = compile(&wasm)
+        .and_then(|artifact| artifact.serialize(&wasm).map_err(String::from))
+        .unwrap_or_else(|err| {
+            eprintln!("aot: {}: {}", args[1], err);
+            exit(1);
+        });
+
+    fs::write(&args[2], artifact)
+        .unwrap_or_else(|err| {
+            eprintln!("aot: can't write {}: {}", args[2], err);
+            exit(1);
+        });
+}
@@ -42,6 +42,24 @@ fn main() -> io::Result<()> {
             let name = entry.file_name().to_str().unwrap();
             println!("packaging: {}", name);
             tar.append_file(name, &mut File::open(entry.path())?)?;
+
+            // The kernel loads code that was compiled ahead of
+            // time next to the wasm, instead of compiling it.
+            let aot_path = entry.path().with_extension("aot");
+            let aot_name = aot_path.file_name().unwrap().to_str().unwrap();
+            println!("compiling ahead of time: {}", aot_name);
+            let status = Command::new("cargo")
+                .arg("aot")
+                .arg(entry.path())
+                .arg(&aot_path)
+                .status()
+                .expect("Failed to run aot");
+
+            if status.success() {
+                tar.append_file(aot_name, &mut File::open(&aot_path)?)?;
+            } else {
+                println!("not compiling {} ahead of time", name);
+            }
         }
     }
 
@@ -0,0 +1,34 @@
+;; Pins the main thread to each cpu in turn and checks
+;; that it's actually running there afterwards.
+(module
+  (import "abi" "cpu_count" (func $cpu_count (result i64)))
+  (import "abi" "cpu_id" (func $cpu_id (result i64)))
+  (import "abi" "thread_set_affinity" (func $thread_set_affinity (param i32 i64) (result i64)))
+  (import "abi" "exit" (func $exit (param i64) (result i64)))
+  (memory $0 1)
+
+  (func $main
+    (local $count i32)
+    (local $cpu i32)
+    (set_local $count (i32.wrap/i64 (call $cpu_count)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (get_local $cpu) (get_local $count)))
+        ;; The main thread is always thread 0.
+        (if (i64.ne (call $thread_set_affinity (i32.const 0)
+              (i64.shl (i64.const 1) (i64.extend_u/i32 (get_local $cpu))))
+              (i64.const 0))
+          (then unreachable))
+        (if (i64.ne (call $cpu_id) (i64.extend_u/i32 (get_local $cpu)))
+          (then unreachable))
+        (set_local $cpu (i32.add (get_local $cpu) (i32.const 1)))
+        (br $next)
+      )
+    )
+    ;; No cpu at all isn't allowed.
+    (if (i64.eqz (call $thread_set_affinity (i32.const 0) (i64.const 0)))
+      (then unreachable))
+    (drop (call $exit (i64.const 0)))
+  )
+  (start $main)
+)
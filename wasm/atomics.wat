@@ -0,0 +1,53 @@
+;; Several threads add to a counter in shared memory with atomic
+;; operators, then notify the main thread, which waits for them.
+(module
+  (import "abi" "thread_spawn" (func $thread_spawn (param i32 i32 i32) (result i64)))
+  (import "abi" "thread_join" (func $thread_join (param i32) (result i64)))
+  (import "abi" "assert_eq" (func $assert_eq (param i64) (param i64) (result i64)))
+  (import "abi" "exit" (func $exit (param i64) (result i64)))
+  (memory $0 2 2 shared)
+  (table anyfunc (elem $work))
+
+  ;; The counter is at 0, and the number of finished threads at 8.
+  (func $work (param $count i32)
+    (local $i i32)
+    (loop $again
+      (drop (i64.atomic.rmw.add (i32.const 0) (i64.const 1)))
+      (set_local $i (i32.add (get_local $i) (i32.const 1)))
+      (br_if $again (i32.lt_u (get_local $i) (get_local $count)))
+    )
+    (drop (i32.atomic.rmw.add (i32.const 8) (i32.const 1)))
+    (drop (atomic.notify (i32.const 8) (i32.const 1)))
+  )
+
+  (func $main
+    (local $t1 i32)
+    (local $t2 i32)
+    (local $done i32)
+    (set_local $t1 (i32.wrap/i64 (call $thread_spawn (i32.const 0) (i32.const 100000) (i32.const 65536))))
+    (set_local $t2 (i32.wrap/i64 (call $thread_spawn (i32.const 0) (i32.const 100000) (i32.const 98304))))
+    (call $work (i32.const 100000))
+
+    ;; Wait until the others are done too.
+    (block $finished
+      (loop $again
+        (set_local $done (i32.atomic.load (i32.const 8)))
+        (br_if $finished (i32.eq (get_local $done) (i32.const 3)))
+        (drop (i32.atomic.wait (i32.const 8) (get_local $done) (i64.const -1)))
+        (br $again)
+      )
+    )
+
+    (drop (call $assert_eq (i64.atomic.load (i32.const 0)) (i64.const 300000)))
+    ;; A value that doesn't match doesn't wait, and a timeout does return.
+    (drop (call $assert_eq (i64.extend_u/i32 (i32.atomic.wait (i32.const 8) (i32.const 0) (i64.const -1))) (i64.const 1)))
+    (drop (call $assert_eq (i64.extend_u/i32 (i32.atomic.wait (i32.const 8) (i32.const 3) (i64.const 1000000))) (i64.const 2)))
+    (drop (call $assert_eq (i64.atomic.rmw8_u.cmpxchg (i32.const 16) (i64.const 0) (i64.const 255)) (i64.const 0)))
+    (drop (call $assert_eq (i64.atomic.load8_u (i32.const 16)) (i64.const 255)))
+
+    (drop (call $thread_join (get_local $t1)))
+    (drop (call $thread_join (get_local $t2)))
+    (drop (call $exit (i64.const 0)))
+  )
+  (start $main)
+)
@@ -0,0 +1,37 @@
+;; The bulk memory operators, with a passive data segment
+;; and a passive element segment.
+(module
+  (import "abi" "assert_eq" (func $assert_eq (param i64) (param i64) (result i64)))
+  (import "abi" "exit" (func $exit (param i64) (result i64)))
+  (memory $0 1)
+  (table 4 anyfunc)
+  (type $get (func (result i32)))
+  (data $greeting "hello, world")
+  (elem $funcs func $one $two)
+
+  (func $one (result i32) (i32.const 1))
+  (func $two (result i32) (i32.const 2))
+
+  (func $main
+    ;; "hello" at 100, then copied over itself, one byte on.
+    (memory.init $greeting (i32.const 100) (i32.const 0) (i32.const 5))
+    (memory.copy (i32.const 101) (i32.const 100) (i32.const 5))
+    (drop (call $assert_eq (i64.load8_u (i32.const 101)) (i64.const 104)))
+    (drop (call $assert_eq (i64.load8_u (i32.const 105)) (i64.const 111)))
+
+    (memory.fill (i32.const 200) (i32.const 42) (i32.const 16))
+    (drop (call $assert_eq (i64.load8_u (i32.const 215)) (i64.const 42)))
+    (drop (call $assert_eq (i64.load8_u (i32.const 216)) (i64.const 0)))
+    (data.drop $greeting)
+
+    (table.init $funcs (i32.const 1) (i32.const 0) (i32.const 2))
+    (table.copy (i32.const 3) (i32.const 1) (i32.const 1))
+    (elem.drop $funcs)
+    (drop (call $assert_eq (i64.extend_u/i32 (call_indirect (type $get) (i32.const 2))) (i64.const 2)))
+    (drop (call $assert_eq (i64.extend_u/i32 (call_indirect (type $get) (i32.const 3))) (i64.const 1)))
+    (drop (call $assert_eq (i64.extend_u/i32 (table.size)) (i64.const 4)))
+
+    (drop (call $exit (i64.const 0)))
+  )
+  (start $main)
+)
@@ -0,0 +1,19 @@
+;; Calls a function through a table with the wrong signature.
+;; The call has to trap with a bad signature instead of
+;; running the function without its argument.
+(module
+  (import "abi" "exit" (func $output (param i64) (result i64)))
+  (type $no_args (func))
+  (table 2 anyfunc)
+  (elem (i32.const 0)
+    $takes_arg
+  )
+  (func $takes_arg (param i64)
+    (drop (call $output (get_local 0)))
+  )
+  (func $main
+    i32.const 0
+    (call_indirect (type $no_args))
+  )
+  (start $main)
+)
@@ -0,0 +1,18 @@
+;; Calls through an index past the end of the table.
+;; The call has to trap instead of reading past the table.
+(module
+  (import "abi" "exit" (func $output (param i64) (result i64)))
+  (type $no_args (func))
+  (table 2 anyfunc)
+  (elem (i32.const 0)
+    $no_args
+  )
+  (func $no_args
+    (drop (call $output (i64.const 0)))
+  )
+  (func $main
+    i32.const 2
+    (call_indirect (type $no_args))
+  )
+  (start $main)
+)
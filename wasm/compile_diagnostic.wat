@@ -0,0 +1,29 @@
+;; Compiles a module that imports an abi function that doesn't
+;; exist, and checks that the diagnostic says which one.
+(module
+  (import "abi" "wasm_compile_ex" (func $wasm_compile_ex (param i32 i32 i32) (result i64)))
+  (import "abi" "exit" (func $exit (param i64) (result i64)))
+  (memory $0 1)
+  ;; (module (import "abi" "nope" (func)))
+  (data (i32.const 0)
+    "\00asm\01\00\00\00"
+    "\01\04\01\60\00\00"
+    "\02\0c\01\03abi\04nope\00\00"
+  )
+
+  (func $main
+    (if (i64.eqz (call $wasm_compile_ex (i32.const 0) (i32.const 28) (i32.const 64)))
+      (then unreachable))
+    ;; kind: UnknownImport
+    (if (i32.ne (i32.load (i32.const 64)) (i32.const 3))
+      (then unreachable))
+    ;; func_index: the import is function 0
+    (if (i32.ne (i32.load (i32.const 68)) (i32.const 0))
+      (then unreachable))
+    ;; field_len: "nope"
+    (if (i32.ne (i32.load (i32.const 80)) (i32.const 4))
+      (then unreachable))
+    (drop (call $exit (i64.const 0)))
+  )
+  (start $main)
+)
@@ -0,0 +1,44 @@
+;; Several threads keep floats in registers while they're preempted.
+;; If the xmm registers weren't part of a thread's context, one
+;; thread's values would end up in another and it would trap.
+(module
+  (import "abi" "thread_spawn" (func $thread_spawn (param i32 i32 i32) (result i64)))
+  (import "abi" "thread_join" (func $thread_join (param i32) (result i64)))
+  (import "abi" "exit" (func $exit (param i64) (result i64)))
+  (memory $0 1)
+  ;; The simulated stack pointer.
+  (global $g0 (mut i32) (i32.const 16384))
+  (table anyfunc (elem $work))
+
+  ;; $a counts up by one and $b by $seed, so $a * $seed is always $b.
+  (func $work (param $seed i32)
+    (local $i i32)
+    (local $s f64)
+    (local $a f64)
+    (local $b f64)
+    (set_local $s (f64.convert_u/i32 (get_local $seed)))
+    (loop $again
+      (set_local $a (f64.add (get_local $a) (f64.const 1)))
+      (set_local $b (f64.add (get_local $b) (get_local $s)))
+      (if (f64.ne (f64.mul (get_local $a) (get_local $s)) (get_local $b))
+        (then unreachable))
+      (set_local $i (i32.add (get_local $i) (i32.const 1)))
+      (br_if $again (i32.lt_u (get_local $i) (i32.const 50000000)))
+    )
+  )
+
+  (func $main
+    (local $t1 i32)
+    (local $t2 i32)
+    (local $t3 i32)
+    (set_local $t1 (i32.wrap/i64 (call $thread_spawn (i32.const 0) (i32.const 3) (i32.const 32768))))
+    (set_local $t2 (i32.wrap/i64 (call $thread_spawn (i32.const 0) (i32.const 5) (i32.const 49152))))
+    (set_local $t3 (i32.wrap/i64 (call $thread_spawn (i32.const 0) (i32.const 7) (i32.const 65536))))
+    (call $work (i32.const 11))
+    (drop (call $thread_join (get_local $t1)))
+    (drop (call $thread_join (get_local $t2)))
+    (drop (call $thread_join (get_local $t3)))
+    (drop (call $exit (i64.const 0)))
+  )
+  (start $main)
+)
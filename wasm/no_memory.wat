@@ -0,0 +1,12 @@
+(module
+  (import "abi" "exit" (func $exit (param i64) (result i64)))
+  (import "abi" "assert_eq" (func $assert_eq (param i64) (param i64) (result i64)))
+  (global $counter (mut i32) (i32.const 0))
+  (func $main
+    (set_global $counter (i32.add (get_global $counter) (i32.const 41)))
+    (set_global $counter (i32.add (get_global $counter) (i32.const 1)))
+    (drop (call $assert_eq (i64.extend_u/i32 (get_global $counter)) (i64.const 42)))
+    (drop (call $exit (i64.const 0)))
+  )
+  (start $main)
+)